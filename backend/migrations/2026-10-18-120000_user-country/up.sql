@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN IF NOT EXISTS country VARCHAR(2) NULL;
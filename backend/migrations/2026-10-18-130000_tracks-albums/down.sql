@@ -0,0 +1 @@
+DROP TABLE tracks_albums;
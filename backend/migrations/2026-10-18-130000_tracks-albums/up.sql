@@ -0,0 +1,5 @@
+-- Maps tracks to the album they're on.  Each track is on exactly one album.
+CREATE TABLE tracks_albums (
+  track_id INT PRIMARY KEY NOT NULL REFERENCES spotify_items(id) ON DELETE CASCADE,
+  album_spotify_id VARCHAR(191) NOT NULL
+);
@@ -0,0 +1,8 @@
+-- Follower count of artists over time.  Shared between all users, with at most one entry per
+-- artist per day.
+CREATE TABLE artist_followers_history (
+  artist_id INT NOT NULL REFERENCES spotify_items(id) ON DELETE CASCADE,
+  day DATE NOT NULL,
+  followers BIGINT UNSIGNED NOT NULL,
+  PRIMARY KEY (artist_id, day)
+);
@@ -0,0 +1,212 @@
+//! Admin API token validation.
+//!
+//! Admin routes accept any of the static tokens in `CONF.admin_api_tokens`: the primary
+//! `ADMIN_API_TOKEN` and optionally `ADMIN_API_TOKEN_SECONDARY`.  To rotate, set the new token as
+//! the secondary, update clients, then promote it to the primary and remove the old one.  Which
+//! token was used is recorded in `admin_auth_total` so stale usage is visible during rotation.
+//!
+//! As an alternative to sending a static token, admin routes also accept short-lived,
+//! action-scoped tokens of the form `<action>:<unix_timestamp_seconds>:<signature>` where
+//! `signature` is the URL-safe base64 (no padding) HMAC-SHA256 of
+//! `<action>:<unix_timestamp_seconds>` keyed with `ADMIN_TOKEN_SIGNING_SECRET`.  A token is only
+//! valid for the single action it was signed for and only for `ADMIN_TOKEN_MAX_AGE_SECONDS` after
+//! its timestamp.
+//!
+//! Tokens can be minted from a shell like this:
+//!
+//! ```sh
+//! MSG="update_user:$(date +%s)"
+//! SIG=$(printf '%s' "$MSG" | openssl dgst -sha256 -hmac "$ADMIN_TOKEN_SIGNING_SECRET" -binary \
+//!   | basenc --base64url | tr -d '=')
+//! curl -X POST -d "$MSG:$SIG" "$API_SERVER_URL/update_user"
+//! ```
+
+use base64::Engine;
+use ring::hmac;
+
+/// Tokens with timestamps further than this in the future are rejected.  This allows for a bit of
+/// clock skew between the server and whatever is minting the tokens.
+const MAX_CLOCK_SKEW_SECONDS: i64 = 30;
+
+/// Compares two byte slices in time that only depends on their lengths
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Returns the index of the static token in `accepted_tokens` that matches `token`, if any.  Every
+/// accepted token is compared so that timing doesn't reveal which one matched.
+pub(crate) fn match_static_admin_token(accepted_tokens: &[String], token: &str) -> Option<usize> {
+    let mut matched_ix = None;
+    for (ix, accepted_token) in accepted_tokens.iter().enumerate() {
+        if constant_time_eq(accepted_token.as_bytes(), token.as_bytes()) {
+            matched_ix = Some(ix);
+        }
+    }
+    matched_ix
+}
+
+/// Label used in metrics and logs for the static token at index `ix`
+pub(crate) fn static_token_label(ix: usize) -> &'static str {
+    match ix {
+        0 => "primary",
+        _ => "secondary",
+    }
+}
+
+fn build_message(action: &str, timestamp: i64) -> String { format!("{}:{}", action, timestamp) }
+
+pub(crate) fn sign_admin_token(secret: &str, action: &str, timestamp: i64) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let msg = build_message(action, timestamp);
+    let signature = hmac::sign(&key, msg.as_bytes());
+    format!(
+        "{}:{}",
+        msg,
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(signature.as_ref())
+    )
+}
+
+/// Returns `true` if `token` is a correctly signed token for `action` that hasn't expired as of
+/// `now` (a unix timestamp in seconds).
+pub(crate) fn verify_admin_token(
+    secret: &str,
+    token: &str,
+    action: &str,
+    now: i64,
+    max_age_seconds: i64,
+) -> bool {
+    let mut parts = token.trim().rsplitn(3, ':');
+    let (signature, timestamp, token_action) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(signature), Some(timestamp), Some(token_action)) =>
+            (signature, timestamp, token_action),
+        _ => return false,
+    };
+    if token_action != action {
+        return false;
+    }
+    let timestamp: i64 = match timestamp.parse() {
+        Ok(timestamp) => timestamp,
+        Err(_) => return false,
+    };
+    let too_old = now
+        .checked_sub(timestamp)
+        .is_none_or(|age| age > max_age_seconds);
+    if timestamp > now.saturating_add(MAX_CLOCK_SKEW_SECONDS) || too_old {
+        return false;
+    }
+    let signature = match base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    hmac::verify(
+        &key,
+        build_message(token_action, timestamp).as_bytes(),
+        &signature,
+    )
+    .is_ok()
+}
+
+#[test]
+fn signed_admin_token_validation() {
+    let secret = "super secret";
+    let now = 1_700_000_000;
+    let token = sign_admin_token(secret, "update_user", now);
+
+    assert!(verify_admin_token(secret, &token, "update_user", now, 300));
+    assert!(verify_admin_token(
+        secret,
+        &token,
+        "update_user",
+        now + 300,
+        300
+    ));
+    // Expired
+    assert!(!verify_admin_token(
+        secret,
+        &token,
+        "update_user",
+        now + 301,
+        300
+    ));
+    // Too far in the future
+    assert!(!verify_admin_token(
+        secret,
+        &token,
+        "update_user",
+        now - MAX_CLOCK_SKEW_SECONDS - 1,
+        300
+    ));
+    // Timestamps far enough in the past to overflow the age calculation
+    let ancient_token = sign_admin_token(secret, "update_user", i64::MIN);
+    assert!(!verify_admin_token(
+        secret,
+        &ancient_token,
+        "update_user",
+        now,
+        300
+    ));
+    // Wrong action
+    assert!(!verify_admin_token(
+        secret,
+        &token,
+        "crawl_related_artists",
+        now,
+        300
+    ));
+    // Wrong secret
+    assert!(!verify_admin_token(
+        "other secret",
+        &token,
+        "update_user",
+        now,
+        300
+    ));
+    // Tampered timestamp
+    let tampered = token.replacen(&now.to_string(), &(now + 100).to_string(), 1);
+    assert!(!verify_admin_token(
+        secret,
+        &tampered,
+        "update_user",
+        now + 100,
+        300
+    ));
+    // Garbage
+    assert!(!verify_admin_token(
+        secret,
+        "update_user",
+        "update_user",
+        now,
+        300
+    ));
+    assert!(!verify_admin_token(secret, "", "update_user", now, 300));
+}
+
+#[test]
+fn static_admin_token_matching() {
+    let primary_only = vec!["primary token".to_owned()];
+    assert_eq!(
+        match_static_admin_token(&primary_only, "primary token"),
+        Some(0)
+    );
+    assert_eq!(
+        match_static_admin_token(&primary_only, "secondary token"),
+        None
+    );
+
+    let both = vec!["primary token".to_owned(), "secondary token".to_owned()];
+    assert_eq!(match_static_admin_token(&both, "primary token"), Some(0));
+    assert_eq!(match_static_admin_token(&both, "secondary token"), Some(1));
+    assert_eq!(static_token_label(1), "secondary");
+
+    // Rejected
+    assert_eq!(match_static_admin_token(&both, "other token"), None);
+    assert_eq!(match_static_admin_token(&both, "primary toke"), None);
+    assert_eq!(match_static_admin_token(&both, "primary token "), None);
+    assert_eq!(match_static_admin_token(&both, ""), None);
+    assert_eq!(match_static_admin_token(&[], "primary token"), None);
+}
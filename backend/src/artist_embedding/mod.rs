@@ -1,5 +1,11 @@
+use float_ord::FloatOrd;
 use fnv::FnvHashMap as HashMap;
-use std::{convert::TryInto, sync::Once};
+use lazy_static::lazy_static;
+use std::{
+    cmp::Reverse,
+    convert::TryInto,
+    sync::{Arc, Mutex, Once},
+};
 
 pub mod map_3d;
 
@@ -177,10 +183,26 @@ fn normalize_vector<const DIMS: usize>(v: &[f32; DIMS]) -> [f32; DIMS] {
     out
 }
 
-/// Just dot product of l2-normalized positions
+/// Just dot product of l2-normalized positions.  The 8-dimensional case used by the embedding is
+/// vectorized on x86_64.
 fn cosine_similarity<const DIMS: usize>(
     normalized_v1: &[f32; DIMS],
     normalized_v2: &[f32; DIMS],
+) -> f32 {
+    #[cfg(target_arch = "x86_64")]
+    if let (Ok(v1), Ok(v2)) = (
+        <&[f32; 8]>::try_from(&normalized_v1[..]),
+        <&[f32; 8]>::try_from(&normalized_v2[..]),
+    ) {
+        return simd::dot_product_8(v1, v2);
+    }
+
+    cosine_similarity_scalar(normalized_v1, normalized_v2)
+}
+
+fn cosine_similarity_scalar<const DIMS: usize>(
+    normalized_v1: &[f32; DIMS],
+    normalized_v2: &[f32; DIMS],
 ) -> f32 {
     let mut sum = 0.;
     for i in 0..normalized_v1.len() {
@@ -189,6 +211,45 @@ fn cosine_similarity<const DIMS: usize>(
     sum
 }
 
+/// SSE implementations of the embedding math for 8-dimensional vectors.  SSE is part of the
+/// x86_64 baseline, so no runtime feature detection is needed.
+#[cfg(target_arch = "x86_64")]
+mod simd {
+    use std::arch::x86_64::*;
+
+    /// Adds up the 4 lanes of `v`
+    #[inline(always)]
+    unsafe fn horizontal_sum(v: __m128) -> f32 {
+        let sums = _mm_add_ps(v, _mm_movehl_ps(v, v));
+        let shuffled = _mm_shuffle_ps(sums, sums, 0b01);
+        _mm_cvtss_f32(_mm_add_ss(sums, shuffled))
+    }
+
+    #[inline]
+    pub(super) fn dot_product_8(v1: &[f32; 8], v2: &[f32; 8]) -> f32 {
+        unsafe {
+            let lo = _mm_mul_ps(_mm_loadu_ps(v1.as_ptr()), _mm_loadu_ps(v2.as_ptr()));
+            let hi = _mm_mul_ps(
+                _mm_loadu_ps(v1.as_ptr().add(4)),
+                _mm_loadu_ps(v2.as_ptr().add(4)),
+            );
+            horizontal_sum(_mm_add_ps(lo, hi))
+        }
+    }
+
+    #[inline]
+    pub(super) fn distance_8(v1: &[f32; 8], v2: &[f32; 8]) -> f32 {
+        unsafe {
+            let lo = _mm_sub_ps(_mm_loadu_ps(v2.as_ptr()), _mm_loadu_ps(v1.as_ptr()));
+            let hi = _mm_sub_ps(
+                _mm_loadu_ps(v2.as_ptr().add(4)),
+                _mm_loadu_ps(v1.as_ptr().add(4)),
+            );
+            horizontal_sum(_mm_add_ps(_mm_mul_ps(lo, lo), _mm_mul_ps(hi, hi))).sqrt()
+        }
+    }
+}
+
 fn weighted_midpoint<const DIMS: usize>(
     v1: &[f32; DIMS],
     v1_bias: f32,
@@ -203,6 +264,18 @@ fn weighted_midpoint<const DIMS: usize>(
 }
 
 fn distance<const DIMS: usize>(v1: &[f32; DIMS], v2: &[f32; DIMS]) -> f32 {
+    #[cfg(target_arch = "x86_64")]
+    if let (Ok(v1), Ok(v2)) = (
+        <&[f32; 8]>::try_from(&v1[..]),
+        <&[f32; 8]>::try_from(&v2[..]),
+    ) {
+        return simd::distance_8(v1, v2);
+    }
+
+    distance_scalar(v1, v2)
+}
+
+fn distance_scalar<const DIMS: usize>(v1: &[f32; DIMS], v2: &[f32; DIMS]) -> f32 {
     v1.iter()
         .zip(v2.iter())
         .fold(0., |acc, (&v1_n, &v2_n)| {
@@ -216,12 +289,100 @@ pub enum ArtistEmbeddingError {
     ArtistIdNotFound(usize),
 }
 
+/// Number of top artists retained in cached average artist rankings.  Requests for more than this
+/// many artists bypass the cache.
+const CACHED_AVERAGE_ARTISTS_RANKING_SIZE: usize = 250;
+/// Max number of average artist rankings to keep cached
+const AVERAGE_ARTISTS_CACHE_CAPACITY: usize = 128;
+
+/// `(artist_1_id, artist_1_bias bits, artist_2_id, artist_2_bias bits)`
+type AverageArtistsCacheKey = (usize, u32, usize, u32);
+
+/// Small LRU cache of average artist rankings.  Entries are kept ordered from most to least
+/// recently used; the capacity is small enough that linear scans are cheaper than hashing.
+struct AverageArtistsCache {
+    entries: Vec<(AverageArtistsCacheKey, Arc<Vec<AverageArtistDescriptor>>)>,
+}
+
+impl AverageArtistsCache {
+    fn get(&mut self, key: &AverageArtistsCacheKey) -> Option<Arc<Vec<AverageArtistDescriptor>>> {
+        let ix = self.entries.iter().position(|(k, _)| k == key)?;
+        let entry = self.entries.remove(ix);
+        let ranking = Arc::clone(&entry.1);
+        self.entries.insert(0, entry);
+        Some(ranking)
+    }
+
+    fn insert(&mut self, key: AverageArtistsCacheKey, ranking: Arc<Vec<AverageArtistDescriptor>>) {
+        self.entries.retain(|(k, _)| *k != key);
+        self.entries.truncate(AVERAGE_ARTISTS_CACHE_CAPACITY - 1);
+        self.entries.insert(0, (key, ranking));
+    }
+}
+
+lazy_static! {
+    static ref AVERAGE_ARTISTS_CACHE: Mutex<AverageArtistsCache> =
+        Mutex::new(AverageArtistsCache {
+            entries: Vec::with_capacity(AVERAGE_ARTISTS_CACHE_CAPACITY),
+        });
+}
+
+/// Returns the `count` artists closest to the weighted midpoint between the two provided artists.
+///
+/// Rankings are computed for the top `CACHED_AVERAGE_ARTISTS_RANKING_SIZE` artists and cached, so
+/// repeated requests for the same pair with increasing `count` are served by slicing the cached
+/// ranking rather than scanning the whole embedding again.
 pub fn get_average_artists(
     artist_1_id: usize,
     artist_1_bias: f32,
     artist_2_id: usize,
     artist_2_bias: f32,
     count: usize,
+) -> Result<Vec<AverageArtistDescriptor>, ArtistEmbeddingError> {
+    if count > CACHED_AVERAGE_ARTISTS_RANKING_SIZE {
+        return compute_average_artists(
+            artist_1_id,
+            artist_1_bias,
+            artist_2_id,
+            artist_2_bias,
+            count,
+        );
+    }
+
+    let key = (
+        artist_1_id,
+        artist_1_bias.to_bits(),
+        artist_2_id,
+        artist_2_bias.to_bits(),
+    );
+    let cached = AVERAGE_ARTISTS_CACHE.lock().unwrap().get(&key);
+    let ranking = match cached {
+        Some(ranking) => ranking,
+        None => {
+            let ranking = Arc::new(compute_average_artists(
+                artist_1_id,
+                artist_1_bias,
+                artist_2_id,
+                artist_2_bias,
+                CACHED_AVERAGE_ARTISTS_RANKING_SIZE,
+            )?);
+            AVERAGE_ARTISTS_CACHE
+                .lock()
+                .unwrap()
+                .insert(key, Arc::clone(&ranking));
+            ranking
+        },
+    };
+
+    Ok(ranking[..count].to_vec())
+}
+
+fn compute_average_artists(
+    artist_1_id: usize,
+    artist_1_bias: f32,
+    artist_2_id: usize,
+    artist_2_bias: f32,
+    count: usize,
 ) -> Result<Vec<AverageArtistDescriptor>, ArtistEmbeddingError> {
     let mut out = vec![AverageArtistDescriptor::new_placeholder(); count];
 
@@ -265,14 +426,71 @@ pub fn get_average_artists(
     Ok(out)
 }
 
+/// Returns the internal IDs of the `count` artists most similar to the provided one in the
+/// embedding along with their cosine similarities, most similar first.
+pub fn get_nearest_artists(
+    artist_id: usize,
+    count: usize,
+) -> Result<Vec<(usize, f32)>, ArtistEmbeddingError> {
+    let ctx = get_artist_embedding_ctx();
+    let target_pos = match ctx.artist_position_by_id.get(&artist_id) {
+        Some(pos) => pos,
+        None => return Err(ArtistEmbeddingError::ArtistIdNotFound(artist_id)),
+    };
+
+    let mut similarities: Vec<(usize, f32)> = ctx
+        .artist_position_by_id
+        .iter()
+        .filter(|(&id, _)| id != artist_id)
+        .map(|(&id, pos)| {
+            (
+                id,
+                cosine_similarity(&target_pos.normalized_pos, &pos.normalized_pos),
+            )
+        })
+        .collect();
+    if similarities.len() > count && count > 0 {
+        similarities.select_nth_unstable_by_key(count - 1, |&(_, similarity)| {
+            Reverse(FloatOrd(similarity))
+        });
+    }
+    similarities.truncate(count);
+    similarities.sort_unstable_by_key(|&(_, similarity)| Reverse(FloatOrd(similarity)));
+
+    Ok(similarities)
+}
+
 static ARTIST_EMBEDDING_INITIALIZED: Once = Once::new();
 
-fn parse_positions<const DIMS: usize>(raw_positions: &str) -> HashMap<usize, ArtistPos<DIMS>> {
-    let mut positions_by_id: HashMap<usize, ArtistPos<DIMS>> = HashMap::default();
+/// Incrementally parses artist positions in the w2v text format from chunks of bytes as they're
+/// downloaded so that the whole file never needs to be buffered in memory at once
+struct PositionsParser<const DIMS: usize> {
+    positions_by_id: HashMap<usize, ArtistPos<DIMS>>,
+    /// Bytes of a line that was split across chunks
+    partial_line: Vec<u8>,
+    header_skipped: bool,
+}
+
+impl<const DIMS: usize> PositionsParser<DIMS> {
+    fn new() -> Self {
+        PositionsParser {
+            positions_by_id: HashMap::default(),
+            partial_line: Vec::new(),
+            header_skipped: false,
+        }
+    }
+
+    fn parse_line(&mut self, line: &[u8]) {
+        // The first line is a header containing the artist count and dimension count
+        if !self.header_skipped {
+            self.header_skipped = true;
+            return;
+        }
 
-    for line in raw_positions.lines().skip(1) {
+        let line = std::str::from_utf8(line).expect("Invalid UTF-8 found in raw positions");
+        let line = line.trim_end_matches('\r');
         if line.is_empty() {
-            continue;
+            return;
         }
 
         let mut artist_id = 0usize;
@@ -290,12 +508,44 @@ fn parse_positions<const DIMS: usize>(raw_positions: &str) -> HashMap<usize, Art
             pos[i] = part.parse().expect("Invalid value for dim in pos");
         }
 
-        positions_by_id.insert(artist_id, ArtistPos::new(pos));
+        self.positions_by_id.insert(artist_id, ArtistPos::new(pos));
+    }
+
+    fn push_chunk(&mut self, mut chunk: &[u8]) {
+        while let Some(newline_ix) = chunk.iter().position(|&b| b == b'\n') {
+            let (line, rest) = (&chunk[..newline_ix], &chunk[newline_ix + 1..]);
+            if self.partial_line.is_empty() {
+                self.parse_line(line);
+            } else {
+                let mut full_line = std::mem::take(&mut self.partial_line);
+                full_line.extend_from_slice(line);
+                self.parse_line(&full_line);
+            }
+            chunk = rest;
+        }
+
+        self.partial_line.extend_from_slice(chunk);
+    }
+
+    fn finish(mut self) -> HashMap<usize, ArtistPos<DIMS>> {
+        if !self.partial_line.is_empty() {
+            let last_line = std::mem::take(&mut self.partial_line);
+            self.parse_line(&last_line);
+        }
+        self.positions_by_id
     }
+}
 
-    positions_by_id
+fn parse_positions<const DIMS: usize>(raw_positions: &str) -> HashMap<usize, ArtistPos<DIMS>> {
+    let mut parser = PositionsParser::new();
+    parser.push_chunk(raw_positions.as_bytes());
+    parser.finish()
 }
 
+/// When the server doesn't send a content length, progress is logged every time this many more
+/// bytes have been downloaded
+const EMBEDDING_PROGRESS_LOG_INTERVAL_BYTES: u64 = 16 * 1024 * 1024;
+
 pub async fn init_artist_embedding_ctx(positions_url: &str) {
     let mut should_initialize = false;
     ARTIST_EMBEDDING_INITIALIZED.call_once(|| {
@@ -310,15 +560,46 @@ pub async fn init_artist_embedding_ctx(positions_url: &str) {
         "Initializing artist embedding ctx.  Fetching pre-computed positions from URL={}...",
         positions_url
     );
-    let raw_positions: String = reqwest::get(positions_url)
-        .await
-        .unwrap()
-        .text()
-        .await
-        .unwrap();
-    println!("Successfully fetched artist embedding positions.  Parsing...");
-    let artist_position_by_id = parse_positions(&raw_positions);
-    println!("Successfully parsed artist embedding positions.  Setting into global context.");
+    let mut res = reqwest::get(positions_url).await.unwrap();
+    let content_length = res.content_length().filter(|&len| len > 0);
+    if content_length.is_none() {
+        println!("No content length sent for artist embedding positions; progress is approximate");
+    }
+
+    let mut parser = PositionsParser::new();
+    let mut downloaded_bytes = 0u64;
+    let mut last_logged_progress = 0u64;
+    while let Some(chunk) = res.chunk().await.unwrap() {
+        downloaded_bytes += chunk.len() as u64;
+        parser.push_chunk(&chunk);
+
+        // Log every 10% when the total size is known and every fixed number of bytes otherwise
+        let progress = match content_length {
+            Some(content_length) => downloaded_bytes * 10 / content_length,
+            None => downloaded_bytes / EMBEDDING_PROGRESS_LOG_INTERVAL_BYTES,
+        };
+        if progress > last_logged_progress {
+            last_logged_progress = progress;
+            match content_length {
+                Some(_) => println!(
+                    "Fetched {}% of artist embedding positions ({} artists parsed)",
+                    (progress * 10).min(100),
+                    parser.positions_by_id.len()
+                ),
+                None => println!(
+                    "Fetched {} MB of artist embedding positions ({} artists parsed)",
+                    downloaded_bytes / (1024 * 1024),
+                    parser.positions_by_id.len()
+                ),
+            }
+        }
+    }
+    let artist_position_by_id = parser.finish();
+    println!(
+        "Successfully fetched and parsed {} artist embedding positions.  Setting into global \
+         context.",
+        artist_position_by_id.len()
+    );
 
     let ctx = Box::new(ArtistEmbeddingContext::new(artist_position_by_id));
     unsafe { ARTIST_EMBEDDING_CTX = Box::into_raw(ctx) };
@@ -336,3 +617,74 @@ fn test_cosine_similarity_accuracy() {
     let expected = 0.80182517;
     assert_eq!(actual, expected);
 }
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn test_simd_matches_scalar() {
+    // Deterministic pseudo-random vectors covering a range of magnitudes and signs
+    let mut seed = 0x2545_f491u32;
+    let mut next_val = || {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        (seed as f32 / u32::MAX as f32) * 20. - 10.
+    };
+
+    for _ in 0..1000 {
+        let mut v1 = [0f32; 8];
+        let mut v2 = [0f32; 8];
+        for i in 0..8 {
+            v1[i] = next_val();
+            v2[i] = next_val();
+        }
+        let (normalized_v1, normalized_v2) = (normalize_vector(&v1), normalize_vector(&v2));
+
+        let scalar_similarity = cosine_similarity_scalar(&normalized_v1, &normalized_v2);
+        let simd_similarity = simd::dot_product_8(&normalized_v1, &normalized_v2);
+        assert!((scalar_similarity - simd_similarity).abs() < 1e-5);
+
+        let scalar_distance = distance_scalar(&v1, &v2);
+        let simd_distance = simd::distance_8(&v1, &v2);
+        assert!((scalar_distance - simd_distance).abs() / scalar_distance.max(1.) < 1e-5);
+    }
+}
+
+#[test]
+fn test_average_artists_cache_eviction() {
+    let mut cache = AverageArtistsCache {
+        entries: Vec::new(),
+    };
+    let ranking = Arc::new(Vec::new());
+    for i in 0..AVERAGE_ARTISTS_CACHE_CAPACITY {
+        cache.insert((i, 0, i + 1, 0), Arc::clone(&ranking));
+    }
+    // Touch the oldest entry so that the second oldest is evicted instead
+    assert!(cache.get(&(0, 0, 1, 0)).is_some());
+    cache.insert((usize::MAX, 0, 0, 0), ranking);
+
+    assert_eq!(cache.entries.len(), AVERAGE_ARTISTS_CACHE_CAPACITY);
+    assert!(cache.get(&(0, 0, 1, 0)).is_some());
+    assert!(cache.get(&(1, 0, 2, 0)).is_none());
+    assert!(cache.get(&(usize::MAX, 0, 0, 0)).is_some());
+}
+
+#[test]
+fn positions_parsed_in_chunks_match_whole_parse() {
+    let raw_positions = "3 2\n1 0.5 -1\n20 1.25 2\r\n\n300 -3 4.5";
+    let whole = parse_positions::<2>(raw_positions);
+    assert_eq!(whole.len(), 3);
+
+    for chunk_size in 1..raw_positions.len() {
+        let mut parser = PositionsParser::<2>::new();
+        for chunk in raw_positions.as_bytes().chunks(chunk_size) {
+            parser.push_chunk(chunk);
+        }
+        let chunked = parser.finish();
+
+        assert_eq!(chunked.len(), whole.len());
+        for (id, pos) in &whole {
+            assert_eq!(chunked[id].pos, pos.pos, "chunk_size={}", chunk_size);
+        }
+    }
+    assert_eq!(whole[&300].pos, [-3., 4.5]);
+}
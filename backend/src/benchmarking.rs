@@ -1,5 +1,7 @@
 use std::time::Instant;
 
+use crate::metrics::db_query_duration;
+
 pub(crate) fn start() -> Instant { Instant::now() }
 
 pub(crate) fn mark(last: Instant, msg: &str) {
@@ -7,3 +9,12 @@ pub(crate) fn mark(last: Instant, msg: &str) {
     let diff = now.saturating_duration_since(last);
     info!("[{:?}] {}", diff, msg);
 }
+
+/// Same as `mark`, but also records the elapsed time into the `db_query_duration` histogram under
+/// `query_name`.  Only use this when everything since `last` was database work.
+pub(crate) fn mark_db_query(last: Instant, msg: &str, query_name: &'static str) {
+    let now = Instant::now();
+    let diff = now.saturating_duration_since(last);
+    info!("[{:?}] {}", diff, msg);
+    db_query_duration(query_name).observe(diff.as_nanos() as u64);
+}
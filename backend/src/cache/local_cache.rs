@@ -1,41 +1,110 @@
-use std::io::Write;
+//! In-process cache of the mapping between Spotify IDs and internal IDs.  Mappings are persisted to
+//! a file so that the cache can be warmed on startup.
+//!
+//! There are far more mapped IDs than can reasonably be kept in memory, so each direction of the
+//! mapping is bounded and approximates LRU eviction with two generations.  Mappings that aren't
+//! cached are looked up in the database instead.
 
-use fnv::FnvHashMap as HashMap;
+use std::{hash::Hash, io::Write};
+
+use fnv::{FnvHashMap as HashMap, FnvHashSet as HashSet};
 use lazy_static::lazy_static;
-use tokio::{
-    sync::{Mutex, RwLock},
-    task::spawn_blocking,
-};
+use tokio::{sync::Mutex, task::spawn_blocking};
+
+use crate::{conf::CONF, metrics::spotify_id_map_cache_lookups_total};
 
 const SPOTIFY_ID_CACHE_FILE_NAME: &str = "./spotify_id_map.kv";
 
+struct BoundedMap<K, V> {
+    current: HashMap<K, V>,
+    previous: HashMap<K, V>,
+    /// Max number of entries in each generation
+    generation_capacity: usize,
+}
+
+impl<K: Hash + Eq, V: Clone> BoundedMap<K, V> {
+    fn new(capacity: usize) -> Self {
+        BoundedMap {
+            current: HashMap::default(),
+            previous: HashMap::default(),
+            generation_capacity: capacity / 2,
+        }
+    }
+
+    /// Returns the cached value, promoting it to the current generation if it's in the previous
+    /// one
+    fn get(&mut self, key: K) -> Option<V> {
+        if let Some(val) = self.current.get(&key) {
+            return Some(val.clone());
+        }
+
+        let val = self.previous.remove(&key)?;
+        self.insert(key, val.clone());
+        Some(val)
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        self.current.contains_key(key) || self.previous.contains_key(key)
+    }
+
+    fn insert(&mut self, key: K, val: V) {
+        if self.generation_capacity == 0 {
+            return;
+        }
+
+        if self.current.len() >= self.generation_capacity && !self.current.contains_key(&key) {
+            self.previous = std::mem::take(&mut self.current);
+        }
+        self.previous.remove(&key);
+        self.current.insert(key, val);
+    }
+
+    fn len(&self) -> usize { self.current.len() + self.previous.len() }
+}
+
 lazy_static! {
-    static ref SPOTIFY_ID_BY_INTERNAL_ID_CACHE: RwLock<HashMap<i32, String>> =
-        RwLock::new(HashMap::default());
-    static ref INTERNAL_ID_BY_SPOTIFY_ID_CACHE: RwLock<HashMap<String, i32>> =
-        RwLock::new(HashMap::default());
+    static ref SPOTIFY_ID_BY_INTERNAL_ID_CACHE: Mutex<BoundedMap<i32, String>> =
+        Mutex::new(BoundedMap::new(CONF.spotify_id_map_cache_capacity));
+    static ref INTERNAL_ID_BY_SPOTIFY_ID_CACHE: Mutex<BoundedMap<String, i32>> =
+        Mutex::new(BoundedMap::new(CONF.spotify_id_map_cache_capacity));
     static ref CACHE_FILE_LOCK: Mutex<()> = Mutex::new(());
 }
 
 pub(crate) async fn get_cached_internal_ids_by_spotify_id(
     spotify_ids: impl Iterator<Item = String>,
 ) -> Vec<Option<i32>> {
-    let locked = INTERNAL_ID_BY_SPOTIFY_ID_CACHE.read().await;
-    spotify_ids
-        .map(|spotify_id| locked.get(&spotify_id).cloned())
-        .collect()
+    let mut locked = INTERNAL_ID_BY_SPOTIFY_ID_CACHE.lock().await;
+    let cached: Vec<Option<i32>> = spotify_ids
+        .map(|spotify_id| locked.get(spotify_id))
+        .collect();
+    drop(locked);
+
+    let hit_count = cached.iter().filter(|id| id.is_some()).count();
+    spotify_id_map_cache_lookups_total("hit").inc_by(hit_count as u64);
+    spotify_id_map_cache_lookups_total("miss").inc_by((cached.len() - hit_count) as u64);
+    cached
+}
+
+/// Caches the internal IDs of Spotify IDs that are aliases of other Spotify IDs.  Since the
+/// internal ID belongs to the canonical Spotify ID, these entries aren't added to the reverse
+/// mapping or persisted to the cache file.
+pub(crate) async fn cache_id_aliases(entries: impl Iterator<Item = (String, i32)>) {
+    let mut locked = INTERNAL_ID_BY_SPOTIFY_ID_CACHE.lock().await;
+    for (alias_spotify_id, internal_id) in entries {
+        locked.insert(alias_spotify_id, internal_id);
+    }
 }
 
 pub(crate) async fn cache_id_entries<T: Into<String>>(
     entries: impl Iterator<Item = (i32, T)> + Clone,
 ) {
-    let mut locked = SPOTIFY_ID_BY_INTERNAL_ID_CACHE.write().await;
+    let mut locked = SPOTIFY_ID_BY_INTERNAL_ID_CACHE.lock().await;
     for (internal_id, spotify_id) in entries.clone() {
         locked.insert(internal_id, spotify_id.into());
     }
     drop(locked);
 
-    let mut locked = INTERNAL_ID_BY_SPOTIFY_ID_CACHE.write().await;
+    let mut locked = INTERNAL_ID_BY_SPOTIFY_ID_CACHE.lock().await;
     for (internal_id, spotify_id) in entries.clone() {
         locked.insert(spotify_id.into(), internal_id);
     }
@@ -59,32 +128,156 @@ pub(crate) async fn cache_id_entries<T: Into<String>>(
     }
 }
 
+/// Removes IDs that are cached from the provided internal IDs.  Cached IDs must not be deleted from
+/// the mapping table since the cache would keep returning them.  That includes IDs that have been
+/// evicted from memory but are still in the cache file since they'd be loaded again on startup.
+pub(crate) async fn filter_uncached_internal_ids(internal_ids: Vec<i32>) -> Vec<i32> {
+    let locked = SPOTIFY_ID_BY_INTERNAL_ID_CACHE.lock().await;
+    let internal_ids: Vec<i32> = internal_ids
+        .into_iter()
+        .filter(|internal_id| !locked.contains_key(internal_id))
+        .collect();
+    drop(locked);
+
+    let _locked = CACHE_FILE_LOCK.lock().await;
+    let file_internal_ids: HashSet<i32> = spawn_blocking(|| {
+        read_cache_file_entries()
+            .into_iter()
+            .map(|(_, internal_id)| internal_id)
+            .collect()
+    })
+    .await
+    .unwrap();
+
+    internal_ids
+        .into_iter()
+        .filter(|internal_id| !file_internal_ids.contains(internal_id))
+        .collect()
+}
+
+/// Returns `(spotify_id, internal_id)` pairs for all lines in the cache file, oldest first
+fn read_cache_file_entries() -> Vec<(String, i32)> {
+    let file_content = std::fs::read_to_string(SPOTIFY_ID_CACHE_FILE_NAME).unwrap_or_default();
+
+    file_content
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let mut parts = line.split_whitespace();
+            let internal_id = parts.next().unwrap().parse::<i32>().unwrap();
+            let spotify_id = parts.next().unwrap();
+            (spotify_id.to_string(), internal_id)
+        })
+        .collect()
+}
+
+/// Returns the most recently written `max_count` distinct entries, oldest first.  Entries are
+/// appended to the cache file whenever they're loaded from the database, so the same mapping can
+/// appear many times if it keeps getting evicted.
+fn select_most_recent_entries(entries: Vec<(String, i32)>, max_count: usize) -> Vec<(String, i32)> {
+    let mut seen_spotify_ids: HashSet<String> = HashSet::default();
+    let mut selected: Vec<(String, i32)> = Vec::new();
+    for (spotify_id, internal_id) in entries.into_iter().rev() {
+        if selected.len() >= max_count {
+            break;
+        }
+
+        if seen_spotify_ids.insert(spotify_id.clone()) {
+            selected.push((spotify_id, internal_id));
+        }
+    }
+    selected.reverse();
+    selected
+}
+
+fn write_cache_file(entries: &[(String, i32)]) -> std::io::Result<()> {
+    let tmp_file_name = format!("{}.tmp", SPOTIFY_ID_CACHE_FILE_NAME);
+    let mut file = std::io::BufWriter::new(std::fs::File::create(&tmp_file_name)?);
+    for (spotify_id, internal_id) in entries {
+        writeln!(file, "{} {}", internal_id, spotify_id)?;
+    }
+    file.flush()?;
+    drop(file);
+    std::fs::rename(tmp_file_name, SPOTIFY_ID_CACHE_FILE_NAME)
+}
+
+/// Loads the most recently used mappings from the cache file into the in-memory cache.  The
+/// file is rewritten to only contain those mappings so that it doesn't grow forever.
 pub(crate) async fn init_spotify_id_map_cache() {
+    let _locked = CACHE_FILE_LOCK.lock().await;
     let cache_entries: Vec<_> = spawn_blocking(|| {
-        let file_content = std::fs::read_to_string(SPOTIFY_ID_CACHE_FILE_NAME).unwrap_or_default();
-
-        file_content
-            .lines()
-            .filter(|line| !line.is_empty())
-            .map(|line| {
-                let mut parts = line.split_whitespace();
-                let internal_id = parts.next().unwrap().parse::<i32>().unwrap();
-                let spotify_id = parts.next().unwrap();
-                (spotify_id.to_string(), internal_id)
-            })
-            .collect()
+        let all_entries = read_cache_file_entries();
+        let total_entry_count = all_entries.len();
+        // Each generation is only filled to half of the capacity before being rotated, so loading
+        // more than that would evict some of the loaded entries right away
+        let entries =
+            select_most_recent_entries(all_entries, CONF.spotify_id_map_cache_capacity / 2);
+
+        if entries.len() < total_entry_count {
+            info!(
+                "Compacting Spotify ID map cache file from {} to {} entries",
+                total_entry_count,
+                entries.len()
+            );
+            if let Err(err) = write_cache_file(&entries) {
+                error!("Error compacting Spotify ID map cache file: {:?}", err);
+            }
+        }
+        entries
     })
     .await
     .unwrap();
 
-    let mut spotify_id_by_internal_id_cache = SPOTIFY_ID_BY_INTERNAL_ID_CACHE.write().await;
+    let mut spotify_id_by_internal_id_cache = SPOTIFY_ID_BY_INTERNAL_ID_CACHE.lock().await;
     for (spotify_id, internal_id) in &cache_entries {
         spotify_id_by_internal_id_cache.insert(*internal_id, spotify_id.clone());
     }
     drop(spotify_id_by_internal_id_cache);
 
-    let mut internal_id_by_spotify_id_cache = INTERNAL_ID_BY_SPOTIFY_ID_CACHE.write().await;
+    let mut internal_id_by_spotify_id_cache = INTERNAL_ID_BY_SPOTIFY_ID_CACHE.lock().await;
     for (spotify_id, internal_id) in cache_entries {
         internal_id_by_spotify_id_cache.insert(spotify_id, internal_id);
     }
+    info!(
+        "Loaded {} entries into the Spotify ID map cache",
+        internal_id_by_spotify_id_cache.len()
+    );
+}
+
+#[test]
+fn spotify_id_map_cache_is_bounded() {
+    let mut map: BoundedMap<String, i32> = BoundedMap::new(10);
+    map.insert("hot".into(), 0);
+    for i in 1..100 {
+        map.insert(format!("id-{}", i), i);
+        assert_eq!(map.get("hot".into()), Some(0));
+    }
+
+    assert!(map.len() <= 10);
+    assert_eq!(map.get("id-1".into()), None);
+    assert_eq!(map.get("id-99".into()), Some(99));
+
+    let mut disabled: BoundedMap<String, i32> = BoundedMap::new(0);
+    disabled.insert("a".into(), 1);
+    assert_eq!(disabled.get("a".into()), None);
+}
+
+#[test]
+fn most_recent_cache_file_entries_are_selected() {
+    let entries = vec![
+        ("a".to_owned(), 1),
+        ("b".to_owned(), 2),
+        ("c".to_owned(), 3),
+        ("a".to_owned(), 1),
+    ];
+
+    assert_eq!(select_most_recent_entries(entries.clone(), 2), vec![
+        ("c".to_owned(), 3),
+        ("a".to_owned(), 1)
+    ]);
+    assert_eq!(select_most_recent_entries(entries, 10), vec![
+        ("b".to_owned(), 2),
+        ("c".to_owned(), 3),
+        ("a".to_owned(), 1)
+    ]);
 }
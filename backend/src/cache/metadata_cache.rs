@@ -0,0 +1,293 @@
+//! Bounded in-process cache in front of Redis for Spotify entity metadata.  Artists and tracks that
+//! show up in almost everyone's top lists are requested constantly, and the Redis round-trips for
+//! them dominate `fetch_with_cache` latency on cache hits.
+//!
+//! Entries are spread across shards which are each guarded by their own lock to keep contention
+//! low.  Each shard approximates LRU eviction with two generations: entries are inserted into the
+//! current generation and promoted back into it when they're read from the previous one.  Once the
+//! current generation fills up, the previous generation is dropped and replaced by it.
+//!
+//! The capacity and TTL come from the dynamic config and are picked up on the next access after
+//! it's reloaded.
+
+use std::{
+    any::Any,
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use arc_swap::ArcSwap;
+use fnv::{FnvHashMap as HashMap, FnvHasher};
+use lazy_static::lazy_static;
+
+use crate::conf::dynamic_conf;
+
+const SHARD_COUNT: usize = 16;
+
+type CacheKey = (String, String);
+
+#[derive(Clone)]
+struct Entry {
+    val: Arc<dyn Any + Send + Sync>,
+    inserted_at: Instant,
+}
+
+#[derive(Default)]
+struct Shard {
+    current: HashMap<CacheKey, Entry>,
+    previous: HashMap<CacheKey, Entry>,
+}
+
+impl Shard {
+    fn get(&mut self, key: &CacheKey, ttl: Duration, generation_capacity: usize) -> Option<Entry> {
+        let entry = match self.current.get(key) {
+            Some(entry) => entry.clone(),
+            None => {
+                let entry = self.previous.remove(key)?;
+                self.insert(key.clone(), entry.clone(), generation_capacity);
+                entry
+            },
+        };
+
+        if entry.inserted_at.elapsed() > ttl {
+            self.current.remove(key);
+            return None;
+        }
+        Some(entry)
+    }
+
+    fn insert(&mut self, key: CacheKey, entry: Entry, generation_capacity: usize) {
+        if self.current.len() >= generation_capacity && !self.current.contains_key(&key) {
+            self.previous = std::mem::take(&mut self.current);
+        }
+        self.previous.remove(&key);
+        self.current.insert(key, entry);
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Limits {
+    /// Total capacity as configured, before being clamped
+    capacity: usize,
+    /// Max number of entries in each generation of each shard
+    generation_capacity: usize,
+    ttl: Duration,
+}
+
+impl Limits {
+    fn new(capacity: usize, ttl: Duration) -> Self {
+        // Each shard holds up to two full generations, so anything smaller than this would round
+        // down to a generation capacity of 0 and silently disable the cache
+        let min_capacity = SHARD_COUNT * 2;
+        let clamped_capacity = if capacity > 0 && capacity < min_capacity {
+            warn!(
+                "Metadata local cache capacity of {} is below the minimum of {}; using {} instead",
+                capacity, min_capacity, min_capacity
+            );
+            min_capacity
+        } else {
+            capacity
+        };
+
+        Limits {
+            capacity,
+            generation_capacity: clamped_capacity / SHARD_COUNT / 2,
+            ttl,
+        }
+    }
+}
+
+pub(crate) struct MetadataCache {
+    shards: Vec<Mutex<Shard>>,
+    limits: ArcSwap<Limits>,
+}
+
+impl MetadataCache {
+    pub(crate) fn new(capacity: usize, ttl: Duration) -> Self {
+        MetadataCache {
+            shards: (0..SHARD_COUNT).map(|_| Mutex::default()).collect(),
+            limits: ArcSwap::from_pointee(Limits::new(capacity, ttl)),
+        }
+    }
+
+    /// Applies a new capacity and TTL if they differ from the current ones.  Entries are dropped
+    /// if the capacity changes so that the new bound holds right away.
+    pub(crate) fn set_limits(&self, capacity: usize, ttl: Duration) {
+        let limits = self.limits.load();
+        if limits.capacity == capacity && limits.ttl == ttl {
+            return;
+        }
+
+        let new_limits = Limits::new(capacity, ttl);
+        if new_limits.generation_capacity != limits.generation_capacity {
+            for shard in &self.shards {
+                *shard.lock().unwrap() = Shard::default();
+            }
+        }
+        self.limits.store(Arc::new(new_limits));
+    }
+
+    fn shard(&self, key: &CacheKey) -> &Mutex<Shard> {
+        let mut hasher = FnvHasher::default();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARD_COUNT]
+    }
+
+    /// Returns the cached value for each of the provided keys, or `None` if it's not cached, has
+    /// expired, or was cached with a different type.
+    pub(crate) fn get_many<T: Clone + Send + Sync + 'static>(
+        &self,
+        hash_name: &str,
+        keys: &[&str],
+    ) -> Vec<Option<T>> {
+        let limits = **self.limits.load();
+        if limits.generation_capacity == 0 {
+            return vec![None; keys.len()];
+        }
+
+        keys.iter()
+            .map(|&key| {
+                let key = (hash_name.to_owned(), key.to_owned());
+                let entry = self.shard(&key).lock().unwrap().get(
+                    &key,
+                    limits.ttl,
+                    limits.generation_capacity,
+                )?;
+                entry.val.downcast_ref::<T>().cloned()
+            })
+            .collect()
+    }
+
+    pub(crate) fn set_many<T: Clone + Send + Sync + 'static>(
+        &self,
+        hash_name: &str,
+        kv_pairs: &[(&str, &T)],
+    ) {
+        let generation_capacity = self.limits.load().generation_capacity;
+        if generation_capacity == 0 {
+            return;
+        }
+
+        let inserted_at = Instant::now();
+        for &(key, val) in kv_pairs {
+            let key = (hash_name.to_owned(), key.to_owned());
+            let entry = Entry {
+                val: Arc::new(val.clone()),
+                inserted_at,
+            };
+            self.shard(&key)
+                .lock()
+                .unwrap()
+                .insert(key, entry, generation_capacity);
+        }
+    }
+
+    pub(crate) fn remove(&self, hash_name: &str, key: &str) {
+        let key = (hash_name.to_owned(), key.to_owned());
+        let mut shard = self.shard(&key).lock().unwrap();
+        shard.current.remove(&key);
+        shard.previous.remove(&key);
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                let shard = shard.lock().unwrap();
+                shard.current.len() + shard.previous.len()
+            })
+            .sum()
+    }
+}
+
+lazy_static! {
+    static ref METADATA_CACHE: MetadataCache = {
+        let conf = dynamic_conf();
+        MetadataCache::new(
+            conf.metadata_local_cache_capacity,
+            conf.metadata_local_cache_ttl,
+        )
+    };
+}
+
+/// Returns the global cache with the limits from the current dynamic config applied
+fn metadata_cache() -> &'static MetadataCache {
+    let conf = dynamic_conf();
+    METADATA_CACHE.set_limits(
+        conf.metadata_local_cache_capacity,
+        conf.metadata_local_cache_ttl,
+    );
+    &METADATA_CACHE
+}
+
+pub(crate) fn get_local_hash_items<T: Clone + Send + Sync + 'static>(
+    hash_name: &str,
+    keys: &[&str],
+) -> Vec<Option<T>> {
+    metadata_cache().get_many(hash_name, keys)
+}
+
+pub(crate) fn set_local_hash_items<T: Clone + Send + Sync + 'static>(
+    hash_name: &str,
+    kv_pairs: &[(&str, &T)],
+) {
+    metadata_cache().set_many(hash_name, kv_pairs)
+}
+
+pub(crate) fn remove_local_hash_item(hash_name: &str, key: &str) {
+    metadata_cache().remove(hash_name, key)
+}
+
+#[test]
+fn metadata_cache_is_bounded_and_keeps_recently_used_entries() {
+    let cache = MetadataCache::new(SHARD_COUNT * 2 * 4, Duration::from_secs(60));
+    let keys: Vec<String> = (0..1000).map(|i| format!("artist-{}", i)).collect();
+
+    cache.set_many("artists", &[("hot", &0u32)]);
+    for (i, key) in keys.iter().enumerate() {
+        cache.set_many("artists", &[(key.as_str(), &(i as u32))]);
+        // Keep reading the hot entry so that it's always promoted to the current generation
+        assert_eq!(cache.get_many::<u32>("artists", &["hot"]), vec![Some(0)]);
+    }
+
+    assert!(cache.len() <= SHARD_COUNT * 2 * 4);
+    assert_eq!(cache.get_many::<u32>("artists", &["artist-0"]), vec![None]);
+    assert_eq!(cache.get_many::<u32>("artists", &["artist-999"]), vec![
+        Some(999)
+    ]);
+    // Entries are namespaced by hash name and type
+    assert_eq!(cache.get_many::<u32>("tracks", &["artist-999"]), vec![None]);
+    assert_eq!(cache.get_many::<String>("artists", &["artist-999"]), vec![
+        None
+    ]);
+}
+
+#[test]
+fn metadata_cache_entries_expire() {
+    let cache = MetadataCache::new(1000, Duration::from_millis(0));
+    cache.set_many("artists", &[("a", &1u32)]);
+    std::thread::sleep(Duration::from_millis(2));
+    assert_eq!(cache.get_many::<u32>("artists", &["a"]), vec![None]);
+
+    let cache = MetadataCache::new(0, Duration::from_secs(60));
+    cache.set_many("artists", &[("a", &1u32)]);
+    assert_eq!(cache.get_many::<u32>("artists", &["a"]), vec![None]);
+}
+
+#[test]
+fn small_metadata_cache_capacities_are_clamped() {
+    let cache = MetadataCache::new(10, Duration::from_secs(60));
+    cache.set_many("artists", &[("a", &1u32)]);
+    assert_eq!(cache.get_many::<u32>("artists", &["a"]), vec![Some(1)]);
+
+    // Raising the capacity to the minimum leaves the clamped size, and so the entries, unchanged
+    cache.set_limits(SHARD_COUNT * 2, Duration::from_secs(60));
+    assert_eq!(cache.get_many::<u32>("artists", &["a"]), vec![Some(1)]);
+    // Disabling the cache drops its entries
+    cache.set_limits(0, Duration::from_secs(60));
+    assert_eq!(cache.get_many::<u32>("artists", &["a"]), vec![None]);
+    cache.set_limits(1000, Duration::from_secs(60));
+    assert_eq!(cache.get_many::<u32>("artists", &["a"]), vec![None]);
+}
@@ -1,12 +1,30 @@
 //! Functions for interacting with Redis which caches data from the Spotify API.
 
-use r2d2_redis::{r2d2, redis::Commands, RedisConnectionManager};
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Instant,
+};
+
+use r2d2_redis::{
+    r2d2,
+    redis::{self, Commands, ErrorKind, RedisError, RedisResult},
+    RedisConnectionManager,
+};
 use serde::{Deserialize, Serialize};
 use serde_json;
 
-use crate::conf::CONF;
+use crate::{
+    conf::CONF,
+    metrics::{
+        cache_deserialize_failures_total, redis_command_time, redis_errors_total,
+        redis_pool_checkout_time, redis_pool_connections,
+    },
+    models::METADATA_CACHE_SCHEMA_VERSION,
+};
 
 pub mod local_cache;
+pub mod metadata_cache;
 
 lazy_static::lazy_static! {
     pub static ref REDIS_CONN_POOL: r2d2::Pool<RedisConnectionManager> = {
@@ -26,16 +44,192 @@ lazy_static::lazy_static! {
     };
 }
 
+/// Set once the pool has been initialized so that sampling pool metrics doesn't force it to be
+/// created early.
+static REDIS_CONN_POOL_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
 pub fn get_redis_conn() -> Result<diesel::r2d2::PooledConnection<RedisConnectionManager>, String> {
-    REDIS_CONN_POOL.get().map_err(|err| -> String {
+    let start = Instant::now();
+    let res = REDIS_CONN_POOL.get();
+    REDIS_CONN_POOL_INITIALIZED.store(true, Ordering::Relaxed);
+    redis_pool_checkout_time().observe(start.elapsed().as_nanos() as u64);
+
+    res.map_err(|err| -> String {
+        redis_errors_total("checkout").inc();
         error!("Error getting client from connection pool: {:?}", err);
         "Error connecting to Spotify metadata cache".into()
     })
 }
 
+/// Records the number of idle and active connections in the Redis pool.  Called periodically along
+/// with the other sampled metrics.
+pub(crate) fn record_redis_pool_metrics() {
+    if !REDIS_CONN_POOL_INITIALIZED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let state = REDIS_CONN_POOL.state();
+    redis_pool_connections("idle").set(state.idle_connections as i64);
+    redis_pool_connections("active").set((state.connections - state.idle_connections) as i64);
+}
+
+fn redis_error_kind(err: &RedisError) -> &'static str {
+    if err.is_timeout() {
+        "timeout"
+    } else if err.is_connection_refusal() || err.is_connection_dropped() {
+        "connection"
+    } else if err.is_io_error() {
+        "io"
+    } else {
+        match err.kind() {
+            ErrorKind::TypeError => "type",
+            ErrorKind::ResponseError | ErrorKind::ExtensionError => "response",
+            _ => "other",
+        }
+    }
+}
+
+/// Runs a Redis command, recording its latency under `operation` and counting any error by kind.
+pub(crate) fn timed_redis_command<T>(
+    operation: &'static str,
+    run: impl FnOnce() -> RedisResult<T>,
+) -> RedisResult<T> {
+    let start = Instant::now();
+    let res = run();
+    redis_command_time(operation).observe(start.elapsed().as_nanos() as u64);
+
+    if let Err(err) = &res {
+        redis_errors_total(redis_error_kind(err)).inc();
+    }
+    res
+}
+
+/// A Redis hash used as a cache
+pub(crate) struct CacheHash {
+    pub base_name: &'static str,
+    /// Versioned hashes hold Spotify entity metadata and have the current
+    /// `METADATA_CACHE_SCHEMA_VERSION` appended to their names by `versioned_hash_name`
+    pub versioned: bool,
+}
+
+impl CacheHash {
+    pub(crate) fn name(&self) -> String {
+        if self.versioned {
+            versioned_hash_name(self.base_name)
+        } else {
+            self.base_name.to_owned()
+        }
+    }
+}
+
+/// Every hash used as a cache.  Add new caches here so that they show up in the admin cache routes
+/// and, if versioned, get purged when the schema version changes.
+pub(crate) const CACHE_HASHES: &[CacheHash] = &[
+    CacheHash {
+        base_name: "artists",
+        versioned: true,
+    },
+    CacheHash {
+        base_name: "tracks",
+        versioned: true,
+    },
+    CacheHash {
+        base_name: "albums",
+        versioned: true,
+    },
+    CacheHash {
+        base_name: "artistSearch",
+        versioned: true,
+    },
+    // Top tracks for markets other than the default are stored in `top-tracks:<market>:<version>`
+    CacheHash {
+        base_name: "top-tracks",
+        versioned: true,
+    },
+    CacheHash {
+        base_name: "related_artists",
+        versioned: false,
+    },
+    CacheHash {
+        base_name: "spotifyIdAliases",
+        versioned: false,
+    },
+];
+
+pub(crate) fn versioned_hash_name(base_name: &str) -> String {
+    format!("{}:{}", base_name, METADATA_CACHE_SCHEMA_VERSION)
+}
+
+/// Returns `true` if the hash name is one of the `CACHE_HASHES` or a per-market variant of one of
+/// the versioned ones for the current schema version
+pub(crate) fn is_known_cache_hash_name(hash_name: &str) -> bool {
+    CACHE_HASHES.iter().any(|hash| {
+        hash_name == hash.name()
+            || (hash.versioned
+                && hash_name.starts_with(&format!("{}:", hash.base_name))
+                && !is_stale_versioned_hash_name(hash_name))
+    })
+}
+
+/// Hashes whose names don't end with the current schema version are either from an older version
+/// or from before hash names were versioned at all
+fn is_stale_versioned_hash_name(hash_name: &str) -> bool {
+    match hash_name.rsplit_once(':') {
+        Some((_, version)) => version != METADATA_CACHE_SCHEMA_VERSION.to_string(),
+        None => true,
+    }
+}
+
+/// Deletes all metadata cache hashes that aren't for the current `METADATA_CACHE_SCHEMA_VERSION`,
+/// returning their names.  `UNLINK` is used so that Redis frees the memory in the background.
+pub(crate) fn purge_stale_versioned_hashes() -> Result<Vec<String>, String> {
+    let mut conn = get_redis_conn()?;
+
+    let mut stale_hash_names = Vec::new();
+    for hash in CACHE_HASHES.iter().filter(|hash| hash.versioned) {
+        let pattern = format!("{}*", hash.base_name);
+        let hash_names: Vec<String> = timed_redis_command("scan", || {
+            conn.scan_match::<&str, String>(&pattern)
+                .map(|iter| iter.collect())
+        })
+        .map_err(|err| -> String {
+            error!(
+                "Error scanning for cache hashes matching {}: {:?}",
+                pattern, err
+            );
+            "Error scanning cache".into()
+        })?;
+        stale_hash_names.extend(
+            hash_names
+                .into_iter()
+                .filter(|hash_name| is_stale_versioned_hash_name(hash_name)),
+        );
+    }
+
+    for hash_name in &stale_hash_names {
+        timed_redis_command("unlink", || {
+            redis::cmd("UNLINK").arg(hash_name).query::<()>(&mut *conn)
+        })
+        .map_err(|err| -> String {
+            error!("Error unlinking stale cache hash {}: {:?}", hash_name, err);
+            "Error deleting stale cache hashes".into()
+        })?;
+    }
+
+    Ok(stale_hash_names)
+}
+
 pub(crate) fn set_hash_items<T: Serialize>(
     hash_name: &str,
     kv_pairs: &[(&str, T)],
+) -> Result<(), String> {
+    set_hash_items_with_batch_size(hash_name, kv_pairs, CONF.redis_hash_batch_size)
+}
+
+fn set_hash_items_with_batch_size<T: Serialize>(
+    hash_name: &str,
+    kv_pairs: &[(&str, T)],
+    batch_size: usize,
 ) -> Result<(), String> {
     if kv_pairs.is_empty() {
         return Ok(());
@@ -45,6 +239,7 @@ pub(crate) fn set_hash_items<T: Serialize>(
         .iter()
         .map(|(key, val)| -> Result<(&str, String), String> {
             let serialized: String = serde_json::to_string(val).map_err(|err| -> String {
+                redis_errors_total("serialize").inc();
                 error!("Error serializing value to string: {:?}", err);
                 "Error saving items to cache".into()
             })?;
@@ -53,20 +248,37 @@ pub(crate) fn set_hash_items<T: Serialize>(
         })
         .collect::<Result<Vec<_>, String>>()?;
 
-    get_redis_conn()?
-        .hset_multiple::<&str, &str, String, ()>(hash_name, &kv_pairs_serialized)
-        .map_err(|err| -> String {
-            error!(
-                "Error setting hash items into hash \"{}\": {:?}",
-                hash_name, err
-            );
-            "Error setting values into cache".into()
-        })
+    // Large writes are split into multiple commands so that a single huge `HSET` doesn't block
+    // Redis for everyone else
+    let mut pipe = redis::pipe();
+    for chunk in kv_pairs_serialized.chunks(batch_size) {
+        pipe.hset_multiple(hash_name, chunk).ignore();
+    }
+
+    let mut conn = get_redis_conn()?;
+    timed_redis_command("hset_multiple", || pipe.query::<()>(&mut *conn)).map_err(|err| -> String {
+        error!(
+            "Error setting hash items into hash \"{}\": {:?}",
+            hash_name, err
+        );
+        "Error setting values into cache".into()
+    })
 }
 
+/// Returns the value of each of the provided keys in the hash, or `None` if it isn't set.  Values
+/// that fail to deserialize (usually because they were cached before a struct changed) are treated
+/// as missing and removed from the hash so that callers re-fetch and re-cache them.
 pub(crate) fn get_hash_items<T: for<'de> Deserialize<'de>>(
     hash_name: &str,
     keys: &[&str],
+) -> Result<Vec<Option<T>>, String> {
+    get_hash_items_with_batch_size(hash_name, keys, CONF.redis_hash_batch_size)
+}
+
+fn get_hash_items_with_batch_size<T: for<'de> Deserialize<'de>>(
+    hash_name: &str,
+    keys: &[&str],
+    batch_size: usize,
 ) -> Result<Vec<Option<T>>, String> {
     if keys.is_empty() {
         return Ok(Vec::new());
@@ -74,32 +286,162 @@ pub(crate) fn get_hash_items<T: for<'de> Deserialize<'de>>(
 
     let mut conn = get_redis_conn()?;
 
-    let mut cmd = redis::cmd("HMGET");
-    let cmd = keys
-        .iter()
-        .fold(cmd.arg(hash_name), |acc, key| acc.arg(*key));
+    // See `set_hash_items`.  Results of each chunk are in the same order as its keys, so flattening
+    // them gives values in the same order as `keys`.
+    let mut pipe = redis::pipe();
+    for chunk in keys.chunks(batch_size) {
+        pipe.cmd("HMGET").arg(hash_name).arg(chunk);
+    }
 
-    cmd.query::<Vec<Option<String>>>(&mut *conn)
-        .map_err(|err| -> String {
-            error!("Error pulling data from Redis cache: {:?}", err);
-            "Error pulling data from Redis cache".into()
-        })?
-        .into_iter()
-        .enumerate()
-        .map(|(i, opt): (usize, Option<String>)| match opt {
-            Some(val) => serde_json::from_str(&val).map_err(|err| -> String {
+    let mut corrupt_keys = Vec::new();
+    let vals = timed_redis_command("hmget", || {
+        pipe.query::<Vec<Vec<Option<String>>>>(&mut *conn)
+    })
+    .map_err(|err| -> String {
+        error!("Error pulling data from Redis cache: {:?}", err);
+        "Error pulling data from Redis cache".into()
+    })?
+    .into_iter()
+    .flatten()
+    .enumerate()
+    .map(|(i, opt): (usize, Option<String>)| {
+        let val = opt?;
+        match serde_json::from_str(&val) {
+            Ok(val) => Some(val),
+            Err(err) => {
+                redis_errors_total("deserialize").inc();
+                cache_deserialize_failures_total(hash_name.to_owned()).inc();
+                let key = keys.get(i).copied().unwrap_or("<NO KEY FOUND FOR INDEX>");
                 error!(
-                    "Error deserializing value of {}: {:?}; key={}; val={}",
+                    "Error deserializing value of {}: {:?}; hash={}; key={}; val={}",
                     std::any::type_name::<T>(),
                     err,
-                    keys.get(i).unwrap_or(&"<NO KEY FOUND FOR INDEX>"),
+                    hash_name,
+                    key,
                     val
                 );
-                "Error reading values from cache".into()
-            }),
-            None => Ok(None),
-        })
-        .collect::<Result<Vec<Option<T>>, String>>()
+                corrupt_keys.push(key);
+                None
+            },
+        }
+    })
+    .collect::<Vec<Option<T>>>();
+
+    if !corrupt_keys.is_empty() {
+        // Best-effort; the values will be overwritten once they're re-fetched anyway
+        if let Err(err) = timed_redis_command("hdel", || {
+            conn.hdel::<&str, &[&str], ()>(hash_name, &corrupt_keys)
+        }) {
+            warn!(
+                "Error deleting corrupt cache entries from hash \"{}\": {:?}",
+                hash_name, err
+            );
+        }
+    }
+
+    Ok(vals)
+}
+
+/// A single cached value as returned by the admin cache inspection route
+#[derive(Serialize)]
+pub(crate) struct RawCacheEntry {
+    pub size_bytes: usize,
+    /// The cached JSON, or the raw string if it isn't valid JSON
+    pub value: serde_json::Value,
+}
+
+pub(crate) fn get_raw_hash_item(
+    hash_name: &str,
+    key: &str,
+) -> Result<Option<RawCacheEntry>, String> {
+    let mut conn = get_redis_conn()?;
+    let raw: Option<String> =
+        timed_redis_command("hget", || conn.hget(hash_name, key)).map_err(|err| -> String {
+            error!("Error pulling data from Redis cache: {:?}", err);
+            "Error pulling data from Redis cache".into()
+        })?;
+
+    Ok(raw.map(|raw| RawCacheEntry {
+        size_bytes: raw.len(),
+        value: serde_json::from_str(&raw).unwrap_or(serde_json::Value::String(raw)),
+    }))
+}
+
+/// Removes the entry from both Redis and the in-process metadata cache.  Returns `false` if it
+/// wasn't in Redis.
+pub(crate) fn delete_hash_item(hash_name: &str, key: &str) -> Result<bool, String> {
+    metadata_cache::remove_local_hash_item(hash_name, key);
+
+    let mut conn = get_redis_conn()?;
+    let deleted_count: usize =
+        timed_redis_command("hdel", || conn.hdel(hash_name, key)).map_err(|err| -> String {
+            error!("Error deleting entry from Redis cache: {:?}", err);
+            "Error deleting entry from Redis cache".into()
+        })?;
+    Ok(deleted_count > 0)
+}
+
+/// Fields from `INFO memory` that are included in the cache stats
+const MEMORY_INFO_HIGHLIGHT_FIELDS: &[&str] = &[
+    "used_memory_human",
+    "used_memory_peak_human",
+    "used_memory_dataset_perc",
+    "maxmemory_human",
+    "maxmemory_policy",
+    "mem_fragmentation_ratio",
+];
+
+#[derive(Serialize)]
+pub(crate) struct CacheStats {
+    /// Number of entries in each of the `CACHE_HASHES`, by hash name
+    pub hash_lengths: BTreeMap<String, usize>,
+    pub memory: BTreeMap<String, String>,
+}
+
+fn parse_memory_info_highlights(info: &str) -> BTreeMap<String, String> {
+    info.lines()
+        .filter_map(|line| line.trim_end().split_once(':'))
+        .filter(|(field, _)| MEMORY_INFO_HIGHLIGHT_FIELDS.contains(field))
+        .map(|(field, val)| (field.to_owned(), val.to_owned()))
+        .collect()
+}
+
+pub(crate) fn get_cache_stats() -> Result<CacheStats, String> {
+    let mut conn = get_redis_conn()?;
+
+    let mut hash_lengths = BTreeMap::new();
+    for hash in CACHE_HASHES {
+        let hash_name = hash.name();
+        let len: usize =
+            timed_redis_command("hlen", || conn.hlen(&hash_name)).map_err(|err| -> String {
+                error!("Error getting length of hash {}: {:?}", hash_name, err);
+                "Error getting cache stats".into()
+            })?;
+        hash_lengths.insert(hash_name, len);
+    }
+
+    let memory_info: String = timed_redis_command("info", || {
+        redis::cmd("INFO").arg("memory").query(&mut *conn)
+    })
+    .map_err(|err| -> String {
+        error!("Error getting Redis memory info: {:?}", err);
+        "Error getting cache stats".into()
+    })?;
+
+    Ok(CacheStats {
+        hash_lengths,
+        memory: parse_memory_info_highlights(&memory_info),
+    })
+}
+
+/// Records that Spotify returned the second ID of each pair when asked for the first
+pub(crate) fn set_spotify_id_aliases(aliases: &[(&str, &str)]) -> Result<(), String> {
+    set_hash_items(&CONF.spotify_id_aliases_hash_name, aliases)
+}
+
+/// Returns the ID that Spotify returns for each of the provided IDs if it differs from the ID
+pub(crate) fn get_spotify_id_aliases(spotify_ids: &[&str]) -> Result<Vec<Option<String>>, String> {
+    get_hash_items(&CONF.spotify_id_aliases_hash_name, spotify_ids)
 }
 
 #[test]
@@ -121,3 +463,136 @@ fn cache_set_get() {
         Some(Foo("val3".into()))
     ]);
 }
+
+#[test]
+fn large_cache_reads_and_writes_are_chunked_in_order() {
+    let keys: Vec<String> = (0..2_000).map(|i| format!("key{}", i)).collect();
+    // Leave every third key unset to make sure that missing values stay in the right spots
+    let kv_pairs: Vec<(&str, usize)> = keys
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| i % 3 != 0)
+        .map(|(i, key)| (key.as_str(), i))
+        .collect();
+    set_hash_items("__test_chunked", &kv_pairs).expect("Error setting hash items");
+
+    let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+    let vals: Vec<Option<usize>> =
+        get_hash_items("__test_chunked", &keys).expect("Error fetching hash values");
+    let expected: Vec<Option<usize>> = (0..2_000)
+        .map(|i| if i % 3 == 0 { None } else { Some(i) })
+        .collect();
+    assert_eq!(vals, expected);
+}
+
+/// Compares the latency of large hash reads and writes that are split into pipelined batches with
+/// ones sent as a single command.  Requires a running Redis; run with
+/// `cargo test --release redis_hash_batch_size_latency_comparison -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn redis_hash_batch_size_latency_comparison() {
+    const ITERATIONS: u32 = 50;
+
+    let keys: Vec<String> = (0..2_000).map(|i| format!("key{}", i)).collect();
+    let kv_pairs: Vec<(&str, usize)> = keys
+        .iter()
+        .enumerate()
+        .map(|(i, key)| (key.as_str(), i))
+        .collect();
+    let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+
+    for batch_size in [500, usize::MAX] {
+        let mut write_time = std::time::Duration::default();
+        let mut read_time = std::time::Duration::default();
+        for _ in 0..ITERATIONS {
+            let start = Instant::now();
+            set_hash_items_with_batch_size("__test_batch_latency", &kv_pairs, batch_size)
+                .expect("Error setting hash items");
+            write_time += start.elapsed();
+
+            let start = Instant::now();
+            let vals: Vec<Option<usize>> =
+                get_hash_items_with_batch_size("__test_batch_latency", &keys, batch_size)
+                    .expect("Error fetching hash values");
+            read_time += start.elapsed();
+            assert_eq!(vals.len(), keys.len());
+        }
+        println!(
+            "batch_size={}: avg write {:?}, avg read {:?} for {} keys",
+            batch_size,
+            write_time / ITERATIONS,
+            read_time / ITERATIONS,
+            keys.len()
+        );
+    }
+}
+
+#[test]
+fn corrupt_cache_entries_are_treated_as_missing() {
+    #[derive(Deserialize, PartialEq, Debug)]
+    struct Foo(String);
+
+    let mut conn = get_redis_conn().expect("Error connecting to Redis");
+    conn.hset_multiple::<&str, &str, &str, ()>("__test_corrupt", &[
+        ("valid", "\"val1\""),
+        ("invalid", "{not json"),
+        ("wrong_type", "123"),
+    ])
+    .expect("Error setting hash items");
+
+    let vals: Vec<Option<Foo>> = get_hash_items("__test_corrupt", &[
+        "valid",
+        "invalid",
+        "missing",
+        "wrong_type",
+    ])
+    .expect("Corrupt entries shouldn't fail the whole read");
+    assert_eq!(vals, vec![Some(Foo("val1".into())), None, None, None]);
+
+    let remaining_keys: Vec<String> = conn
+        .hkeys("__test_corrupt")
+        .expect("Error listing hash keys");
+    assert_eq!(remaining_keys, vec!["valid".to_owned()]);
+}
+
+#[test]
+fn stale_versioned_hash_names_are_detected() {
+    let current = versioned_hash_name("artists");
+    assert!(!is_stale_versioned_hash_name(&current));
+    assert!(!is_stale_versioned_hash_name(&versioned_hash_name(
+        "top-tracks:de"
+    )));
+
+    assert!(is_stale_versioned_hash_name("artists"));
+    assert!(is_stale_versioned_hash_name("artists_v2"));
+    assert!(is_stale_versioned_hash_name("top-tracks:de"));
+    assert!(is_stale_versioned_hash_name(&format!(
+        "artists:{}",
+        METADATA_CACHE_SCHEMA_VERSION + 1
+    )));
+}
+
+#[test]
+fn cache_hash_names_are_recognized() {
+    assert!(is_known_cache_hash_name(&versioned_hash_name("artists")));
+    assert!(is_known_cache_hash_name(&versioned_hash_name(
+        "top-tracks:de"
+    )));
+    assert!(is_known_cache_hash_name("related_artists"));
+
+    assert!(!is_known_cache_hash_name("artists"));
+    assert!(!is_known_cache_hash_name("related_artists:1"));
+    assert!(!is_known_cache_hash_name("some_other_hash"));
+}
+
+#[test]
+fn memory_info_highlights_are_parsed() {
+    let info = "# Memory\r\nused_memory:1048576\r\nused_memory_human:1.00M\r\nmaxmemory_policy:\
+                allkeys-lru\r\nmem_fragmentation_ratio:1.23\r\n";
+    let highlights = parse_memory_info_highlights(info);
+
+    assert_eq!(highlights.len(), 3);
+    assert_eq!(highlights["used_memory_human"], "1.00M");
+    assert_eq!(highlights["maxmemory_policy"], "allkeys-lru");
+    assert_eq!(highlights["mem_fragmentation_ratio"], "1.23");
+}
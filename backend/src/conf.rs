@@ -1,8 +1,32 @@
-use std::env;
+use std::{collections::HashMap, env, str::FromStr, sync::Arc};
 
+use arc_swap::ArcSwap;
 use base64;
 use chrono::Duration;
 
+use crate::cache::versioned_hash_name;
+
+/// Compression codec used for parquet files written to external storage.  Reads always detect
+/// the codec from the file metadata, so this can be changed without re-writing existing files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ExternalStorageCompression {
+    Gzip,
+    Zstd,
+}
+
+impl std::str::FromStr for ExternalStorageCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "gzip" => Ok(Self::Gzip),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(format!("Unknown external storage compression codec: {}", s)),
+        }
+    }
+}
+
+/// Bootstrap settings that are read once at startup.  Changing any of these requires a restart.
 pub(crate) struct Conf {
     pub client_id: String,
     pub client_secret: String,
@@ -12,14 +36,54 @@ pub(crate) struct Conf {
     // Internal Config
     pub artists_cache_hash_name: String,
     pub tracks_cache_hash_name: String,
-    // Scraper config
-    pub min_update_interval: Duration,
-    pub admin_api_token: String,
+    pub albums_cache_hash_name: String,
+    pub artist_search_cache_hash_name: String,
+    /// Maps Spotify IDs that Spotify has answered with a different ID for to the ID it returned
+    pub spotify_id_aliases_hash_name: String,
+    /// Static admin API tokens that are accepted.  The first is the primary token from
+    /// `ADMIN_API_TOKEN`; the second, if set, is `ADMIN_API_TOKEN_SECONDARY` which allows tokens
+    /// to be rotated without updating every client at once.
+    pub admin_api_tokens: Vec<String>,
+    /// Secret used to verify short-lived signed admin tokens.  If not set, only the static
+    /// `admin_api_tokens` are accepted.
+    pub admin_token_signing_secret: Option<String>,
     pub telemetry_server_port: u16,
+    /// How many top tracks and artists to fetch for each timeframe when updating users.  Spotify
+    /// returns at most 50 per request, so more than that are fetched in two pages.  At most 99.
+    pub top_entity_fetch_count: usize,
+    /// Max time to wait when establishing a connection to the Spotify API
+    pub spotify_api_connect_timeout: std::time::Duration,
+    /// Max total time for a single request to the Spotify API, including reading the response
+    pub spotify_api_request_timeout: std::time::Duration,
+    /// Idle connections to the Spotify API are closed after this long
+    pub spotify_api_pool_idle_timeout: std::time::Duration,
+    /// The shared reqwest client is rebuilt after this long to pick up DNS changes and the like
+    pub reqwest_client_lifetime: std::time::Duration,
+    /// Albums with fewer than this many tracks in a user's current top tracks are omitted from
+    /// their album stats
+    pub album_stats_min_track_count: usize,
+    /// Max number of Spotify ID <-> internal ID mappings kept in memory.  Mappings that don't fit
+    /// are looked up in the database instead.
+    pub spotify_id_map_cache_capacity: usize,
+    /// Max number of fields read or written by a single Redis hash command.  Larger reads and
+    /// writes are split into multiple pipelined commands.
+    pub redis_hash_batch_size: usize,
+}
+
+fn parse_duration_secs_var(key: &str, default: u64) -> std::time::Duration {
+    let secs = env::var(key)
+        .ok()
+        .map(|secs| {
+            secs.parse()
+                .unwrap_or_else(|_| panic!("Invalid value provided for `{}`; must be a u64", key))
+        })
+        .unwrap_or(default);
+    std::time::Duration::from_secs(secs)
 }
 
 impl Conf {
     pub(crate) fn build_from_env() -> Self {
+        lazy_static::initialize(&PROCESS_ENV);
         dotenv::dotenv().expect("dotenv file parsing failed");
 
         Conf {
@@ -32,23 +96,73 @@ impl Conf {
             website_url: env::var("WEBSITE_URL").expect("The `WEBSITE_URL` must be set."),
             redis_url: env::var("REDIS_URL")
                 .expect("The `REDIS_URL` environment variable must be set."),
-            artists_cache_hash_name: "artists".into(),
-            tracks_cache_hash_name: "tracks".into(),
-            min_update_interval: Duration::seconds(
-                env::var("MIN_UPDATE_INTERVAL_SECONDS")
-                    .unwrap_or_else(|_| -> String { (60 * 60 * 6).to_string() })
-                    .parse()
-                    .expect(
-                        "Invalid value provided for `MIN_UPDATE_INTERVAL_SECONDS`; must be an \
-                         unsigned integer",
-                    ),
-            ),
-            admin_api_token: env::var("ADMIN_API_TOKEN")
-                .expect("The `ADMIN_API_TOKEN` environment variable must be set"),
+            artists_cache_hash_name: versioned_hash_name("artists"),
+            tracks_cache_hash_name: versioned_hash_name("tracks"),
+            albums_cache_hash_name: versioned_hash_name("albums"),
+            artist_search_cache_hash_name: versioned_hash_name("artistSearch"),
+            spotify_id_aliases_hash_name: "spotifyIdAliases".into(),
+            admin_api_tokens: std::iter::once(
+                env::var("ADMIN_API_TOKEN")
+                    .expect("The `ADMIN_API_TOKEN` environment variable must be set"),
+            )
+            .chain(env::var("ADMIN_API_TOKEN_SECONDARY").ok())
+            .filter(|token| !token.is_empty())
+            .collect(),
+            admin_token_signing_secret: env::var("ADMIN_TOKEN_SIGNING_SECRET")
+                .ok()
+                .filter(|secret| !secret.is_empty()),
             telemetry_server_port: env::var("TELEMETRY_SERVER_PORT")
                 .unwrap_or_else(|_| -> String { "4101".to_string() })
                 .parse()
                 .expect("Invalid value provided for `TELEMETRY_SERVER_PORT`; must be a u16"),
+            top_entity_fetch_count: env::var("TOP_ENTITY_FETCH_COUNT")
+                .ok()
+                .map(|count| {
+                    count
+                        .parse()
+                        .ok()
+                        .filter(|count| (1..=99).contains(count))
+                        .expect("Invalid `TOP_ENTITY_FETCH_COUNT`; must be between 1 and 99")
+                })
+                .unwrap_or(50),
+            spotify_api_connect_timeout: parse_duration_secs_var(
+                "SPOTIFY_API_CONNECT_TIMEOUT_SECONDS",
+                10,
+            ),
+            spotify_api_request_timeout: parse_duration_secs_var(
+                "SPOTIFY_API_REQUEST_TIMEOUT_SECONDS",
+                30,
+            ),
+            spotify_api_pool_idle_timeout: parse_duration_secs_var(
+                "SPOTIFY_API_POOL_IDLE_TIMEOUT_SECONDS",
+                90,
+            ),
+            reqwest_client_lifetime: parse_duration_secs_var(
+                "REQWEST_CLIENT_LIFETIME_SECONDS",
+                60 * 5,
+            ),
+            album_stats_min_track_count: env::var("ALBUM_STATS_MIN_TRACK_COUNT")
+                .unwrap_or_else(|_| -> String { "2".to_string() })
+                .parse()
+                .expect(
+                    "Invalid value provided for `ALBUM_STATS_MIN_TRACK_COUNT`; must be a usize",
+                ),
+            spotify_id_map_cache_capacity: env::var("SPOTIFY_ID_MAP_CACHE_CAPACITY")
+                .unwrap_or_else(|_| -> String { "250000".to_string() })
+                .parse()
+                .expect(
+                    "Invalid value provided for `SPOTIFY_ID_MAP_CACHE_CAPACITY`; must be a usize",
+                ),
+            redis_hash_batch_size: env::var("REDIS_HASH_BATCH_SIZE")
+                .ok()
+                .map(|batch_size| {
+                    batch_size
+                        .parse()
+                        .ok()
+                        .filter(|&batch_size: &usize| batch_size > 0)
+                        .expect("Invalid `REDIS_HASH_BATCH_SIZE`; must be a positive integer")
+                })
+                .unwrap_or(500),
         }
     }
 
@@ -67,6 +181,157 @@ impl Conf {
     }
 }
 
+/// Tuning values that can be changed at runtime without a restart via `reload_dynamic_conf`.
+///
+/// Variables set in the process environment take precedence, falling back to the dotenv file for
+/// variables that aren't set there.
+#[derive(Debug)]
+pub(crate) struct DynamicConf {
+    /// Users updated more recently than this aren't due for an update yet
+    pub min_update_interval: Duration,
+    pub admin_token_max_age_seconds: i64,
+    pub external_storage_compression: ExternalStorageCompression,
+    /// If set, external storage objects are moved under this prefix after their data is restored
+    /// to the database rather than being deleted.
+    pub external_storage_restored_object_archive_prefix: Option<String>,
+    /// Users that haven't viewed their profile in this many days are transferred to external
+    /// storage by bulk transfers that don't specify their own cutoff
+    pub bulk_transfer_default_inactive_days: i64,
+    /// How long to wait for in-flight tasks to finish when shutting down
+    pub shutdown_grace_period_seconds: u64,
+    /// Max number of Spotify entities kept in the in-process cache in front of Redis.  0 disables
+    /// the in-process cache.
+    pub metadata_local_cache_capacity: usize,
+    /// Entities in the in-process cache are re-read from Redis after this long so that metadata
+    /// updates propagate
+    pub metadata_local_cache_ttl: std::time::Duration,
+}
+
+fn parse_var<T: FromStr>(
+    vars: &HashMap<String, String>,
+    key: &str,
+    default: &str,
+    expected: &str,
+) -> Result<T, String> {
+    let val = PROCESS_ENV
+        .get(key)
+        .or_else(|| vars.get(key))
+        .cloned()
+        .unwrap_or_else(|| default.to_owned());
+    val.parse()
+        .map_err(|_| format!("Invalid value provided for `{}`; must be {}", key, expected))
+}
+
+impl DynamicConf {
+    /// Reads the dotenv file and builds a new config from it, returning an error if any of the
+    /// values are invalid.
+    pub(crate) fn load() -> Result<Self, String> {
+        let vars = dotenv::dotenv_iter()
+            .map_err(|err| format!("Error reading dotenv file: {}", err))?
+            .collect::<Result<HashMap<_, _>, _>>()
+            .map_err(|err| format!("Error parsing dotenv file: {}", err))?;
+
+        let min_update_interval_seconds: i64 = parse_var(
+            &vars,
+            "MIN_UPDATE_INTERVAL_SECONDS",
+            &(60 * 60 * 6).to_string(),
+            "a positive integer",
+        )?;
+        if min_update_interval_seconds <= 0 {
+            return Err("`MIN_UPDATE_INTERVAL_SECONDS` must be a positive integer".into());
+        }
+        let admin_token_max_age_seconds: i64 = parse_var(
+            &vars,
+            "ADMIN_TOKEN_MAX_AGE_SECONDS",
+            "300",
+            "a positive integer",
+        )?;
+        if admin_token_max_age_seconds <= 0 {
+            return Err("`ADMIN_TOKEN_MAX_AGE_SECONDS` must be a positive integer".into());
+        }
+        let bulk_transfer_default_inactive_days: i64 = parse_var(
+            &vars,
+            "BULK_TRANSFER_DEFAULT_INACTIVE_DAYS",
+            "120",
+            "a positive integer",
+        )?;
+        if bulk_transfer_default_inactive_days <= 0 {
+            return Err("`BULK_TRANSFER_DEFAULT_INACTIVE_DAYS` must be a positive integer".into());
+        }
+
+        Ok(DynamicConf {
+            min_update_interval: Duration::seconds(min_update_interval_seconds),
+            admin_token_max_age_seconds,
+            external_storage_compression: parse_var(
+                &vars,
+                "EXTERNAL_STORAGE_COMPRESSION",
+                "gzip",
+                "`gzip` or `zstd`",
+            )?,
+            external_storage_restored_object_archive_prefix: parse_var::<String>(
+                &vars,
+                "EXTERNAL_STORAGE_RESTORED_OBJECT_ARCHIVE_PREFIX",
+                "",
+                "a string",
+            )
+            .map(|prefix| Some(prefix).filter(|prefix| !prefix.is_empty()))?,
+            bulk_transfer_default_inactive_days,
+            shutdown_grace_period_seconds: parse_var(
+                &vars,
+                "SHUTDOWN_GRACE_PERIOD_SECONDS",
+                "60",
+                "an unsigned integer",
+            )?,
+            metadata_local_cache_capacity: parse_var(
+                &vars,
+                "METADATA_LOCAL_CACHE_CAPACITY",
+                "10000",
+                "an unsigned integer",
+            )?,
+            metadata_local_cache_ttl: parse_var(
+                &vars,
+                "METADATA_LOCAL_CACHE_TTL_SECONDS",
+                "60",
+                "an unsigned integer",
+            )
+            .map(std::time::Duration::from_secs)?,
+        })
+    }
+}
+
 lazy_static::lazy_static! {
+    /// The process environment as it was before the dotenv file was loaded into it, so that dynamic
+    /// config reloads can tell real env vars apart from values copied out of an older dotenv file
+    pub(crate) static ref PROCESS_ENV: HashMap<String, String> = env::vars().collect();
     pub(crate) static ref CONF: Conf = Conf::build_from_env();
+    static ref DYNAMIC_CONF: ArcSwap<DynamicConf> = ArcSwap::from_pointee(
+        DynamicConf::load().expect("Invalid dynamic config")
+    );
+}
+
+/// Returns the current dynamic config.  The returned handle is a snapshot; it won't reflect
+/// reloads that happen while it's held, so it shouldn't be kept around longer than a single
+/// request or task iteration.
+pub(crate) fn dynamic_conf() -> Arc<DynamicConf> { DYNAMIC_CONF.load_full() }
+
+/// Re-reads the dotenv file and swaps in the new dynamic config.  If the new config is invalid,
+/// the old one is left in place and an error is returned.
+pub(crate) fn reload_dynamic_conf() -> Result<Arc<DynamicConf>, String> {
+    let new_conf = Arc::new(DynamicConf::load()?);
+    DYNAMIC_CONF.store(Arc::clone(&new_conf));
+    Ok(new_conf)
+}
+
+#[test]
+fn edited_dotenv_values_win_after_reload() {
+    const KEY: &str = "TEST_DYNAMIC_CONF_EDITED_VAR";
+
+    lazy_static::initialize(&PROCESS_ENV);
+    // Mimics `dotenv::dotenv()` copying the file's original value into the environment at startup
+    env::set_var(KEY, "1");
+
+    // The file has since been edited
+    let mut vars = HashMap::new();
+    vars.insert(KEY.to_owned(), "2".to_owned());
+    assert_eq!(parse_var::<u64>(&vars, KEY, "0", "an integer"), Ok(2));
 }
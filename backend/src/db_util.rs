@@ -1,6 +1,6 @@
 use std::fmt::Debug;
 
-use chrono::{NaiveDateTime, Utc};
+use chrono::{NaiveDate, NaiveDateTime, Utc};
 use diesel::{
     mysql::{Mysql, MysqlConnection},
     prelude::*,
@@ -11,18 +11,38 @@ use fnv::{FnvHashMap as HashMap, FnvHashSet as HashSet};
 use futures::Future;
 use rocket::{http::Status, response::status};
 use serde::Serialize;
+use tokio::task::block_in_place;
 
 use crate::{
-    benchmarking::{mark, start},
-    cache::local_cache::{cache_id_entries, get_cached_internal_ids_by_spotify_id},
+    benchmarking::{mark, mark_db_query, start},
+    cache::local_cache::{
+        cache_id_aliases, cache_id_entries, filter_uncached_internal_ids,
+        get_cached_internal_ids_by_spotify_id,
+    },
+    metrics::db_query_duration,
     models::{
-        Artist, ArtistGenrePair, ArtistRankHistoryResItem, HasSpotifyId, NewRelatedArtistEntry,
-        NewSpotifyIdMapping, SpotifyIdMapping, StatsHistoryQueryResItem, TimeFrames, Track,
-        TrackArtistPair, User,
+        AlbumRankQueryResItem, Artist, ArtistFollowersHistoryItem, ArtistGenrePair,
+        ArtistRankHistoryResItem, BestRankingQueryResItem, GenreCountQueryResItem,
+        GenreRankQueryResItem, HasSpotifyId, NewArtistFollowersEntry, NewRelatedArtistEntry,
+        NewSpotifyIdMapping, SortOrder, SpotifyIdMapping, StatsHistoryQueryResItem, TimeFrames,
+        TopArtistDetail, Track, TrackAlbumPair, TrackArtistPair, User,
     },
     DbConn,
 };
 
+/// Runs `query_fn` with a pooled connection, recording how long it took (including time spent
+/// waiting for a connection) into the `db_query_duration` histogram under `query_name`.
+pub(crate) async fn timed_query<T: Send + 'static>(
+    query_name: &'static str,
+    conn: &DbConn,
+    query_fn: impl FnOnce(&mut MysqlConnection) -> T + Send + 'static,
+) -> T {
+    let tok = start();
+    let res = conn.run(query_fn).await;
+    db_query_duration(query_name).observe(tok.elapsed().as_nanos() as u64);
+    res
+}
+
 pub(crate) async fn get_user_by_spotify_id(
     conn: &DbConn,
     supplied_spotify_id: String,
@@ -60,6 +80,163 @@ struct StatsQueryResultItem {
     spotify_id: String,
 }
 
+/// Keeps only the items that are from the latest update of their timeframe.  Partial updates only
+/// store snapshots for the timeframes that were captured, so each timeframe can have a different
+/// latest update.  `items` are `(timeframe, update_time, item)`.
+fn retain_latest_timeframe_items<T>(
+    items: Vec<(u8, NaiveDateTime, T)>,
+    latest_update_times: &[(u8, NaiveDateTime)],
+) -> Vec<(u8, T)> {
+    items
+        .into_iter()
+        .filter(|(timeframe, update_time, _)| {
+            latest_update_times.contains(&(*timeframe, *update_time))
+        })
+        .map(|(timeframe, _, item)| (timeframe, item))
+        .collect()
+}
+
+/// Returns the time of the latest artist rank snapshot at or before `at` for each timeframe.
+async fn get_latest_artist_update_times_by_timeframe(
+    conn: &DbConn,
+    user_id: i64,
+    at: NaiveDateTime,
+) -> QueryResult<Vec<(u8, NaiveDateTime)>> {
+    use crate::schema::artist_rank_snapshots::dsl;
+
+    let query = dsl::artist_rank_snapshots
+        .filter(dsl::user_id.eq(user_id))
+        .filter(dsl::update_time.le(at))
+        .group_by(dsl::timeframe)
+        .select((dsl::timeframe, diesel::dsl::max(dsl::update_time)));
+    let latest: Vec<(u8, Option<NaiveDateTime>)> = timed_query(
+        "latest_artist_update_times_by_timeframe",
+        conn,
+        move |conn| query.load(conn),
+    )
+    .await?;
+    Ok(latest
+        .into_iter()
+        .filter_map(|(timeframe, update_time)| Some((timeframe, update_time?)))
+        .collect())
+}
+
+/// Returns the time of the latest track rank snapshot at or before `at` for each timeframe.
+async fn get_latest_track_update_times_by_timeframe(
+    conn: &DbConn,
+    user_id: i64,
+    at: NaiveDateTime,
+) -> QueryResult<Vec<(u8, NaiveDateTime)>> {
+    use crate::schema::track_rank_snapshots::dsl;
+
+    let query = dsl::track_rank_snapshots
+        .filter(dsl::user_id.eq(user_id))
+        .filter(dsl::update_time.le(at))
+        .group_by(dsl::timeframe)
+        .select((dsl::timeframe, diesel::dsl::max(dsl::update_time)));
+    let latest: Vec<(u8, Option<NaiveDateTime>)> = timed_query(
+        "latest_track_update_times_by_timeframe",
+        conn,
+        move |conn| query.load(conn),
+    )
+    .await?;
+    Ok(latest
+        .into_iter()
+        .filter_map(|(timeframe, update_time)| Some((timeframe, update_time?)))
+        .collect())
+}
+
+/// Returns the time of the user's most recent artist rank snapshot, or `None` if they have none.
+pub(crate) async fn get_last_artist_update_time(
+    conn: &DbConn,
+    user_id: i64,
+) -> QueryResult<Option<NaiveDateTime>> {
+    use crate::schema::artist_rank_snapshots;
+
+    let query = artist_rank_snapshots::table
+        .filter(artist_rank_snapshots::dsl::user_id.eq(user_id))
+        .select(artist_rank_snapshots::dsl::update_time)
+        .order_by(artist_rank_snapshots::dsl::update_time.desc());
+    conn.run(move |conn| query.first(conn).optional()).await
+}
+
+/// Picks whichever of the closest update times before and after `target` is nearer to it
+fn pick_nearest_update_time(
+    target: NaiveDateTime,
+    before: Option<NaiveDateTime>,
+    after: Option<NaiveDateTime>,
+) -> Option<NaiveDateTime> {
+    match (before, after) {
+        (Some(before), Some(after)) =>
+            if target - before <= after - target {
+                Some(before)
+            } else {
+                Some(after)
+            },
+        (before, after) => before.or(after),
+    }
+}
+
+/// Returns the time of the user's artist rank snapshot closest to `target`, or `None` if they have
+/// no artist rank snapshots.
+pub(crate) async fn get_nearest_artist_update_time(
+    conn: &DbConn,
+    user_id: i64,
+    target: NaiveDateTime,
+) -> QueryResult<Option<NaiveDateTime>> {
+    use crate::schema::artist_rank_snapshots::dsl;
+
+    let before_query = dsl::artist_rank_snapshots
+        .filter(dsl::user_id.eq(user_id))
+        .filter(dsl::update_time.le(target))
+        .select(dsl::update_time)
+        .order_by(dsl::update_time.desc());
+    let after_query = dsl::artist_rank_snapshots
+        .filter(dsl::user_id.eq(user_id))
+        .filter(dsl::update_time.gt(target))
+        .select(dsl::update_time)
+        .order_by(dsl::update_time.asc());
+    let (before, after) = timed_query("nearest_artist_update_time", conn, move |conn| {
+        Ok::<_, diesel::result::Error>((
+            before_query.first(conn).optional()?,
+            after_query.first(conn).optional()?,
+        ))
+    })
+    .await?;
+
+    Ok(pick_nearest_update_time(target, before, after))
+}
+
+/// Returns the time of the user's track rank snapshot closest to `target`, or `None` if they have
+/// no track rank snapshots.
+pub(crate) async fn get_nearest_track_update_time(
+    conn: &DbConn,
+    user_id: i64,
+    target: NaiveDateTime,
+) -> QueryResult<Option<NaiveDateTime>> {
+    use crate::schema::track_rank_snapshots::dsl;
+
+    let before_query = dsl::track_rank_snapshots
+        .filter(dsl::user_id.eq(user_id))
+        .filter(dsl::update_time.le(target))
+        .select(dsl::update_time)
+        .order_by(dsl::update_time.desc());
+    let after_query = dsl::track_rank_snapshots
+        .filter(dsl::user_id.eq(user_id))
+        .filter(dsl::update_time.gt(target))
+        .select(dsl::update_time)
+        .order_by(dsl::update_time.asc());
+    let (before, after) = timed_query("nearest_track_update_time", conn, move |conn| {
+        Ok::<_, diesel::result::Error>((
+            before_query.first(conn).optional()?,
+            after_query.first(conn).optional()?,
+        ))
+    })
+    .await?;
+
+    Ok(pick_nearest_update_time(target, before, after))
+}
+
 /// Returns the top artists for the last update for the given user.  Items are returned as
 /// `(timeframe_id, artist)`.
 pub(crate) async fn get_artist_stats(
@@ -67,22 +244,11 @@ pub(crate) async fn get_artist_stats(
     conn: DbConn,
     spotify_access_token: &str,
 ) -> Result<Option<Vec<(u8, Artist)>>, String> {
-    use crate::schema::{
-        artist_rank_snapshots::{self, dsl::*},
-        spotify_items::{self, dsl::*},
-    };
-
     if !user.external_data_retrieved {
         retrieve_cold_data_for_user(&conn, user).await;
     }
 
-    let tok = start();
-    let query = artist_rank_snapshots
-        .filter(user_id.eq(user.id))
-        .select(update_time)
-        .order_by(update_time.desc());
-    let last_update_time: Option<NaiveDateTime> = conn
-        .run(move |conn| query.first(conn).optional())
+    let last_update_time = get_last_artist_update_time(&conn, user.id)
         .await
         .map_err(stringify_diesel_err)?;
     let last_update_time = match last_update_time {
@@ -90,16 +256,61 @@ pub(crate) async fn get_artist_stats(
         None => return Ok(None),
     };
 
-    let query = artist_rank_snapshots
+    get_artist_stats_at(user, conn, spotify_access_token, last_update_time, None).await
+}
+
+/// Returns the top artists for the given user as of `snapshot_update_time`, taking each timeframe
+/// from its latest snapshot at or before that time.  Items are returned as
+/// `(timeframe_id, artist)`.  If `top_n` is set, only the `top_n` highest-ranked artists of each
+/// timeframe are included.
+pub(crate) async fn get_artist_stats_at(
+    user: &User,
+    conn: DbConn,
+    spotify_access_token: &str,
+    snapshot_update_time: NaiveDateTime,
+    top_n: Option<u8>,
+) -> Result<Option<Vec<(u8, Artist)>>, String> {
+    use crate::schema::{
+        artist_rank_snapshots::{self, dsl::*},
+        spotify_items::{self, dsl::*},
+    };
+
+    let latest_update_times =
+        get_latest_artist_update_times_by_timeframe(&conn, user.id, snapshot_update_time)
+            .await
+            .map_err(stringify_diesel_err)?;
+    let update_times: Vec<NaiveDateTime> = latest_update_times
+        .iter()
+        .map(|(_timeframe, update_time)| *update_time)
+        .collect();
+
+    let tok = start();
+    let mut query = artist_rank_snapshots
         .filter(user_id.eq(user.id))
-        .filter(update_time.eq(last_update_time))
+        .filter(update_time.eq_any(update_times))
         .inner_join(spotify_items)
-        .select((artist_rank_snapshots::timeframe, spotify_items::spotify_id));
+        .select((
+            artist_rank_snapshots::timeframe,
+            update_time,
+            spotify_items::spotify_id,
+        ))
+        .into_boxed();
+    if let Some(top_n) = top_n {
+        query = query.filter(ranking.lt(top_n));
+    }
     let artist_stats = conn
-        .run(move |conn| query.load::<StatsQueryResultItem>(conn))
+        .run(move |conn| query.load::<(u8, NaiveDateTime, String)>(conn))
         .await
         .map_err(stringify_diesel_err)?;
-    mark(tok, "Got artist stats from database");
+    let artist_stats: Vec<StatsQueryResultItem> =
+        retain_latest_timeframe_items(artist_stats, &latest_update_times)
+            .into_iter()
+            .map(|(timeframe, spotify_id)| StatsQueryResultItem {
+                timeframe,
+                spotify_id,
+            })
+            .collect();
+    mark_db_query(tok, "Got artist stats from database", "artist_stats");
 
     if artist_stats.is_empty() {
         return Ok(None);
@@ -124,7 +335,7 @@ pub(crate) async fn get_artist_stats(
     Ok(Some(fetched_artists))
 }
 
-async fn retrieve_cold_data_for_user(conn: &DbConn, user: &User) {
+pub(crate) async fn retrieve_cold_data_for_user(conn: &DbConn, user: &User) {
     let tok = start();
     crate::external_storage::download::retrieve_external_user_data(
         conn,
@@ -169,11 +380,22 @@ pub(crate) async fn get_artist_rank_history_single_artist(
         return Ok(None);
     }
 
+    let output = group_artist_rank_history(res);
+    mark(tok, "get_artist_rank_history_single_artist");
+
+    Ok(Some(output))
+}
+
+/// Groups rank snapshots, which must be sorted by update time and non-empty, into the rankings for
+/// each timeframe at each update
+fn group_artist_rank_history(
+    updates: Vec<ArtistRankHistoryResItem>,
+) -> Vec<(NaiveDateTime, [Option<u8>; 3])> {
     let mut output: Vec<(NaiveDateTime, [Option<u8>; 3])> = Vec::new();
 
     let mut cur_update: (NaiveDateTime, [Option<u8>; 3]) =
-        (res.first().unwrap().update_time.clone(), [None; 3]);
-    for update in res {
+        (updates.first().unwrap().update_time.clone(), [None; 3]);
+    for update in updates {
         if update.update_time != cur_update.0 {
             output.push(std::mem::replace(
                 &mut cur_update,
@@ -184,9 +406,70 @@ pub(crate) async fn get_artist_rank_history_single_artist(
         cur_update.1[update.timeframe as usize] = Some(update.ranking);
     }
     output.push(cur_update);
-    mark(tok, "get_artist_rank_history_single_artist");
+    output
+}
 
-    Ok(Some(output))
+/// Like `get_artist_rank_history_single_artist`, but loads the histories of many artists with a
+/// single query.  The output is in the same order as `artist_spotify_ids`, with `None` for artists
+/// that have never been in the user's top artists.
+pub(crate) async fn get_artist_rank_history_multi(
+    user: &User,
+    conn: &DbConn,
+    artist_spotify_ids: Vec<String>,
+) -> Result<Vec<Option<Vec<(NaiveDateTime, [Option<u8>; 3])>>>, String> {
+    use crate::schema::{artist_rank_snapshots::dsl::*, spotify_items::dsl::*};
+
+    if !user.external_data_retrieved {
+        retrieve_cold_data_for_user(conn, user).await;
+    }
+
+    let tok = start();
+    let query = artist_rank_snapshots
+        .filter(user_id.eq(user.id))
+        .inner_join(spotify_items)
+        .filter(spotify_id.eq_any(artist_spotify_ids.clone()))
+        .order_by((spotify_id.asc(), update_time.asc()))
+        .select((spotify_id, update_time, ranking, timeframe));
+    let res: Vec<(String, NaiveDateTime, u8, u8)> = conn
+        .run(move |conn| query.load(conn))
+        .await
+        .map_err(stringify_diesel_err)?;
+
+    let histories = group_artist_rank_histories(&artist_spotify_ids, res);
+    mark(tok, "get_artist_rank_history_multi");
+    Ok(histories)
+}
+
+/// Groups `(spotify_id, update_time, ranking, timeframe)` rows into the rank history of each of
+/// `artist_spotify_ids`, in the same order.  IDs that are repeated get the same history each time.
+fn group_artist_rank_histories(
+    artist_spotify_ids: &[String],
+    rows: Vec<(String, NaiveDateTime, u8, u8)>,
+) -> Vec<Option<Vec<(NaiveDateTime, [Option<u8>; 3])>>> {
+    let mut updates_by_artist_id: HashMap<String, Vec<ArtistRankHistoryResItem>> =
+        HashMap::default();
+    for (artist_spotify_id, update_time, ranking, timeframe) in rows {
+        updates_by_artist_id
+            .entry(artist_spotify_id)
+            .or_default()
+            .push(ArtistRankHistoryResItem {
+                update_time,
+                ranking,
+                timeframe,
+            });
+    }
+    let histories_by_artist_id: HashMap<String, Vec<(NaiveDateTime, [Option<u8>; 3])>> =
+        updates_by_artist_id
+            .into_iter()
+            .map(|(artist_spotify_id, updates)| {
+                (artist_spotify_id, group_artist_rank_history(updates))
+            })
+            .collect();
+
+    artist_spotify_ids
+        .iter()
+        .map(|artist_spotify_id| histories_by_artist_id.get(artist_spotify_id).cloned())
+        .collect()
 }
 
 pub(crate) fn group_updates_by_timestamp<T>(
@@ -224,14 +507,17 @@ async fn get_entity_stats_history<
     F: Future<Output = Result<Vec<T>, String>>,
 >(
     conn: DbConn,
+    query_name: &'static str,
     query: Q,
     spotify_access_token: &str,
     fetch_entities: fn(spotify_access_token: String, entity_spotify_ids: Vec<String>) -> F,
     get_update_item: fn(&StatsHistoryQueryResItem) -> U,
 ) -> Result<Option<(HashMap<String, T>, Vec<(NaiveDateTime, TimeFrames<U>)>)>, String> {
     debug!("{}", diesel::debug_query::<diesel::mysql::Mysql, _>(&query));
-    let entity_stats_opt: Option<Vec<StatsHistoryQueryResItem>> = conn
-        .run(|conn| diesel_not_found_to_none(query.load::<StatsHistoryQueryResItem>(conn)))
+    let entity_stats_opt: Option<Vec<StatsHistoryQueryResItem>> =
+        timed_query(query_name, &conn, |conn| {
+            diesel_not_found_to_none(query.load::<StatsHistoryQueryResItem>(conn))
+        })
         .await?;
 
     let entity_stats: Vec<StatsHistoryQueryResItem> = match entity_stats_opt {
@@ -313,6 +599,7 @@ pub(crate) async fn get_artist_stats_history(
 
         get_entity_stats_history(
             conn,
+            "artist_stats_history",
             query,
             spotify_access_token,
             |spotify_access_token: String, spotify_ids: Vec<String>| async move {
@@ -333,6 +620,7 @@ pub(crate) async fn get_artist_stats_history(
 
         get_entity_stats_history(
             conn,
+            "artist_stats_history",
             query,
             spotify_access_token,
             |spotify_access_token: String, spotify_ids: Vec<String>| async move {
@@ -396,6 +684,7 @@ pub(crate) async fn get_genre_stats_history(
 
     get_entity_stats_history(
         conn,
+        "genre_stats_history",
         query,
         spotify_access_token,
         |spotify_access_token: String, spotify_ids: Vec<String>| async move {
@@ -419,18 +708,11 @@ pub(crate) async fn get_track_stats(
     conn: DbConn,
     spotify_access_token: &str,
 ) -> Result<Option<Vec<(u8, Track)>>, String> {
-    use crate::schema::{spotify_items::dsl::*, track_rank_snapshots::dsl::*};
-
     if !user.external_data_retrieved {
         retrieve_cold_data_for_user(&conn, user).await;
     }
 
-    let query = track_rank_snapshots
-        .filter(user_id.eq(user.id))
-        .select(update_time)
-        .order_by(update_time.desc());
-    let last_update_time: Option<NaiveDateTime> = conn
-        .run(move |conn| query.first(conn).optional())
+    let last_update_time = get_last_track_update_time(&conn, user.id)
         .await
         .map_err(stringify_diesel_err)?;
     let last_update_time = match last_update_time {
@@ -438,21 +720,58 @@ pub(crate) async fn get_track_stats(
         None => return Ok(None),
     };
 
-    let query = track_rank_snapshots
+    get_track_stats_at(user, conn, spotify_access_token, last_update_time, None).await
+}
+
+/// Returns the top tracks for the given user as of `snapshot_update_time`, taking each timeframe
+/// from its latest snapshot at or before that time.  Items are returned as `(timeframe_id, track)`.
+/// If `top_n` is set, only the `top_n` highest-ranked tracks of each timeframe are included.
+pub(crate) async fn get_track_stats_at(
+    user: &User,
+    conn: DbConn,
+    spotify_access_token: &str,
+    snapshot_update_time: NaiveDateTime,
+    top_n: Option<u8>,
+) -> Result<Option<Vec<(u8, Track)>>, String> {
+    use crate::schema::{spotify_items::dsl::*, track_rank_snapshots::dsl::*};
+
+    let latest_update_times =
+        get_latest_track_update_times_by_timeframe(&conn, user.id, snapshot_update_time)
+            .await
+            .map_err(stringify_diesel_err)?;
+    let update_times: Vec<NaiveDateTime> = latest_update_times
+        .iter()
+        .map(|(_timeframe, update_time)| *update_time)
+        .collect();
+
+    let mut query = track_rank_snapshots
         .filter(user_id.eq(user.id))
-        // Only include tracks from the most recent update
-        .filter(update_time.eq(last_update_time))
+        // Only include tracks from the latest update of each timeframe
+        .filter(update_time.eq_any(update_times))
         .order_by(update_time)
         .inner_join(spotify_items)
-        .select((timeframe, spotify_id));
+        .select((timeframe, update_time, spotify_id))
+        .into_boxed();
+    if let Some(top_n) = top_n {
+        query = query.filter(ranking.lt(top_n));
+    }
     let track_stats_opt = conn
-        .run(move |conn| diesel_not_found_to_none(query.load::<StatsQueryResultItem>(conn)))
+        .run(move |conn| diesel_not_found_to_none(query.load::<(u8, NaiveDateTime, String)>(conn)))
         .await?;
 
-    let track_stats = match track_stats_opt {
+    let track_stats: Vec<StatsQueryResultItem> = match track_stats_opt {
         None => return Ok(None),
-        Some(res) => res,
+        Some(res) => retain_latest_timeframe_items(res, &latest_update_times)
+            .into_iter()
+            .map(|(timeframe, spotify_id)| StatsQueryResultItem {
+                timeframe,
+                spotify_id,
+            })
+            .collect(),
     };
+    if track_stats.is_empty() {
+        return Ok(None);
+    }
 
     let track_spotify_ids: Vec<&str> = track_stats
         .iter()
@@ -517,6 +836,7 @@ pub(crate) async fn get_track_stats_history(
 
     let res = get_entity_stats_history(
         conn,
+        "track_stats_history",
         query,
         spotify_access_token,
         |spotify_access_token: String, spotify_ids: Vec<String>| async move {
@@ -559,6 +879,15 @@ pub(crate) async fn get_internal_ids_by_spotify_id<
         return Ok(mapped_ids_mapping);
     }
 
+    // IDs that Spotify has answered with a different ID for are mapped to the internal ID of the ID
+    // that it returned so that both refer to the same entity
+    let aliases = {
+        let missing_ids: Vec<&str> = missing_ids.iter().map(String::as_str).collect();
+        block_in_place(|| crate::cache::get_spotify_id_aliases(&missing_ids))
+    }
+    .unwrap_or_else(|_| vec![None; missing_ids.len()]);
+    let (missing_ids, aliased_ids) = resolve_spotify_id_aliases(missing_ids, aliases);
+
     let spotify_id_items: Vec<NewSpotifyIdMapping> = missing_ids
         .iter()
         .cloned()
@@ -609,9 +938,51 @@ pub(crate) async fn get_internal_ids_by_spotify_id<
         mapped_ids_mapping.insert(mapping.spotify_id, mapping.id);
     }
 
+    if !aliased_ids.is_empty() {
+        let mut resolved_aliases = Vec::with_capacity(aliased_ids.len());
+        for (alias_id, canonical_id) in aliased_ids {
+            // Canonical IDs are only included in the output if they were also requested directly
+            let internal_id = if spotify_ids_v.contains(&&canonical_id) {
+                mapped_ids_mapping.get(&canonical_id).copied()
+            } else {
+                mapped_ids_mapping.remove(&canonical_id)
+            };
+            if let Some(internal_id) = internal_id {
+                mapped_ids_mapping.insert(alias_id.clone(), internal_id);
+                resolved_aliases.push((alias_id, internal_id));
+            }
+        }
+        cache_id_aliases(resolved_aliases.into_iter()).await;
+    }
+
     Ok(mapped_ids_mapping)
 }
 
+/// Replaces each ID that has an alias with the ID that it's an alias of, returning the
+/// de-duplicated IDs to look up along with `(alias_id, canonical_id)` pairs for the replaced IDs.
+fn resolve_spotify_id_aliases(
+    spotify_ids: Vec<String>,
+    aliases: Vec<Option<String>>,
+) -> (Vec<String>, Vec<(String, String)>) {
+    let mut ids_to_look_up: Vec<String> = Vec::with_capacity(spotify_ids.len());
+    let mut seen_ids: HashSet<String> = HashSet::default();
+    let mut aliased_ids = Vec::new();
+    for (spotify_id, alias) in spotify_ids.into_iter().zip(aliases) {
+        let id_to_look_up = match alias {
+            Some(canonical_id) => {
+                aliased_ids.push((spotify_id, canonical_id.clone()));
+                canonical_id
+            },
+            None => spotify_id,
+        };
+        if seen_ids.insert(id_to_look_up.clone()) {
+            ids_to_look_up.push(id_to_look_up);
+        }
+    }
+
+    (ids_to_look_up, aliased_ids)
+}
+
 /// Using the list of all stored track spotify IDs, retrieves fresh track metadata for all of them
 /// and populates the mapping table with artist-track pairs for all of them
 pub(crate) async fn populate_tracks_artists_table(
@@ -824,6 +1195,141 @@ pub(crate) async fn populate_artists_genres_table(
     .map(|_| ())
 }
 
+/// Clears the user's first-seen entries and recomputes them from their rank snapshots, using the
+/// earliest snapshot each artist/track appears in.  Returns the number of artist and track
+/// first-seen rows inserted.
+pub(crate) async fn rebuild_first_seen_for_user(
+    conn: &DbConn,
+    user_id: i64,
+) -> Result<(usize, usize), diesel::result::Error> {
+    use diesel::sql_types::Bigint;
+
+    use crate::schema::{artists_users_first_seen, tracks_users_first_seen};
+
+    conn.run(move |conn| {
+        conn.transaction(|| {
+            diesel::delete(
+                artists_users_first_seen::table
+                    .filter(artists_users_first_seen::dsl::user_id.eq(user_id)),
+            )
+            .execute(conn)?;
+            let artist_count = diesel::sql_query(
+                "INSERT INTO artists_users_first_seen (user_id, mapped_spotify_id, first_seen) \
+                 SELECT user_id, mapped_spotify_id, MIN(update_time) FROM artist_rank_snapshots \
+                 WHERE user_id = ? GROUP BY user_id, mapped_spotify_id",
+            )
+            .bind::<Bigint, _>(user_id)
+            .execute(conn)?;
+
+            diesel::delete(
+                tracks_users_first_seen::table
+                    .filter(tracks_users_first_seen::dsl::user_id.eq(user_id)),
+            )
+            .execute(conn)?;
+            let track_count = diesel::sql_query(
+                "INSERT INTO tracks_users_first_seen (user_id, mapped_spotify_id, first_seen) \
+                 SELECT user_id, mapped_spotify_id, MIN(update_time) FROM track_rank_snapshots \
+                 WHERE user_id = ? GROUP BY user_id, mapped_spotify_id",
+            )
+            .bind::<Bigint, _>(user_id)
+            .execute(conn)?;
+
+            Ok((artist_count, track_count))
+        })
+    })
+    .await
+}
+
+/// Condition matching `spotify_items` rows that aren't referenced by any other table
+const ORPHANED_SPOTIFY_ITEM_CONDITION: &str = r#"
+    NOT EXISTS (
+        SELECT 1 FROM `artist_rank_snapshots`
+        WHERE `artist_rank_snapshots`.`mapped_spotify_id` = `spotify_items`.`id`
+    )
+    AND NOT EXISTS (
+        SELECT 1 FROM `track_rank_snapshots`
+        WHERE `track_rank_snapshots`.`mapped_spotify_id` = `spotify_items`.`id`
+    )
+    AND NOT EXISTS (
+        SELECT 1 FROM `tracks_artists` WHERE `tracks_artists`.`track_id` = `spotify_items`.`id`
+    )
+    AND NOT EXISTS (
+        SELECT 1 FROM `tracks_artists` WHERE `tracks_artists`.`artist_id` = `spotify_items`.`id`
+    )
+    AND NOT EXISTS (
+        SELECT 1 FROM `artists_genres` WHERE `artists_genres`.`artist_id` = `spotify_items`.`id`
+    )
+    AND NOT EXISTS (
+        SELECT 1 FROM `related_artists`
+        WHERE `related_artists`.`artist_spotify_id` = `spotify_items`.`id`
+    )
+    AND NOT EXISTS (
+        SELECT 1 FROM `artists_users_first_seen`
+        WHERE `artists_users_first_seen`.`mapped_spotify_id` = `spotify_items`.`id`
+    )
+    AND NOT EXISTS (
+        SELECT 1 FROM `tracks_users_first_seen`
+        WHERE `tracks_users_first_seen`.`mapped_spotify_id` = `spotify_items`.`id`
+    )
+    AND NOT EXISTS (
+        SELECT 1 FROM `artist_followers_history`
+        WHERE `artist_followers_history`.`artist_id` = `spotify_items`.`id`
+    )
+    AND NOT EXISTS (
+        SELECT 1 FROM `tracks_albums` WHERE `tracks_albums`.`track_id` = `spotify_items`.`id`
+    )
+"#;
+
+const ORPHANED_SPOTIFY_ITEMS_DELETE_BATCH_SIZE: usize = 5_000;
+
+/// Deletes `spotify_items` rows that aren't referenced by any snapshot or mapping table and aren't
+/// in the local ID cache, returning how many were deleted.  If `dry_run` is set, nothing is deleted
+/// and the number of rows that would have been deleted is returned.
+pub(crate) async fn prune_orphaned_spotify_items(
+    conn: &DbConn,
+    dry_run: bool,
+) -> QueryResult<usize> {
+    #[derive(QueryableByName)]
+    struct Id {
+        #[sql_type = "::diesel::sql_types::Integer"]
+        id: i32,
+    }
+
+    let query = diesel::sql_query(format!(
+        "SELECT `id` FROM `spotify_items` WHERE {}",
+        ORPHANED_SPOTIFY_ITEM_CONDITION
+    ));
+    let orphaned_ids: Vec<Id> =
+        timed_query("orphaned_spotify_items", conn, move |conn| query.load(conn)).await?;
+    let orphaned_ids =
+        filter_uncached_internal_ids(orphaned_ids.into_iter().map(|Id { id }| id).collect()).await;
+    if dry_run || orphaned_ids.is_empty() {
+        return Ok(orphaned_ids.len());
+    }
+
+    conn.run(move |conn| {
+        conn.transaction(|| {
+            let mut deleted_count = 0;
+            for chunk in orphaned_ids.chunks(ORPHANED_SPOTIFY_ITEMS_DELETE_BATCH_SIZE) {
+                let ids = chunk
+                    .iter()
+                    .map(i32::to_string)
+                    .collect::<Vec<_>>()
+                    .join(",");
+                // Rows may have become referenced since they were selected, so the condition is
+                // checked again when deleting
+                deleted_count += diesel::sql_query(format!(
+                    "DELETE FROM `spotify_items` WHERE `id` IN ({}) AND {}",
+                    ids, ORPHANED_SPOTIFY_ITEM_CONDITION
+                ))
+                .execute(conn)?;
+            }
+            Ok(deleted_count)
+        })
+    })
+    .await
+}
+
 /// Sets the `last_updated_time` column for the provided user to the provided `update_time`.
 /// Returns the number of rows updated or an error message.
 pub(crate) async fn update_user_last_updated(
@@ -847,10 +1353,12 @@ pub(crate) async fn get_artist_timeline_events(
     user_id: i64,
     start_day: NaiveDateTime,
     end_day: NaiveDateTime,
+    order: SortOrder,
+    limit: Option<i64>,
 ) -> Result<Vec<(String, NaiveDateTime)>, diesel::result::Error> {
     use crate::schema::{artists_users_first_seen, spotify_items};
 
-    let query = artists_users_first_seen::table
+    let mut query = artists_users_first_seen::table
         .filter(
             artists_users_first_seen::dsl::user_id.eq(user_id).and(
                 artists_users_first_seen::dsl::first_seen
@@ -858,7 +1366,6 @@ pub(crate) async fn get_artist_timeline_events(
                     .and(artists_users_first_seen::dsl::first_seen.le(end_day)),
             ),
         )
-        .order_by(artists_users_first_seen::dsl::first_seen)
         .inner_join(
             spotify_items::table
                 .on(spotify_items::dsl::id.eq(artists_users_first_seen::dsl::mapped_spotify_id)),
@@ -866,8 +1373,16 @@ pub(crate) async fn get_artist_timeline_events(
         .select((
             spotify_items::dsl::spotify_id,
             artists_users_first_seen::dsl::first_seen,
-        ));
-    conn.run(move |conn| query.load(conn)).await
+        ))
+        .into_boxed();
+    query = match order {
+        SortOrder::Ascending => query.order_by(artists_users_first_seen::dsl::first_seen.asc()),
+        SortOrder::Descending => query.order_by(artists_users_first_seen::dsl::first_seen.desc()),
+    };
+    if let Some(limit) = limit {
+        query = query.limit(limit);
+    }
+    timed_query("artist_timeline_events", conn, move |conn| query.load(conn)).await
 }
 
 pub(crate) async fn get_track_timeline_events(
@@ -875,10 +1390,12 @@ pub(crate) async fn get_track_timeline_events(
     user_id: i64,
     start_day: NaiveDateTime,
     end_day: NaiveDateTime,
+    order: SortOrder,
+    limit: Option<i64>,
 ) -> Result<Vec<(String, NaiveDateTime)>, diesel::result::Error> {
     use crate::schema::{spotify_items, tracks_users_first_seen};
 
-    let query = tracks_users_first_seen::table
+    let mut query = tracks_users_first_seen::table
         .filter(
             tracks_users_first_seen::dsl::user_id.eq(user_id).and(
                 tracks_users_first_seen::dsl::first_seen
@@ -886,7 +1403,6 @@ pub(crate) async fn get_track_timeline_events(
                     .and(tracks_users_first_seen::dsl::first_seen.le(end_day)),
             ),
         )
-        .order_by(tracks_users_first_seen::dsl::first_seen)
         .inner_join(
             spotify_items::table
                 .on(spotify_items::dsl::id.eq(tracks_users_first_seen::dsl::mapped_spotify_id)),
@@ -894,8 +1410,321 @@ pub(crate) async fn get_track_timeline_events(
         .select((
             spotify_items::dsl::spotify_id,
             tracks_users_first_seen::dsl::first_seen,
-        ));
-    conn.run(move |conn| query.load(conn)).await
+        ))
+        .into_boxed();
+    query = match order {
+        SortOrder::Ascending => query.order_by(tracks_users_first_seen::dsl::first_seen.asc()),
+        SortOrder::Descending => query.order_by(tracks_users_first_seen::dsl::first_seen.desc()),
+    };
+    if let Some(limit) = limit {
+        query = query.limit(limit);
+    }
+    timed_query("track_timeline_events", conn, move |conn| query.load(conn)).await
+}
+
+/// Returns the total number of artists the user has ever had in their top artists along with how
+/// many of those have each of the provided genres.  Genres that none of the user's artists have
+/// are omitted from the returned map.
+pub(crate) async fn count_user_artists_by_genres(
+    conn: &DbConn,
+    user_id: i64,
+    genres: Vec<String>,
+) -> QueryResult<(i64, HashMap<String, i64>)> {
+    use crate::schema::{artists_genres, artists_users_first_seen};
+
+    timed_query("user_artists_by_genres", conn, move |conn| {
+        let total_artist_count: i64 = artists_users_first_seen::table
+            .filter(artists_users_first_seen::dsl::user_id.eq(user_id))
+            .count()
+            .get_result(conn)?;
+        let artist_counts_by_genre: Vec<(String, i64)> = artists_users_first_seen::table
+            .inner_join(artists_genres::table.on(
+                artists_genres::dsl::artist_id.eq(artists_users_first_seen::dsl::mapped_spotify_id),
+            ))
+            .filter(artists_users_first_seen::dsl::user_id.eq(user_id))
+            .filter(artists_genres::dsl::genre.eq_any(genres))
+            .group_by(artists_genres::dsl::genre)
+            .select((artists_genres::dsl::genre, diesel::dsl::count_star()))
+            .load(conn)?;
+
+        Ok((
+            total_artist_count,
+            artist_counts_by_genre.into_iter().collect(),
+        ))
+    })
+    .await
+}
+
+/// Returns every genre of any artist the user has ever had in their top artists along with how many
+/// of those artists have it, sorted by count descending.  The first-seen table holds exactly the
+/// distinct artists from the user's artist rank snapshots, so it's used instead of scanning them.
+pub(crate) async fn get_all_genres_for_user(
+    conn: &DbConn,
+    user_id: i64,
+) -> QueryResult<Vec<(String, i64)>> {
+    use crate::schema::{artists_genres, artists_users_first_seen};
+
+    let query = artists_users_first_seen::table
+        .inner_join(artists_genres::table.on(
+            artists_genres::dsl::artist_id.eq(artists_users_first_seen::dsl::mapped_spotify_id),
+        ))
+        .filter(artists_users_first_seen::dsl::user_id.eq(user_id))
+        .group_by(artists_genres::dsl::genre)
+        .select((artists_genres::dsl::genre, diesel::dsl::count_star()))
+        .order_by(diesel::dsl::count_star().desc());
+    timed_query("all_genres_for_user", conn, move |conn| query.load(conn)).await
+}
+
+/// Returns the genres of each of the user's top artists, taking each timeframe from its most recent
+/// snapshot, along with the time of the most recent update, or `None` if the user has no artist
+/// rank snapshots.  Artists without any genres
+/// are included with a `None` genre so that the number of artists in each timeframe can be
+/// determined.
+pub(crate) async fn get_current_genre_breakdown(
+    conn: &DbConn,
+    user_id: i64,
+) -> QueryResult<Option<(NaiveDateTime, Vec<GenreRankQueryResItem>)>> {
+    use diesel::sql_types::Bigint;
+
+    let last_update_time = match get_last_artist_update_time(conn, user_id).await? {
+        Some(last_update_time) => last_update_time,
+        None => return Ok(None),
+    };
+
+    let query = diesel::sql_query(
+        r#"
+            SELECT
+                `artist_rank_snapshots`.`mapped_spotify_id`,
+                `artist_rank_snapshots`.`timeframe`,
+                `artist_rank_snapshots`.`ranking`,
+                `artists_genres`.`genre`
+            FROM `artist_rank_snapshots`
+            LEFT JOIN `artists_genres`
+                ON `artists_genres`.`artist_id` = `artist_rank_snapshots`.`mapped_spotify_id`
+            WHERE `artist_rank_snapshots`.`user_id` = ?
+                AND (
+                    `artist_rank_snapshots`.`timeframe`,
+                    `artist_rank_snapshots`.`update_time`
+                ) IN (
+                    SELECT `timeframe`, MAX(`update_time`)
+                    FROM `artist_rank_snapshots`
+                    WHERE `user_id` = ?
+                    GROUP BY `timeframe`
+                )
+        "#,
+    )
+    .bind::<Bigint, _>(user_id)
+    .bind::<Bigint, _>(user_id);
+    let items = timed_query("current_genre_breakdown", conn, move |conn| {
+        query.load(conn)
+    })
+    .await?;
+
+    Ok(Some((last_update_time, items)))
+}
+
+pub(crate) async fn get_last_track_update_time(
+    conn: &DbConn,
+    user_id: i64,
+) -> QueryResult<Option<NaiveDateTime>> {
+    use crate::schema::track_rank_snapshots;
+
+    let query = track_rank_snapshots::table
+        .filter(track_rank_snapshots::dsl::user_id.eq(user_id))
+        .select(track_rank_snapshots::dsl::update_time)
+        .order_by(track_rank_snapshots::dsl::update_time.desc());
+    conn.run(move |conn| query.first(conn).optional()).await
+}
+
+/// Returns the tracks in the user's most recent track snapshot of each timeframe along with the
+/// spotify ID of the album each is on, if known.
+pub(crate) async fn get_current_album_ranks(
+    conn: &DbConn,
+    user_id: i64,
+) -> QueryResult<Option<(NaiveDateTime, Vec<AlbumRankQueryResItem>)>> {
+    use diesel::sql_types::Bigint;
+
+    let last_update_time = match get_last_track_update_time(conn, user_id).await? {
+        Some(last_update_time) => last_update_time,
+        None => return Ok(None),
+    };
+
+    let query = diesel::sql_query(
+        r#"
+            SELECT
+                `track_rank_snapshots`.`mapped_spotify_id`,
+                `spotify_items`.`spotify_id` AS `track_spotify_id`,
+                `tracks_albums`.`album_spotify_id`,
+                `track_rank_snapshots`.`timeframe`,
+                `track_rank_snapshots`.`ranking`
+            FROM `track_rank_snapshots`
+            INNER JOIN `spotify_items`
+                ON `spotify_items`.`id` = `track_rank_snapshots`.`mapped_spotify_id`
+            LEFT JOIN `tracks_albums`
+                ON `tracks_albums`.`track_id` = `track_rank_snapshots`.`mapped_spotify_id`
+            WHERE `track_rank_snapshots`.`user_id` = ?
+                AND (
+                    `track_rank_snapshots`.`timeframe`,
+                    `track_rank_snapshots`.`update_time`
+                ) IN (
+                    SELECT `timeframe`, MAX(`update_time`)
+                    FROM `track_rank_snapshots`
+                    WHERE `user_id` = ?
+                    GROUP BY `timeframe`
+                )
+        "#,
+    )
+    .bind::<Bigint, _>(user_id)
+    .bind::<Bigint, _>(user_id);
+    let items = timed_query("current_album_ranks", conn, move |conn| query.load(conn)).await?;
+
+    Ok(Some((last_update_time, items)))
+}
+
+/// Records which album each track is on.  Tracks that already have an album recorded are skipped.
+pub(crate) async fn insert_track_album_pairs(
+    conn: &DbConn,
+    pairs: Vec<TrackAlbumPair>,
+) -> QueryResult<usize> {
+    if pairs.is_empty() {
+        return Ok(0);
+    }
+
+    conn.run(move |conn| {
+        diesel::insert_or_ignore_into(crate::schema::tracks_albums::table)
+            .values(&pairs)
+            .execute(conn)
+    })
+    .await
+}
+
+/// Records the follower count of each of the provided `(artist_internal_id, followers)` pairs for
+/// `day`.  Follower counts are shared between all users, so artists that already have an entry for
+/// `day` are skipped to avoid re-writing the same rows for every user that updates.
+pub(crate) async fn record_artist_followers(
+    conn: &DbConn,
+    followers_by_artist_id: Vec<(i32, u64)>,
+    day: NaiveDate,
+) -> QueryResult<usize> {
+    use crate::schema::artist_followers_history::dsl;
+
+    if followers_by_artist_id.is_empty() {
+        return Ok(0);
+    }
+
+    let artist_ids: Vec<i32> = followers_by_artist_id
+        .iter()
+        .map(|(artist_id, _)| *artist_id)
+        .collect();
+    let query = dsl::artist_followers_history
+        .filter(dsl::artist_id.eq_any(artist_ids))
+        .filter(dsl::day.ge(day))
+        .select(dsl::artist_id);
+    let recorded_artist_ids: HashSet<i32> =
+        timed_query("recently_recorded_artist_followers", conn, move |conn| {
+            query.load::<i32>(conn)
+        })
+        .await?
+        .into_iter()
+        .collect();
+
+    let entries: Vec<NewArtistFollowersEntry> = followers_by_artist_id
+        .into_iter()
+        .filter(|(artist_id, _)| !recorded_artist_ids.contains(artist_id))
+        .map(|(artist_id, followers)| NewArtistFollowersEntry {
+            artist_id,
+            day,
+            followers,
+        })
+        .collect();
+    if entries.is_empty() {
+        return Ok(0);
+    }
+
+    timed_query("insert_artist_followers", conn, move |conn| {
+        diesel::insert_or_ignore_into(crate::schema::artist_followers_history::table)
+            .values(&entries)
+            .execute(conn)
+    })
+    .await
+}
+
+/// Returns the recorded follower counts for the artist, ordered by day ascending
+pub(crate) async fn get_artist_followers_history(
+    conn: &DbConn,
+    artist_internal_id: i32,
+) -> QueryResult<Vec<ArtistFollowersHistoryItem>> {
+    use crate::schema::artist_followers_history::dsl;
+
+    let query = dsl::artist_followers_history
+        .filter(dsl::artist_id.eq(artist_internal_id))
+        .order_by(dsl::day.asc())
+        .select((dsl::day, dsl::followers));
+    timed_query("artist_followers_history", conn, move |conn| {
+        query.load(conn)
+    })
+    .await
+}
+
+/// Returns the first time that each of the users had the artist in their top artists.  Users that
+/// never had the artist aren't included.
+pub(crate) async fn get_artist_first_seen_for_users(
+    conn: &DbConn,
+    user_ids: Vec<i64>,
+    artist_internal_id: i32,
+) -> QueryResult<Vec<(i64, NaiveDateTime)>> {
+    use crate::schema::artists_users_first_seen::dsl;
+
+    let query = dsl::artists_users_first_seen
+        .filter(
+            dsl::mapped_spotify_id
+                .eq(artist_internal_id)
+                .and(dsl::user_id.eq_any(user_ids)),
+        )
+        .select((dsl::user_id, dsl::first_seen));
+    timed_query("artist_first_seen_for_users", conn, move |conn| {
+        query.load(conn)
+    })
+    .await
+}
+
+/// Returns the genres that most often co-occur with `genre` among the artists the user has ever
+/// had in their top artists, along with how many of those artists have both genres.  Sorted by
+/// count descending.
+pub(crate) async fn get_related_genres_for_user(
+    conn: &DbConn,
+    user_id: i64,
+    genre: String,
+    limit: i64,
+) -> QueryResult<Vec<GenreCountQueryResItem>> {
+    use diesel::sql_types::{Bigint, Text};
+
+    // Starting from the target genre lets the `(genre, artist_id)` unique index narrow down the
+    // artists first.  The user's artists are then filtered by the first-seen primary key and the
+    // other genres of each artist are found via the `artist_id` index.
+    let query = diesel::sql_query(
+        r#"
+            SELECT `other_genres`.`genre` AS `genre`, COUNT(*) AS `count`
+            FROM `artists_genres` AS `target_genre`
+            INNER JOIN `artists_users_first_seen` AS `first_seen`
+                ON `first_seen`.`user_id` = ?
+                AND `first_seen`.`mapped_spotify_id` = `target_genre`.`artist_id`
+            INNER JOIN `artists_genres` AS `other_genres`
+                ON `other_genres`.`artist_id` = `target_genre`.`artist_id`
+            WHERE `target_genre`.`genre` = ? AND `other_genres`.`genre` != `target_genre`.`genre`
+            GROUP BY `other_genres`.`genre`
+            ORDER BY `count` DESC
+            LIMIT ?
+        "#,
+    )
+    .bind::<Bigint, _>(user_id)
+    .bind::<Text, _>(genre)
+    .bind::<Bigint, _>(limit);
+
+    timed_query("related_genres_for_user", conn, move |conn| {
+        query.load(conn)
+    })
+    .await
 }
 
 pub(crate) async fn get_all_top_tracks_for_user(
@@ -918,6 +1747,33 @@ pub(crate) async fn get_all_top_tracks_for_user(
     conn.run(move |conn| query.load(conn)).await
 }
 
+/// Returns the best (lowest) ranking that each track has ever reached for the user in each
+/// timeframe across all of their stored snapshots.
+pub(crate) async fn get_best_track_rankings_for_user(
+    conn: &DbConn,
+    user: &User,
+) -> Result<Vec<BestRankingQueryResItem>, diesel::result::Error> {
+    if !user.external_data_retrieved {
+        retrieve_cold_data_for_user(conn, user).await;
+    }
+
+    let query = diesel::sql_query(
+        r#"
+            SELECT
+                `spotify_items`.`spotify_id`,
+                `track_rank_snapshots`.`timeframe`,
+                MIN(`track_rank_snapshots`.`ranking`) AS `ranking`
+            FROM `track_rank_snapshots`
+            INNER JOIN `spotify_items`
+                ON `track_rank_snapshots`.`mapped_spotify_id` = `spotify_items`.`id`
+            WHERE `track_rank_snapshots`.`user_id` = ?
+            GROUP BY `spotify_items`.`spotify_id`, `track_rank_snapshots`.`timeframe`
+    "#,
+    )
+    .bind::<diesel::sql_types::BigInt, _>(user.id);
+    conn.run(move |conn| query.load(conn)).await
+}
+
 pub(crate) async fn get_all_top_artists_for_user(
     conn: &DbConn,
     user_id: i64,
@@ -938,6 +1794,115 @@ pub(crate) async fn get_all_top_artists_for_user(
     conn.run(move |conn| query.load(conn)).await
 }
 
+/// Returns `true` if a user with the provided spotify ID is tracked
+pub(crate) async fn user_exists(
+    conn: &DbConn,
+    spotify_id: String,
+) -> Result<bool, diesel::result::Error> {
+    use crate::schema::users;
+
+    let query = diesel::select(diesel::dsl::exists(
+        users::table.filter(users::dsl::spotify_id.eq(spotify_id)),
+    ));
+    timed_query("user_exists", conn, move |conn| query.get_result(conn)).await
+}
+
+/// Returns a map of spotify ID to display name for all of the provided users that exist
+pub(crate) async fn get_display_names_by_spotify_id(
+    conn: &DbConn,
+    spotify_ids: Vec<String>,
+) -> Result<HashMap<String, String>, diesel::result::Error> {
+    use crate::schema::users;
+
+    let query = users::table
+        .filter(users::dsl::spotify_id.eq_any(spotify_ids))
+        .select((users::dsl::spotify_id, users::dsl::username));
+    let pairs: Vec<(String, String)> = conn.run(move |conn| query.load(conn)).await?;
+    Ok(pairs.into_iter().collect())
+}
+
+/// Returns all artists that have ever been in the user's top artists along with their ranks as of
+/// the user's most recent update, ordered by best rank.  Artists that aren't currently top artists
+/// come last, ordered by when they were first seen.
+pub(crate) async fn get_top_artists_detailed_for_user(
+    conn: &DbConn,
+    user_id: i64,
+) -> Result<Vec<TopArtistDetail>, diesel::result::Error> {
+    use crate::schema::{artist_rank_snapshots, artists_users_first_seen};
+
+    let first_seen_query = artists_users_first_seen::table
+        .filter(artists_users_first_seen::dsl::user_id.eq(user_id))
+        .select((
+            artists_users_first_seen::dsl::mapped_spotify_id,
+            artists_users_first_seen::dsl::first_seen,
+        ));
+    let first_seen: Vec<(i32, NaiveDateTime)> =
+        conn.run(move |conn| first_seen_query.load(conn)).await?;
+
+    let last_update_time = get_last_artist_update_time(conn, user_id).await?;
+    let mut current_ranks: Vec<(i32, u8, u8)> = match last_update_time {
+        Some(last_update_time) => {
+            let query = artist_rank_snapshots::table
+                .filter(artist_rank_snapshots::dsl::user_id.eq(user_id))
+                .filter(artist_rank_snapshots::dsl::update_time.eq(last_update_time))
+                .select((
+                    artist_rank_snapshots::dsl::mapped_spotify_id,
+                    artist_rank_snapshots::dsl::timeframe,
+                    artist_rank_snapshots::dsl::ranking,
+                ));
+            conn.run(move |conn| query.load(conn)).await?
+        },
+        None => Vec::new(),
+    };
+
+    let mut details_by_id: HashMap<i32, TopArtistDetail> = first_seen
+        .into_iter()
+        .map(|(internal_id, first_seen)| {
+            (internal_id, TopArtistDetail {
+                internal_id,
+                best_rank: None,
+                timeframes_seen: Vec::new(),
+                first_seen,
+            })
+        })
+        .collect();
+    // Ranks are inserted in timeframe order so that `timeframes_seen` ends up ordered as well
+    current_ranks.sort_unstable_by_key(|&(_, timeframe, _)| timeframe);
+    for (internal_id, timeframe, ranking) in current_ranks {
+        let detail = match details_by_id.get_mut(&internal_id) {
+            Some(detail) => detail,
+            None => continue,
+        };
+        detail.best_rank = Some(detail.best_rank.map_or(ranking, |best| best.min(ranking)));
+        let timeframe_name = match timeframe {
+            0 => "short",
+            1 => "medium",
+            2 => "long",
+            _ => {
+                error!(
+                    "Invalid timeframe id {} in artist rank snapshots",
+                    timeframe
+                );
+                continue;
+            },
+        };
+        if !detail.timeframes_seen.contains(&timeframe_name) {
+            detail.timeframes_seen.push(timeframe_name);
+        }
+    }
+
+    let mut details: Vec<TopArtistDetail> = details_by_id.into_values().collect();
+    details.sort_unstable_by_key(|detail| {
+        (
+            detail.best_rank.is_none(),
+            detail.best_rank,
+            detail.first_seen,
+            detail.internal_id,
+        )
+    });
+    Ok(details)
+}
+
 pub(crate) async fn refresh_user_access_token(
     conn: &DbConn,
     user: &mut User,
@@ -1006,12 +1971,124 @@ pub(crate) async fn insert_related_artists(
     Ok(())
 }
 
-pub(crate) async fn update_user_last_viewed(user: &User, conn: &DbConn) -> QueryResult<()> {
+/// Sets `last_viewed` to the current time for all of the provided users.
+pub(crate) fn update_users_last_viewed(
+    conn: &mut MysqlConnection,
+    user_ids: &[i64],
+) -> QueryResult<usize> {
     use crate::schema::users;
 
-    let query = diesel::update(users::table.filter(users::dsl::id.eq(user.id)))
-        .set(users::dsl::last_viewed.eq(diesel::dsl::now));
-    conn.run(move |conn| query.execute(conn)).await?;
+    diesel::update(users::table.filter(users::dsl::id.eq_any(user_ids)))
+        .set(users::dsl::last_viewed.eq(diesel::dsl::now))
+        .execute(conn)
+}
 
-    Ok(())
+#[test]
+fn spotify_id_aliases_are_resolved_to_canonical_ids() {
+    let (ids_to_look_up, aliased_ids) = resolve_spotify_id_aliases(
+        vec![
+            "requested".to_owned(),
+            "canonical".to_owned(),
+            "other".to_owned(),
+        ],
+        vec![Some("canonical".to_owned()), None, None],
+    );
+
+    assert_eq!(ids_to_look_up, vec![
+        "canonical".to_owned(),
+        "other".to_owned()
+    ]);
+    assert_eq!(aliased_ids, vec![(
+        "requested".to_owned(),
+        "canonical".to_owned()
+    )]);
+}
+
+#[test]
+fn nearest_update_time_is_picked() {
+    let ts = |day: u32| chrono::NaiveDate::from_ymd(2021, 1, day).and_hms(0, 0, 0);
+
+    assert_eq!(
+        pick_nearest_update_time(ts(10), Some(ts(8)), Some(ts(13))),
+        Some(ts(8))
+    );
+    assert_eq!(
+        pick_nearest_update_time(ts(10), Some(ts(5)), Some(ts(11))),
+        Some(ts(11))
+    );
+    assert_eq!(
+        pick_nearest_update_time(ts(10), None, Some(ts(20))),
+        Some(ts(20))
+    );
+    assert_eq!(
+        pick_nearest_update_time(ts(10), Some(ts(1)), None),
+        Some(ts(1))
+    );
+    assert_eq!(pick_nearest_update_time(ts(10), None, None), None);
+}
+
+#[test]
+fn partial_snapshots_are_read_per_timeframe() {
+    let ts = |day: u32| chrono::NaiveDate::from_ymd(2021, 1, day).and_hms(0, 0, 0);
+
+    // A full snapshot on the 1st followed by a partial one on the 2nd that only captured the short
+    // timeframe
+    let items = vec![
+        (0, ts(1), "short-old"),
+        (1, ts(1), "medium"),
+        (2, ts(1), "long"),
+        (0, ts(2), "short-new"),
+    ];
+    let latest_update_times = vec![(0, ts(2)), (1, ts(1)), (2, ts(1))];
+
+    assert_eq!(
+        retain_latest_timeframe_items(items, &latest_update_times),
+        vec![(1, "medium"), (2, "long"), (0, "short-new")]
+    );
+}
+
+#[test]
+fn items_referenced_from_any_table_are_not_orphaned() {
+    // Several of these reference `spotify_items` with `ON DELETE CASCADE`, so pruning an item
+    // that's still referenced would silently delete history along with it
+    let referencing_columns = [
+        ("artist_rank_snapshots", "mapped_spotify_id"),
+        ("track_rank_snapshots", "mapped_spotify_id"),
+        ("tracks_artists", "track_id"),
+        ("tracks_artists", "artist_id"),
+        ("artists_genres", "artist_id"),
+        ("related_artists", "artist_spotify_id"),
+        ("artists_users_first_seen", "mapped_spotify_id"),
+        ("tracks_users_first_seen", "mapped_spotify_id"),
+        ("artist_followers_history", "artist_id"),
+        ("tracks_albums", "track_id"),
+    ];
+    for (table, column) in referencing_columns {
+        let clause = format!("`{}`.`{}` = `spotify_items`.`id`", table, column);
+        assert!(
+            ORPHANED_SPOTIFY_ITEM_CONDITION.contains(&clause),
+            "items referenced only from `{}`.`{}` would be pruned",
+            table,
+            column
+        );
+    }
+}
+
+#[test]
+fn repeated_artist_ids_get_the_same_rank_history() {
+    let ts = |day: u32| chrono::NaiveDate::from_ymd(2021, 1, day).and_hms(0, 0, 0);
+    let ids = |ids: &[&str]| ids.iter().map(|&id| id.to_owned()).collect::<Vec<_>>();
+
+    let histories = group_artist_rank_histories(&ids(&["a", "missing", "a"]), vec![
+        ("a".to_owned(), ts(1), 3, 0),
+        ("a".to_owned(), ts(2), 5, 0),
+    ]);
+    assert_eq!(histories.len(), 3);
+    let history = Some(vec![
+        (ts(1), [Some(3), None, None]),
+        (ts(2), [Some(5), None, None]),
+    ]);
+    assert_eq!(histories[0], history);
+    assert_eq!(histories[1], None);
+    assert_eq!(histories[2], history);
 }
@@ -0,0 +1,144 @@
+//! Cleanup of external storage objects once their data has been restored to the database.
+//!
+//! Once a restore finishes and the user is flagged as having their data retrieved, the parquet
+//! files in external storage are redundant.  They're deleted (or moved under
+//! `DynamicConf::external_storage_restored_object_archive_prefix` if set) right away.  If that
+//! fails, the user is queued and the janitor task retries the cleanup periodically.
+//!
+//! The queue is only kept in memory, so pending cleanups are lost on restart.  That only leaves
+//! behind redundant objects; they're overwritten the next time the user's data is archived.
+
+use std::{sync::Arc, time::Duration};
+
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use object_store::ObjectStore;
+
+use crate::{
+    conf::dynamic_conf,
+    metrics::{
+        external_storage_restored_object_cleanup_failure_total,
+        external_storage_restored_object_cleanup_total,
+    },
+    shutdown::{register_in_flight_task, wait_for_shutdown},
+};
+
+use super::{build_filenames, build_object_store, RETRIEVE_LOCKS, WRITE_LOCKS};
+
+const JANITOR_INTERVAL: Duration = Duration::from_secs(60 * 10);
+
+lazy_static! {
+    /// Spotify IDs of users whose objects still need to be cleaned up after a restore
+    static ref PENDING_CLEANUPS: DashMap<String, ()> = DashMap::new();
+}
+
+async fn cleanup_object(
+    object_store: &dyn ObjectStore,
+    filename: String,
+) -> Result<(), object_store::Error> {
+    let location: object_store::path::Path = filename.clone().into();
+    let res = match dynamic_conf()
+        .external_storage_restored_object_archive_prefix
+        .as_deref()
+    {
+        Some(prefix) => {
+            let archive_location: object_store::path::Path = format!("{prefix}{filename}").into();
+            object_store.rename(&location, &archive_location).await
+        },
+        None => object_store.delete(&location).await,
+    };
+    match res {
+        // The user may not have had both artist and track data stored
+        Ok(()) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+async fn cleanup_restored_objects_inner(
+    user_spotify_id: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let object_store = Arc::new(build_object_store()?) as Arc<dyn ObjectStore>;
+    let (artists_filename, tracks_filename) = build_filenames(user_spotify_id);
+    for filename in [artists_filename, tracks_filename] {
+        cleanup_object(&*object_store, filename.clone())
+            .await
+            .inspect_err(|err| {
+                error!("Error cleaning up external storage object {filename}: {err}");
+            })?;
+        external_storage_restored_object_cleanup_total().inc();
+    }
+    Ok(())
+}
+
+/// Cleans up the external storage objects for a user whose data was just restored to the
+/// database.  If this fails, the cleanup is queued for the janitor to retry.
+///
+/// Must be called while the user's retrieve or write lock is held so that the objects can't be
+/// re-written concurrently.
+pub(crate) async fn cleanup_restored_objects(user_spotify_id: &str) {
+    if let Err(err) = cleanup_restored_objects_inner(user_spotify_id).await {
+        external_storage_restored_object_cleanup_failure_total().inc();
+        warn!(
+            "Error cleaning up external storage objects for user {user_spotify_id} after restore; \
+             queueing for retry: {err}"
+        );
+        PENDING_CLEANUPS.insert(user_spotify_id.to_owned(), ());
+    } else {
+        info!("Cleaned up external storage objects for user {user_spotify_id} after restore");
+    }
+}
+
+/// Removes `user_spotify_id` from the janitor's retry queue; queued cleanups for other users are
+/// left alone.  Called when new data is about to be written for the user, since their objects are
+/// no longer redundant after that.
+///
+/// Must be called while the user's write lock is held.  The janitor takes that lock before
+/// cleaning up, so this can't race with a retry that's already running for the user.
+pub(crate) fn cancel_pending_cleanup(user_spotify_id: &str) {
+    PENDING_CLEANUPS.remove(user_spotify_id);
+}
+
+async fn run_janitor_pass() {
+    let user_spotify_ids: Vec<String> = PENDING_CLEANUPS
+        .iter()
+        .map(|entry| entry.key().clone())
+        .collect();
+    if user_spotify_ids.is_empty() {
+        return;
+    }
+    info!(
+        "Retrying external storage cleanup for {} user(s)",
+        user_spotify_ids.len()
+    );
+
+    for user_spotify_id in user_spotify_ids {
+        // Take the write lock so that nothing can be re-archived for this user while we're
+        // cleaning up.  If anything else is going on for the user, try again next time.
+        if RETRIEVE_LOCKS.contains_key(&user_spotify_id) {
+            continue;
+        }
+        if WRITE_LOCKS.insert(user_spotify_id.clone(), ()).is_some() {
+            continue;
+        }
+        let _in_flight_guard =
+            register_in_flight_task(format!("cleanup_restored_objects {}", user_spotify_id));
+
+        // The cleanup may have been cancelled by a write that finished before we took the lock
+        if PENDING_CLEANUPS.remove(&user_spotify_id).is_some() {
+            cleanup_restored_objects(&user_spotify_id).await;
+        }
+
+        WRITE_LOCKS.remove(&user_spotify_id);
+    }
+}
+
+/// Periodically retries cleanups that failed after restores.  Runs until shutdown.
+pub(crate) async fn run_cleanup_janitor() {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(JANITOR_INTERVAL) => (),
+            _ = wait_for_shutdown() => return,
+        }
+        run_janitor_pass().await;
+    }
+}
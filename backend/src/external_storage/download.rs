@@ -1,4 +1,5 @@
 use std::{
+    future::Future,
     sync::Arc,
     time::{Duration, Instant},
 };
@@ -6,7 +7,7 @@ use std::{
 use arrow_array::{RecordBatch, TimestampSecondArray, UInt32Array, UInt64Array, UInt8Array};
 use chrono::NaiveDateTime;
 use diesel::{prelude::*, QueryResult};
-use futures::StreamExt;
+use futures::{StreamExt, TryFutureExt};
 use object_store::ObjectStore;
 use parquet::arrow::{
     async_reader::{ParquetObjectReader, ParquetRecordBatchStream},
@@ -17,23 +18,29 @@ use tokio::sync::watch;
 use crate::{
     db_util::get_user_by_spotify_id,
     metrics::{
-        external_user_data_retrieval_failure_total, external_user_data_retrieval_success_total,
-        external_user_data_retrieval_time,
+        external_storage_downloads_total, external_storage_transfer_bytes,
+        external_storage_transfer_duration, external_user_data_retrieval_failure_total,
+        external_user_data_retrieval_success_total, external_user_data_retrieval_time,
     },
     models::{ArtistHistoryEntry, TrackHistoryEntry},
+    shutdown::register_in_flight_task,
     DbConn,
 };
 
 use super::{
-    build_filenames, build_object_store, set_data_retrieved_flag_for_user, BATCH_SIZE,
-    RETRIEVE_LOCKS, WRITE_LOCKS,
+    build_filenames, build_object_store, cleanup::cleanup_restored_objects, run_transfer_halves,
+    set_data_retrieved_flag_for_user, BATCH_SIZE, RETRIEVE_LOCKS, WRITE_LOCKS,
 };
 
-/// Returns `(artists_reader, tracks_reader)`
+/// Returns `(artists_reader, tracks_reader, total_size_bytes)`
 async fn build_parquet_readers(
     user_spotify_id: &str,
 ) -> Result<
-    (Option<ParquetObjectReader>, Option<ParquetObjectReader>),
+    (
+        Option<ParquetObjectReader>,
+        Option<ParquetObjectReader>,
+        usize,
+    ),
     Box<dyn std::error::Error + Send + Sync + 'static>,
 > {
     let object_store = Arc::new(build_object_store()?) as Arc<dyn ObjectStore>;
@@ -59,13 +66,18 @@ async fn build_parquet_readers(
         },
     };
 
+    let total_size_bytes = artists_obj_meta.as_ref().map(|meta| meta.size).unwrap_or(0)
+        + tracks_artist_meta
+            .as_ref()
+            .map(|meta| meta.size)
+            .unwrap_or(0);
     let artists_reader = artists_obj_meta.map(|artists_obj_meta| {
         ParquetObjectReader::new(Arc::clone(&object_store), artists_obj_meta)
     });
     let tracks_reader = tracks_artist_meta.map(|tracks_artist_meta| {
         ParquetObjectReader::new(Arc::clone(&object_store), tracks_artist_meta)
     });
-    Ok((artists_reader, tracks_reader))
+    Ok((artists_reader, tracks_reader, total_size_bytes))
 }
 
 async fn insert_artist_snapshots(
@@ -144,22 +156,41 @@ fn record_batch_to_history_entries(record_batch: RecordBatch) -> Vec<ArtistHisto
     artist_history_entries
 }
 
-async fn consume_and_insert_track_record_batches(
-    mut tracks_record_batch_reader: ParquetRecordBatchStream<ParquetObjectReader>,
-    conn: &DbConn,
-    user_spotify_id: &str,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
-    let mut total_records_received = 0usize;
-    let mut total_records_written_to_db = 0usize;
-    'outer: while let Some(res) = tracks_record_batch_reader.next().await {
-        let record_batch = match res {
-            Ok(record_batch) => record_batch,
+/// Reads all record batches from the provided parquet reader into memory.  They're kept in their
+/// columnar form until they're inserted, which is more compact than the converted entries.
+/// Returns an empty vec if no reader is provided, which happens when the object doesn't exist in
+/// external storage.
+async fn read_all_record_batches(
+    reader_opt: Option<ParquetObjectReader>,
+) -> Result<Vec<RecordBatch>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let reader = match reader_opt {
+        Some(reader) => reader,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut record_batch_reader = build_record_batch_reader(reader).await?;
+    let mut record_batches = Vec::new();
+    while let Some(res) = record_batch_reader.next().await {
+        match res {
+            Ok(record_batch) => record_batches.push(record_batch),
             Err(err) => {
                 error!("Error reading parquet record batch: {}", err);
                 return Err(err.into());
             },
-        };
+        }
+    }
 
+    Ok(record_batches)
+}
+
+async fn insert_track_record_batches(
+    conn: &DbConn,
+    record_batches: Vec<RecordBatch>,
+    user_spotify_id: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let mut total_records_received = 0usize;
+    let mut total_records_written_to_db = 0usize;
+    'outer: for record_batch in record_batches {
         let track_history_entries = record_batch_to_history_entries(record_batch);
         // ;)
         let track_history_entries: Vec<TrackHistoryEntry> =
@@ -184,29 +215,21 @@ async fn consume_and_insert_track_record_batches(
         return Err(err.into());
     }
     info!(
-        "Successfully downloaded track data for user {}; {} records received, {} records written \
-         to db",
+        "Successfully inserted track data for user {}; {} records received, {} records written to \
+         db",
         user_spotify_id, total_records_received, total_records_written_to_db
     );
     Ok(())
 }
 
-async fn consume_and_insert_artist_record_batches(
-    mut artists_record_batch_reader: ParquetRecordBatchStream<ParquetObjectReader>,
+async fn insert_artist_record_batches(
     conn: &DbConn,
-    user_spotify_id: &String,
+    record_batches: Vec<RecordBatch>,
+    user_spotify_id: &str,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
     let mut total_records_received = 0usize;
     let mut total_records_written_to_db = 0usize;
-    'outer: while let Some(res) = artists_record_batch_reader.next().await {
-        let record_batch = match res {
-            Ok(record_batch) => record_batch,
-            Err(err) => {
-                error!("Error reading parquet record batch: {}", err);
-                return Err(err.into());
-            },
-        };
-
+    'outer: for record_batch in record_batches {
         let artist_history_entries = record_batch_to_history_entries(record_batch);
         total_records_received += artist_history_entries.len();
         let mut last_err = None;
@@ -228,26 +251,54 @@ async fn consume_and_insert_artist_record_batches(
         return Err(err.into());
     }
     info!(
-        "Successfully downloaded artist data for user {}; {} records received, {} records written \
+        "Successfully inserted artist data for user {}; {} records received, {} records written \
          to db",
         user_spotify_id, total_records_received, total_records_written_to_db
     );
     Ok(())
 }
 
-/// Loads external user data from cloud storage and returns the record batches directly.  Does NOT
-/// load into the database.
-pub(crate) async fn load_external_user_data(
-    user_spotify_id: String,
+/// Downloads and parses the artist and track data concurrently, staging the record batches of
+/// each in memory, and then passes both to `insert`.  If either half fails, the other is dropped
+/// and `insert` is never called, so nothing is written to the database.
+async fn stage_and_insert_record_batches<C>(
+    artists_reader_opt: Option<ParquetObjectReader>,
+    tracks_reader_opt: Option<ParquetObjectReader>,
+    insert: impl FnOnce(Vec<RecordBatch>, Vec<RecordBatch>) -> C,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>>
+where
+    C: Future<Output = Result<(), Box<dyn std::error::Error + Send + Sync + 'static>>>,
+{
+    run_transfer_halves(
+        read_all_record_batches(artists_reader_opt).inspect_err(|err| {
+            error!("Error reading artist record batches: {}", err);
+        }),
+        read_all_record_batches(tracks_reader_opt).inspect_err(|err| {
+            error!("Error reading track record batches: {}", err);
+        }),
+        insert,
+    )
+    .await
+}
+
+/// Builds parquet readers for the user's artist and track data in external storage, retrying if
+/// it takes too long.  Returns `(artists_reader, tracks_reader)`; either is `None` if there's no
+/// data stored for it.
+pub(super) async fn build_parquet_readers_with_timeout(
+    user_spotify_id: &str,
 ) -> Result<
-    (Vec<ArtistHistoryEntry>, Vec<TrackHistoryEntry>),
+    (
+        Option<ParquetObjectReader>,
+        Option<ParquetObjectReader>,
+        usize,
+    ),
     Box<dyn std::error::Error + Send + Sync + 'static>,
 > {
     info!("Building parquet readers...");
-    let (artists_reader_opt, tracks_reader_opt) = loop {
+    loop {
         match tokio::time::timeout(
             Duration::from_secs(10),
-            build_parquet_readers(&user_spotify_id),
+            build_parquet_readers(user_spotify_id),
         )
         .await
         {
@@ -260,53 +311,10 @@ pub(crate) async fn load_external_user_data(
     }
     .inspect_err(|err| {
         error!("Error building parquet reader: {}", err);
-    })?;
-
-    let mut artist_entries: Vec<ArtistHistoryEntry> = Vec::new();
-    let mut track_entries: Vec<TrackHistoryEntry> = Vec::new();
-
-    if let Some(artists_reader) = artists_reader_opt {
-        let mut artists_record_batch_reader = build_record_batch_reader(artists_reader).await?;
-
-        while let Some(res) = artists_record_batch_reader.next().await {
-            let record_batch = match res {
-                Ok(record_batch) => record_batch,
-                Err(err) => {
-                    error!("Error reading parquet record batch: {}", err);
-                    return Err(err.into());
-                },
-            };
-
-            let artist_history_chunk = record_batch_to_history_entries(record_batch);
-            artist_entries.extend(artist_history_chunk);
-        }
-    }
-
-    if let Some(tracks_reader) = tracks_reader_opt {
-        let mut tracks_record_batch_reader = build_record_batch_reader(tracks_reader).await?;
-
-        while let Some(res) = tracks_record_batch_reader.next().await {
-            let record_batch = match res {
-                Ok(record_batch) => record_batch,
-                Err(err) => {
-                    error!("Error reading parquet record batch: {}", err);
-                    return Err(err.into());
-                },
-            };
-
-            let track_history_chunk = record_batch_to_history_entries(record_batch);
-            // ;)
-            let track_history_chunk: Vec<TrackHistoryEntry> =
-                unsafe { std::mem::transmute(track_history_chunk) };
-
-            track_entries.extend(track_history_chunk);
-        }
-    }
-
-    Ok((artist_entries, track_entries))
+    })
 }
 
-async fn build_record_batch_reader(
+pub(super) async fn build_record_batch_reader(
     reader: ParquetObjectReader,
 ) -> Result<
     ParquetRecordBatchStream<ParquetObjectReader>,
@@ -320,6 +328,20 @@ async fn build_record_batch_reader(
                 err
             );
         })?;
+    // Files may be compressed with different codecs depending on the config at the time they were
+    // written.  The codec is read from the column chunk metadata so nothing special is needed to
+    // handle that here; it's just logged to make it easier to track the migration.
+    if let Some(column) = record_batch_reader_builder
+        .metadata()
+        .row_groups()
+        .first()
+        .and_then(|row_group| row_group.columns().first())
+    {
+        debug!(
+            "Reading external storage parquet file compressed with {}",
+            column.compression()
+        );
+    }
     let record_batch_reader = record_batch_reader_builder
         .with_batch_size(BATCH_SIZE)
         .build()
@@ -329,95 +351,61 @@ async fn build_record_batch_reader(
     Ok(record_batch_reader)
 }
 
-/// Loads external user data from cloud storage into the local database.
+/// Loads external user data from cloud storage into the local database.  Returns the total size
+/// of the downloaded objects in bytes.
 async fn retrieve_external_user_data_inner(
     conn: &DbConn,
     user_spotify_id: String,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
-    info!("Building parquet readers...");
-    let (artists_reader_opt, tracks_reader_opt) = loop {
-        match tokio::time::timeout(
-            Duration::from_secs(10),
-            build_parquet_readers(&user_spotify_id),
-        )
-        .await
-        {
-            Err(err) => {
-                error!("Error building parquet readers: {}", err);
-                continue;
-            },
-            Ok(res) => break res,
-        };
-    }
-    .inspect_err(|err| {
-        error!("Error building parquet reader: {}", err);
-    })?;
+) -> Result<usize, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (artists_reader_opt, tracks_reader_opt, total_size_bytes) =
+        build_parquet_readers_with_timeout(&user_spotify_id).await?;
     info!("Successfully built parquet readers");
-    if let Some(artists_reader) = artists_reader_opt {
-        info!(
-            "Starting download of artist data for user {}...",
-            user_spotify_id
-        );
-
-        let artists_record_batch_reader = build_record_batch_reader(artists_reader).await?;
-
-        consume_and_insert_artist_record_batches(
-            artists_record_batch_reader,
-            conn,
-            &user_spotify_id,
-        )
-        .await
-        .inspect_err(|err| {
-            error!(
-                "Error consuming and inserting artist record batches: {}",
-                err
-            );
-        })?;
-        info!(
-            "Successfully downloaded artist data for user {} and inserted into db",
-            user_spotify_id
-        );
-    } else {
+    if artists_reader_opt.is_none() {
         warn!(
             "No artist data found for user {}; skipping artist data download",
             user_spotify_id
         );
     }
-
-    if let Some(tracks_reader) = tracks_reader_opt {
-        info!(
-            "Starting download of track data for user {}...",
-            user_spotify_id
-        );
-
-        let tracks_record_batch_reader = build_record_batch_reader(tracks_reader).await?;
-
-        consume_and_insert_track_record_batches(tracks_record_batch_reader, conn, &user_spotify_id)
-            .await
-            .inspect_err(|err| {
-                error!(
-                    "Error consuming and inserting track record batches: {}",
-                    err
-                );
-            })?;
-        info!(
-            "Successfully downloaded + loaded track data for user {} into local DB",
-            user_spotify_id
-        );
-    } else {
+    if tracks_reader_opt.is_none() {
         warn!(
             "No track data found for user {}; skipping track data download",
             user_spotify_id
         );
     }
 
+    // Artists and tracks are downloaded + parsed concurrently.  Nothing is written to the database
+    // until both halves have been fully read.
+    info!(
+        "Starting download of artist and track data for user {}...",
+        user_spotify_id
+    );
+    let user_spotify_id_ref = user_spotify_id.as_str();
+    stage_and_insert_record_batches(
+        artists_reader_opt,
+        tracks_reader_opt,
+        |artist_batches, track_batches| async move {
+            insert_artist_record_batches(conn, artist_batches, user_spotify_id_ref)
+                .await
+                .inspect_err(|err| {
+                    error!("Error inserting artist entries: {}", err);
+                })?;
+            insert_track_record_batches(conn, track_batches, user_spotify_id_ref)
+                .await
+                .inspect_err(|err| {
+                    error!("Error inserting track entries: {}", err);
+                })?;
+            Ok(())
+        },
+    )
+    .await?;
+
     info!(
         "Successfully downloaded all data for user {} from external storage and loaded into local \
          DB",
         user_spotify_id
     );
 
-    Ok(())
+    Ok(total_size_bytes)
 }
 
 /// Retrieve user data from external storage and write it to the database.  If there is currently a
@@ -427,6 +415,8 @@ pub(crate) async fn retrieve_external_user_data(
     user_spotify_id: String,
     ignore_write_lock: bool,
 ) {
+    let _in_flight_guard =
+        register_in_flight_task(format!("retrieve_external_user_data {}", user_spotify_id));
     let mut tx_opt = None;
     let mut rx = RETRIEVE_LOCKS
         .entry(user_spotify_id.clone())
@@ -464,6 +454,7 @@ pub(crate) async fn retrieve_external_user_data(
                 let _ = tx.send(());
                 RETRIEVE_LOCKS.remove(&user_spotify_id);
                 external_user_data_retrieval_failure_total().inc();
+                external_storage_downloads_total("failure").inc();
                 return;
             },
             Err(err) => {
@@ -474,6 +465,7 @@ pub(crate) async fn retrieve_external_user_data(
                 let _ = tx.send(());
                 RETRIEVE_LOCKS.remove(&user_spotify_id);
                 external_user_data_retrieval_failure_total().inc();
+                external_storage_downloads_total("failure").inc();
                 return;
             },
         };
@@ -484,21 +476,33 @@ pub(crate) async fn retrieve_external_user_data(
             );
             let _ = tx.send(());
             RETRIEVE_LOCKS.remove(&user_spotify_id);
+            external_storage_downloads_total("skipped").inc();
             return;
         }
 
         info!("User {user_spotify_id} has data in cold storage; starting retrieval...",);
+        let transfer_start = Instant::now();
+        let mut succeeded = false;
         for _ in 0..10 {
             let user_spotify_id = user_spotify_id.clone();
             let start = Instant::now();
             let res = retrieve_external_user_data_inner(conn, user_spotify_id.clone()).await;
             match res {
-                Ok(()) => {
+                Ok(downloaded_bytes) => {
                     external_user_data_retrieval_success_total().inc();
                     external_user_data_retrieval_time().observe(start.elapsed().as_nanos() as u64);
+                    external_storage_transfer_duration("download")
+                        .observe(transfer_start.elapsed().as_nanos() as u64);
+                    external_storage_transfer_bytes("download").observe(downloaded_bytes as f64);
+                    succeeded = true;
                     info!("Finished retrieval for user {user_spotify_id}");
                     // Update users table to indicate that retrieval is complete
-                    set_data_retrieved_flag_for_user(conn, user_spotify_id, true).await;
+                    if set_data_retrieved_flag_for_user(conn, user_spotify_id.clone(), true).await {
+                        // The data now lives in the database, so the external copy is redundant.
+                        // We're still holding the retrieve lock, so nothing can re-archive it
+                        // while we clean up.
+                        cleanup_restored_objects(&user_spotify_id).await;
+                    }
                     break;
                 },
                 Err(err) => {
@@ -507,6 +511,7 @@ pub(crate) async fn retrieve_external_user_data(
                 },
             }
         }
+        external_storage_downloads_total(if succeeded { "success" } else { "failure" }).inc();
         let _ = tx.send(());
 
         RETRIEVE_LOCKS.remove(&user_spotify_id);
@@ -518,3 +523,75 @@ pub(crate) async fn retrieve_external_user_data(
 
     let _ = rx.changed().await;
 }
+
+#[tokio::test]
+async fn retrieval_failure_in_second_half_writes_nothing() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use object_store::{memory::InMemory, path::Path};
+
+    use super::upload::{build_synthetic_entries, encode_entries};
+    use crate::conf::ExternalStorageCompression;
+
+    let object_store = Arc::new(InMemory::new()) as Arc<dyn ObjectStore>;
+    let (artists_filename, tracks_filename) = build_filenames("test_user");
+    let (artists_location, tracks_location) =
+        (Path::from(artists_filename), Path::from(tracks_filename));
+    let encoded = encode_entries(
+        build_synthetic_entries(1_000),
+        ExternalStorageCompression::Zstd,
+    )
+    .await;
+    object_store
+        .put(&artists_location, encoded.clone().into())
+        .await
+        .unwrap();
+    object_store
+        .put(&tracks_location, encoded.into())
+        .await
+        .unwrap();
+
+    let build_reader = |location: Path| {
+        let object_store = Arc::clone(&object_store);
+        async move {
+            let meta = object_store.head(&location).await.unwrap();
+            Some(ParquetObjectReader::new(object_store, meta))
+        }
+    };
+    let rows_written = AtomicUsize::new(0);
+    let insert = |artist_batches: Vec<RecordBatch>, track_batches: Vec<RecordBatch>| {
+        let rows_written = &rows_written;
+        async move {
+            let row_count: usize = artist_batches
+                .iter()
+                .chain(track_batches.iter())
+                .map(RecordBatch::num_rows)
+                .sum();
+            rows_written.fetch_add(row_count, Ordering::SeqCst);
+            Ok::<_, Box<dyn std::error::Error + Send + Sync + 'static>>(())
+        }
+    };
+
+    stage_and_insert_record_batches(
+        build_reader(artists_location.clone()).await,
+        build_reader(tracks_location.clone()).await,
+        insert,
+    )
+    .await
+    .unwrap();
+    assert_eq!(rows_written.swap(0, Ordering::SeqCst), 2_000);
+
+    // Corrupt the tracks file so that the second half fails after the artists have been read
+    object_store
+        .put(&tracks_location, b"not a parquet file".to_vec().into())
+        .await
+        .unwrap();
+    let res = stage_and_insert_record_batches(
+        build_reader(artists_location).await,
+        build_reader(tracks_location).await,
+        insert,
+    )
+    .await;
+    assert!(res.is_err());
+    assert_eq!(rows_written.load(Ordering::SeqCst), 0);
+}
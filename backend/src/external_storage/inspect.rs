@@ -0,0 +1,81 @@
+//! Read-only views of what's actually stored in the external storage bucket, used to reconcile
+//! the `users` table against reality.
+
+use std::sync::Arc;
+
+use futures::{StreamExt, TryStreamExt};
+use object_store::{ObjectMeta, ObjectStore};
+use parquet::arrow::{async_reader::ParquetObjectReader, ParquetRecordBatchStreamBuilder};
+
+use crate::models::{ExternalStorageObjectDetail, ExternalStorageObjectInfo};
+
+use super::{build_object_store, FILENAME_SEPARATOR};
+
+impl From<ObjectMeta> for ExternalStorageObjectInfo {
+    fn from(meta: ObjectMeta) -> Self {
+        let key = meta.location.to_string();
+        let user_spotify_id = key
+            .split_once(FILENAME_SEPARATOR)
+            .map(|(user_spotify_id, _)| user_spotify_id.to_owned());
+        ExternalStorageObjectInfo {
+            key,
+            user_spotify_id,
+            size: meta.size,
+            last_modified: meta.last_modified,
+        }
+    }
+}
+
+/// Lists up to `limit` objects in the bucket with keys starting with `prefix`.
+pub(crate) async fn list_objects(
+    prefix: Option<String>,
+    limit: usize,
+) -> Result<Vec<ExternalStorageObjectInfo>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let object_store = build_object_store()?;
+    let prefix: Option<object_store::path::Path> = prefix.map(Into::into);
+    let objects: Vec<ExternalStorageObjectInfo> = object_store
+        .list(prefix.as_ref())
+        .take(limit)
+        .map_ok(ExternalStorageObjectInfo::from)
+        .try_collect()
+        .await
+        .inspect_err(|err| error!("Error listing external storage objects: {}", err))?;
+    Ok(objects)
+}
+
+/// Reads the parquet footer of the object at `key` to report its row counts without downloading
+/// the whole file.  Returns `None` if the object doesn't exist.
+pub(crate) async fn inspect_object(
+    key: String,
+) -> Result<Option<ExternalStorageObjectDetail>, Box<dyn std::error::Error + Send + Sync + 'static>>
+{
+    let object_store = Arc::new(build_object_store()?) as Arc<dyn ObjectStore>;
+    let location: object_store::path::Path = key.into();
+    let meta = match object_store.head(&location).await {
+        Ok(meta) => meta,
+        Err(object_store::Error::NotFound { .. }) => return Ok(None),
+        Err(err) => {
+            error!("Error getting external storage object metadata: {}", err);
+            return Err(err.into());
+        },
+    };
+
+    // Building the stream only fetches the footer via range reads; no row data is read
+    let reader = ParquetObjectReader::new(Arc::clone(&object_store), meta.clone());
+    let builder = ParquetRecordBatchStreamBuilder::new(reader)
+        .await
+        .inspect_err(|err| error!("Error reading parquet metadata: {}", err))?;
+    let metadata = builder.metadata();
+    let compression = metadata
+        .row_groups()
+        .first()
+        .and_then(|row_group| row_group.columns().first())
+        .map(|column| column.compression().to_string());
+
+    Ok(Some(ExternalStorageObjectDetail {
+        row_count: metadata.file_metadata().num_rows(),
+        row_group_count: metadata.num_row_groups(),
+        compression,
+        object: meta.into(),
+    }))
+}
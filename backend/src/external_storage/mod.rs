@@ -10,9 +10,11 @@
 //! fetch happens at the same time for each user.
 //!
 //! The external storage is a S3-compatible bucket hosted on Cloudflare R2.   The file format is
-//! gzip-compressed parquet.
+//! parquet, compressed with gzip or zstd depending on `DynamicConf::external_storage_compression`.
+//! The codec is recorded in the parquet metadata, so files written with either can be read back
+//! regardless of the current setting.
 
-use std::sync::Arc;
+use std::{error::Error, future::Future, sync::Arc};
 
 use arrow_schema::{DataType, Field, Schema, SchemaRef};
 use dashmap::DashMap;
@@ -22,12 +24,18 @@ use object_store::aws::{AmazonS3, AmazonS3Builder};
 
 use tokio::sync::watch;
 
-use crate::DbConn;
+use crate::{
+    metrics::{external_storage_client_build_failure_total, external_storage_held_locks},
+    DbConn,
+};
 
+pub(crate) mod cleanup;
 pub(crate) mod download;
+pub(crate) mod inspect;
 pub(crate) mod upload;
 
 const EXTERNAL_STORAGE_BUCKET_NAME: &'static str = "spotifytrack-cold-storage";
+const FILENAME_SEPARATOR: &'static str = "--SPOTIFYTRACK_INTERNAL_SEPARATOR--";
 const BATCH_SIZE: usize = 5000;
 
 lazy_static! {
@@ -57,26 +65,58 @@ fn build_object_store() -> Result<AmazonS3, object_store::Error> {
         .with_region("auto")
         .with_bucket_name(EXTERNAL_STORAGE_BUCKET_NAME.to_string())
         .build()
+        .inspect_err(|err| {
+            external_storage_client_build_failure_total().inc();
+            error!("Error building external storage client: {}", err);
+        })
+}
+
+/// Records the number of currently held retrieve and write locks.  Called periodically along with
+/// the other sampled metrics.
+pub(crate) fn record_lock_metrics() {
+    external_storage_held_locks("retrieve").set(RETRIEVE_LOCKS.len() as i64);
+    external_storage_held_locks("write").set(WRITE_LOCKS.len() as i64);
+}
+
+/// Returns the Spotify IDs of users whose data is currently being written to or cleaned up in
+/// external storage.  Exiting part-way through one of these can leave the user's
+/// `external_data_retrieved` flag out of sync with where their data actually is.
+pub(crate) fn users_with_pending_writes() -> Vec<String> {
+    WRITE_LOCKS
+        .iter()
+        .map(|entry| entry.key().clone())
+        .collect()
 }
 
 fn build_filenames(user_spotify_id: &str) -> (String, String) {
     (
-        format!(
-            "{}--SPOTIFYTRACK_INTERNAL_SEPARATOR--artists.parquet",
-            user_spotify_id
-        ),
-        format!(
-            "{}--SPOTIFYTRACK_INTERNAL_SEPARATOR--tracks.parquet",
-            user_spotify_id
-        ),
+        format!("{user_spotify_id}{FILENAME_SEPARATOR}artists.parquet"),
+        format!("{user_spotify_id}{FILENAME_SEPARATOR}tracks.parquet"),
     )
 }
 
+/// Runs the artist and track halves of a transfer concurrently, each of which makes its own object
+/// store requests.  `commit` is only run once both halves have succeeded.  If either half fails,
+/// the other is dropped immediately and the error is returned without running `commit`, so nothing
+/// is mutated in the database.
+async fn run_transfer_halves<A, T, R, C>(
+    artists_half: impl Future<Output = Result<A, Box<dyn Error + Send + Sync + 'static>>>,
+    tracks_half: impl Future<Output = Result<T, Box<dyn Error + Send + Sync + 'static>>>,
+    commit: impl FnOnce(A, T) -> C,
+) -> Result<R, Box<dyn Error + Send + Sync + 'static>>
+where
+    C: Future<Output = Result<R, Box<dyn Error + Send + Sync + 'static>>>,
+{
+    let (artists, tracks) = tokio::try_join!(artists_half, tracks_half)?;
+    commit(artists, tracks).await
+}
+
+/// Returns `true` if the flag was successfully updated.
 async fn set_data_retrieved_flag_for_user(
     conn: &DbConn,
     user_spotify_id: String,
     is_now_retrieved: bool,
-) {
+) -> bool {
     conn.run(move |conn| {
         use crate::schema::users;
 
@@ -102,7 +142,7 @@ async fn set_data_retrieved_flag_for_user(
                          for user {}",
                         user_spotify_id
                     );
-                    return;
+                    return true;
                 },
                 Err(e) => {
                     error!("Error updating users table: {}", e);
@@ -115,6 +155,36 @@ async fn set_data_retrieved_flag_for_user(
              after many retries; it's genuinely over.",
             user_spotify_id
         );
+        false
     })
+    .await
+}
+
+#[tokio::test]
+async fn transfer_halves_failure_in_second_half_aborts_first_and_skips_commit() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let artists_half_finished = AtomicBool::new(false);
+    let committed = AtomicBool::new(false);
+
+    let res = run_transfer_halves(
+        async {
+            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+            artists_half_finished.store(true, Ordering::SeqCst);
+            Ok(())
+        },
+        async { Err::<(), _>("simulated tracks failure".into()) },
+        |_, _| async {
+            committed.store(true, Ordering::SeqCst);
+            Ok(())
+        },
+    )
     .await;
+
+    assert_eq!(
+        res.unwrap_err().to_string(),
+        "simulated tracks failure".to_string()
+    );
+    assert!(!artists_half_finished.load(Ordering::SeqCst));
+    assert!(!committed.load(Ordering::SeqCst));
 }
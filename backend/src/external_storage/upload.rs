@@ -1,5 +1,7 @@
 use diesel::prelude::*;
 use std::{
+    future::Future,
+    path::{Path, PathBuf},
     sync::Arc,
     time::{Duration, Instant},
 };
@@ -8,44 +10,60 @@ use arrow_array::{
     builder::{TimestampSecondBuilder, UInt32Builder, UInt64Builder, UInt8Builder},
     ArrayRef, RecordBatch,
 };
-use object_store::ObjectStore;
+use futures::{Stream, StreamExt};
+use object_store::{ObjectStore, WriteMultipart};
 use parquet::{
-    arrow::AsyncArrowWriter,
-    basic::GzipLevel,
+    arrow::{async_reader::ParquetObjectReader, AsyncArrowWriter},
+    basic::{GzipLevel, ZstdLevel},
     file::properties::{WriterProperties, WriterVersion},
 };
-use tokio::io::AsyncWrite;
+use tokio::io::{AsyncReadExt, AsyncWrite};
 
 use crate::{
-    external_storage::download::load_external_user_data,
+    conf::{dynamic_conf, ExternalStorageCompression},
+    db_util::get_user_by_spotify_id,
+    external_storage::download::{build_parquet_readers_with_timeout, build_record_batch_reader},
     metrics::{
-        external_user_data_export_failure_total, external_user_data_export_success_total,
-        external_user_data_export_time,
+        external_storage_transfer_bytes, external_storage_transfer_duration,
+        external_storage_uploads_total, external_user_data_export_failure_total,
+        external_user_data_export_success_total, external_user_data_export_time,
     },
-    models::{ArtistHistoryEntry, TrackHistoryEntry, UserHistoryEntry},
+    models::UserHistoryEntry,
+    shutdown::register_in_flight_task,
     DbConn,
 };
 
 use super::{
-    build_filenames, set_data_retrieved_flag_for_user, EXTERNAL_STORAGE_ARROW_SCHEMA,
-    RETRIEVE_LOCKS, WRITE_LOCKS,
+    build_filenames, cleanup::cancel_pending_cleanup, run_transfer_halves,
+    set_data_retrieved_flag_for_user, BATCH_SIZE, EXTERNAL_STORAGE_ARROW_SCHEMA, RETRIEVE_LOCKS,
+    WRITE_LOCKS,
 };
 
-async fn build_parquet_writer<'a>(
-    buf: &'a mut Vec<u8>,
-) -> Result<
-    AsyncArrowWriter<impl AsyncWrite + Send + Unpin + 'a>,
-    Box<dyn std::error::Error + Send + Sync + 'static>,
-> {
+/// Max number of rows buffered by the parquet writer before a row group is flushed out
+const ROW_GROUP_SIZE: usize = BATCH_SIZE * 10;
+/// Max number of multipart upload parts in flight at once for a single file
+const MAX_CONCURRENT_PART_UPLOADS: usize = 4;
+const UPLOAD_READ_CHUNK_SIZE: usize = 1024 * 1024;
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(60 * 5);
+
+async fn build_parquet_writer<W: AsyncWrite + Send + Unpin>(
+    out: W,
+    compression: ExternalStorageCompression,
+) -> Result<AsyncArrowWriter<W>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let compression = match compression {
+        ExternalStorageCompression::Gzip =>
+            parquet::basic::Compression::GZIP(GzipLevel::try_new(8).unwrap()),
+        ExternalStorageCompression::Zstd =>
+            parquet::basic::Compression::ZSTD(ZstdLevel::try_new(7).unwrap()),
+    };
     let props = WriterProperties::builder()
         .set_writer_version(WriterVersion::PARQUET_2_0)
-        .set_compression(parquet::basic::Compression::GZIP(
-            GzipLevel::try_new(8).unwrap(),
-        ))
+        .set_compression(compression)
+        .set_max_row_group_size(ROW_GROUP_SIZE)
         .build();
 
     let schema = &EXTERNAL_STORAGE_ARROW_SCHEMA;
-    let writer = AsyncArrowWriter::try_new(buf, Arc::clone(&*schema), Some(props))?;
+    let writer = AsyncArrowWriter::try_new(out, Arc::clone(&*schema), Some(props))?;
 
     Ok(writer)
 }
@@ -86,213 +104,380 @@ fn build_record_batch(items: Vec<UserHistoryEntry>) -> RecordBatch {
     RecordBatch::try_new(schema, columns).unwrap()
 }
 
-async fn store_external_user_data_inner(
-    conn: &DbConn,
-    user_spotify_id: String,
-    extra_artist_entries: Vec<ArtistHistoryEntry>,
-    extra_track_entries: Vec<TrackHistoryEntry>,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
-    let (artists_filename, tracks_filename) = build_filenames(&user_spotify_id);
+#[derive(Clone, Copy)]
+enum SnapshotEntity {
+    Artist,
+    Track,
+}
 
-    info!(
-        "Fetching all local artist data for user {}...",
-        user_spotify_id
-    );
-    let user_spotify_id_clone = user_spotify_id.clone();
-    let mut artist_stats_for_user: Vec<UserHistoryEntry> = conn
+impl SnapshotEntity {
+    fn name(self) -> &'static str {
+        match self {
+            SnapshotEntity::Artist => "artist",
+            SnapshotEntity::Track => "track",
+        }
+    }
+}
+
+/// Loads up to `BATCH_SIZE` of the user's local snapshot entries with IDs greater than `after_id`,
+/// ordered by ID.
+async fn load_local_entries_page(
+    conn: &DbConn,
+    entity: SnapshotEntity,
+    user_id: i64,
+    after_id: i64,
+) -> Result<Vec<UserHistoryEntry>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let entries = conn
         .run(move |conn| {
-            use crate::schema::{artist_rank_snapshots, users};
+            use crate::schema::{artist_rank_snapshots, track_rank_snapshots};
 
             let mut last_err = None;
             for _ in 0..8 {
-                match artist_rank_snapshots::table
-                    .inner_join(users::table)
-                    .filter(users::dsl::spotify_id.eq(user_spotify_id_clone.clone()))
-                    .select(artist_rank_snapshots::all_columns)
-                    .load::<UserHistoryEntry>(conn)
-                {
+                let res = match entity {
+                    SnapshotEntity::Artist => artist_rank_snapshots::table
+                        .filter(artist_rank_snapshots::dsl::user_id.eq(user_id))
+                        .filter(artist_rank_snapshots::dsl::id.gt(after_id))
+                        .order_by(artist_rank_snapshots::dsl::id.asc())
+                        .limit(BATCH_SIZE as i64)
+                        .load::<UserHistoryEntry>(conn),
+                    SnapshotEntity::Track => track_rank_snapshots::table
+                        .filter(track_rank_snapshots::dsl::user_id.eq(user_id))
+                        .filter(track_rank_snapshots::dsl::id.gt(after_id))
+                        .order_by(track_rank_snapshots::dsl::id.asc())
+                        .limit(BATCH_SIZE as i64)
+                        .load::<UserHistoryEntry>(conn),
+                };
+                match res {
                     Ok(rows) => return Ok(rows),
                     Err(err) => {
-                        error!("Error loading artist rank snapshots: {}", err);
+                        error!("Error loading {} rank snapshots: {}", entity.name(), err);
                         last_err = Some(err);
                         std::thread::sleep(std::time::Duration::from_secs(1));
                     },
                 }
             }
             let err = last_err.unwrap();
-            error!("Error loading artist rank snapshots after retries: {}", err);
+            error!(
+                "Error loading {} rank snapshots after retries: {}",
+                entity.name(),
+                err
+            );
             Err(err)
         })
         .await?;
-    let local_artist_entry_count = artist_stats_for_user.len();
-    let extra_artist_entry_count = extra_artist_entries.len();
-    artist_stats_for_user.extend(extra_artist_entries.into_iter().map(Into::into));
-    info!(
-        "Successfully fetched all local artist data for user {}. Starting upload to external \
-         storage...",
-        user_spotify_id
-    );
+    Ok(entries)
+}
 
-    let mut artists_data_buf = Vec::new();
-    let mut artists_writer = build_parquet_writer(&mut artists_data_buf)
-        .await
-        .inspect_err(|err| {
-            error!("Error building parquet writer: {}", err);
-        })?;
-    let artists_record_batch = build_record_batch(artist_stats_for_user);
-    artists_writer
-        .write(&artists_record_batch)
-        .await
-        .inspect_err(|err| {
-            error!("Error writing artist data to parquet: {}", err);
-        })?;
-    artists_writer.close().await.inspect_err(|err| {
-        error!("Error closing parquet writer: {}", err);
-    })?;
-    info!(
-        "Successfully encoded all {local_artist_entry_count} local + {extra_artist_entry_count} \
-         extra track data for user {user_spotify_id}. Starting upload to external storage at \
-         {artists_filename}...",
-    );
-    let object_store = super::build_object_store()?;
-    let location: object_store::path::Path = artists_filename.into();
-    let mut upload_attempts = 0usize;
+/// Writes all of the `existing_batches` followed by every page of local entries returned by
+/// `load_page` to `writer`.  Only one batch/page is held in memory at a time.
+///
+/// `load_page` is called with the ID of the last entry from the previous page (starting at
+/// `i64::MIN`) and should return up to `BATCH_SIZE` entries ordered by ID.
+///
+/// Returns `(existing_entry_count, local_entry_count)`.
+async fn write_entries_streaming<W, S, E, F, Fut>(
+    writer: &mut AsyncArrowWriter<W>,
+    existing_batches: Option<S>,
+    mut load_page: F,
+) -> Result<(usize, usize), Box<dyn std::error::Error + Send + Sync + 'static>>
+where
+    W: AsyncWrite + Send + Unpin,
+    S: Stream<Item = Result<RecordBatch, E>>,
+    E: Into<Box<dyn std::error::Error + Send + Sync + 'static>>,
+    F: FnMut(i64) -> Fut,
+    Fut: Future<
+        Output = Result<Vec<UserHistoryEntry>, Box<dyn std::error::Error + Send + Sync + 'static>>,
+    >,
+{
+    let mut existing_entry_count = 0usize;
+    if let Some(existing_batches) = existing_batches {
+        futures::pin_mut!(existing_batches);
+        // Existing data from external storage has the same schema, so batches can be written back
+        // out as-is
+        while let Some(res) = existing_batches.next().await {
+            let record_batch = res.map_err(
+                |err| -> Box<dyn std::error::Error + Send + Sync + 'static> { err.into() },
+            )?;
+            existing_entry_count += record_batch.num_rows();
+            writer.write(&record_batch).await?;
+        }
+    }
+
+    let mut local_entry_count = 0usize;
+    let mut after_id = i64::MIN;
     loop {
-        match tokio::time::timeout(
-            Duration::from_secs(30),
-            object_store.put(&location, artists_data_buf.clone().into()),
-        )
-        .await
-        {
-            Ok(Ok(_)) => break,
-            Err(err) => {
-                error!("Timeout uploading artist data to external storage");
-                if upload_attempts >= 8 {
-                    return Err(err.into());
-                }
-            },
-            Ok(Err(err)) => {
-                error!("Error uploading artist data to external storage: {}", err);
-                if upload_attempts >= 8 {
-                    return Err(err.into());
-                }
-            },
+        let page = load_page(after_id).await?;
+        let page_len = page.len();
+        let last_id = match page.last() {
+            Some(entry) => entry.id,
+            None => break,
+        };
+        local_entry_count += page_len;
+        writer.write(&build_record_batch(page)).await?;
+
+        if page_len < BATCH_SIZE {
+            break;
         }
-        upload_attempts += 1;
+        after_id = last_id;
     }
-    info!(
-        "Successfully uploaded all {local_artist_entry_count} local + {extra_artist_entry_count} \
-         extra artist data for user {user_spotify_id}",
-    );
 
-    info!(
-        "Fetching all local track data for user {}...",
-        user_spotify_id
-    );
-    let user_spotify_id_clone = user_spotify_id.clone();
-    let mut track_stats_for_user: Vec<UserHistoryEntry> = conn
-        .run(move |conn| {
-            use crate::schema::{track_rank_snapshots, users};
+    Ok((existing_entry_count, local_entry_count))
+}
 
-            let mut last_err = None;
-            for _ in 0..8 {
-                match track_rank_snapshots::table
-                    .inner_join(users::table)
-                    .filter(users::dsl::spotify_id.eq(user_spotify_id_clone.clone()))
-                    .select(track_rank_snapshots::all_columns)
-                    .load::<UserHistoryEntry>(conn)
-                {
-                    Ok(rows) => return Ok(rows),
-                    Err(err) => {
-                        error!("Error loading track rank snapshots: {}", err);
-                        last_err = Some(err);
-                        std::thread::sleep(std::time::Duration::from_secs(1));
-                    },
-                }
+/// Deletes the file at the wrapped path when dropped.
+struct TempFileGuard(PathBuf);
+
+impl Drop for TempFileGuard {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.0) {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                error!("Error removing temp file {:?}: {}", self.0, err);
+            }
+        }
+    }
+}
+
+fn build_temp_file_path(entity: SnapshotEntity) -> TempFileGuard {
+    TempFileGuard(std::env::temp_dir().join(format!(
+        "spotifytrack-external-storage-{}-{:016x}.parquet",
+        entity.name(),
+        rand::random::<u64>()
+    )))
+}
+
+/// Uploads the file at `path` to external storage at `location` using a multipart upload, so that
+/// the whole file never needs to be held in memory.
+async fn upload_file_multipart(
+    path: &Path,
+    location: &object_store::path::Path,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let object_store = super::build_object_store()?;
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut upload = WriteMultipart::new(object_store.put_multipart(location).await?);
+
+    let res: Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> = async {
+        let mut buf = vec![0u8; UPLOAD_READ_CHUNK_SIZE];
+        loop {
+            let read_byte_count = file.read(&mut buf).await?;
+            if read_byte_count == 0 {
+                return Ok(());
+            }
+            upload
+                .wait_for_capacity(MAX_CONCURRENT_PART_UPLOADS)
+                .await?;
+            upload.write(&buf[..read_byte_count]);
+        }
+    }
+    .await;
+
+    match res {
+        Ok(()) => {
+            upload.finish().await?;
+            Ok(())
+        },
+        Err(err) => {
+            if let Err(abort_err) = upload.abort().await {
+                error!("Error aborting multipart upload: {}", abort_err);
             }
-            let err = last_err.unwrap();
-            error!("Error loading track rank snapshots after retries: {}", err);
             Err(err)
-        })
-        .await?;
-    let local_track_entry_count = track_stats_for_user.len();
-    let extra_track_entry_count = extra_track_entries.len();
-    track_stats_for_user.extend(extra_track_entries.into_iter().map(Into::into));
-    info!(
-        "Successfully fetched all local track data for user {user_spotify_id}; Starting upload to \
-         external storage...",
-    );
+        },
+    }
+}
 
-    let mut tracks_data_buf = Vec::new();
-    let mut tracks_writer = build_parquet_writer(&mut tracks_data_buf)
+/// A parquet file on disk holding all of a user's data for one entity, ready to be uploaded.  The
+/// file is deleted when this is dropped.
+struct EncodedEntityFile {
+    file: TempFileGuard,
+    entity: SnapshotEntity,
+    entry_count: usize,
+    byte_count: usize,
+}
+
+/// Streams the user's local data for `entity` merged with the existing external data from
+/// `existing_reader` into a parquet file on disk.
+async fn encode_entity_data_to_file(
+    conn: &DbConn,
+    entity: SnapshotEntity,
+    user_id: i64,
+    user_spotify_id: &str,
+    existing_reader: Option<ParquetObjectReader>,
+) -> Result<EncodedEntityFile, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let entity_name = entity.name();
+    let temp_file = build_temp_file_path(entity);
+
+    info!("Encoding all {entity_name} data for user {user_spotify_id}...");
+    let file = tokio::fs::File::create(&temp_file.0).await?;
+    let mut writer = build_parquet_writer(file, dynamic_conf().external_storage_compression)
         .await
         .inspect_err(|err| {
             error!("Error building parquet writer: {}", err);
         })?;
-    let tracks_record_batch = build_record_batch(track_stats_for_user);
-    tracks_writer
-        .write(&tracks_record_batch)
+    let existing_batches = match existing_reader {
+        Some(reader) => Some(build_record_batch_reader(reader).await?),
+        None => None,
+    };
+    let (existing_entry_count, local_entry_count) =
+        write_entries_streaming(&mut writer, existing_batches, |after_id| {
+            load_local_entries_page(conn, entity, user_id, after_id)
+        })
         .await
         .inspect_err(|err| {
-            error!("Error writing track data to parquet: {}", err);
+            error!("Error writing {entity_name} data to parquet: {}", err);
         })?;
-    tracks_writer.close().await.inspect_err(|err| {
+    writer.close().await.inspect_err(|err| {
         error!("Error closing parquet writer: {}", err);
     })?;
+    let byte_count = tokio::fs::metadata(&temp_file.0).await?.len() as usize;
+
     info!(
-        "Successfully encoded all {local_track_entry_count} local + {extra_track_entry_count} \
-         extra track data for user {user_spotify_id}. Starting upload to external storage at \
-         {tracks_filename}...",
+        "Encoded {local_entry_count} local + {existing_entry_count} existing external \
+         {entity_name} entries for user {user_spotify_id} ({byte_count} bytes)"
     );
-    let object_store = super::build_object_store()?;
-    let location: object_store::path::Path = tracks_filename.into();
+    Ok(EncodedEntityFile {
+        file: temp_file,
+        entity,
+        entry_count: existing_entry_count + local_entry_count,
+        byte_count,
+    })
+}
+
+/// Encodes all of the user's artist and track data, merged with any data that's already in
+/// external storage, into parquet files on disk.
+///
+/// This is only done once per transfer.  Uploads may partially succeed and overwrite the existing
+/// external data, so retrying them must not read it again.
+async fn encode_user_data(
+    conn: &DbConn,
+    user_spotify_id: &str,
+) -> Result<
+    (EncodedEntityFile, EncodedEntityFile),
+    Box<dyn std::error::Error + Send + Sync + 'static>,
+> {
+    let user = get_user_by_spotify_id(conn, user_spotify_id.to_owned())
+        .await?
+        .ok_or("User not found")?;
+
+    // We're holding the write lock, so nothing else can modify the external data in the meantime
+    let (existing_artists_reader, existing_tracks_reader, _) =
+        build_parquet_readers_with_timeout(user_spotify_id).await?;
+
+    run_transfer_halves(
+        encode_entity_data_to_file(
+            conn,
+            SnapshotEntity::Artist,
+            user.id,
+            user_spotify_id,
+            existing_artists_reader,
+        ),
+        encode_entity_data_to_file(
+            conn,
+            SnapshotEntity::Track,
+            user.id,
+            user_spotify_id,
+            existing_tracks_reader,
+        ),
+        |artists_file, tracks_file| async move { Ok((artists_file, tracks_file)) },
+    )
+    .await
+}
+
+async fn upload_encoded_file(
+    encoded: &EncodedEntityFile,
+    filename: String,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let entity_name = encoded.entity.name();
+    info!("Starting upload of {entity_name} data to external storage at {filename}...");
+
+    let location: object_store::path::Path = filename.into();
     let mut upload_attempts = 0usize;
     loop {
         match tokio::time::timeout(
-            Duration::from_secs(30),
-            object_store.put(&location, tracks_data_buf.clone().into()),
+            UPLOAD_TIMEOUT,
+            upload_file_multipart(&encoded.file.0, &location),
         )
         .await
         {
-            Ok(Ok(_)) => break,
+            Ok(Ok(())) => return Ok(()),
             Err(err) => {
-                error!("Timeout uploading track data to external storage");
+                error!("Timeout uploading {entity_name} data to external storage");
                 if upload_attempts >= 8 {
                     return Err(err.into());
                 }
             },
             Ok(Err(err)) => {
-                error!("Error uploading track data to external storage: {}", err);
+                error!(
+                    "Error uploading {entity_name} data to external storage: {}",
+                    err
+                );
                 if upload_attempts >= 8 {
-                    return Err(err.into());
+                    return Err(err);
                 }
             },
         }
         upload_attempts += 1;
     }
-    info!(
-        "Successfully uploaded all {local_track_entry_count} local + {extra_track_entry_count} \
-         extra track data for user {user_spotify_id}",
-    );
+}
+
+/// Summary of a successful upload of a user's data to external storage.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct ExternalUserDataUploadStats {
+    /// Total artist entries uploaded, including ones merged in from existing external data
+    pub artist_entry_count: usize,
+    /// Total track entries uploaded, including ones merged in from existing external data
+    pub track_entry_count: usize,
+    pub uploaded_bytes: usize,
+}
 
+async fn store_external_user_data_inner(
+    user_spotify_id: &str,
+    artists_file: &EncodedEntityFile,
+    tracks_file: &EncodedEntityFile,
+) -> Result<ExternalUserDataUploadStats, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (artists_filename, tracks_filename) = build_filenames(user_spotify_id);
+
+    // Artists and tracks are uploaded concurrently.  Local data is only deleted by the caller once
+    // both halves have been uploaded successfully.
+    run_transfer_halves(
+        upload_encoded_file(artists_file, artists_filename),
+        upload_encoded_file(tracks_file, tracks_filename),
+        |(), ()| async move { Ok(()) },
+    )
+    .await?;
+
+    let (artist_entry_count, track_entry_count) =
+        (artists_file.entry_count, tracks_file.entry_count);
     info!(
-        "Successfully uploaded all {} artist data and all {} track data for user {user_spotify_id}",
-        local_artist_entry_count + extra_artist_entry_count,
-        local_track_entry_count + extra_track_entry_count,
+        "Successfully uploaded all {artist_entry_count} artist data and all {track_entry_count} \
+         track data for user {user_spotify_id}",
     );
 
-    Ok(())
+    Ok(ExternalUserDataUploadStats {
+        artist_entry_count,
+        track_entry_count,
+        uploaded_bytes: artists_file.byte_count + tracks_file.byte_count,
+    })
 }
 
-pub(crate) async fn store_external_user_data(conn: &DbConn, user_spotify_id: String) {
+/// Moves all of the user's data to external storage, merging it with any data that's already
+/// stored there, and deletes the local copy.  Returns an error message describing what went wrong
+/// if the upload didn't complete.
+pub(crate) async fn store_external_user_data(
+    conn: &DbConn,
+    user_spotify_id: String,
+) -> Result<ExternalUserDataUploadStats, String> {
     let lock_exists = WRITE_LOCKS.insert(user_spotify_id.clone(), ()).is_some();
     if lock_exists {
         warn!(
             "Write lock already exists for user {}, skipping...",
             user_spotify_id
         );
-        return;
+        external_storage_uploads_total("skipped").inc();
+        return Err(String::from("Write lock already held for user; skipped"));
     }
+    let _in_flight_guard =
+        register_in_flight_task(format!("store_external_user_data {}", user_spotify_id));
+    // Any objects left over from a previous restore are about to be overwritten
+    cancel_pending_cleanup(&user_spotify_id);
 
     // If we're super unlucky and there's currently a read operation ongoing for this user, wait
     // for it to finish first.  We'll hold the write lock while we wait.
@@ -304,49 +489,39 @@ pub(crate) async fn store_external_user_data(conn: &DbConn, user_spotify_id: Str
         tokio::time::sleep(std::time::Duration::from_secs(1)).await;
     }
 
-    // To start, we first do a full retrieve for the user so that we can merge any existing external
-    // data with the local data before writing it out.
-    //
-    // We're already holding the write lock, so no other reads or writes can happen for this user
+    let transfer_start = Instant::now();
+    let mut start = transfer_start;
     info!(
-        "Starting full retrieval for user {} before writing...",
+        "Encoding all data for user {} before upload",
         user_spotify_id
     );
-
-    let mut start = Instant::now();
-    let (existing_external_artist_entries, existing_external_track_entries) =
-        match load_external_user_data(user_spotify_id.clone()).await {
-            Ok((artists, tracks)) => (artists, tracks),
-            Err(err) => {
-                error!(
-                    "Error loading existing external data for user {}: {}",
-                    user_spotify_id, err
-                );
-                return;
-            },
-        };
-    info!(
-        "Finished full retrieval for user {} before writing; pulled {} external artist entries \
-         and {} external track entries",
-        user_spotify_id,
-        existing_external_artist_entries.len(),
-        existing_external_track_entries.len()
-    );
+    let (artists_file, tracks_file) = match encode_user_data(conn, &user_spotify_id).await {
+        Ok(files) => files,
+        Err(err) => {
+            external_user_data_export_failure_total().inc();
+            error!(
+                "Error encoding data for user {} before upload: {}",
+                user_spotify_id, err
+            );
+            WRITE_LOCKS.remove(&user_spotify_id);
+            external_storage_uploads_total("failure").inc();
+            return Err(format!("Error encoding data: {}", err));
+        },
+    };
 
     info!("Starting external data upload for user {}", user_spotify_id);
+    let mut outcome = Err(String::from("Upload never attempted"));
     for _ in 0..10 {
         let user_spotify_id = user_spotify_id.clone();
-        let res = store_external_user_data_inner(
-            conn,
-            user_spotify_id.clone(),
-            existing_external_artist_entries.clone(),
-            existing_external_track_entries.clone(),
-        )
-        .await;
+        let res =
+            store_external_user_data_inner(&user_spotify_id, &artists_file, &tracks_file).await;
         match res {
-            Ok(()) => {
+            Ok(stats) => {
                 external_user_data_export_success_total().inc();
                 external_user_data_export_time().observe(start.elapsed().as_nanos() as u64);
+                external_storage_transfer_duration("upload")
+                    .observe(transfer_start.elapsed().as_nanos() as u64);
+                external_storage_transfer_bytes("upload").observe(stats.uploaded_bytes as f64);
                 info!("Finished external data upload for user {}", user_spotify_id);
 
                 // Update users table to indicate that upload is complete
@@ -360,17 +535,26 @@ pub(crate) async fn store_external_user_data(conn: &DbConn, user_spotify_id: Str
                     );
                 }
 
+                outcome = Ok(stats);
                 break;
             },
             Err(e) => {
                 external_user_data_export_failure_total().inc();
                 error!("Error storing data for user {}: {}", user_spotify_id, e);
+                outcome = Err(format!("Error storing data: {}", e));
                 start = Instant::now();
             },
         }
     }
 
+    external_storage_uploads_total(if outcome.is_ok() {
+        "success"
+    } else {
+        "failure"
+    })
+    .inc();
     WRITE_LOCKS.remove(&user_spotify_id);
+    outcome
 }
 
 async fn delete_local_user_data(conn: &DbConn, user_spotify_id: String) -> QueryResult<()> {
@@ -469,3 +653,161 @@ async fn delete_local_user_data(conn: &DbConn, user_spotify_id: String) -> Query
             )
         })?
 }
+
+/// Builds a synthetic set of snapshot entries resembling a long-tracked user: 50 entries for each
+/// of the 3 timeframes per update, drawn from a limited pool of artists/tracks.
+#[cfg(test)]
+pub(super) fn build_synthetic_entries(count: usize) -> Vec<UserHistoryEntry> {
+    use rand::{Rng, SeedableRng};
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    let start_time = chrono::DateTime::from_timestamp(1_500_000_000, 0)
+        .unwrap()
+        .naive_utc();
+    (0..count)
+        .map(|i| {
+            let update_ix = i / 150;
+            UserHistoryEntry {
+                id: 100_000_000 + i as i64,
+                user_id: 1234,
+                update_time: start_time + chrono::Duration::hours(6 * update_ix as i64),
+                mapped_spotify_id: rng.gen_range(0..3000),
+                timeframe: ((i / 50) % 3) as u8,
+                ranking: (i % 50) as u8 + 1,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub(super) async fn encode_entries(
+    entries: Vec<UserHistoryEntry>,
+    compression: ExternalStorageCompression,
+) -> Vec<u8> {
+    let mut buf = Vec::new();
+    let mut writer = build_parquet_writer(&mut buf, compression).await.unwrap();
+    writer.write(&build_record_batch(entries)).await.unwrap();
+    writer.close().await.unwrap();
+    buf
+}
+
+#[tokio::test]
+async fn parquet_round_trip_gzip_and_zstd() {
+    use futures::TryStreamExt;
+    use parquet::arrow::ParquetRecordBatchStreamBuilder;
+
+    const ENTRY_COUNT: usize = 10_000;
+
+    for compression in [
+        ExternalStorageCompression::Gzip,
+        ExternalStorageCompression::Zstd,
+    ] {
+        let expected = build_record_batch(build_synthetic_entries(ENTRY_COUNT));
+        let encoded = encode_entries(build_synthetic_entries(ENTRY_COUNT), compression).await;
+
+        // Reads don't get told which codec was used; it's detected from the file metadata
+        let batches: Vec<RecordBatch> =
+            ParquetRecordBatchStreamBuilder::new(std::io::Cursor::new(encoded))
+                .await
+                .unwrap()
+                .with_batch_size(ENTRY_COUNT)
+                .build()
+                .unwrap()
+                .try_collect()
+                .await
+                .unwrap();
+        assert_eq!(batches.len(), 1, "{compression:?}");
+        assert_eq!(batches[0], expected, "{compression:?}");
+    }
+}
+
+/// Compares encoded size and encode time for each codec.  Run with
+/// `cargo test --release parquet_compression_size_comparison -- --ignored --nocapture`.
+#[tokio::test]
+#[ignore]
+async fn parquet_compression_size_comparison() {
+    const ENTRY_COUNT: usize = 50_000;
+
+    for compression in [
+        ExternalStorageCompression::Gzip,
+        ExternalStorageCompression::Zstd,
+    ] {
+        let entries = build_synthetic_entries(ENTRY_COUNT);
+        let start = Instant::now();
+        let encoded = encode_entries(entries, compression).await;
+        println!(
+            "{compression:?}: {} bytes for {ENTRY_COUNT} entries; encoded in {:?}",
+            encoded.len(),
+            start.elapsed()
+        );
+    }
+}
+
+#[tokio::test]
+async fn streaming_write_of_large_user_is_paged() {
+    use futures::TryStreamExt;
+    use parquet::arrow::ParquetRecordBatchStreamBuilder;
+
+    const LOCAL_ENTRY_COUNT: usize = 100_000;
+    const EXISTING_ENTRY_COUNT: usize = 12_345;
+
+    // Stand-in for the DB: serves pages of entries ordered by ID after the requested one
+    let local_entries = build_synthetic_entries(LOCAL_ENTRY_COUNT);
+    let mut page_count = 0usize;
+    let mut max_page_len = 0usize;
+    let load_page = |after_id: i64| {
+        let page: Vec<UserHistoryEntry> = local_entries
+            .iter()
+            .filter(|entry| entry.id > after_id)
+            .take(BATCH_SIZE)
+            .cloned()
+            .collect();
+        page_count += 1;
+        max_page_len = max_page_len.max(page.len());
+        async move { Ok::<_, Box<dyn std::error::Error + Send + Sync + 'static>>(page) }
+    };
+
+    let existing_batches = futures::stream::iter(
+        build_synthetic_entries(EXISTING_ENTRY_COUNT)
+            .chunks(1000)
+            .map(|chunk| {
+                Ok::<_, parquet::errors::ParquetError>(build_record_batch(
+                    chunk
+                        .iter()
+                        .map(|entry| UserHistoryEntry {
+                            id: entry.id - 1_000_000,
+                            ..entry.clone()
+                        })
+                        .collect(),
+                ))
+            })
+            .collect::<Vec<_>>(),
+    );
+
+    let mut buf = Vec::new();
+    let mut writer = build_parquet_writer(&mut buf, ExternalStorageCompression::Zstd)
+        .await
+        .unwrap();
+    let (existing_entry_count, local_entry_count) =
+        write_entries_streaming(&mut writer, Some(existing_batches), load_page)
+            .await
+            .unwrap();
+    writer.close().await.unwrap();
+
+    assert_eq!(existing_entry_count, EXISTING_ENTRY_COUNT);
+    assert_eq!(local_entry_count, LOCAL_ENTRY_COUNT);
+    // Never more than one page of local entries is loaded at a time
+    assert_eq!(max_page_len, BATCH_SIZE);
+    assert_eq!(page_count, LOCAL_ENTRY_COUNT / BATCH_SIZE + 1);
+
+    let batches: Vec<RecordBatch> = ParquetRecordBatchStreamBuilder::new(std::io::Cursor::new(buf))
+        .await
+        .unwrap()
+        .build()
+        .unwrap()
+        .try_collect()
+        .await
+        .unwrap();
+    let row_count: usize = batches.iter().map(RecordBatch::num_rows).sum();
+    assert_eq!(row_count, EXISTING_ENTRY_COUNT + LOCAL_ENTRY_COUNT);
+}
@@ -0,0 +1,99 @@
+//! Batched persistence of users' `last_viewed` times.
+//!
+//! Profile views are recorded in memory and flushed to the database periodically, coalescing
+//! multiple views of the same user into a single write.  `last_viewed` only drives the external
+//! storage archival cutoff, so it's fine for it to lag behind by up to one flush interval.
+//!
+//! Pending views are flushed one last time on shutdown.  If the process exits without a clean
+//! shutdown, views recorded since the last flush are lost.
+
+use std::time::Duration;
+
+use dashmap::DashMap;
+use diesel::MysqlConnection;
+use lazy_static::lazy_static;
+use rocket::{fairing::AdHoc, Orbit, Rocket};
+use rocket_sync_db_pools::ConnectionPool;
+
+use crate::{
+    db_util::update_users_last_viewed,
+    shutdown::{register_in_flight_task, wait_for_shutdown},
+    DbConn,
+};
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60 * 5);
+const FLUSH_CHUNK_SIZE: usize = 1000;
+
+lazy_static! {
+    /// Internal IDs of users that have been viewed since the last flush
+    static ref PENDING_VIEWS: DashMap<i64, ()> = DashMap::new();
+}
+
+/// Records that the user with the given internal ID was viewed.  The write to the database happens
+/// in the next flush.
+pub(crate) fn record_user_view(user_id: i64) { PENDING_VIEWS.insert(user_id, ()); }
+
+async fn flush_pending_views(pool: &ConnectionPool<DbConn, MysqlConnection>) {
+    let user_ids: Vec<i64> = PENDING_VIEWS.iter().map(|entry| *entry.key()).collect();
+    if user_ids.is_empty() {
+        return;
+    }
+    for user_id in &user_ids {
+        PENDING_VIEWS.remove(user_id);
+    }
+
+    let conn = match pool.get().await {
+        Some(conn) => conn,
+        None => {
+            error!("Failed to check out a database connection to flush user last viewed times");
+            user_ids.into_iter().for_each(record_user_view);
+            return;
+        },
+    };
+
+    for chunk in user_ids.chunks(FLUSH_CHUNK_SIZE) {
+        let chunk = chunk.to_owned();
+        let chunk_clone = chunk.clone();
+        let res = conn
+            .run(move |conn| update_users_last_viewed(conn, &chunk_clone))
+            .await;
+        if let Err(err) = res {
+            error!(
+                "Error updating last viewed time for {} users: {:?}",
+                chunk.len(),
+                err
+            );
+            chunk.into_iter().for_each(record_user_view);
+        }
+    }
+}
+
+async fn run_last_viewed_flusher(pool: ConnectionPool<DbConn, MysqlConnection>) {
+    // Held for the flusher's whole lifetime so that shutdown waits for the final flush
+    let _in_flight_guard = register_in_flight_task("last_viewed_flusher");
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(FLUSH_INTERVAL) => flush_pending_views(&pool).await,
+            _ = wait_for_shutdown() => {
+                flush_pending_views(&pool).await;
+                return;
+            },
+        }
+    }
+}
+
+/// Starts the background task that flushes recorded views once the database pool exists.
+pub(crate) fn last_viewed_flush_fairing() -> AdHoc {
+    AdHoc::on_liftoff("Last Viewed Flusher", |rocket: &Rocket<Orbit>| {
+        Box::pin(async move {
+            match DbConn::pool(rocket) {
+                Some(pool) => {
+                    tokio::task::spawn(run_last_viewed_flusher(pool.clone()));
+                },
+                None =>
+                    error!("Database pool not initialized; user last viewed times won't be saved"),
+            }
+        })
+    })
+}
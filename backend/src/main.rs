@@ -1,4 +1,4 @@
-#![feature(proc_macro_hygiene, decl_macro, box_patterns, try_trait_v2)]
+#![feature(proc_macro_hygiene, decl_macro, box_patterns)]
 #![allow(clippy::identity_conversion)]
 
 #[macro_use]
@@ -16,8 +16,8 @@ use foundations::telemetry::{
     tokio_runtime_metrics::record_runtime_metrics_sample,
 };
 // use rocket_async_compression::Compression;
-use tokio::sync::Mutex;
 
+pub mod admin_auth;
 pub mod artist_embedding;
 pub mod benchmarking;
 pub mod cache;
@@ -25,14 +25,18 @@ pub mod conf;
 pub mod cors;
 pub mod db_util;
 pub mod external_storage;
+pub mod last_viewed;
 pub mod metrics;
 pub mod models;
+pub mod request_metrics;
 pub mod routes;
 pub mod schema;
 pub mod shared_playlist_gen;
+pub mod shutdown;
 pub mod spotify_api;
 pub mod spotify_token;
 pub mod stats;
+pub mod user_metrics;
 
 use crate::{cache::local_cache::init_spotify_id_map_cache, conf::CONF};
 
@@ -43,7 +47,11 @@ pub struct DbConn(diesel::MysqlConnection);
 
 #[rocket::main]
 pub async fn main() {
+    // Must be captured before the dotenv file is loaded into the environment
+    lazy_static::initialize(&conf::PROCESS_ENV);
     dotenv::dotenv().expect("dotenv file parsing failed");
+    // Validate the dynamic config up front rather than the first time it's used
+    conf::dynamic_conf();
 
     let tele_serv_fut = foundations::telemetry::init_with_server(
         &foundations::service_info!(),
@@ -73,46 +81,78 @@ pub async fn main() {
     tokio::task::spawn(async move {
         loop {
             record_runtime_metrics_sample();
+            external_storage::record_lock_metrics();
+            cache::record_redis_pool_metrics();
 
             // record metrics roughly twice a second
-            tokio::time::sleep(Duration::from_millis(500)).await;
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_millis(500)) => (),
+                _ = shutdown::wait_for_shutdown() => break,
+            }
         }
     });
 
     tokio::task::spawn(init_spotify_id_map_cache());
+    tokio::task::spawn(external_storage::cleanup::run_cleanup_janitor());
     init_artist_embedding_ctx("https://ameo.dev/artist_embedding_8d.w2v").await;
 
     let all_routes = routes![
         routes::index,
         routes::get_current_stats,
+        routes::get_stats_snapshot,
+        routes::get_stats_summary,
         routes::oauth_cb,
         routes::authorize,
         routes::update_user,
         routes::get_artist_stats,
+        routes::get_artist_rank_histories,
         routes::get_genre_history,
+        routes::get_genre_breakdown,
+        routes::get_all_genres,
+        routes::get_album_stats,
+        routes::get_artist_growth,
         routes::populate_tracks_artists_mapping_table,
         routes::populate_artists_genres_mapping_table,
+        routes::rebuild_first_seen,
+        routes::prune_orphaned_spotify_items,
         routes::get_genre_stats,
         routes::get_timeline,
+        routes::get_new_artists,
+        routes::get_who_first,
         routes::compare_users,
         routes::get_related_artists_graph,
         routes::get_related_artists,
+        routes::get_recommendations,
+        routes::get_artist_neighborhood,
         routes::get_display_name,
+        routes::get_display_names,
+        routes::user_exists,
         routes::dump_redis_related_artists_to_database,
         routes::crawl_related_artists,
         routes::search_artist,
         routes::get_average_artists_route,
+        routes::get_packed_average_artists_route,
+        routes::get_artist_embedding,
         routes::get_artist_image_url,
         routes::get_packed_3d_artist_coords_route,
         routes::refetch_cached_artists_missing_popularity,
+        routes::purge_stale_cache_versions,
+        routes::get_cache_entry,
+        routes::delete_cache_entry,
+        routes::get_cache_stats_route,
         routes::get_artists_by_internal_ids,
+        routes::get_artist_metadata_by_internal_ids,
         routes::get_packed_artist_relationships_by_internal_ids,
         routes::get_preview_urls_by_internal_id,
         routes::get_top_artists_internal_ids_for_user,
+        routes::get_top_artists_detailed_for_user,
         routes::get_artist_relationships_chunk,
         routes::transfer_user_data_to_external_storage,
         routes::transfer_user_data_from_external_storage,
         routes::bulk_transfer_user_data_to_external_storage,
+        routes::list_external_storage_objects,
+        routes::reload_config,
+        routes::drain,
     ];
 
     // Pre-populate the packed 3D artist map embedding to make the first request for it instant
@@ -123,10 +163,32 @@ pub async fn main() {
     let builder = rocket::build()
         .mount("/", all_routes.clone())
         .mount("/api/", all_routes)
-        .manage(Mutex::new(SpotifyTokenData::new().await))
+        .manage(SpotifyTokenData::new().await)
         .attach(DbConn::fairing())
-        .attach(cors::CorsFairing);
+        .attach(cors::CorsFairing)
+        .attach(request_metrics::RequestMetricsFairing)
+        .attach(user_metrics::user_metrics_fairing())
+        .attach(last_viewed::last_viewed_flush_fairing());
 
-    builder.launch().await.expect("Error launching Rocket");
+    let rocket = builder.ignite().await.expect("Error igniting Rocket");
+
+    // Rocket only handles ctrl-c on its own, but SIGTERM is what we get when being redeployed.
+    // In-flight tasks are drained before Rocket is told to shut down since Rocket cancels any
+    // requests that are still running shortly after that, which would include the tasks.
+    let shutdown_handle = rocket.shutdown();
+    tokio::task::spawn(async move {
+        let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to register SIGTERM handler");
+        sigterm.recv().await;
+        info!("Received SIGTERM; draining in-flight tasks before shutting down...");
+        shutdown::shutdown().await;
+        shutdown_handle.notify();
+    });
+
+    rocket.launch().await.expect("Error launching Rocket");
     info!("Rocket exited cleanly");
+
+    // After SIGTERM everything has already been drained so this returns right away, but it's still
+    // needed for when Rocket shuts down on its own, e.g. from ctrl-c
+    shutdown::shutdown().await;
 }
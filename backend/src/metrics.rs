@@ -1,4 +1,6 @@
-use foundations::telemetry::metrics::{metrics, Counter, HistogramBuilder, TimeHistogram};
+use foundations::telemetry::metrics::{
+    metrics, Counter, Gauge, Histogram, HistogramBuilder, TimeHistogram,
+};
 
 use foundations;
 
@@ -16,18 +18,40 @@ pub(crate) mod metrics {
     /// Total number of rate limited requests made to all Spotify API endpoints
     pub fn spotify_api_requests_rate_limited_total(endpoint_name: &'static str) -> Counter;
 
+    /// Total number of top entity timeframes discarded because Spotify returned the same ID for
+    /// most of the items, by entity type (`tracks` or `artists`)
+    pub fn spotify_corrupt_top_entities_total(entity_type: &'static str) -> Counter;
+
     /// Distribution of response times for the Spotify API
     #[ctor = HistogramBuilder {
         buckets: &[0.005, 0.01, 0.025, 0.05, 0.1, 0.15, 0.2, 0.25, 0.35, 0.5, 1.0, 2.5, 5.0, 10.0],
     }]
     pub fn spotify_api_response_time(endpoint_name: &'static str) -> TimeHistogram;
 
+    /// Total number of HTTP responses sent, by the name of the matched route and status class
+    /// (`2xx`, `4xx`, etc.)
+    pub fn http_requests_total(route: &'static str, status_class: &'static str) -> Counter;
+
+    /// Total number of errors returned by routes, by route name and kind of error
+    pub fn endpoint_errors_total(route: &'static str, kind: &'static str) -> Counter;
+
     /// Total number of successful user updates
     pub fn user_updates_success_total() -> Counter;
 
     /// Total number of failed user updates
     pub fn user_updates_failure_total() -> Counter;
 
+    /// Total number of stats snapshots stored with some timeframes missing because they failed to
+    /// be fetched from Spotify
+    pub fn partial_stats_snapshots_total() -> Counter;
+
+    /// Total number of top entity timeframes that failed to be fetched while updating user stats,
+    /// by entity type and timeframe
+    pub fn stats_timeframe_fetch_failures_total(
+        entity_type: &'static str,
+        timeframe: &'static str,
+    ) -> Counter;
+
     /// Total number of successful external user data retrieval events
     pub fn external_user_data_retrieval_success_total() -> Counter;
 
@@ -51,6 +75,100 @@ pub(crate) mod metrics {
         buckets: &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 15.0, 20.0, 30.0, 60.0, 120.0, 300.0, 600.0],
     }]
     pub fn external_user_data_export_time() -> TimeHistogram;
+
+    /// Total number of external storage objects cleaned up after their data was restored to the
+    /// database
+    pub fn external_storage_restored_object_cleanup_total() -> Counter;
+
+    /// Total number of failed attempts to clean up external storage objects after their data was
+    /// restored to the database
+    pub fn external_storage_restored_object_cleanup_failure_total() -> Counter;
+
+    /// Total number of transfers of user data to external storage, by final outcome (`success`,
+    /// `failure`, or `skipped` if another transfer for the user was already in progress)
+    pub fn external_storage_uploads_total(outcome: &'static str) -> Counter;
+
+    /// Total number of retrievals of user data from external storage, by final outcome (`success`,
+    /// `failure`, or `skipped` if the data had already been retrieved)
+    pub fn external_storage_downloads_total(outcome: &'static str) -> Counter;
+
+    /// Distribution of the total time taken by successful external storage transfers including
+    /// retries, by direction (`upload` or `download`)
+    #[ctor = HistogramBuilder {
+        buckets: &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 15.0, 20.0, 30.0, 60.0, 120.0, 300.0, 600.0],
+    }]
+    pub fn external_storage_transfer_duration(direction: &'static str) -> TimeHistogram;
+
+    /// Distribution of the compressed size in bytes of user data moved by successful external
+    /// storage transfers, by direction (`upload` or `download`)
+    #[ctor = HistogramBuilder {
+        buckets: &[
+            1024.0, 16384.0, 65536.0, 262144.0, 1048576.0, 4194304.0, 16777216.0, 67108864.0,
+            268435456.0,
+        ],
+    }]
+    pub fn external_storage_transfer_bytes(direction: &'static str) -> Histogram;
+
+    /// Number of external storage locks currently held, by kind (`retrieve` or `write`)
+    pub fn external_storage_held_locks(kind: &'static str) -> Gauge;
+
+    /// Distribution of database query times including time spent waiting for a pooled connection,
+    /// by query name
+    #[ctor = HistogramBuilder {
+        buckets: &[0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0],
+    }]
+    pub fn db_query_duration(query_name: &'static str) -> TimeHistogram;
+
+    /// Distribution of Redis command latencies, by operation
+    #[ctor = HistogramBuilder {
+        buckets: &[0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0],
+    }]
+    pub fn redis_command_time(operation: &'static str) -> TimeHistogram;
+
+    /// Distribution of time spent waiting to check out a connection from the Redis pool
+    #[ctor = HistogramBuilder {
+        buckets: &[
+            0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+        ],
+    }]
+    pub fn redis_pool_checkout_time() -> TimeHistogram;
+
+    /// Total number of errors encountered while interacting with Redis, by kind
+    pub fn redis_errors_total(kind: &'static str) -> Counter;
+
+    /// Total number of Spotify entity cache lookups, by cache layer (`local` or `redis`) and result
+    /// (`hit` or `miss`)
+    pub fn metadata_cache_lookups_total(layer: &'static str, result: &'static str) -> Counter;
+
+    /// Total number of lookups of internal IDs in the in-process Spotify ID map cache, by result
+    /// (`hit` or `miss`)
+    pub fn spotify_id_map_cache_lookups_total(result: &'static str) -> Counter;
+
+    /// Total number of cached values that failed to deserialize and were evicted, by hash name
+    pub fn cache_deserialize_failures_total(hash_name: String) -> Counter;
+
+    /// Number of connections in the Redis pool, by state (`idle` or `active`)
+    pub fn redis_pool_connections(state: &'static str) -> Gauge;
+
+    /// Total number of failures to build the external storage client, usually caused by missing
+    /// or invalid credentials
+    pub fn external_storage_client_build_failure_total() -> Counter;
+
+    /// Total number of admin API authentication attempts, by the kind of token that was used
+    /// (`primary`, `secondary`, `signed`, or `rejected`)
+    pub fn admin_auth_total(token: &'static str) -> Counter;
+
+    /// Total number of users in the database
+    pub fn users_total() -> Gauge;
+
+    /// Number of users whose stats have been updated in the last 24 hours
+    pub fn users_updated_last_day() -> Gauge;
+
+    /// Number of users whose last update is more than 4x the minimum update interval in the past
+    pub fn users_overdue_for_update() -> Gauge;
+
+    /// Number of users whose historical data currently lives in external storage
+    pub fn users_with_external_data() -> Gauge;
 }
 
 pub use metrics::*;
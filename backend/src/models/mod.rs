@@ -1,14 +1,21 @@
-use std::{default::Default, fmt::Debug, vec};
+use std::{
+    default::Default,
+    fmt::{Debug, Display},
+    vec,
+};
 
-use chrono::{NaiveDate, NaiveDateTime};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
 use float_ord::FloatOrd;
 use fnv::FnvHashMap as HashMap;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::schema::{
-    artist_rank_snapshots, artists_genres, related_artists, spotify_items, track_rank_snapshots,
-    tracks_artists, users,
+use crate::{
+    schema::{
+        artist_followers_history, artist_rank_snapshots, artists_genres, related_artists,
+        spotify_items, track_rank_snapshots, tracks_albums, tracks_artists, users,
+    },
+    spotify_api::SpotifyApiError,
 };
 
 #[derive(Insertable)]
@@ -20,6 +27,7 @@ pub(crate) struct NewUser {
     pub username: String,
     pub token: String,
     pub refresh_token: String,
+    pub country: Option<String>,
 }
 
 #[derive(Serialize, Queryable, Clone, Debug)]
@@ -34,6 +42,9 @@ pub(crate) struct User {
     pub external_data_retrieved: bool,
     pub last_viewed: NaiveDateTime,
     pub last_external_data_store: NaiveDateTime,
+    /// ISO 3166-1 alpha-2 country code of the user's Spotify account, if known.  Used as the
+    /// market when fetching tracks so that preview URLs are available to them.
+    pub country: Option<String>,
 }
 
 #[derive(Serialize, Insertable, Associations)]
@@ -58,7 +69,7 @@ pub(crate) struct NewArtistHistoryEntry {
     pub ranking: u8,
 }
 
-#[derive(Queryable)]
+#[derive(Clone, Queryable)]
 pub(crate) struct UserHistoryEntry {
     pub id: i64,
     pub user_id: i64,
@@ -136,6 +147,27 @@ pub(crate) struct TrackArtistPair {
     pub artist_id: i32,
 }
 
+#[derive(Insertable)]
+#[table_name = "tracks_albums"]
+pub(crate) struct TrackAlbumPair {
+    pub track_id: i32,
+    pub album_spotify_id: String,
+}
+
+#[derive(Insertable)]
+#[table_name = "artist_followers_history"]
+pub(crate) struct NewArtistFollowersEntry {
+    pub artist_id: i32,
+    pub day: NaiveDate,
+    pub followers: u64,
+}
+
+#[derive(Serialize, Queryable)]
+pub(crate) struct ArtistFollowersHistoryItem {
+    pub day: NaiveDate,
+    pub followers: u64,
+}
+
 #[derive(Insertable)]
 #[table_name = "artists_genres"]
 pub(crate) struct ArtistGenrePair {
@@ -243,11 +275,84 @@ impl<'a, T: Serialize> TimeFrames<T> {
     }
 }
 
+/// Which timeframes of each entity type were successfully fetched from Spotify, indexed by
+/// timeframe ID
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct CapturedTimeframes {
+    pub tracks: [bool; 3],
+    pub artists: [bool; 3],
+}
+
+impl CapturedTimeframes {
+    fn entity_timeframes(&self, entity_type: &str) -> &[bool; 3] {
+        match entity_type {
+            "tracks" => &self.tracks,
+            "artists" => &self.artists,
+            _ => panic!("Invalid entity type passed to `CapturedTimeframes`"),
+        }
+    }
+
+    fn timeframe_id(timeframe: &str) -> usize {
+        match timeframe {
+            "short" => 0,
+            "medium" => 1,
+            "long" => 2,
+            _ => panic!("Invalid timeframe passed to `CapturedTimeframes`"),
+        }
+    }
+
+    pub(crate) fn set_captured(&mut self, entity_type: &str, timeframe: &str) {
+        let timeframes = match entity_type {
+            "tracks" => &mut self.tracks,
+            "artists" => &mut self.artists,
+            _ => panic!("Invalid entity type passed to `CapturedTimeframes::set_captured`"),
+        };
+        timeframes[Self::timeframe_id(timeframe)] = true;
+    }
+
+    pub(crate) fn is_captured(&self, entity_type: &str, timeframe: &str) -> bool {
+        self.entity_timeframes(entity_type)[Self::timeframe_id(timeframe)]
+    }
+
+    pub(crate) fn is_complete(&self) -> bool {
+        self.tracks
+            .iter()
+            .chain(self.artists.iter())
+            .all(|&captured| captured)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        !self
+            .tracks
+            .iter()
+            .chain(self.artists.iter())
+            .any(|&captured| captured)
+    }
+}
+
+impl Display for CapturedTimeframes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, entity_type) in ["tracks", "artists"].into_iter().enumerate() {
+            let timeframes: Vec<&str> = ["short", "medium", "long"]
+                .into_iter()
+                .filter(|timeframe| self.is_captured(entity_type, timeframe))
+                .collect();
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}: [{}]", entity_type, timeframes.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Serialize)]
 pub(crate) struct StatsSnapshot {
     pub last_update_time: NaiveDateTime,
     pub tracks: TimeFrames<Track>,
     pub artists: TimeFrames<Artist>,
+    #[serde(skip)]
+    pub captured_timeframes: CapturedTimeframes,
 }
 
 impl StatsSnapshot {
@@ -256,6 +361,7 @@ impl StatsSnapshot {
             last_update_time,
             tracks: TimeFrames::default(),
             artists: TimeFrames::default(),
+            captured_timeframes: CapturedTimeframes::default(),
         }
     }
 }
@@ -284,9 +390,68 @@ pub(crate) struct Followers {
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub(crate) struct Image {
-    // pub height: Option<usize>,
+    pub height: Option<usize>,
     pub url: String,
-    // pub width: Option<usize>,
+    pub width: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ImageSize {
+    Small,
+    Medium,
+    Large,
+}
+
+impl ImageSize {
+    /// Width in pixels of the ideal image for this size.  `None` means the largest available.
+    pub fn target_width(self) -> Option<usize> {
+        match self {
+            Self::Small => Some(160),
+            Self::Medium => Some(320),
+            Self::Large => None,
+        }
+    }
+
+    /// Picks the image with the width closest to this size's target width from images sorted
+    /// largest first, as Spotify returns them.  Images without a known width are only picked if
+    /// none of the images have one.
+    pub fn pick_image(self, images: Vec<Image>) -> Option<Image> {
+        let target_width = match self.target_width() {
+            Some(target_width) => target_width,
+            None => return images.into_iter().next(),
+        };
+        let distance = |image: &Image| {
+            image
+                .width
+                .map(|width| (width as isize - target_width as isize).abs())
+        };
+
+        let mut images = images.into_iter();
+        let first = images.next()?;
+        Some(images.fold(first, |best, image| {
+            match (distance(&best), distance(&image)) {
+                (Some(best_distance), Some(distance)) if distance < best_distance => image,
+                (None, Some(_)) => image,
+                _ => best,
+            }
+        }))
+    }
+}
+
+impl std::str::FromStr for ImageSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "small" => Ok(Self::Small),
+            "medium" => Ok(Self::Medium),
+            "large" => Ok(Self::Large),
+            _ => Err(format!(
+                "Invalid image size \"{}\"; must be `small`, `medium`, or `large`",
+                s
+            )),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -309,6 +474,88 @@ pub(crate) struct TopTracksResponse {
     pub items: Vec<Option<Track>>,
 }
 
+#[derive(QueryableByName)]
+pub(crate) struct BestRankingQueryResItem {
+    #[sql_type = "::diesel::sql_types::Text"]
+    pub spotify_id: String,
+    #[sql_type = "::diesel::sql_types::Unsigned<::diesel::sql_types::TinyInt>"]
+    pub timeframe: u8,
+    #[sql_type = "::diesel::sql_types::Unsigned<::diesel::sql_types::TinyInt>"]
+    pub ranking: u8,
+}
+
+#[derive(QueryableByName)]
+pub(crate) struct GenreRankQueryResItem {
+    #[sql_type = "::diesel::sql_types::Integer"]
+    pub mapped_spotify_id: i32,
+    #[sql_type = "::diesel::sql_types::Unsigned<::diesel::sql_types::TinyInt>"]
+    pub timeframe: u8,
+    #[sql_type = "::diesel::sql_types::Unsigned<::diesel::sql_types::TinyInt>"]
+    pub ranking: u8,
+    /// `None` for artists that don't have any genres
+    #[sql_type = "::diesel::sql_types::Nullable<::diesel::sql_types::Text>"]
+    pub genre: Option<String>,
+}
+
+#[derive(QueryableByName)]
+pub(crate) struct AlbumRankQueryResItem {
+    #[sql_type = "::diesel::sql_types::Integer"]
+    pub mapped_spotify_id: i32,
+    #[sql_type = "::diesel::sql_types::Text"]
+    pub track_spotify_id: String,
+    /// `None` for tracks stored before albums started being recorded
+    #[sql_type = "::diesel::sql_types::Nullable<::diesel::sql_types::Text>"]
+    pub album_spotify_id: Option<String>,
+    #[sql_type = "::diesel::sql_types::Unsigned<::diesel::sql_types::TinyInt>"]
+    pub timeframe: u8,
+    #[sql_type = "::diesel::sql_types::Unsigned<::diesel::sql_types::TinyInt>"]
+    pub ranking: u8,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct AlbumTrackCount {
+    pub album_spotify_id: String,
+    /// Number of distinct tracks from the album across all timeframes
+    pub track_count: usize,
+    /// Best ranking of any of the album's tracks in each of the short, medium, and long timeframes
+    pub best_ranks: [Option<u8>; 3],
+}
+
+#[derive(Serialize)]
+pub(crate) struct AlbumStatsItem {
+    pub album: Album,
+    pub track_count: usize,
+    pub best_ranks: [Option<u8>; 3],
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct GenreBreakdownItem {
+    pub genre: String,
+    pub artist_count: usize,
+    /// Sum of the rank-weighted scores of each of the artists with the genre
+    pub score: usize,
+}
+
+#[derive(QueryableByName)]
+pub(crate) struct GenreCountQueryResItem {
+    #[sql_type = "::diesel::sql_types::Text"]
+    pub genre: String,
+    #[sql_type = "::diesel::sql_types::BigInt"]
+    pub count: i64,
+}
+
+#[derive(QueryableByName)]
+pub(crate) struct UserCountsQueryResItem {
+    #[sql_type = "::diesel::sql_types::BigInt"]
+    pub total_users: i64,
+    #[sql_type = "::diesel::sql_types::BigInt"]
+    pub recently_updated_users: i64,
+    #[sql_type = "::diesel::sql_types::BigInt"]
+    pub overdue_users: i64,
+    #[sql_type = "::diesel::sql_types::BigInt"]
+    pub external_data_users: i64,
+}
+
 #[derive(Queryable, QueryableByName)]
 pub(crate) struct StatsHistoryQueryResItem {
     #[sql_type = "::diesel::sql_types::Text"]
@@ -351,9 +598,16 @@ pub(crate) struct TopArtistsResponse {
     pub items: Vec<Artist>,
 }
 
+/// Version of the serialized shape of `Artist`, `Track`, `Album`, and `ArtistSearchResult` as
+/// they're cached in Redis.  It's appended to the names of the hashes they're cached in, so bump it
+/// along with any change to their serialized fields to keep old entries from being read.  Hashes
+/// for old versions can then be deleted with `/purge_stale_cache_versions`.
+pub(crate) const METADATA_CACHE_SCHEMA_VERSION: u32 = 1;
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub(crate) struct Artist {
-    // pub followers: Option<Followers>,
+    /// Missing for artists included in simplified objects like the artists of a track
+    pub followers: Option<Followers>,
     pub genres: Option<Vec<String>>,
     // pub href: String,
     pub id: String,
@@ -371,6 +625,9 @@ pub(crate) struct UserProfile {
     pub images: Vec<Image>,
     pub id: String,
     // pub uri: String,
+    /// Only included if the `user-read-private` scope was granted
+    #[serde(default)]
+    pub country: Option<String>,
 }
 
 // {
@@ -386,8 +643,8 @@ pub(crate) struct SpotifyBatchArtistsResponse {
 
 #[derive(Deserialize, Clone, Debug)]
 pub(crate) struct SpotifyErrorInner {
-    status: Option<i32>,
-    message: Option<String>,
+    pub status: Option<i32>,
+    pub message: Option<String>,
     #[serde(flatten)]
     other: HashMap<String, Value>,
 }
@@ -407,53 +664,23 @@ pub(crate) enum SpotifyResponse<T: std::fmt::Debug + Clone> {
     Error(SpotifyError),
 }
 
-impl<T: for<'de> Deserialize<'de> + std::fmt::Debug + Clone> SpotifyResponse<T> {
-    pub fn into_result(self) -> Result<T, String> {
-        match self {
-            Self::Success(val) => Ok(val),
-            Self::Error(err) => Err(err
-                .error
-                .message
-                .unwrap_or_else(|| -> String { "No error message supplied".into() })),
-        }
-    }
-}
-
-impl<T: for<'de> Deserialize<'de> + std::fmt::Debug + Clone> std::ops::FromResidual
-    for SpotifyResponse<T>
-{
-    fn from_residual(err_msg: String) -> Self {
-        SpotifyResponse::Error(SpotifyError {
-            error: SpotifyErrorInner {
-                status: None,
-                message: Some(err_msg),
-                other: HashMap::default(),
-            },
-            other: HashMap::default(),
-        })
-    }
-}
-
-impl<T: for<'de> Deserialize<'de> + std::fmt::Debug + Clone> std::ops::Try for SpotifyResponse<T> {
-    type Output = T;
-    type Residual = String;
-
-    fn branch(self) -> std::ops::ControlFlow<Self::Residual, Self::Output> {
-        match self {
-            SpotifyResponse::Success(val) => std::ops::ControlFlow::Continue(val),
+impl<T: std::fmt::Debug + Clone> From<SpotifyResponse<T>> for Result<T, SpotifyApiError> {
+    fn from(res: SpotifyResponse<T>) -> Self {
+        match res {
+            SpotifyResponse::Success(val) => Ok(val),
             SpotifyResponse::Error(err) => {
                 error!("Error fetching data from Spotify API: {:?}", err);
 
-                std::ops::ControlFlow::Break(
-                    err.error
+                Err(SpotifyApiError::Upstream {
+                    status: err.error.status.unwrap_or(0) as u16,
+                    message: err
+                        .error
                         .message
                         .unwrap_or_else(|| -> String { "No error message supplied".into() }),
-                )
+                })
             },
         }
     }
-
-    fn from_output(val: T) -> Self { SpotifyResponse::Success(val) }
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -461,6 +688,11 @@ pub(crate) struct SpotifyBatchTracksResponse {
     pub tracks: Vec<Track>,
 }
 
+#[derive(Deserialize, Clone, Debug)]
+pub(crate) struct SpotifyBatchAlbumsResponse {
+    pub albums: Vec<Album>,
+}
+
 #[derive(Deserialize, Clone, Debug)]
 pub(crate) struct AccessTokenResponse {
     pub access_token: String,
@@ -480,15 +712,34 @@ impl HasSpotifyId for Track {
     fn get_spotify_id(&self) -> &str { &self.id }
 }
 
+impl HasSpotifyId for Album {
+    fn get_spotify_id(&self) -> &str { &self.id }
+}
+
 #[derive(Serialize)]
 #[serde(tag = "type")]
 pub(crate) enum TimelineEventType {
     #[serde(rename = "firstUpdate")]
     FirstUpdate,
     #[serde(rename = "artistFirstSeen")]
-    ArtistFirstSeen { artist: Artist },
+    ArtistFirstSeen { artist: Artist, genres: Vec<String> },
+    /// `primary_genre` is the first genre of the track's first artist, if it has any
     #[serde(rename = "topTrackFirstSeen")]
-    TopTrackFirstSeen { track: Track },
+    TopTrackFirstSeen {
+        track: Track,
+        primary_genre: Option<String>,
+    },
+}
+
+impl TimelineEventType {
+    pub fn matches_genre(&self, genre: &str) -> bool {
+        match self {
+            TimelineEventType::FirstUpdate => false,
+            TimelineEventType::ArtistFirstSeen { genres, .. } => genres.iter().any(|g| g == genre),
+            TimelineEventType::TopTrackFirstSeen { primary_genre, .. } =>
+                primary_genre.as_deref() == Some(genre),
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -499,11 +750,55 @@ pub(crate) struct TimelineEvent {
     pub event_type: TimelineEventType,
 }
 
+/// Query params for the timeline endpoint
+#[derive(FromForm)]
+pub(crate) struct TimelineQuery {
+    pub start_day_id: String,
+    pub end_day_id: String,
+    /// If provided, only events for artists with this genre and tracks whose primary genre
+    /// matches it are returned.
+    pub genre: Option<String>,
+    /// Max number of events to return.  The limit is applied before filtering by `genre`.
+    pub limit: Option<i64>,
+    /// `asc` (default) or `desc`
+    pub order: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum SortOrder {
+    Ascending,
+    Descending,
+}
+
+impl std::str::FromStr for SortOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "asc" => Ok(Self::Ascending),
+            "desc" => Ok(Self::Descending),
+            _ => Err(format!(
+                "Invalid sort order \"{}\"; must be `asc` or `desc`",
+                s
+            )),
+        }
+    }
+}
+
 #[derive(Serialize)]
 pub(crate) struct Timeline {
     pub events: Vec<TimelineEvent>,
 }
 
+/// How much data we have stored for one side of a comparison.  If either user has nothing stored
+/// (e.g. they just signed up), the comparison will be empty and the frontend can use this to
+/// explain why.
+#[derive(Serialize)]
+pub(crate) struct UserComparisonDataStatus {
+    pub stored_track_count: usize,
+    pub stored_artist_count: usize,
+}
+
 #[derive(Serialize)]
 pub(crate) struct UserComparison {
     pub tracks: Vec<Track>,
@@ -511,6 +806,8 @@ pub(crate) struct UserComparison {
     pub genres: Vec<String>,
     pub user1_username: String,
     pub user2_username: String,
+    pub user1_data_status: UserComparisonDataStatus,
+    pub user2_data_status: UserComparisonDataStatus,
 }
 
 #[derive(Default, Debug, Clone, Deserialize)]
@@ -582,6 +879,15 @@ pub(crate) struct UpdatePlaylistResponse {
 pub(crate) struct CreateSharedPlaylistRequest {
     pub user1_id: String,
     pub user2_id: String,
+    /// If provided, tracks are ranked by how highly they've charted for both users across all
+    /// timeframes, weighted towards long-term charts as this approaches 1.  See
+    /// `shared_playlist_gen::score_track_for_user`.
+    #[serde(default)]
+    pub long_term_weight: Option<f32>,
+    /// If set, the playlist only contains tracks that neither user has ever had in their top
+    /// tracks, pulled from artists related to both users' top artists.
+    #[serde(default)]
+    pub discoveries_only: bool,
 }
 
 #[derive(Deserialize)]
@@ -616,6 +922,59 @@ pub(crate) struct ArtistSearchResult {
     #[serde(rename = "internalID")]
     pub internal_id: Option<i32>,
     pub name: String,
+    /// `None` if Spotify didn't provide it
+    #[serde(default)]
+    pub popularity: Option<usize>,
+    #[serde(default)]
+    pub genres: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct TopArtistDetail {
+    pub internal_id: i32,
+    /// Best (lowest, 0-indexed) rank the artist has in any timeframe as of the user's most recent
+    /// update.  `None` if the artist isn't in any of the user's current top artists.
+    pub best_rank: Option<u8>,
+    /// Timeframes in which the artist is one of the user's current top artists
+    pub timeframes_seen: Vec<&'static str>,
+    pub first_seen: NaiveDateTime,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RecommendedArtist {
+    pub artist: Artist,
+    pub score: f32,
+    /// Position of the artist in the seed artist's Spotify related artists list, if it's in it
+    pub related_artists_rank: Option<usize>,
+    /// Cosine similarity to the seed artist in the artist embedding, if it's one of the seed
+    /// artist's nearest neighbors
+    pub embedding_similarity: Option<f32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum NeighborhoodArtistSource {
+    /// One of the seed artist's nearest neighbors in the artist embedding
+    Embedding,
+    /// In the seed artist's Spotify related artists
+    Related,
+    Both,
+}
+
+#[derive(Serialize)]
+pub(crate) struct NeighborhoodArtist {
+    pub id: String,
+    pub name: String,
+    /// Position in the artist embedding, or `None` if the artist isn't in it
+    pub position: Option<Vec<f32>>,
+    pub popularity: Option<usize>,
+    pub source: NeighborhoodArtistSource,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ArtistNeighborhood {
+    pub artists: Vec<NeighborhoodArtist>,
 }
 
 #[derive(Serialize)]
@@ -670,3 +1029,103 @@ pub(crate) struct AverageArtistsResponse {
     pub similarity: f32,
     pub distance: f32,
 }
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ArtistEmbeddingResponse {
+    #[serde(rename = "spotifyID")]
+    pub spotify_id: String,
+    #[serde(rename = "internalID")]
+    pub internal_id: i32,
+    pub position: Vec<f32>,
+    pub normalized_position: Vec<f32>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum BulkTransferUserStatus {
+    DryRun,
+    Success,
+    Failure,
+}
+
+#[derive(Serialize)]
+pub(crate) struct BulkTransferUserReport {
+    pub spotify_id: String,
+    /// Number of local artist snapshot rows for the user at the time of selection
+    pub local_artist_entry_count: i64,
+    /// Number of local track snapshot rows for the user at the time of selection
+    pub local_track_entry_count: i64,
+    /// Rough size of the local data before compression
+    pub estimated_uncompressed_bytes: u64,
+    pub status: BulkTransferUserStatus,
+    pub uploaded_bytes: Option<usize>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct BulkTransferReport {
+    pub dry_run: bool,
+    pub inactive_days: i64,
+    pub users: Vec<BulkTransferUserReport>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ExternalStorageObjectInfo {
+    pub key: String,
+    /// Parsed from the key; `None` if the key doesn't follow the expected naming scheme
+    pub user_spotify_id: Option<String>,
+    pub size: usize,
+    pub last_modified: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ExternalStorageObjectDetail {
+    pub object: ExternalStorageObjectInfo,
+    pub row_count: i64,
+    pub row_group_count: usize,
+    pub compression: Option<String>,
+}
+
+#[test]
+fn spotify_responses_convert_to_results() {
+    let res: SpotifyResponse<Vec<u32>> = serde_json::from_str("[1, 2]").unwrap();
+    assert_eq!(Result::from(res), Ok(vec![1, 2]));
+
+    let res: SpotifyResponse<Vec<u32>> =
+        serde_json::from_str(r#"{"error": {"status": 401, "message": "Invalid access token"}}"#)
+            .unwrap();
+    assert_eq!(
+        Result::from(res),
+        Err(SpotifyApiError::Upstream {
+            status: 401,
+            message: String::from("Invalid access token"),
+        })
+    );
+
+    let res: SpotifyResponse<Vec<u32>> =
+        serde_json::from_str(r#"{"error": {"status": 500}}"#).unwrap();
+    assert_eq!(
+        Result::from(res),
+        Err(SpotifyApiError::Upstream {
+            status: 500,
+            message: String::from("No error message supplied"),
+        })
+    );
+}
+
+#[test]
+fn artist_followers_are_optional() {
+    let artist: Artist = serde_json::from_str(
+        r#"{"followers": {"href": null, "total": 1234}, "id": "a", "name": "A"}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        artist.followers.map(|followers| followers.total),
+        Some(1234)
+    );
+
+    // Artists cached before followers were recorded and simplified artists don't have them
+    let artist: Artist = serde_json::from_str(r#"{"id": "b", "name": "B"}"#).unwrap();
+    assert!(artist.followers.is_none());
+}
@@ -0,0 +1,40 @@
+use std::borrow::Cow;
+
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::StatusClass,
+    Request, Response,
+};
+
+use crate::metrics::http_requests_total;
+
+pub(crate) struct RequestMetricsFairing;
+
+#[rocket::async_trait]
+impl Fairing for RequestMetricsFairing {
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        // Use the name of the matched route rather than the URI to keep label cardinality bounded
+        let route = match req.route().and_then(|route| route.name.as_ref()) {
+            Some(Cow::Borrowed(name)) => *name,
+            Some(Cow::Owned(_)) => "unknown",
+            None => "unmatched",
+        };
+        let status_class = match res.status().class() {
+            StatusClass::Informational => "1xx",
+            StatusClass::Success => "2xx",
+            StatusClass::Redirection => "3xx",
+            StatusClass::ClientError => "4xx",
+            StatusClass::ServerError => "5xx",
+            StatusClass::Unknown => "unknown",
+        };
+
+        http_requests_total(route, status_class).inc();
+    }
+
+    fn info(&self) -> Info {
+        Info {
+            name: "Request Metrics Fairing",
+            kind: Kind::Response,
+        }
+    }
+}
@@ -1,13 +1,15 @@
 use std::{cmp::Reverse, sync::Arc};
 
-use chrono::{NaiveDateTime, Utc};
+use chrono::{NaiveDate, NaiveDateTime, Utc};
 use diesel::{self, prelude::*};
+use float_ord::FloatOrd;
 use fnv::{FnvHashMap as HashMap, FnvHashSet};
 use futures::{stream::FuturesUnordered, StreamExt, TryFutureExt, TryStreamExt};
 use redis::Commands;
 use rocket::{
     data::ToByteUnit,
     http::{RawStr, Status},
+    request::{self, FromRequest, Request},
     response::{status, Redirect},
     serde::json::Json,
     State,
@@ -18,34 +20,72 @@ use tokio::{
 };
 
 use crate::{
+    admin_auth::{match_static_admin_token, static_token_label, verify_admin_token},
     artist_embedding::{
-        get_artist_embedding_ctx, get_average_artists,
+        get_artist_embedding_ctx, get_average_artists, get_nearest_artists,
         map_3d::{get_map_3d_artist_ctx, get_packed_3d_artist_coords},
-        ArtistEmbeddingError,
+        ArtistEmbeddingError, AverageArtistDescriptor,
     },
     benchmarking::{mark, start},
-    cache::{get_hash_items, get_redis_conn, set_hash_items},
-    conf::CONF,
+    cache::{
+        delete_hash_item, get_cache_stats, get_hash_items, get_raw_hash_item, get_redis_conn,
+        is_known_cache_hash_name, purge_stale_versioned_hashes, set_hash_items,
+        timed_redis_command,
+    },
+    conf::{dynamic_conf, reload_dynamic_conf, CONF},
     db_util::{
         self, get_all_top_artists_for_user, get_artist_spotify_ids_by_internal_id,
         get_internal_ids_by_spotify_id, insert_related_artists,
     },
-    metrics::{user_updates_failure_total, user_updates_success_total},
+    last_viewed,
+    metrics::{
+        admin_auth_total, endpoint_errors_total, user_updates_failure_total,
+        user_updates_success_total,
+    },
     models::{
-        Artist, ArtistSearchResult, AverageArtistItem, AverageArtistsResponse, CompareToRequest,
-        CreateSharedPlaylistRequest, NewRelatedArtistEntry, NewUser, OAuthTokenResponse, Playlist,
-        RelatedArtistsGraph, StatsSnapshot, TimeFrames, Timeline, TimelineEvent, TimelineEventType,
-        Track, User, UserComparison,
+        AlbumStatsItem, Artist, ArtistEmbeddingResponse, ArtistFollowersHistoryItem,
+        ArtistNeighborhood, ArtistSearchResult, AverageArtistItem, AverageArtistsResponse,
+        BulkTransferReport, BulkTransferUserReport, BulkTransferUserStatus, CapturedTimeframes,
+        CompareToRequest, CreateSharedPlaylistRequest, GenreBreakdownItem, ImageSize,
+        NeighborhoodArtist, NeighborhoodArtistSource, NewRelatedArtistEntry, NewUser,
+        OAuthTokenResponse, Playlist, RecommendedArtist, RelatedArtistsGraph, SortOrder,
+        StatsSnapshot, TimeFrames, Timeline, TimelineEvent, TimelineEventType, TimelineQuery,
+        TopArtistDetail, Track, TrackAlbumPair, User, UserComparison, UserComparisonDataStatus,
     },
+    shutdown,
     spotify_api::{
-        fetch_artists, fetch_top_tracks_for_artist, get_multiple_related_artists,
+        fetch_albums, fetch_artists, fetch_artists_with_all_images, fetch_top_tracks_for_artist,
+        fetch_tracks, get_multiple_related_artists, get_multiple_related_artists_partial,
         get_reqwest_client, search_artists,
     },
+    stats::TimeframeWeights,
     DbConn, SpotifyTokenData,
 };
 
 const SPOTIFY_TOKEN_FETCH_URL: &str = "https://accounts.spotify.com/api/token";
 
+/// Counts errors returned by a route in `endpoint_errors_total`.  Routes that return `Err` still
+/// respond with a 200, so they can't be told apart from successful responses by status code.
+fn track_endpoint_errors<T>(route: &'static str, res: Result<T, String>) -> Result<T, String> {
+    if res.is_err() {
+        endpoint_errors_total(route, "error").inc();
+    }
+    res
+}
+
+/// Returns a response refusing new admin-triggered work if the server is draining in preparation
+/// for a shutdown
+fn draining_response() -> Option<status::Custom<String>> {
+    if !shutdown::is_draining() {
+        return None;
+    }
+
+    Some(status::Custom(
+        Status::ServiceUnavailable,
+        "Server is draining; not accepting new work".into(),
+    ))
+}
+
 #[get("/")]
 pub(crate) fn index() -> &'static str { "Application successfully started!" }
 
@@ -55,7 +95,19 @@ pub(crate) async fn get_current_stats(
     conn: DbConn,
     conn2: DbConn,
     username: String,
-    token_data: &State<Mutex<SpotifyTokenData>>,
+    token_data: &State<SpotifyTokenData>,
+) -> Result<Option<Json<StatsSnapshot>>, String> {
+    track_endpoint_errors(
+        "get_current_stats",
+        get_current_stats_inner(conn, conn2, username, token_data).await,
+    )
+}
+
+async fn get_current_stats_inner(
+    conn: DbConn,
+    conn2: DbConn,
+    username: String,
+    token_data: &State<SpotifyTokenData>,
 ) -> Result<Option<Json<StatsSnapshot>>, String> {
     let tok = start();
     let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
@@ -66,10 +118,7 @@ pub(crate) async fn get_current_stats(
     };
     mark(tok, "Finished getting spotify user by id");
 
-    let spotify_access_token = {
-        let token_data = &mut *(&*token_data).lock().await;
-        token_data.get().await
-    }?;
+    let spotify_access_token = token_data.get().await?;
 
     let tok = start();
     let (artist_stats, track_stats) = match tokio::join!(
@@ -95,19 +144,238 @@ pub(crate) async fn get_current_stats(
     Ok(Some(Json(snapshot)))
 }
 
+/// Snapshots further than this from the date requested from `/stats/<username>/snapshot` are
+/// treated as not existing
+const MAX_SNAPSHOT_DISTANCE_DAYS: i64 = 14;
+
+/// Retrieves the top tracks and artists for the user from the snapshot closest to `date`, which
+/// should be formatted like `2021-03-14`.  Returns `None` if the user has no snapshots within
+/// `MAX_SNAPSHOT_DISTANCE_DAYS` of that date.
+#[get("/stats/<username>/snapshot?<date>")]
+pub(crate) async fn get_stats_snapshot(
+    conn: DbConn,
+    conn2: DbConn,
+    username: String,
+    date: String,
+    token_data: &State<SpotifyTokenData>,
+) -> Result<Option<Json<StatsSnapshot>>, String> {
+    track_endpoint_errors(
+        "get_stats_snapshot",
+        get_stats_snapshot_inner(conn, conn2, username, date, token_data).await,
+    )
+}
+
+async fn get_stats_snapshot_inner(
+    conn: DbConn,
+    conn2: DbConn,
+    username: String,
+    date: String,
+    token_data: &State<SpotifyTokenData>,
+) -> Result<Option<Json<StatsSnapshot>>, String> {
+    // Snapshots are matched against the middle of the requested day so that ones taken early and
+    // late in the day are treated equally
+    let target = NaiveDate::parse_from_str(&date, "%Y-%m-%d")
+        .map_err(|_| String::from("Invalid `date` provided"))?
+        .and_hms(12, 0, 0);
+
+    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    if !user.external_data_retrieved {
+        db_util::retrieve_cold_data_for_user(&conn, &user).await;
+    }
+
+    let tok = start();
+    let (artist_update_time, track_update_time) = match tokio::join!(
+        db_util::get_nearest_artist_update_time(&conn, user.id, target),
+        db_util::get_nearest_track_update_time(&conn2, user.id, target),
+    ) {
+        (Err(err), _) | (Ok(_), Err(err)) => return Err(db_util::stringify_diesel_err(err)),
+        (Ok(Some(artist_update_time)), Ok(Some(track_update_time))) =>
+            (artist_update_time, track_update_time),
+        _ => return Ok(None),
+    };
+    mark(tok, "Found nearest snapshot update times");
+
+    let max_distance = chrono::Duration::days(MAX_SNAPSHOT_DISTANCE_DAYS);
+    if (artist_update_time - target).abs() > max_distance {
+        return Ok(None);
+    }
+
+    let spotify_access_token = token_data.get().await?;
+
+    let tok = start();
+    let (artist_stats, track_stats) = match tokio::join!(
+        db_util::get_artist_stats_at(&user, conn, &spotify_access_token, artist_update_time, None),
+        db_util::get_track_stats_at(&user, conn2, &spotify_access_token, track_update_time, None),
+    ) {
+        (Err(err), _) | (Ok(_), Err(err)) => return Err(err),
+        (Ok(None), _) | (_, Ok(None)) => return Ok(None),
+        (Ok(Some(artist_stats)), Ok(Some(track_stats))) => (artist_stats, track_stats),
+    };
+    mark(tok, "Fetched artist and track stats for snapshot");
+
+    let mut snapshot = StatsSnapshot::new(artist_update_time);
+
+    for (timeframe_id, artist) in artist_stats {
+        snapshot.artists.add_item_by_id(timeframe_id, artist);
+    }
+
+    for (timeframe_id, track) in track_stats {
+        snapshot.tracks.add_item_by_id(timeframe_id, track);
+    }
+
+    Ok(Some(Json(snapshot)))
+}
+
+/// The user's single top artist, track, and genre for each timeframe.  Each timeframe has at most
+/// one item.
+#[derive(Serialize)]
+pub(crate) struct StatsSummary {
+    pub last_update_time: NaiveDateTime,
+    pub top_artist: TimeFrames<Artist>,
+    pub top_track: TimeFrames<Track>,
+    pub top_genre: TimeFrames<String>,
+}
+
+/// A lightweight alternative to `/stats/<username>` that only includes the top item of each kind
+#[get("/stats/<username>/summary")]
+pub(crate) async fn get_stats_summary(
+    conn: DbConn,
+    conn2: DbConn,
+    username: String,
+    token_data: &State<SpotifyTokenData>,
+) -> Result<Option<Json<StatsSummary>>, String> {
+    track_endpoint_errors(
+        "get_stats_summary",
+        get_stats_summary_inner(conn, conn2, username, token_data).await,
+    )
+}
+
+async fn get_stats_summary_inner(
+    conn: DbConn,
+    conn2: DbConn,
+    username: String,
+    token_data: &State<SpotifyTokenData>,
+) -> Result<Option<Json<StatsSummary>>, String> {
+    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    if !user.external_data_retrieved {
+        db_util::retrieve_cold_data_for_user(&conn, &user).await;
+    }
+
+    let (artist_update_time, track_update_time) = match tokio::try_join!(
+        db_util::get_last_artist_update_time(&conn, user.id),
+        db_util::get_last_track_update_time(&conn2, user.id),
+    )
+    .map_err(db_util::stringify_diesel_err)?
+    {
+        (Some(artist_update_time), Some(track_update_time)) =>
+            (artist_update_time, track_update_time),
+        _ => return Ok(None),
+    };
+
+    let genre_breakdown = db_util::get_current_genre_breakdown(&conn, user.id)
+        .await
+        .map_err(db_util::stringify_diesel_err)?
+        .map(|(_last_update_time, items)| crate::stats::compute_genre_breakdown(&items));
+
+    let spotify_access_token = token_data.get().await?;
+    let (artist_stats, track_stats) = match tokio::join!(
+        db_util::get_artist_stats_at(
+            &user,
+            conn,
+            &spotify_access_token,
+            artist_update_time,
+            Some(1)
+        ),
+        db_util::get_track_stats_at(
+            &user,
+            conn2,
+            &spotify_access_token,
+            track_update_time,
+            Some(1)
+        ),
+    ) {
+        (Err(err), _) | (Ok(_), Err(err)) => return Err(err),
+        (Ok(None), _) | (_, Ok(None)) => return Ok(None),
+        (Ok(Some(artist_stats)), Ok(Some(track_stats))) => (artist_stats, track_stats),
+    };
+
+    let mut summary = StatsSummary {
+        last_update_time: user.last_update_time,
+        top_artist: TimeFrames::default(),
+        top_track: TimeFrames::default(),
+        top_genre: TimeFrames::default(),
+    };
+    for (timeframe_id, artist) in artist_stats {
+        summary.top_artist.add_item_by_id(timeframe_id, artist);
+    }
+    for (timeframe_id, track) in track_stats {
+        summary.top_track.add_item_by_id(timeframe_id, track);
+    }
+    if let Some(genre_breakdown) = genre_breakdown {
+        for (timeframe_id, genres) in [
+            genre_breakdown.short,
+            genre_breakdown.medium,
+            genre_breakdown.long,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            if let Some(top_genre) = genres.into_iter().next() {
+                summary
+                    .top_genre
+                    .add_item_by_id(timeframe_id as u8, top_genre.genre);
+            }
+        }
+    }
+
+    Ok(Some(Json(summary)))
+}
+
+/// How common one of an artist's genres is among all of the artists the user has listened to
+#[derive(Serialize)]
+pub(crate) struct GenreAffinity {
+    pub genre: String,
+    pub user_artist_count: i64,
+    /// Fraction of all of the user's artists that have this genre
+    pub fraction: f32,
+}
+
 #[derive(Serialize)]
 pub(crate) struct ArtistStats {
     pub artist: Artist,
     pub tracks_by_id: HashMap<String, Track>,
     pub popularity_history: Vec<(NaiveDateTime, [Option<u8>; 3])>,
     pub top_tracks: Vec<(String, usize)>,
+    /// Empty if the artist has no genres
+    pub genre_affinity: Vec<GenreAffinity>,
 }
 
 #[get("/stats/<username>/artist/<artist_id>")]
 pub(crate) async fn get_artist_stats(
     conn: DbConn,
     conn2: DbConn,
-    token_data: &State<Mutex<SpotifyTokenData>>,
+    conn3: DbConn,
+    token_data: &State<SpotifyTokenData>,
+    username: String,
+    artist_id: String,
+) -> Result<Option<Json<ArtistStats>>, String> {
+    track_endpoint_errors(
+        "get_artist_stats",
+        get_artist_stats_inner(conn, conn2, conn3, token_data, username, artist_id).await,
+    )
+}
+
+async fn get_artist_stats_inner(
+    conn: DbConn,
+    conn2: DbConn,
+    conn3: DbConn,
+    token_data: &State<SpotifyTokenData>,
     username: String,
     artist_id: String,
 ) -> Result<Option<Json<ArtistStats>>, String> {
@@ -120,10 +388,7 @@ pub(crate) async fn get_artist_stats(
     };
     mark(tok, "Finished getting spotify user by id");
 
-    let spotify_access_token = {
-        let token_data = &mut *(&*token_data).lock().await;
-        token_data.get().await
-    }?;
+    let spotify_access_token = token_data.get().await?;
 
     let tok = start();
     let user_clone = user.clone();
@@ -165,11 +430,40 @@ pub(crate) async fn get_artist_stats(
     };
     mark(tok, "Found matching artist to use");
 
+    let genres = artist.genres.clone().unwrap_or_default();
+    let genre_affinity = if genres.is_empty() {
+        Vec::new()
+    } else {
+        let tok = start();
+        let (total_artist_count, artist_counts_by_genre) =
+            db_util::count_user_artists_by_genres(&conn3, user.id, genres.clone())
+                .await
+                .map_err(db_util::stringify_diesel_err)?;
+        mark(tok, "Counted user artists by genre");
+
+        genres
+            .into_iter()
+            .map(|genre| {
+                let user_artist_count = artist_counts_by_genre.get(&genre).copied().unwrap_or(0);
+                GenreAffinity {
+                    genre,
+                    user_artist_count,
+                    fraction: if total_artist_count == 0 {
+                        0.
+                    } else {
+                        user_artist_count as f32 / total_artist_count as f32
+                    },
+                }
+            })
+            .collect()
+    };
+
     let stats = ArtistStats {
         artist,
         tracks_by_id,
         popularity_history: artist_popularity_history,
         top_tracks: top_track_scores,
+        genre_affinity,
     };
     Ok(Some(Json(stats)))
 }
@@ -180,10 +474,62 @@ pub(crate) struct GenresHistory {
     pub history_by_genre: HashMap<String, Vec<Option<usize>>>,
 }
 
+const MAX_ARTIST_RANK_HISTORIES_BATCH_SIZE: usize = 20;
+
+/// Returns the rank history of each of the provided artists for the user, in the same format as
+/// `popularity_history` from `/stats/<username>/artist/<artist_id>`.  The output is in the same
+/// order as the provided artist IDs, with `null` for artists that have never been top artists.
+#[post("/stats/<username>/artist_rank_histories", data = "<artist_ids>")]
+pub(crate) async fn get_artist_rank_histories(
+    conn: DbConn,
+    username: String,
+    artist_ids: Json<Vec<String>>,
+) -> Result<Option<Json<Vec<Option<Vec<(NaiveDateTime, [Option<u8>; 3])>>>>>, String> {
+    track_endpoint_errors(
+        "get_artist_rank_histories",
+        get_artist_rank_histories_inner(conn, username, artist_ids.0).await,
+    )
+}
+
+async fn get_artist_rank_histories_inner(
+    conn: DbConn,
+    username: String,
+    artist_ids: Vec<String>,
+) -> Result<Option<Json<Vec<Option<Vec<(NaiveDateTime, [Option<u8>; 3])>>>>>, String> {
+    if artist_ids.len() > MAX_ARTIST_RANK_HISTORIES_BATCH_SIZE {
+        return Err(format!(
+            "Can't fetch more than {} artist rank histories at once",
+            MAX_ARTIST_RANK_HISTORIES_BATCH_SIZE
+        ));
+    }
+
+    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    if artist_ids.is_empty() {
+        return Ok(Some(Json(Vec::new())));
+    }
+
+    let histories = db_util::get_artist_rank_history_multi(&user, &conn, artist_ids).await?;
+    Ok(Some(Json(histories)))
+}
+
 #[get("/stats/<username>/genre_history")]
 pub(crate) async fn get_genre_history(
     conn: DbConn,
-    token_data: &State<Mutex<SpotifyTokenData>>,
+    token_data: &State<SpotifyTokenData>,
+    username: String,
+) -> Result<Option<Json<GenresHistory>>, String> {
+    track_endpoint_errors(
+        "get_genre_history",
+        get_genre_history_inner(conn, token_data, username).await,
+    )
+}
+
+async fn get_genre_history_inner(
+    conn: DbConn,
+    token_data: &State<SpotifyTokenData>,
     username: String,
 ) -> Result<Option<Json<GenresHistory>>, String> {
     let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
@@ -192,10 +538,7 @@ pub(crate) async fn get_genre_history(
             return Ok(None);
         },
     };
-    let spotify_access_token = {
-        let token_data = &mut *(&*token_data).lock().await;
-        token_data.get().await
-    }?;
+    let spotify_access_token = token_data.get().await?;
 
     // Only include data from the "short" timeframe since we're producing a timeseries
     let (artists_by_id, artist_stats_history) =
@@ -213,20 +556,259 @@ pub(crate) async fn get_genre_history(
     })))
 }
 
+#[derive(Serialize)]
+pub(crate) struct GenreBreakdown {
+    pub last_update_time: NaiveDateTime,
+    pub genres: TimeFrames<GenreBreakdownItem>,
+}
+
+#[get("/stats/<username>/genres")]
+pub(crate) async fn get_genre_breakdown(
+    conn: DbConn,
+    username: String,
+) -> Result<Option<Json<GenreBreakdown>>, String> {
+    track_endpoint_errors(
+        "get_genre_breakdown",
+        get_genre_breakdown_inner(conn, username).await,
+    )
+}
+
+async fn get_genre_breakdown_inner(
+    conn: DbConn,
+    username: String,
+) -> Result<Option<Json<GenreBreakdown>>, String> {
+    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+
+    let (last_update_time, items) = match db_util::get_current_genre_breakdown(&conn, user.id)
+        .await
+        .map_err(db_util::stringify_diesel_err)?
+    {
+        Some(res) => res,
+        None => return Ok(None),
+    };
+
+    Ok(Some(Json(GenreBreakdown {
+        last_update_time,
+        genres: crate::stats::compute_genre_breakdown(&items),
+    })))
+}
+
+/// Returns `(genre, artist_count)` pairs for every genre of any artist the user has ever had in
+/// their top artists, sorted by artist count descending
+#[get("/stats/<username>/all_genres")]
+pub(crate) async fn get_all_genres(
+    conn: DbConn,
+    username: String,
+) -> Result<Option<Json<Vec<(String, i64)>>>, String> {
+    track_endpoint_errors("get_all_genres", get_all_genres_inner(conn, username).await)
+}
+
+async fn get_all_genres_inner(
+    conn: DbConn,
+    username: String,
+) -> Result<Option<Json<Vec<(String, i64)>>>, String> {
+    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+
+    let genres = db_util::get_all_genres_for_user(&conn, user.id)
+        .await
+        .map_err(db_util::stringify_diesel_err)?;
+    Ok(Some(Json(genres)))
+}
+
+#[derive(Serialize)]
+pub(crate) struct AlbumStats {
+    pub last_update_time: NaiveDateTime,
+    pub albums: Vec<AlbumStatsItem>,
+}
+
+/// Aggregates the user's current top tracks by album, returning albums with at least
+/// `CONF.album_stats_min_track_count` tracks along with how many tracks and the best ranking of
+/// any of them in each timeframe
+#[get("/stats/<username>/albums")]
+pub(crate) async fn get_album_stats(
+    conn: DbConn,
+    token_data: &State<SpotifyTokenData>,
+    username: String,
+) -> Result<Option<Json<AlbumStats>>, String> {
+    track_endpoint_errors(
+        "get_album_stats",
+        get_album_stats_inner(conn, token_data, username).await,
+    )
+}
+
+async fn get_album_stats_inner(
+    conn: DbConn,
+    token_data: &State<SpotifyTokenData>,
+    username: String,
+) -> Result<Option<Json<AlbumStats>>, String> {
+    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+
+    let (last_update_time, mut items) = match db_util::get_current_album_ranks(&conn, user.id)
+        .await
+        .map_err(db_util::stringify_diesel_err)?
+    {
+        Some(res) => res,
+        None => return Ok(None),
+    };
+
+    let spotify_access_token = token_data.get().await?;
+
+    // Tracks stored before albums started being recorded don't have them yet, so they're looked
+    // up and recorded now
+    let mut tracks_missing_albums: Vec<&str> = items
+        .iter()
+        .filter(|item| item.album_spotify_id.is_none())
+        .map(|item| item.track_spotify_id.as_str())
+        .collect();
+    tracks_missing_albums.sort_unstable();
+    tracks_missing_albums.dedup();
+    if !tracks_missing_albums.is_empty() {
+        let tracks = fetch_tracks(&spotify_access_token, &tracks_missing_albums).await?;
+        let album_ids_by_track_id: HashMap<String, String> = tracks
+            .into_iter()
+            .map(|track| (track.id, track.album.id))
+            .collect();
+
+        let mut pairs: Vec<TrackAlbumPair> = Vec::new();
+        for item in &mut items {
+            if item.album_spotify_id.is_some() {
+                continue;
+            }
+            let album_spotify_id = match album_ids_by_track_id.get(&item.track_spotify_id) {
+                Some(album_spotify_id) => album_spotify_id.clone(),
+                None => continue,
+            };
+            if !pairs
+                .iter()
+                .any(|pair| pair.track_id == item.mapped_spotify_id)
+            {
+                pairs.push(TrackAlbumPair {
+                    track_id: item.mapped_spotify_id,
+                    album_spotify_id: album_spotify_id.clone(),
+                });
+            }
+            item.album_spotify_id = Some(album_spotify_id);
+        }
+        db_util::insert_track_album_pairs(&conn, pairs)
+            .await
+            .map_err(db_util::stringify_diesel_err)?;
+    }
+
+    let counts = crate::stats::compute_album_track_counts(&items, CONF.album_stats_min_track_count);
+    let album_spotify_ids: Vec<&str> = counts
+        .iter()
+        .map(|count| count.album_spotify_id.as_str())
+        .collect();
+    let albums = fetch_albums(&spotify_access_token, &album_spotify_ids).await?;
+
+    let albums = counts
+        .into_iter()
+        .zip(albums)
+        .map(|(count, album)| AlbumStatsItem {
+            album,
+            track_count: count.track_count,
+            best_ranks: count.best_ranks,
+        })
+        .collect();
+    Ok(Some(Json(AlbumStats {
+        last_update_time,
+        albums,
+    })))
+}
+
+/// Returns the follower count history of the artist, with at most one entry per day.  Follower
+/// counts are recorded whenever the artist appears in the top artists of any user that updates.
+#[get("/artist_growth/<artist_spotify_id>")]
+pub(crate) async fn get_artist_growth(
+    conn: DbConn,
+    artist_spotify_id: String,
+) -> Result<Json<Vec<ArtistFollowersHistoryItem>>, String> {
+    track_endpoint_errors(
+        "get_artist_growth",
+        get_artist_growth_inner(conn, artist_spotify_id).await,
+    )
+}
+
+async fn get_artist_growth_inner(
+    conn: DbConn,
+    artist_spotify_id: String,
+) -> Result<Json<Vec<ArtistFollowersHistoryItem>>, String> {
+    let artist_internal_id =
+        get_internal_ids_by_spotify_id(&conn, std::iter::once(&artist_spotify_id))
+            .await?
+            .get(&artist_spotify_id)
+            .copied()
+            .ok_or_else(|| format!("No internal ID found for artist {}", artist_spotify_id))?;
+
+    let history = db_util::get_artist_followers_history(&conn, artist_internal_id)
+        .await
+        .map_err(db_util::stringify_diesel_err)?;
+    Ok(Json(history))
+}
+
 #[derive(Serialize)]
 pub(crate) struct GenreStats {
     pub artists_by_id: HashMap<String, Artist>,
     pub top_artists: Vec<(String, f32)>,
     pub timestamps: Vec<NaiveDateTime>,
     pub popularity_history: TimeFrames<usize>,
+    /// Genres that the user's artists in this genre most often also have, along with how many of
+    /// the user's artists have both
+    pub related_genres: Vec<(String, i64)>,
+    /// Image URLs of the top artists in the genre
+    pub representative_images: Vec<String>,
+}
+
+const RELATED_GENRES_COUNT: i64 = 5;
+const REPRESENTATIVE_GENRE_IMAGES_COUNT: usize = 3;
+
+/// Uses the provided timeframe weight if it's valid, falling back to the default otherwise
+fn parse_timeframe_weight(weight: Option<f32>, default: f32) -> f32 {
+    weight
+        .filter(|weight| weight.is_finite() && *weight >= 0.)
+        .unwrap_or(default)
 }
 
-#[get("/stats/<username>/genre/<genre>")]
+#[get("/stats/<username>/genre/<genre>?<short_weight>&<medium_weight>&<long_weight>")]
 pub(crate) async fn get_genre_stats(
     conn: DbConn,
-    token_data: &State<Mutex<SpotifyTokenData>>,
+    conn2: DbConn,
+    token_data: &State<SpotifyTokenData>,
+    username: String,
+    genre: String,
+    short_weight: Option<f32>,
+    medium_weight: Option<f32>,
+    long_weight: Option<f32>,
+) -> Result<Option<Json<GenreStats>>, String> {
+    let default_weights = TimeframeWeights::default();
+    let timeframe_weights = TimeframeWeights {
+        short: parse_timeframe_weight(short_weight, default_weights.short),
+        medium: parse_timeframe_weight(medium_weight, default_weights.medium),
+        long: parse_timeframe_weight(long_weight, default_weights.long),
+    };
+
+    track_endpoint_errors(
+        "get_genre_stats",
+        get_genre_stats_inner(conn, conn2, token_data, username, genre, timeframe_weights).await,
+    )
+}
+
+async fn get_genre_stats_inner(
+    conn: DbConn,
+    conn2: DbConn,
+    token_data: &State<SpotifyTokenData>,
     username: String,
     genre: String,
+    timeframe_weights: TimeframeWeights,
 ) -> Result<Option<Json<GenreStats>>, String> {
     let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
         Some(user) => user,
@@ -234,48 +816,91 @@ pub(crate) async fn get_genre_stats(
             return Ok(None);
         },
     };
-    let spotify_access_token = {
-        let token_data = &mut *(&*token_data).lock().await;
-        token_data.get().await
-    }?;
+    let spotify_access_token = token_data.get().await?;
 
-    let (artists_by_id, genre_stats_history) =
-        match db_util::get_genre_stats_history(&user, conn, &spotify_access_token, genre).await? {
-            Some(res) => res,
-            None => return Ok(None),
-        };
+    let (genre_stats_history, related_genres) = tokio::join!(
+        db_util::get_genre_stats_history(&user, conn, &spotify_access_token, genre.clone()),
+        db_util::get_related_genres_for_user(&conn2, user.id, genre, RELATED_GENRES_COUNT)
+            .map_err(db_util::stringify_diesel_err),
+    );
+    let (artists_by_id, genre_stats_history) = match genre_stats_history? {
+        Some(res) => res,
+        None => return Ok(None),
+    };
+    let related_genres = related_genres?
+        .into_iter()
+        .map(|item| (item.genre, item.count))
+        .collect();
 
     // Compute ranking scores for each of the update items
     let (timestamps, ranking_by_artist_spotify_id_by_timeframe, popularity_history) =
-        crate::stats::compute_genre_ranking_history(genre_stats_history);
+        crate::stats::compute_genre_ranking_history(genre_stats_history, timeframe_weights);
+
+    let representative_images = ranking_by_artist_spotify_id_by_timeframe
+        .iter()
+        .filter_map(|(artist_id, _)| {
+            let images = artists_by_id.get(artist_id)?.images.clone()?;
+            ImageSize::Large.pick_image(images).map(|image| image.url)
+        })
+        .take(REPRESENTATIVE_GENRE_IMAGES_COUNT)
+        .collect();
 
     Ok(Some(Json(GenreStats {
         artists_by_id,
         top_artists: ranking_by_artist_spotify_id_by_timeframe,
         popularity_history,
         timestamps,
+        related_genres,
+        representative_images,
     })))
 }
 
-#[get("/stats/<username>/timeline?<start_day_id>&<end_day_id>")]
+/// Parses a day ID like `2021-03-14` as used by the timeline into the time that day starts
+fn parse_day_id(day_id: &str, param_name: &str) -> Result<NaiveDateTime, String> {
+    NaiveDateTime::parse_from_str(&format!("{}T08:00:00+08:00", day_id), "%Y-%m-%dT%H:%M:%S%z")
+        .map_err(|_| format!("Invalid `{}` provided", param_name))
+}
+
+/// Returns events for artists and tracks that the user saw for the first time between
+/// `start_day_id` and `end_day_id`.  See `TimelineQuery` for the other supported params.
+#[get("/stats/<username>/timeline?<query..>")]
 pub(crate) async fn get_timeline(
     conn: DbConn,
-    token_data: &State<Mutex<SpotifyTokenData>>,
+    token_data: &State<SpotifyTokenData>,
     conn_2: DbConn,
     username: String,
-    start_day_id: String,
-    end_day_id: String,
+    query: TimelineQuery,
 ) -> Result<Option<Json<Timeline>>, String> {
-    let start_day = NaiveDateTime::parse_from_str(
-        &format!("{}T08:00:00+08:00", start_day_id),
-        "%Y-%m-%dT%H:%M:%S%z",
-    )
-    .map_err(|_| String::from("Invalid `start_day_id` provided"))?;
-    let end_day = NaiveDateTime::parse_from_str(
-        &format!("{}T08:00:00+08:00", end_day_id),
-        "%Y-%m-%dT%H:%M:%S%z",
+    track_endpoint_errors(
+        "get_timeline",
+        get_timeline_inner(conn, token_data, conn_2, username, query).await,
     )
-    .map_err(|_| String::from("Invalid `end_day_id` provided"))?;
+}
+
+async fn get_timeline_inner(
+    conn: DbConn,
+    token_data: &State<SpotifyTokenData>,
+    conn_2: DbConn,
+    username: String,
+    query: TimelineQuery,
+) -> Result<Option<Json<Timeline>>, String> {
+    let TimelineQuery {
+        start_day_id,
+        end_day_id,
+        genre,
+        limit,
+        order,
+    } = query;
+    let order: SortOrder = match order {
+        Some(order) => order.parse()?,
+        None => SortOrder::Ascending,
+    };
+    if limit.map(|limit| limit < 0).unwrap_or(false) {
+        return Err(String::from("Invalid `limit` provided"));
+    }
+
+    let start_day = parse_day_id(&start_day_id, "start_day_id")?;
+    let end_day = parse_day_id(&end_day_id, "end_day_id")?;
 
     let User { id: user_id, .. } = match db_util::get_user_by_spotify_id(&conn, username).await? {
         Some(user) => user,
@@ -283,16 +908,30 @@ pub(crate) async fn get_timeline(
             return Ok(None);
         },
     };
-    let spotify_access_token = {
-        let token_data = &mut *(&*token_data).lock().await;
-        token_data.get().await
-    }?;
+    let spotify_access_token = token_data.get().await?;
 
+    // Genres come from Spotify metadata rather than the database, so the limit can only be applied
+    // after filtering when a genre is provided
+    let query_limit = if genre.is_some() { None } else { limit };
     let (artist_events, track_events) = tokio::join!(
-        crate::db_util::get_artist_timeline_events(&conn, user_id, start_day, end_day)
-            .map_err(crate::db_util::stringify_diesel_err),
-        crate::db_util::get_track_timeline_events(&conn_2, user_id, start_day, end_day)
-            .map_err(crate::db_util::stringify_diesel_err),
+        crate::db_util::get_artist_timeline_events(
+            &conn,
+            user_id,
+            start_day,
+            end_day,
+            order,
+            query_limit
+        )
+        .map_err(crate::db_util::stringify_diesel_err),
+        crate::db_util::get_track_timeline_events(
+            &conn_2,
+            user_id,
+            start_day,
+            end_day,
+            order,
+            query_limit
+        )
+        .map_err(crate::db_util::stringify_diesel_err),
     );
     let (artist_events, track_events) = (artist_events?, track_events?);
 
@@ -312,13 +951,32 @@ pub(crate) async fn get_timeline(
     )?;
     let (artists, tracks) = items;
 
+    // Tracks only come with simplified artist objects which don't include genres, so we fetch the
+    // full artist for the first artist of each track to determine its primary genre.
+    let mut track_artist_ids = tracks
+        .iter()
+        .filter_map(|track| track.artists.first().map(|artist| artist.id.as_str()))
+        .collect::<Vec<_>>();
+    track_artist_ids.sort_unstable();
+    track_artist_ids.dedup();
+    let track_artists =
+        crate::spotify_api::fetch_artists(&spotify_access_token, &track_artist_ids).await?;
+    let primary_genre_by_artist_id: HashMap<String, String> = track_artists
+        .into_iter()
+        .filter_map(|artist| {
+            let primary_genre = artist.genres?.into_iter().next()?;
+            Some((artist.id, primary_genre))
+        })
+        .collect();
+
     let mut events = Vec::new();
     let mut event_count = 0;
     events.extend(artist_events.into_iter().zip(artists.into_iter()).map(
         |((_artist_id, first_seen), artist)| {
             event_count += 1;
+            let genres = artist.genres.clone().unwrap_or_default();
             TimelineEvent {
-                event_type: TimelineEventType::ArtistFirstSeen { artist },
+                event_type: TimelineEventType::ArtistFirstSeen { artist, genres },
                 date: first_seen.date(),
                 id: event_count,
             }
@@ -327,25 +985,125 @@ pub(crate) async fn get_timeline(
     events.extend(track_events.into_iter().zip(tracks.into_iter()).map(
         |((_track_id, first_seen), track)| {
             event_count += 1;
+            let primary_genre = track
+                .artists
+                .first()
+                .and_then(|artist| primary_genre_by_artist_id.get(&artist.id))
+                .cloned();
             TimelineEvent {
-                event_type: TimelineEventType::TopTrackFirstSeen { track },
+                event_type: TimelineEventType::TopTrackFirstSeen {
+                    track,
+                    primary_genre,
+                },
                 date: first_seen.date(),
                 id: event_count,
             }
         },
     ));
 
-    events.sort_unstable_by_key(|evt| evt.date);
-
+    let events = finalize_timeline_events(events, genre.as_deref(), order, limit);
     Ok(Some(Json(Timeline { events })))
 }
 
+/// Filters timeline events down to those matching `genre`, sorts them by date, and then applies
+/// `limit`.  Each of the artist and track queries are limited individually, so there can be up to
+/// twice as many events as the limit before truncating.
+fn finalize_timeline_events(
+    mut events: Vec<TimelineEvent>,
+    genre: Option<&str>,
+    order: SortOrder,
+    limit: Option<i64>,
+) -> Vec<TimelineEvent> {
+    if let Some(genre) = genre {
+        events.retain(|evt| evt.event_type.matches_genre(genre));
+    }
+    match order {
+        SortOrder::Ascending => events.sort_by_key(|evt| evt.date),
+        SortOrder::Descending => events.sort_by_key(|evt| Reverse(evt.date)),
+    }
+    if let Some(limit) = limit {
+        events.truncate(limit as usize);
+    }
+    events
+}
+
+#[derive(Serialize)]
+pub(crate) struct NewArtistItem {
+    pub artist: Artist,
+    pub first_seen: NaiveDate,
+}
+
+/// Returns artists that the user saw in their top artists for the first time on or after
+/// `since_day_id`, most recently discovered first.  Unlike the timeline, tracks aren't included.
+#[get("/stats/<username>/new_artists?<since_day_id>&<limit>")]
+pub(crate) async fn get_new_artists(
+    conn: DbConn,
+    token_data: &State<SpotifyTokenData>,
+    username: String,
+    since_day_id: String,
+    limit: Option<i64>,
+) -> Result<Option<Json<Vec<NewArtistItem>>>, String> {
+    track_endpoint_errors(
+        "get_new_artists",
+        get_new_artists_inner(conn, token_data, username, since_day_id, limit).await,
+    )
+}
+
+async fn get_new_artists_inner(
+    conn: DbConn,
+    token_data: &State<SpotifyTokenData>,
+    username: String,
+    since_day_id: String,
+    limit: Option<i64>,
+) -> Result<Option<Json<Vec<NewArtistItem>>>, String> {
+    if limit.map(|limit| limit < 0).unwrap_or(false) {
+        return Err(String::from("Invalid `limit` provided"));
+    }
+    let start_day = parse_day_id(&since_day_id, "since_day_id")?;
+
+    let User { id: user_id, .. } = match db_util::get_user_by_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+
+    let artist_events = db_util::get_artist_timeline_events(
+        &conn,
+        user_id,
+        start_day,
+        Utc::now().naive_utc(),
+        SortOrder::Descending,
+        limit,
+    )
+    .await
+    .map_err(db_util::stringify_diesel_err)?;
+    if artist_events.is_empty() {
+        return Ok(Some(Json(Vec::new())));
+    }
+
+    let spotify_access_token = token_data.get().await?;
+    let artist_ids = artist_events
+        .iter()
+        .map(|(artist_id, _first_seen)| artist_id.as_str())
+        .collect::<Vec<_>>();
+    let artists = fetch_artists(&spotify_access_token, &artist_ids).await?;
+
+    let new_artists = artist_events
+        .into_iter()
+        .zip(artists)
+        .map(|((_artist_id, first_seen), artist)| NewArtistItem {
+            artist,
+            first_seen: first_seen.date(),
+        })
+        .collect();
+    Ok(Some(Json(new_artists)))
+}
+
 /// Redirects to the Spotify authorization page for the application
 #[get("/authorize?<playlist_perms>&<state>")]
 pub(crate) fn authorize(playlist_perms: Option<&str>, state: Option<&str>) -> Redirect {
     let scopes = match playlist_perms {
-        None | Some("false") | Some("False") | Some("0") => "user-top-read",
-        _ => "user-top-read%20playlist-modify-public",
+        None | Some("false") | Some("False") | Some("0") => "user-top-read%20user-read-private",
+        _ => "user-top-read%20user-read-private%20playlist-modify-public",
     };
     let callback_uri = crate::conf::CONF.get_absolute_oauth_cb_uri();
 
@@ -364,10 +1122,12 @@ async fn generate_shared_playlist(
     conn2: DbConn,
     conn3: DbConn,
     conn4: DbConn,
-    token_data: &State<Mutex<SpotifyTokenData>>,
+    token_data: &State<SpotifyTokenData>,
     bearer_token: &str,
     user1: &str,
     user2: &str,
+    long_term_weight: Option<f32>,
+    discoveries_only: bool,
 ) -> Result<Option<Playlist>, String> {
     let (user1_res, user2_res) = tokio::join!(
         async move {
@@ -394,10 +1154,7 @@ async fn generate_shared_playlist(
         },
     };
 
-    let spotify_access_token = {
-        let token_data = &mut *(&*token_data).lock().await;
-        token_data.get().await
-    }?;
+    let spotify_access_token = token_data.get().await?;
 
     if let Some(res) = db_util::refresh_user_access_token(&conn1, &mut user2).await? {
         error!("Error refreshing access token: {:?}", res);
@@ -413,6 +1170,8 @@ async fn generate_shared_playlist(
             &user1,
             &user2,
             &spotify_access_token,
+            long_term_weight,
+            discoveries_only,
         )
         .await?;
 
@@ -420,10 +1179,17 @@ async fn generate_shared_playlist(
         bearer_token,
         &user2,
         format!("Shared Tastes of {} and {}", user1.username, user2.username),
-        Some(format!(
-            "Contains tracks and artists that both {} and {} enjoy, {}",
-            user1.username, user2.username, "generated by spotifytrack.net"
-        )),
+        Some(if discoveries_only {
+            format!(
+                "Contains new discoveries that both {} and {} might enjoy, {}",
+                user1.username, user2.username, "generated by spotifytrack.net"
+            )
+        } else {
+            format!(
+                "Contains tracks and artists that both {} and {} enjoy, {}",
+                user1.username, user2.username, "generated by spotifytrack.net"
+            )
+        }),
         &playlist_track_spotify_ids,
     )
     .await?;
@@ -440,7 +1206,7 @@ pub(crate) async fn oauth_cb(
     conn2: DbConn,
     conn3: DbConn,
     conn4: DbConn,
-    token_data: &State<Mutex<SpotifyTokenData>>,
+    token_data: &State<SpotifyTokenData>,
     error: Option<&str>,
     code: &str,
     state: Option<&str>,
@@ -509,6 +1275,7 @@ pub(crate) async fn oauth_cb(
     let user_profile_info = crate::spotify_api::get_user_profile_info(&access_token).await?;
     let user_spotify_id = user_profile_info.id;
     let username = user_profile_info.display_name;
+    let country = user_profile_info.country;
 
     let user = NewUser {
         creation_time: Utc::now().naive_utc(),
@@ -517,6 +1284,7 @@ pub(crate) async fn oauth_cb(
         username: username.clone(),
         token: access_token.clone(),
         refresh_token: refresh_token.clone(),
+        country: country.clone(),
     };
 
     let query = diesel::insert_into(crate::schema::users::table).values(user);
@@ -530,6 +1298,7 @@ pub(crate) async fn oauth_cb(
                 .set((
                     users::dsl::refresh_token.eq(refresh_token),
                     users::dsl::token.eq(access_token.clone()),
+                    users::dsl::country.eq(country),
                 ));
             conn1
                 .run(move |conn| query.execute(conn))
@@ -589,7 +1358,12 @@ pub(crate) async fn oauth_cb(
                     })?;
 
             match serde_json::from_str(percent_decoded.as_ref()) {
-                Ok(CreateSharedPlaylistRequest { user1_id, user2_id }) => {
+                Ok(CreateSharedPlaylistRequest {
+                    user1_id,
+                    user2_id,
+                    long_term_weight,
+                    discoveries_only,
+                }) => {
                     let playlist = generate_shared_playlist(
                         conn1,
                         conn2,
@@ -599,6 +1373,8 @@ pub(crate) async fn oauth_cb(
                         &access_token,
                         &user1_id,
                         &user2_id,
+                        long_term_weight,
+                        discoveries_only,
                     )
                     .await?;
 
@@ -662,8 +1438,12 @@ pub(crate) async fn oauth_cb(
     Ok(Redirect::to(redirect_url))
 }
 
-/// Returns `true` if the token is valid, false if it's not
-async fn validate_api_token(api_token_data: rocket::data::Data<'_>) -> Result<bool, String> {
+/// Reads the admin API token from the request body and returns `true` if it's valid for `action`.
+/// See `check_admin_token`.
+async fn validate_api_token(
+    api_token_data: rocket::data::Data<'_>,
+    action: &str,
+) -> Result<bool, String> {
     let api_token = api_token_data
         .open(1usize.mebibytes())
         .into_string()
@@ -673,17 +1453,83 @@ async fn validate_api_token(api_token_data: rocket::data::Data<'_>) -> Result<bo
             String::from("Error reading post data body")
         })?
         .into_inner();
-    Ok(api_token == CONF.admin_api_token)
+    Ok(check_admin_token(&api_token, action))
 }
 
+/// Returns `true` if the token is valid, false if it's not.
+///
+/// Accepts either the static admin API token or a short-lived token signed for `action`; see
+/// `crate::admin_auth` for the format of the latter.
+fn check_admin_token(api_token: &str, action: &str) -> bool {
+    if let Some(token_ix) = match_static_admin_token(&CONF.admin_api_tokens, api_token) {
+        let token_label = static_token_label(token_ix);
+        admin_auth_total(token_label).inc();
+        info!(
+            "Admin action \"{}\" authenticated with {} admin API token",
+            action, token_label
+        );
+        return true;
+    }
+
+    let signing_secret = match CONF.admin_token_signing_secret.as_deref() {
+        Some(secret) => secret,
+        None => {
+            admin_auth_total("rejected").inc();
+            return false;
+        },
+    };
+    let is_valid = verify_admin_token(
+        signing_secret,
+        api_token,
+        action,
+        Utc::now().timestamp(),
+        dynamic_conf().admin_token_max_age_seconds,
+    );
+    if is_valid {
+        admin_auth_total("signed").inc();
+    } else {
+        admin_auth_total("rejected").inc();
+        warn!(
+            "Rejected invalid or expired signed admin token for action \"{}\"",
+            action
+        );
+    }
+    is_valid
+}
+
+/// Admin API token provided in the `X-Admin-Token` header, for read-only admin routes that are
+/// `GET`s and so can't take the token as a request body.  Empty if the header is missing.
+pub(crate) struct AdminTokenHeader(String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminTokenHeader {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let api_token = req.headers().get_one("X-Admin-Token").unwrap_or_default();
+        request::Outcome::Success(AdminTokenHeader(api_token.to_owned()))
+    }
+}
+
+/// Returns which timeframes were captured for the user if they were updated
 async fn update_user_inner(
     conn: &DbConn,
     user_id: Option<String>,
-) -> Result<(), status::Custom<String>> {
+) -> Result<CapturedTimeframes, status::Custom<String>> {
     use crate::schema::users::dsl::*;
 
-    // Get the least recently updated user
-    let mut user: User = match user_id.clone().map(|s| -> Result<String, _> {
+    let _in_flight_guard = shutdown::register_in_flight_task(format!(
+        "update_user {}",
+        user_id.as_deref().unwrap_or("(least recently updated)")
+    ));
+
+    // Users updated more recently than this aren't due for an update yet
+    let min_update_interval_seconds = dynamic_conf().min_update_interval;
+    let update_cutoff = chrono::Utc::now().naive_utc() - min_update_interval_seconds;
+
+    // Get the least recently updated user that's due for an update.  If a specific user was
+    // requested, they're updated regardless of when they were last updated.
+    let user_opt: Option<User> = match user_id.clone().map(|s| -> Result<String, _> {
         let s = RawStr::new(s.as_str());
         match s.percent_decode() {
             Ok(decoded) => Ok(decoded.into()),
@@ -699,12 +1545,18 @@ async fn update_user_inner(
                 )
             })?;
 
-            conn.run(move |conn| users.filter(spotify_id.eq(user_id)).first(conn))
+            conn.run(move |conn| users.filter(spotify_id.eq(user_id)).first(conn).map(Some))
                 .await
         },
         None =>
-            conn.run(move |conn| users.order_by(last_update_time).first(conn))
-                .await,
+            conn.run(move |conn| {
+                users
+                    .filter(last_update_time.lt(update_cutoff))
+                    .order_by(last_update_time)
+                    .first(conn)
+                    .optional()
+            })
+            .await,
     }
     .map_err(|err| {
         error!("{:?}", err);
@@ -713,6 +1565,15 @@ async fn update_user_inner(
             "Error querying user to update from database".into(),
         )
     })?;
+    let mut user = match user_opt {
+        Some(user) => user,
+        None => {
+            let msg =
+                String::from("No users are due for an update; not updating anything right now.");
+            info!("{}", msg);
+            return Err(status::Custom(Status::Ok, msg));
+        },
+    };
 
     if let Some(res) = db_util::refresh_user_access_token(&conn, &mut user)
         .await
@@ -721,18 +1582,7 @@ async fn update_user_inner(
         return Err(res);
     }
 
-    // Only update the user if it's been longer than the minimum update interval
-    let min_update_interval_seconds = crate::conf::CONF.min_update_interval;
-    let now = chrono::Utc::now().naive_utc();
-    let diff = now - user.last_update_time;
-    if user_id.is_none() && diff < min_update_interval_seconds {
-        let msg = format!(
-            "{} since last update; not updating anything right now.",
-            diff
-        );
-        info!("{}", msg);
-        return Err(status::Custom(Status::Ok, msg));
-    }
+    let diff = chrono::Utc::now().naive_utc() - user.last_update_time;
     info!("{diff} since last update; proceeding with update.");
 
     if let Err(err) =
@@ -752,9 +1602,19 @@ async fn update_user_inner(
         Ok(Some(stats)) => stats,
         Ok(None) => {
             error!(
-                "Error when fetching stats for user {:?}; no stats returned.",
+                "Spotify returned corrupt stats for user {:?}; aborting update.",
                 user
             );
+            // Restore the previous last update time so that the user gets retried on the next
+            // update cycle rather than waiting for the full update interval
+            if let Err(err) =
+                crate::db_util::update_user_last_updated(&user, &conn, user.last_update_time).await
+            {
+                error!(
+                    "Error restoring user {:?} last updated time: {:?}",
+                    user, err
+                );
+            }
             return Err(status::Custom(
                 Status::InternalServerError,
                 "No data from Spotify API for that user".into(),
@@ -769,13 +1629,17 @@ async fn update_user_inner(
         },
     };
 
+    let captured_timeframes = stats.captured_timeframes;
     crate::spotify_api::store_stats_snapshot(&conn, &user, stats)
         .await
         .map_err(|err| status::Custom(Status::InternalServerError, err))?;
 
-    info!("Successfully updated user {}", user.spotify_id);
+    info!(
+        "Successfully updated user {}; captured {}",
+        user.spotify_id, captured_timeframes
+    );
 
-    Ok(())
+    Ok(captured_timeframes)
 }
 
 /// This route is internal and hit by the cron job that is called to periodically update the stats
@@ -787,33 +1651,60 @@ pub(crate) async fn update_user(
     user_id: Option<String>,
     count: Option<usize>,
 ) -> Result<status::Custom<String>, String> {
-    if !validate_api_token(api_token_data).await? {
+    if !validate_api_token(api_token_data, "update_user").await? {
         return Ok(status::Custom(
             Status::Unauthorized,
             "Invalid API token supplied".into(),
         ));
     }
+    if let Some(res) = draining_response() {
+        return Ok(res);
+    }
 
     if let Some(user_id) = user_id {
-        if let Err(status) = update_user_inner(&conn, Some(user_id)).await {
-            user_updates_failure_total().inc();
-            return Ok(status);
-        }
+        let captured_timeframes = match update_user_inner(&conn, Some(user_id)).await {
+            Ok(captured_timeframes) => captured_timeframes,
+            Err(status) => {
+                user_updates_failure_total().inc();
+                return Ok(status);
+            },
+        };
         user_updates_success_total().inc();
-        return Ok(status::Custom(Status::Ok, "User updated".into()));
+        return Ok(status::Custom(
+            Status::Ok,
+            if captured_timeframes.is_complete() {
+                "User updated".into()
+            } else {
+                format!("User partially updated; captured {}", captured_timeframes)
+            },
+        ));
     }
 
     let count = count.unwrap_or(1);
     let mut success_count = 0usize;
+    let mut partial_count = 0usize;
     let mut fail_count = 0usize;
     for _ in 0..count {
-        let success = update_user_inner(&conn, None).await.is_ok();
-        if success {
-            user_updates_success_total().inc();
-            success_count += 1;
-        } else {
-            user_updates_failure_total().inc();
-            fail_count += 1;
+        // Stop picking up new users once draining starts; the ones already updated are reported
+        if shutdown::is_draining() {
+            warn!("Server started draining; stopping multi-user update early");
+            break;
+        }
+
+        match update_user_inner(&conn, None).await {
+            Ok(captured_timeframes) => {
+                user_updates_success_total().inc();
+                success_count += 1;
+                if !captured_timeframes.is_complete() {
+                    partial_count += 1;
+                }
+            },
+            // No more users are due for an update, so there's nothing left to do this time around
+            Err(status::Custom(Status::Ok, _)) => break,
+            Err(_) => {
+                user_updates_failure_total().inc();
+                fail_count += 1;
+            },
         }
         tokio::time::sleep(std::time::Duration::from_secs(1)).await;
     }
@@ -821,8 +1712,8 @@ pub(crate) async fn update_user(
     Ok(status::Custom(
         Status::Ok,
         format!(
-            "Successfully updated {} user(s); failed to update {} user(s)",
-            success_count, fail_count
+            "Successfully updated {} user(s) ({} partially); failed to update {} user(s)",
+            success_count, partial_count, fail_count
         ),
     ))
 }
@@ -831,19 +1722,19 @@ pub(crate) async fn update_user(
 pub(crate) async fn populate_tracks_artists_mapping_table(
     conn: DbConn,
     api_token_data: rocket::data::Data<'_>,
-    token_data: &State<Mutex<SpotifyTokenData>>,
+    token_data: &State<SpotifyTokenData>,
 ) -> Result<status::Custom<String>, String> {
-    if !validate_api_token(api_token_data).await? {
+    if !validate_api_token(api_token_data, "populate_tracks_artists_mapping_table").await? {
         return Ok(status::Custom(
             Status::Unauthorized,
             "Invalid API token supplied".into(),
         ));
     }
+    if let Some(res) = draining_response() {
+        return Ok(res);
+    }
 
-    let spotify_access_token = {
-        let token_data = &mut *(&*token_data).lock().await;
-        token_data.get().await
-    }?;
+    let spotify_access_token = token_data.get().await?;
 
     crate::db_util::populate_tracks_artists_table(&conn, &spotify_access_token).await?;
 
@@ -857,19 +1748,19 @@ pub(crate) async fn populate_tracks_artists_mapping_table(
 pub(crate) async fn populate_artists_genres_mapping_table(
     conn: DbConn,
     api_token_data: rocket::data::Data<'_>,
-    token_data: &State<Mutex<SpotifyTokenData>>,
+    token_data: &State<SpotifyTokenData>,
 ) -> Result<status::Custom<String>, String> {
-    if !validate_api_token(api_token_data).await? {
+    if !validate_api_token(api_token_data, "populate_artists_genres_mapping_table").await? {
         return Ok(status::Custom(
             Status::Unauthorized,
             "Invalid API token supplied".into(),
         ));
     }
+    if let Some(res) = draining_response() {
+        return Ok(res);
+    }
 
-    let spotify_access_token = {
-        let token_data = &mut *(&*token_data).lock().await;
-        token_data.get().await
-    }?;
+    let spotify_access_token = token_data.get().await?;
 
     crate::db_util::populate_artists_genres_table(&conn, &spotify_access_token).await?;
 
@@ -879,6 +1770,83 @@ pub(crate) async fn populate_artists_genres_mapping_table(
     ))
 }
 
+/// Deletes rows from the `spotify_items` mapping table that aren't referenced anywhere.  With
+/// `dry_run`, only reports how many rows would be deleted.
+#[post("/prune_orphaned_spotify_items?<dry_run>", data = "<api_token_data>")]
+pub(crate) async fn prune_orphaned_spotify_items(
+    conn: DbConn,
+    api_token_data: rocket::data::Data<'_>,
+    dry_run: Option<bool>,
+) -> Result<status::Custom<String>, String> {
+    if !validate_api_token(api_token_data, "prune_orphaned_spotify_items").await? {
+        return Ok(status::Custom(
+            Status::Unauthorized,
+            "Invalid API token supplied".into(),
+        ));
+    }
+    if let Some(res) = draining_response() {
+        return Ok(res);
+    }
+
+    let dry_run = dry_run.unwrap_or(false);
+    let count = db_util::prune_orphaned_spotify_items(&conn, dry_run)
+        .await
+        .map_err(db_util::stringify_diesel_err)?;
+    info!(
+        "Pruned orphaned spotify items; dry_run={}, count={}",
+        dry_run, count
+    );
+
+    Ok(status::Custom(
+        Status::Ok,
+        if dry_run {
+            format!("Would delete {} orphaned spotify items", count)
+        } else {
+            format!("Deleted {} orphaned spotify items", count)
+        },
+    ))
+}
+
+/// Recovery tool that recomputes a user's first-seen artists and tracks from their rank snapshots,
+/// replacing any existing first-seen entries.
+#[post("/rebuild_first_seen/<username>", data = "<api_token_data>")]
+pub(crate) async fn rebuild_first_seen(
+    conn: DbConn,
+    api_token_data: rocket::data::Data<'_>,
+    username: String,
+) -> Result<status::Custom<String>, String> {
+    if !validate_api_token(api_token_data, "rebuild_first_seen").await? {
+        return Ok(status::Custom(
+            Status::Unauthorized,
+            "Invalid API token supplied".into(),
+        ));
+    }
+    if let Some(res) = draining_response() {
+        return Ok(res);
+    }
+
+    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => return Ok(status::Custom(Status::NotFound, "User not found".into())),
+    };
+
+    let (artist_count, track_count) = db_util::rebuild_first_seen_for_user(&conn, user.id)
+        .await
+        .map_err(db_util::stringify_diesel_err)?;
+    info!(
+        "Rebuilt first-seen entries for user {}: {} artists, {} tracks",
+        user.spotify_id, artist_count, track_count
+    );
+
+    Ok(status::Custom(
+        Status::Ok,
+        format!(
+            "Rebuilt first-seen entries for {} artists and {} tracks",
+            artist_count, track_count
+        ),
+    ))
+}
+
 async fn compute_comparison(
     user1: String,
     user2: String,
@@ -886,7 +1854,7 @@ async fn compute_comparison(
     conn2: DbConn,
     conn3: DbConn,
     conn4: DbConn,
-    token_data: &State<Mutex<SpotifyTokenData>>,
+    token_data: &State<SpotifyTokenData>,
 ) -> Result<Option<UserComparison>, String> {
     let (user1_res, user2_res) = tokio::join!(
         async move {
@@ -914,10 +1882,7 @@ async fn compute_comparison(
     };
     let (user1_id, user2_id) = (user1.id, user2.id);
 
-    let spotify_access_token = {
-        let token_data = &mut *(&*token_data).lock().await;
-        token_data.get().await
-    }?;
+    let spotify_access_token = token_data.get().await?;
     let spotify_access_token_clone = spotify_access_token.clone();
 
     let stats = tokio::try_join!(
@@ -931,6 +1896,14 @@ async fn compute_comparison(
             .map_err(db_util::stringify_diesel_err),
     )?;
     let (user1_tracks, user2_tracks, user1_artists, user2_artists) = stats;
+    let user1_data_status = UserComparisonDataStatus {
+        stored_track_count: user1_tracks.len(),
+        stored_artist_count: user1_artists.len(),
+    };
+    let user2_data_status = UserComparisonDataStatus {
+        stored_track_count: user2_tracks.len(),
+        stored_artist_count: user2_artists.len(),
+    };
 
     let tracks_intersection = async move {
         let mut intersection = user1_tracks;
@@ -961,6 +1934,8 @@ async fn compute_comparison(
         genres: Vec::new(), // TODO
         user1_username: user1.username,
         user2_username: user2.username,
+        user1_data_status,
+        user2_data_status,
     }))
 }
 
@@ -970,7 +1945,22 @@ pub(crate) async fn compare_users(
     conn2: DbConn,
     conn3: DbConn,
     conn4: DbConn,
-    token_data: &State<Mutex<SpotifyTokenData>>,
+    token_data: &State<SpotifyTokenData>,
+    user1: String,
+    user2: String,
+) -> Result<Option<Json<UserComparison>>, String> {
+    track_endpoint_errors(
+        "compare_users",
+        compare_users_inner(conn1, conn2, conn3, conn4, token_data, user1, user2).await,
+    )
+}
+
+async fn compare_users_inner(
+    conn1: DbConn,
+    conn2: DbConn,
+    conn3: DbConn,
+    conn4: DbConn,
+    token_data: &State<SpotifyTokenData>,
     user1: String,
     user2: String,
 ) -> Result<Option<Json<UserComparison>>, String> {
@@ -979,32 +1969,220 @@ pub(crate) async fn compare_users(
         .map(|res| res.map(Json))
 }
 
-async fn build_related_artists_graph(
-    spotify_access_token: String,
-    artist_ids: &[&str],
-) -> Result<RelatedArtistsGraph, String> {
-    // Get related artists for all of them
-    let related_artists =
-        get_multiple_related_artists(spotify_access_token.clone(), artist_ids).await?;
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum FirstDiscoverer {
+    User1,
+    User2,
+    SameDay,
+}
 
-    let all_artist_ids: FnvHashSet<String> = artist_ids
-        .iter()
+#[derive(Serialize)]
+pub(crate) struct WhoFirstResponse {
+    /// `None` if the user never had the artist in their top artists
+    pub user1_first_seen: Option<NaiveDate>,
+    pub user2_first_seen: Option<NaiveDate>,
+    /// `None` if neither user ever had the artist in their top artists
+    pub first: Option<FirstDiscoverer>,
+}
+
+/// A user that had the artist at all counts as having discovered it before one that never did
+fn pick_first_discoverer(
+    user1_first_seen: Option<NaiveDate>,
+    user2_first_seen: Option<NaiveDate>,
+) -> Option<FirstDiscoverer> {
+    match (user1_first_seen, user2_first_seen) {
+        (None, None) => None,
+        (Some(_), None) => Some(FirstDiscoverer::User1),
+        (None, Some(_)) => Some(FirstDiscoverer::User2),
+        (Some(user1), Some(user2)) if user1 < user2 => Some(FirstDiscoverer::User1),
+        (Some(user1), Some(user2)) if user2 < user1 => Some(FirstDiscoverer::User2),
+        (Some(_), Some(_)) => Some(FirstDiscoverer::SameDay),
+    }
+}
+
+/// Returns when each of the users first had the artist in their top artists and which of them had
+/// it first
+#[get("/compare/<user1>/<user2>/who_first/<artist_id>")]
+pub(crate) async fn get_who_first(
+    conn: DbConn,
+    user1: String,
+    user2: String,
+    artist_id: String,
+) -> Result<Option<Json<WhoFirstResponse>>, String> {
+    track_endpoint_errors(
+        "get_who_first",
+        get_who_first_inner(conn, user1, user2, artist_id).await,
+    )
+}
+
+async fn get_who_first_inner(
+    conn: DbConn,
+    user1: String,
+    user2: String,
+    artist_id: String,
+) -> Result<Option<Json<WhoFirstResponse>>, String> {
+    let user1 = match db_util::get_user_by_spotify_id(&conn, user1).await? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    let user2 = match db_util::get_user_by_spotify_id(&conn, user2).await? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+
+    let artist_internal_id = get_internal_ids_by_spotify_id(&conn, std::iter::once(&artist_id))
+        .await?
+        .get(&artist_id)
         .copied()
-        .map(String::from)
-        .chain(
-            related_artists
-                .iter()
-                .flat_map(|related_artists| related_artists.iter().cloned()),
-        )
+        .ok_or_else(|| format!("No internal ID found for artist {}", artist_id))?;
+
+    let first_seen_by_user_id: HashMap<i64, NaiveDate> = db_util::get_artist_first_seen_for_users(
+        &conn,
+        vec![user1.id, user2.id],
+        artist_internal_id,
+    )
+    .await
+    .map_err(db_util::stringify_diesel_err)?
+    .into_iter()
+    .map(|(user_id, first_seen)| (user_id, first_seen.date()))
+    .collect();
+
+    let user1_first_seen = first_seen_by_user_id.get(&user1.id).copied();
+    let user2_first_seen = first_seen_by_user_id.get(&user2.id).copied();
+    Ok(Some(Json(WhoFirstResponse {
+        user1_first_seen,
+        user2_first_seen,
+        first: pick_first_discoverer(user1_first_seen, user2_first_seen),
+    })))
+}
+
+/// Trims a related artists graph down to a size that the frontend can lay out.
+///
+/// `per_artist_limit` keeps only the most popular related artists of each artist.  `max_nodes` then
+/// caps the total number of artists in the graph by dropping the least-connected ones, starting
+/// with leaf nodes (artists whose own related artists weren't expanded).
+fn limit_related_artists_graph(
+    graph: &mut RelatedArtistsGraph,
+    per_artist_limit: Option<usize>,
+    max_nodes: Option<usize>,
+) {
+    if let Some(per_artist_limit) = per_artist_limit {
+        let extra_artists = &graph.extra_artists;
+        for related_artist_ids in graph.related_artists.values_mut() {
+            // Stable sort so that Spotify's ordering is preserved between equally popular artists
+            related_artist_ids.sort_by_key(|id| {
+                Reverse(
+                    extra_artists
+                        .get(id)
+                        .and_then(|artist| artist.popularity)
+                        .unwrap_or(0),
+                )
+            });
+            related_artist_ids.truncate(per_artist_limit);
+        }
+
+        let retained_ids = related_artists_graph_node_ids(&graph.related_artists);
+        graph
+            .extra_artists
+            .retain(|id, _| retained_ids.contains(id));
+    }
+
+    if let Some(max_nodes) = max_nodes {
+        let node_ids: Vec<String> = graph.extra_artists.keys().cloned().collect();
+        let removed_ids =
+            trim_least_connected_artists(&mut graph.related_artists, node_ids, max_nodes);
+        graph
+            .extra_artists
+            .retain(|id, _| !removed_ids.contains(id));
+    }
+}
+
+/// Removes the least-connected artists from `related_artists` until at most `max_nodes` of
+/// `node_ids` remain, preferring to remove leaf nodes (artists whose own related artists weren't
+/// expanded).  Returns the IDs of the removed artists.
+fn trim_least_connected_artists(
+    related_artists: &mut HashMap<String, Vec<String>>,
+    node_ids: Vec<String>,
+    max_nodes: usize,
+) -> FnvHashSet<String> {
+    if node_ids.len() <= max_nodes {
+        return FnvHashSet::default();
+    }
+
+    let mut connection_counts: HashMap<&str, usize> = HashMap::default();
+    for (id, related_artist_ids) in related_artists.iter() {
+        *connection_counts.entry(id.as_str()).or_default() += related_artist_ids.len();
+        for related_id in related_artist_ids {
+            *connection_counts.entry(related_id.as_str()).or_default() += 1;
+        }
+    }
+    let removed_count = node_ids.len() - max_nodes;
+    let mut candidates: Vec<(bool, usize, String)> = node_ids
+        .into_iter()
+        .map(|id| {
+            let is_expanded = related_artists.contains_key(&id);
+            let connection_count = connection_counts.get(id.as_str()).copied().unwrap_or(0);
+            (is_expanded, connection_count, id)
+        })
         .collect();
+    candidates.sort_unstable();
+    let removed_ids: FnvHashSet<String> = candidates
+        .into_iter()
+        .take(removed_count)
+        .map(|(_, _, id)| id)
+        .collect();
+
+    related_artists.retain(|id, _| !removed_ids.contains(id));
+    for related_artist_ids in related_artists.values_mut() {
+        related_artist_ids.retain(|id| !removed_ids.contains(id));
+    }
+    removed_ids
+}
+
+async fn fetch_related_artists_by_id(
+    spotify_access_token: &str,
+    artist_ids: &[&str],
+) -> Result<HashMap<String, Vec<String>>, String> {
+    let related_artists =
+        get_multiple_related_artists(spotify_access_token.to_owned(), artist_ids).await?;
 
     let mut related_artists_by_id = HashMap::default();
-    for (&artist_id, related_artists) in artist_ids.into_iter().zip(related_artists.iter()) {
-        related_artists_by_id.insert(artist_id.to_owned(), related_artists.clone());
+    for (&artist_id, related_artists) in artist_ids.iter().zip(related_artists.into_iter()) {
+        related_artists_by_id.insert(artist_id.to_owned(), related_artists);
     }
+    Ok(related_artists_by_id)
+}
+
+/// Returns the IDs of all artists in the graph, both the expanded ones and those they're related to
+fn related_artists_graph_node_ids(
+    related_artists_by_id: &HashMap<String, Vec<String>>,
+) -> FnvHashSet<String> {
+    related_artists_by_id
+        .iter()
+        .flat_map(|(id, related_artist_ids)| std::iter::once(id).chain(related_artist_ids.iter()))
+        .cloned()
+        .collect()
+}
 
+async fn build_related_artists_graph(
+    spotify_access_token: String,
+    artist_ids: &[&str],
+) -> Result<RelatedArtistsGraph, String> {
+    // Get related artists for all of them
+    let related_artists_by_id =
+        fetch_related_artists_by_id(&spotify_access_token, artist_ids).await?;
+    populate_related_artists_graph(&spotify_access_token, related_artists_by_id).await
+}
+
+/// Fetches metadata for all artists in the graph in a single batch
+async fn populate_related_artists_graph(
+    spotify_access_token: &str,
+    related_artists_by_id: HashMap<String, Vec<String>>,
+) -> Result<RelatedArtistsGraph, String> {
+    let all_artist_ids = related_artists_graph_node_ids(&related_artists_by_id);
     let all_artist_ids: Vec<_> = all_artist_ids.iter().map(String::as_str).collect();
-    let extra_artists_list = fetch_artists(&spotify_access_token, &all_artist_ids).await?;
+    let extra_artists_list = fetch_artists(spotify_access_token, &all_artist_ids).await?;
     let mut extra_artists = HashMap::default();
     for artist in extra_artists_list {
         extra_artists.insert(artist.id.clone(), artist);
@@ -1016,11 +2194,29 @@ async fn build_related_artists_graph(
     })
 }
 
-#[get("/stats/<user_id>/related_artists_graph")]
+/// `per_artist_limit` truncates each artist's related artists to the most popular ones and
+/// `max_nodes` caps the total number of artists in the graph.  Both default to unlimited.
+#[get("/stats/<user_id>/related_artists_graph?<max_nodes>&<per_artist_limit>")]
 pub(crate) async fn get_related_artists_graph(
     conn: DbConn,
     user_id: String,
-    token_data: &State<Mutex<SpotifyTokenData>>,
+    max_nodes: Option<usize>,
+    per_artist_limit: Option<usize>,
+    token_data: &State<SpotifyTokenData>,
+) -> Result<Option<Json<RelatedArtistsGraph>>, String> {
+    track_endpoint_errors(
+        "get_related_artists_graph",
+        get_related_artists_graph_inner(conn, user_id, max_nodes, per_artist_limit, token_data)
+            .await,
+    )
+}
+
+async fn get_related_artists_graph_inner(
+    conn: DbConn,
+    user_id: String,
+    max_nodes: Option<usize>,
+    per_artist_limit: Option<usize>,
+    token_data: &State<SpotifyTokenData>,
 ) -> Result<Option<Json<RelatedArtistsGraph>>, String> {
     let User { id: user_id, .. } = match db_util::get_user_by_spotify_id(&conn, user_id).await? {
         Some(user) => user,
@@ -1028,10 +2224,7 @@ pub(crate) async fn get_related_artists_graph(
             return Ok(None);
         },
     };
-    let spotify_access_token = {
-        let token_data = &mut *(&*token_data).lock().await;
-        token_data.get().await
-    }?;
+    let spotify_access_token = token_data.get().await?;
 
     // Start off by getting all artists for the user from all timeframes
     let all_artists_for_user =
@@ -1046,19 +2239,74 @@ pub(crate) async fn get_related_artists_graph(
         .map(|(_internal_id, spotify_id)| spotify_id.as_str())
         .collect();
 
-    let out = build_related_artists_graph(spotify_access_token, &all_artist_ids_for_user).await?;
+    let mut out =
+        build_related_artists_graph(spotify_access_token, &all_artist_ids_for_user).await?;
+    limit_related_artists_graph(&mut out, per_artist_limit, max_nodes);
     Ok(Some(Json(out)))
 }
 
-#[get("/related_artists/<artist_id>")]
+const MAX_RELATED_ARTISTS_DEPTH: u8 = 2;
+
+/// Returns the graph formed by the artists related to the provided one and their own related
+/// artists.
+///
+/// With `depth=2`, the artists discovered that way are expanded one hop further in a single batch.
+/// The seed artist is never expanded and edges that already exist in the opposite direction aren't
+/// added again.  `max_nodes` caps the total number of artists in the graph.
+#[get("/related_artists/<artist_id>?<depth>&<max_nodes>")]
 pub(crate) async fn get_related_artists(
     artist_id: String,
-    token_data: &State<Mutex<SpotifyTokenData>>,
+    depth: Option<u8>,
+    max_nodes: Option<usize>,
+    token_data: &State<SpotifyTokenData>,
+) -> Result<Option<Json<RelatedArtistsGraph>>, String> {
+    track_endpoint_errors(
+        "get_related_artists",
+        get_related_artists_inner(artist_id, depth, max_nodes, token_data).await,
+    )
+}
+
+/// Expands all artists in the graph that haven't been expanded yet (other than the seed artist)
+/// and merges their related artists in.
+async fn expand_related_artists_graph(
+    spotify_access_token: &str,
+    seed_artist_id: &str,
+    related_artists_by_id: &mut HashMap<String, Vec<String>>,
+) -> Result<(), String> {
+    let mut frontier: Vec<String> = related_artists_graph_node_ids(related_artists_by_id)
+        .into_iter()
+        .filter(|id| id != seed_artist_id && !related_artists_by_id.contains_key(id))
+        .collect();
+    frontier.sort_unstable();
+    let frontier: Vec<&str> = frontier.iter().map(String::as_str).collect();
+
+    let next_hop = fetch_related_artists_by_id(spotify_access_token, &frontier).await?;
+    for (artist_id, related_artist_ids) in next_hop {
+        let mut seen_ids = FnvHashSet::default();
+        let related_artist_ids = related_artist_ids
+            .into_iter()
+            .filter(|related_id| {
+                let is_reverse_edge = related_artists_by_id
+                    .get(related_id)
+                    .map(|ids| ids.contains(&artist_id))
+                    .unwrap_or(false);
+                !is_reverse_edge && seen_ids.insert(related_id.clone())
+            })
+            .collect();
+        related_artists_by_id.insert(artist_id, related_artist_ids);
+    }
+    Ok(())
+}
+
+async fn get_related_artists_inner(
+    artist_id: String,
+    depth: Option<u8>,
+    max_nodes: Option<usize>,
+    token_data: &State<SpotifyTokenData>,
 ) -> Result<Option<Json<RelatedArtistsGraph>>, String> {
-    let spotify_access_token = {
-        let token_data = &mut *(&*token_data).lock().await;
-        token_data.get().await
-    }?;
+    let depth = depth.unwrap_or(1).clamp(1, MAX_RELATED_ARTISTS_DEPTH);
+
+    let spotify_access_token = token_data.get().await?;
 
     let related_artist_ids =
         get_multiple_related_artists(spotify_access_token.clone(), &[&artist_id]).await?;
@@ -1069,13 +2317,290 @@ pub(crate) async fn get_related_artists(
             return Ok(None);
         },
     };
-    let related_artist_ids = related_artist_ids
+    let related_artist_ids = related_artist_ids
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+
+    let mut related_artists_by_id =
+        fetch_related_artists_by_id(&spotify_access_token, &related_artist_ids).await?;
+    if depth > 1 {
+        expand_related_artists_graph(
+            &spotify_access_token,
+            &artist_id,
+            &mut related_artists_by_id,
+        )
+        .await?;
+    }
+    if let Some(max_nodes) = max_nodes {
+        let node_ids = related_artists_graph_node_ids(&related_artists_by_id)
+            .into_iter()
+            .collect();
+        trim_least_connected_artists(&mut related_artists_by_id, node_ids, max_nodes);
+    }
+
+    let out = populate_related_artists_graph(&spotify_access_token, related_artists_by_id).await?;
+    Ok(Some(Json(out)))
+}
+
+const DEFAULT_RECOMMENDATION_COUNT: usize = 20;
+const MAX_RECOMMENDATION_COUNT: usize = 50;
+/// Bonus added to the score of artists that are recommended by both Spotify and the embedding
+const RECOMMENDED_BY_BOTH_BONUS: f32 = 0.5;
+
+/// Recommends artists similar to the provided one by blending its Spotify related artists with its
+/// nearest neighbors in the artist embedding.  Artists that show up in both are ranked higher.
+#[get("/recommend/<spotify_id>?<count>")]
+pub(crate) async fn get_recommendations(
+    conn: DbConn,
+    token_data: &State<SpotifyTokenData>,
+    spotify_id: String,
+    count: Option<usize>,
+) -> Result<Json<Vec<RecommendedArtist>>, String> {
+    track_endpoint_errors(
+        "get_recommendations",
+        get_recommendations_inner(conn, token_data, spotify_id, count).await,
+    )
+}
+
+async fn get_recommendations_inner(
+    conn: DbConn,
+    token_data: &State<SpotifyTokenData>,
+    spotify_id: String,
+    count: Option<usize>,
+) -> Result<Json<Vec<RecommendedArtist>>, String> {
+    let count = count
+        .unwrap_or(DEFAULT_RECOMMENDATION_COUNT)
+        .min(MAX_RECOMMENDATION_COUNT);
+    let spotify_access_token = token_data.get().await?;
+
+    let related_artist_ids =
+        get_multiple_related_artists(spotify_access_token.clone(), &[&spotify_id])
+            .await?
+            .into_iter()
+            .next()
+            .unwrap_or_default();
+
+    // Artists that aren't in the embedding just don't get any embedding-based recommendations
+    let internal_ids_by_spotify_id =
+        get_internal_ids_by_spotify_id(&conn, std::iter::once(&spotify_id)).await?;
+    let nearest_artists = match internal_ids_by_spotify_id.get(&spotify_id) {
+        Some(&internal_id) => match get_nearest_artists(internal_id as usize, count) {
+            Ok(nearest) => nearest,
+            Err(ArtistEmbeddingError::ArtistIdNotFound(_)) => Vec::new(),
+        },
+        None => Vec::new(),
+    };
+    let nearest_artist_spotify_ids_by_internal_id = get_artist_spotify_ids_by_internal_id(
+        &conn,
+        nearest_artists.iter().map(|&(id, _)| id as i32).collect(),
+    )
+    .await
+    .map_err(db_util::stringify_diesel_err)?;
+
+    // (related artists rank, embedding similarity) by spotify ID
+    let mut sources_by_spotify_id: HashMap<String, (Option<usize>, Option<f32>)> =
+        HashMap::default();
+    for (rank, related_artist_id) in related_artist_ids.iter().enumerate() {
+        sources_by_spotify_id
+            .entry(related_artist_id.clone())
+            .or_default()
+            .0 = Some(rank);
+    }
+    for (internal_id, similarity) in &nearest_artists {
+        let nearest_spotify_id =
+            match nearest_artist_spotify_ids_by_internal_id.get(&(*internal_id as i32)) {
+                Some(id) => id,
+                None => continue,
+            };
+        sources_by_spotify_id
+            .entry(nearest_spotify_id.clone())
+            .or_default()
+            .1 = Some(*similarity);
+    }
+    sources_by_spotify_id.remove(&spotify_id);
+
+    // Spotify's related artists are ordered by relevance, so they're scored by position.  Cosine
+    // similarities in the embedding are already in [-1, 1].
+    let related_artist_count = related_artist_ids.len().max(1) as f32;
+    let mut scored = sources_by_spotify_id
+        .into_iter()
+        .map(|(id, (related_artists_rank, embedding_similarity))| {
+            let related_score = related_artists_rank
+                .map(|rank| 1. - rank as f32 / related_artist_count)
+                .unwrap_or(0.);
+            let mut score = related_score + embedding_similarity.unwrap_or(0.);
+            if related_artists_rank.is_some() && embedding_similarity.is_some() {
+                score += RECOMMENDED_BY_BOTH_BONUS;
+            }
+            (id, score, related_artists_rank, embedding_similarity)
+        })
+        .collect::<Vec<_>>();
+    scored.sort_unstable_by(|a, b| {
+        FloatOrd(b.1)
+            .cmp(&FloatOrd(a.1))
+            .then_with(|| a.0.cmp(&b.0))
+    });
+    scored.truncate(count);
+
+    let recommended_ids = scored
+        .iter()
+        .map(|(id, ..)| id.as_str())
+        .collect::<Vec<_>>();
+    let mut artists_by_id: HashMap<String, Artist> =
+        fetch_artists(&spotify_access_token, &recommended_ids)
+            .await?
+            .into_iter()
+            .map(|artist| (artist.id.clone(), artist))
+            .collect();
+
+    let recommendations = scored
+        .into_iter()
+        .filter_map(|(id, score, related_artists_rank, embedding_similarity)| {
+            let artist = artists_by_id.remove(&id)?;
+            Some(RecommendedArtist {
+                artist,
+                score,
+                related_artists_rank,
+                embedding_similarity,
+            })
+        })
+        .collect();
+    Ok(Json(recommendations))
+}
+
+const DEFAULT_NEIGHBORHOOD_EMBEDDING_COUNT: usize = 20;
+const MAX_NEIGHBORHOOD_EMBEDDING_COUNT: usize = 50;
+
+/// Merges the seed artist's Spotify related artists with its nearest neighbors in the embedding,
+/// tagging each with where it came from.  Related artists come first in Spotify's order, followed
+/// by the remaining nearest neighbors from most to least similar.  The seed artist is excluded.
+fn merge_artist_neighborhood_sources(
+    seed_spotify_id: &str,
+    related_artist_ids: &[String],
+    nearest_artist_ids: &[String],
+) -> Vec<(String, NeighborhoodArtistSource)> {
+    let mut merged: Vec<(String, NeighborhoodArtistSource)> = Vec::new();
+    let mut ix_by_id: HashMap<&str, usize> = HashMap::default();
+    for id in related_artist_ids {
+        if id == seed_spotify_id || ix_by_id.contains_key(id.as_str()) {
+            continue;
+        }
+        ix_by_id.insert(id.as_str(), merged.len());
+        merged.push((id.clone(), NeighborhoodArtistSource::Related));
+    }
+    for id in nearest_artist_ids {
+        if id == seed_spotify_id {
+            continue;
+        }
+        match ix_by_id.get(id.as_str()) {
+            Some(&ix) => match merged[ix].1 {
+                NeighborhoodArtistSource::Related => merged[ix].1 = NeighborhoodArtistSource::Both,
+                NeighborhoodArtistSource::Embedding | NeighborhoodArtistSource::Both => (),
+            },
+            None => {
+                ix_by_id.insert(id.as_str(), merged.len());
+                merged.push((id.clone(), NeighborhoodArtistSource::Embedding));
+            },
+        }
+    }
+    merged
+}
+
+/// Returns the union of the artist's Spotify related artists and its `count` nearest neighbors in
+/// the artist embedding along with their embedding positions so that the galaxy can render the
+/// local subgraph around it.
+#[get("/artist_neighborhood/<spotify_id>?<count>")]
+pub(crate) async fn get_artist_neighborhood(
+    conn: DbConn,
+    token_data: &State<SpotifyTokenData>,
+    spotify_id: String,
+    count: Option<usize>,
+) -> Result<Json<ArtistNeighborhood>, String> {
+    track_endpoint_errors(
+        "get_artist_neighborhood",
+        get_artist_neighborhood_inner(conn, token_data, spotify_id, count).await,
+    )
+}
+
+async fn get_artist_neighborhood_inner(
+    conn: DbConn,
+    token_data: &State<SpotifyTokenData>,
+    spotify_id: String,
+    count: Option<usize>,
+) -> Result<Json<ArtistNeighborhood>, String> {
+    let count = count
+        .unwrap_or(DEFAULT_NEIGHBORHOOD_EMBEDDING_COUNT)
+        .min(MAX_NEIGHBORHOOD_EMBEDDING_COUNT);
+    let spotify_access_token = token_data.get().await?;
+
+    let related_artist_ids =
+        get_multiple_related_artists(spotify_access_token.clone(), &[&spotify_id])
+            .await?
+            .into_iter()
+            .next()
+            .unwrap_or_default();
+
+    // Artists that aren't in the embedding just don't get any embedding neighbors
+    let internal_ids_by_spotify_id =
+        get_internal_ids_by_spotify_id(&conn, std::iter::once(&spotify_id)).await?;
+    let nearest_artists = match internal_ids_by_spotify_id.get(&spotify_id) {
+        Some(&internal_id) => match get_nearest_artists(internal_id as usize, count) {
+            Ok(nearest) => nearest,
+            Err(ArtistEmbeddingError::ArtistIdNotFound(_)) => Vec::new(),
+        },
+        None => Vec::new(),
+    };
+    let nearest_artist_spotify_ids_by_internal_id = get_artist_spotify_ids_by_internal_id(
+        &conn,
+        nearest_artists.iter().map(|&(id, _)| id as i32).collect(),
+    )
+    .await
+    .map_err(db_util::stringify_diesel_err)?;
+    let nearest_artist_ids = nearest_artists
         .iter()
+        .filter_map(|&(internal_id, _)| {
+            nearest_artist_spotify_ids_by_internal_id
+                .get(&(internal_id as i32))
+                .cloned()
+        })
+        .collect::<Vec<_>>();
+
+    let merged =
+        merge_artist_neighborhood_sources(&spotify_id, &related_artist_ids, &nearest_artist_ids);
+    let merged_ids = merged.iter().map(|(id, _)| id).collect::<Vec<_>>();
+    let merged_internal_ids_by_spotify_id =
+        get_internal_ids_by_spotify_id(&conn, merged_ids.iter().copied()).await?;
+    let merged_ids = merged_ids
+        .into_iter()
         .map(String::as_str)
         .collect::<Vec<_>>();
+    let mut artists_by_id: HashMap<String, Artist> =
+        fetch_artists(&spotify_access_token, &merged_ids)
+            .await?
+            .into_iter()
+            .map(|artist| (artist.id.clone(), artist))
+            .collect();
 
-    let out = build_related_artists_graph(spotify_access_token, &related_artist_ids).await?;
-    Ok(Some(Json(out)))
+    let ctx = get_artist_embedding_ctx();
+    let artists = merged
+        .into_iter()
+        .filter_map(|(id, source)| {
+            let artist = artists_by_id.remove(&id)?;
+            let position = merged_internal_ids_by_spotify_id
+                .get(&id)
+                .and_then(|&internal_id| ctx.artist_position_by_id.get(&(internal_id as usize)))
+                .map(|pos| pos.pos.to_vec());
+            Some(NeighborhoodArtist {
+                id,
+                name: artist.name,
+                position,
+                popularity: artist.popularity,
+                source,
+            })
+        })
+        .collect();
+    Ok(Json(ArtistNeighborhood { artists }))
 }
 
 #[get("/display_name/<username>")]
@@ -1083,42 +2608,97 @@ pub(crate) async fn get_display_name(
     conn: DbConn,
     username: String,
 ) -> Result<Option<String>, String> {
+    track_endpoint_errors(
+        "get_display_name",
+        get_display_name_inner(conn, username).await,
+    )
+}
+
+async fn get_display_name_inner(conn: DbConn, username: String) -> Result<Option<String>, String> {
     match db_util::get_user_by_spotify_id(&conn, username).await? {
         Some(user) => {
-            let user_clone = user.clone();
-            tokio::task::spawn(async move {
-                if let Err(err) = db_util::update_user_last_viewed(&user_clone, &conn).await {
-                    error!(
-                        "Error updating user last viewed time for {}: {:?}",
-                        user_clone.username, err
-                    );
-                }
-            });
-
+            last_viewed::record_user_view(user.id);
             Ok(Some(user.username))
         },
         None => Ok(None),
     }
 }
 
+/// Returns whether the user is tracked.  Unlike `/display_name`, this doesn't count as a view of
+/// the user.
+#[get("/user_exists/<username>")]
+pub(crate) async fn user_exists(conn: DbConn, username: String) -> Result<Json<bool>, String> {
+    track_endpoint_errors("user_exists", user_exists_inner(conn, username).await)
+}
+
+async fn user_exists_inner(conn: DbConn, username: String) -> Result<Json<bool>, String> {
+    db_util::user_exists(&conn, username)
+        .await
+        .map(Json)
+        .map_err(db_util::stringify_diesel_err)
+}
+
+const MAX_DISPLAY_NAMES_BATCH_SIZE: usize = 100;
+
+/// Returns a map of spotify ID to display name for the provided users.  Unknown users are omitted.
+///
+/// Unlike `/display_name`, this doesn't count as a view of the users.
+#[post("/display_names", data = "<usernames>")]
+pub(crate) async fn get_display_names(
+    conn: DbConn,
+    usernames: Json<Vec<String>>,
+) -> Result<Json<HashMap<String, String>>, String> {
+    track_endpoint_errors(
+        "get_display_names",
+        get_display_names_inner(conn, usernames.0).await,
+    )
+}
+
+async fn get_display_names_inner(
+    conn: DbConn,
+    usernames: Vec<String>,
+) -> Result<Json<HashMap<String, String>>, String> {
+    if usernames.len() > MAX_DISPLAY_NAMES_BATCH_SIZE {
+        return Err(format!(
+            "Can't fetch more than {} display names at once",
+            MAX_DISPLAY_NAMES_BATCH_SIZE
+        ));
+    }
+    if usernames.is_empty() {
+        return Ok(Json(HashMap::default()));
+    }
+
+    db_util::get_display_names_by_spotify_id(&conn, usernames)
+        .await
+        .map(Json)
+        .map_err(|err| {
+            error!("Error fetching display names: {:?}", err);
+            String::from("Internal DB error")
+        })
+}
+
 #[post("/dump_redis_related_artists_to_database", data = "<api_token_data>")]
 pub(crate) async fn dump_redis_related_artists_to_database(
     conn: DbConn,
     api_token_data: rocket::Data<'_>,
 ) -> Result<status::Custom<String>, String> {
-    if !validate_api_token(api_token_data).await? {
+    if !validate_api_token(api_token_data, "dump_redis_related_artists_to_database").await? {
         return Ok(status::Custom(
             Status::Unauthorized,
             "Invalid API token supplied".into(),
         ));
     }
+    if let Some(res) = draining_response() {
+        return Ok(res);
+    }
 
     let mut redis_conn = get_redis_conn()?;
-    let all_values: Vec<String> = block_in_place(|| redis_conn.hgetall("related_artists"))
-        .map_err(|err| {
-            error!("Error with HGETALL on related artists data: {:?}", err);
-            String::from("Redis error")
-        })?;
+    let all_values: Vec<String> =
+        block_in_place(|| timed_redis_command("hgetall", || redis_conn.hgetall("related_artists")))
+            .map_err(|err| {
+                error!("Error with HGETALL on related artists data: {:?}", err);
+                String::from("Redis error")
+            })?;
 
     let mut all_mapped_spotify_ids: HashMap<String, i32> = HashMap::default();
 
@@ -1170,26 +2750,30 @@ pub(crate) async fn dump_redis_related_artists_to_database(
 #[post("/crawl_related_artists", data = "<api_token_data>")]
 pub(crate) async fn crawl_related_artists(
     api_token_data: rocket::Data<'_>,
-    token_data: &State<Mutex<SpotifyTokenData>>,
+    token_data: &State<SpotifyTokenData>,
 ) -> Result<status::Custom<String>, String> {
-    if !validate_api_token(api_token_data).await? {
+    if !validate_api_token(api_token_data, "crawl_related_artists").await? {
         return Ok(status::Custom(
             Status::Unauthorized,
             "Invalid API token supplied".into(),
         ));
     }
+    if let Some(res) = draining_response() {
+        return Ok(res);
+    }
 
-    let spotify_access_token = {
-        let token_data = &mut *(&*token_data).lock().await;
-        token_data.get().await
-    }?;
+    let _in_flight_guard = shutdown::register_in_flight_task("crawl_related_artists");
+
+    let spotify_access_token = token_data.get().await?;
 
     let mut redis_conn = get_redis_conn()?;
     let artist_ids: Vec<String> = block_in_place(|| {
-        redis::cmd("HRANDFIELD")
-            .arg("related_artists")
-            .arg("8")
-            .query::<Vec<String>>(&mut *redis_conn)
+        timed_redis_command("hrandfield", || {
+            redis::cmd("HRANDFIELD")
+                .arg("related_artists")
+                .arg("8")
+                .query::<Vec<String>>(&mut *redis_conn)
+        })
     })
     .map_err(|err| {
         error!(
@@ -1202,12 +2786,12 @@ pub(crate) async fn crawl_related_artists(
     let mut all_related_artists: Vec<String> = Vec::new();
 
     let related_artists_jsons: Vec<String> = block_in_place(|| {
-        redis_conn
-            .hget("related_artists", artist_ids)
-            .map_err(|err| {
+        timed_redis_command("hget", || redis_conn.hget("related_artists", artist_ids)).map_err(
+            |err| {
                 error!("Error getting related artist from Redis: {:?}", err);
                 String::from("Redis error")
-            })
+            },
+        )
     })?;
 
     for related_artists_json in related_artists_jsons {
@@ -1244,24 +2828,33 @@ pub(crate) async fn crawl_related_artists(
 #[get("/search_artist?<q>")]
 pub(crate) async fn search_artist(
     conn: DbConn,
-    token_data: &State<Mutex<SpotifyTokenData>>,
+    token_data: &State<SpotifyTokenData>,
+    q: String,
+) -> Result<Json<Vec<ArtistSearchResult>>, String> {
+    track_endpoint_errors(
+        "search_artist",
+        search_artist_inner(conn, token_data, q).await,
+    )
+}
+
+async fn search_artist_inner(
+    conn: DbConn,
+    token_data: &State<SpotifyTokenData>,
     q: String,
 ) -> Result<Json<Vec<ArtistSearchResult>>, String> {
-    let spotify_access_token = {
-        let token_data = &mut *(&*token_data).lock().await;
-        token_data.get().await
-    }?;
+    let spotify_access_token = token_data.get().await?;
 
     // First check cache
-    let cached_item =
-        block_in_place(|| get_hash_items::<Vec<ArtistSearchResult>>("artistSearch", &[&q]))
-            .map_err(|err| {
-                error!("Error checking cache for artist search results: {}", err);
-                String::from("Internal error with cache")
-            })?
-            .into_iter()
-            .next()
-            .flatten();
+    let cached_item = block_in_place(|| {
+        get_hash_items::<Vec<ArtistSearchResult>>(&CONF.artist_search_cache_hash_name, &[&q])
+    })
+    .map_err(|err| {
+        error!("Error checking cache for artist search results: {}", err);
+        String::from("Internal error with cache")
+    })?
+    .into_iter()
+    .next()
+    .flatten();
 
     if let Some(cached_item) = cached_item {
         info!("Found hit in cache for artist search query={}", q);
@@ -1270,11 +2863,14 @@ pub(crate) async fn search_artist(
 
     // Hit the Spotify API and store in the cache
     let search_results = search_artists(&conn, spotify_access_token, &q).await?;
-    set_hash_items::<Vec<ArtistSearchResult>>("artistSearch", &[(&q, search_results.clone())])
-        .map_err(|err| {
-            error!("Error storing artist search in cache: {}", err);
-            String::from("Internal error with cache")
-        })?;
+    set_hash_items::<Vec<ArtistSearchResult>>(&CONF.artist_search_cache_hash_name, &[(
+        &q,
+        search_results.clone(),
+    )])
+    .map_err(|err| {
+        error!("Error storing artist search in cache: {}", err);
+        String::from("Internal error with cache")
+    })?;
     info!(
         "Successfully hit Spotify API for artist search query={:?} and stored in cache",
         q
@@ -1283,30 +2879,29 @@ pub(crate) async fn search_artist(
     Ok(Json(search_results))
 }
 
-#[get(
-    "/average_artists/<artist_1_spotify_id>/<artist_2_spotify_id>?<count>&<artist_1_bias>&\
-     <artist_2_bias>"
-)]
-pub(crate) async fn get_average_artists_route(
-    conn: DbConn,
-    artist_1_spotify_id: String,
-    artist_2_spotify_id: String,
+/// Looks up the internal IDs of the two provided artists and finds the `count` artists closest to
+/// the weighted midpoint between them in the artist embedding.  Returns the internal IDs of the two
+/// artists along with the averaged artists.
+async fn lookup_average_artists(
+    conn: &DbConn,
+    artist_1_spotify_id: &str,
+    artist_2_spotify_id: &str,
     count: Option<usize>,
     artist_1_bias: Option<f32>,
     artist_2_bias: Option<f32>,
-    token_data: &State<Mutex<SpotifyTokenData>>,
-) -> Result<Json<AverageArtistsResponse>, String> {
+) -> Result<(i32, i32, Vec<AverageArtistDescriptor>), String> {
     // Look up internal IDs for provided spotify IDs
-    let internal_ids_by_spotify_id = get_internal_ids_by_spotify_id(
-        &conn,
-        [artist_1_spotify_id.clone(), artist_2_spotify_id.clone()].iter(),
-    )
-    .await?;
-    let artist_1_id = match internal_ids_by_spotify_id.get(&artist_1_spotify_id) {
+    let spotify_ids = [
+        artist_1_spotify_id.to_owned(),
+        artist_2_spotify_id.to_owned(),
+    ];
+    let internal_ids_by_spotify_id =
+        get_internal_ids_by_spotify_id(conn, spotify_ids.iter()).await?;
+    let artist_1_id = match internal_ids_by_spotify_id.get(artist_1_spotify_id) {
         Some(id) => *id,
         None => return Err(format!("No artist found with id={}", artist_1_spotify_id)),
     };
-    let artist_2_id = match internal_ids_by_spotify_id.get(&artist_2_spotify_id) {
+    let artist_2_id = match internal_ids_by_spotify_id.get(artist_2_spotify_id) {
         Some(id) => *id,
         None => return Err(format!("No artist found with id={}", artist_2_spotify_id)),
     };
@@ -1314,22 +2909,72 @@ pub(crate) async fn get_average_artists_route(
     assert!(artist_1_id > 0);
     assert!(artist_2_id > 0);
 
-    let mut average_artists = match get_average_artists(
+    match get_average_artists(
         artist_1_id as usize,
         artist_1_bias.unwrap_or(1.),
         artist_2_id as usize,
         artist_2_bias.unwrap_or(1.),
         count,
     ) {
-        Ok(res) => res,
+        Ok(res) => Ok((artist_1_id, artist_2_id, res)),
         Err(err) => match err {
-            ArtistEmbeddingError::ArtistIdNotFound(id) =>
-                return Err(format!(
-                    "No artist found in embedding with internal id={}",
-                    id
-                )),
+            ArtistEmbeddingError::ArtistIdNotFound(id) => Err(format!(
+                "No artist found in embedding with internal id={}",
+                id
+            )),
         },
-    };
+    }
+}
+
+#[get(
+    "/average_artists/<artist_1_spotify_id>/<artist_2_spotify_id>?<count>&<artist_1_bias>&\
+     <artist_2_bias>&<market>"
+)]
+pub(crate) async fn get_average_artists_route(
+    conn: DbConn,
+    artist_1_spotify_id: String,
+    artist_2_spotify_id: String,
+    count: Option<usize>,
+    artist_1_bias: Option<f32>,
+    artist_2_bias: Option<f32>,
+    market: Option<String>,
+    token_data: &State<SpotifyTokenData>,
+) -> Result<Json<AverageArtistsResponse>, String> {
+    track_endpoint_errors(
+        "get_average_artists_route",
+        get_average_artists_route_inner(
+            conn,
+            artist_1_spotify_id,
+            artist_2_spotify_id,
+            count,
+            artist_1_bias,
+            artist_2_bias,
+            market,
+            token_data,
+        )
+        .await,
+    )
+}
+
+async fn get_average_artists_route_inner(
+    conn: DbConn,
+    artist_1_spotify_id: String,
+    artist_2_spotify_id: String,
+    count: Option<usize>,
+    artist_1_bias: Option<f32>,
+    artist_2_bias: Option<f32>,
+    market: Option<String>,
+    token_data: &State<SpotifyTokenData>,
+) -> Result<Json<AverageArtistsResponse>, String> {
+    let (artist_1_id, artist_2_id, mut average_artists) = lookup_average_artists(
+        &conn,
+        &artist_1_spotify_id,
+        &artist_2_spotify_id,
+        count,
+        artist_1_bias,
+        artist_2_bias,
+    )
+    .await?;
 
     let all_artist_internal_ids: Vec<i32> = average_artists.iter().map(|d| d.id as i32).collect();
     let artist_spotify_ids_by_internal_id: HashMap<i32, String> =
@@ -1349,17 +2994,18 @@ pub(crate) async fn get_average_artists_route(
         .map(String::as_str)
         .collect();
 
-    let spotify_access_token = {
-        let token_data = &mut *(&*token_data).lock().await;
-        token_data.get().await
-    }?;
+    let spotify_access_token = token_data.get().await?;
 
     let top_tracks_for_artists = FuturesUnordered::new();
     for artist_spotify_id in &all_spotify_ids {
         let artist_spotify_id_clone = String::from(*artist_spotify_id);
         top_tracks_for_artists.push(
-            fetch_top_tracks_for_artist(&spotify_access_token, artist_spotify_id)
-                .map_ok(move |res| (artist_spotify_id_clone, res)),
+            fetch_top_tracks_for_artist(
+                &spotify_access_token,
+                artist_spotify_id,
+                market.as_deref(),
+            )
+            .map_ok(move |res| (artist_spotify_id_clone, res)),
         );
     }
 
@@ -1370,6 +3016,21 @@ pub(crate) async fn get_average_artists_route(
     let mut top_tracks_by_artist_spotify_id: HashMap<String, Vec<Track>> =
         top_tracks.into_iter().collect();
 
+    // Spotify sometimes gives a different ID back than the one we requested, both of which refer
+    // to the same actual artist.  Those IDs are recorded as aliases when fetching the artists.
+    let aliases = block_in_place(|| crate::cache::get_spotify_id_aliases(&all_spotify_ids))?;
+    let canonical_ids_by_spotify_id: HashMap<&str, String> = all_spotify_ids
+        .iter()
+        .zip(aliases)
+        .filter_map(|(&spotify_id, alias)| alias.map(|alias| (spotify_id, alias)))
+        .collect();
+    let is_same_artist = |artist: &Artist, spotify_id: &str| {
+        artist.id == spotify_id
+            || canonical_ids_by_spotify_id
+                .get(spotify_id)
+                .map_or(false, |canonical_id| artist.id == *canonical_id)
+    };
+
     if fetched_artists.len() != average_artists.len() {
         assert!(fetched_artists.len() < average_artists.len());
         average_artists.retain(|d| {
@@ -1387,7 +3048,7 @@ pub(crate) async fn get_average_artists_route(
             };
             let was_fetched = fetched_artists
                 .iter()
-                .any(|a| a.id == *avg_artist_spotify_id);
+                .any(|a| is_same_artist(a, avg_artist_spotify_id));
             if !was_fetched {
                 error!(
                     "Failed to find artist metadata for artist with spotify_id={}",
@@ -1416,7 +3077,7 @@ pub(crate) async fn get_average_artists_route(
             };
             let artist = match fetched_artists
                 .iter()
-                .find(|artist| artist.id == *avg_artist_spotify_id)
+                .find(|artist| is_same_artist(artist, avg_artist_spotify_id))
                 .cloned()
             {
                 Some(artist) => artist,
@@ -1474,27 +3135,297 @@ pub(crate) async fn get_average_artists_route(
     }))
 }
 
-#[get("/artist_image_url/<artist_spotify_id>")]
+/// Packs averaged artists into a compact binary format for the averager map view, which only needs
+/// IDs and similarities:
+///
+/// 1 * u32: number of artists
+/// [number of artists] * u32: artist internal ids
+/// [number of artists] * 3 * f32: similarity to target point, artist 1, and artist 2
+/// UTF-8 string: comma-separated spotify IDs of the artists, in the same order
+fn pack_average_artists(average_artists: &[(AverageArtistDescriptor, &str)]) -> Vec<u8> {
+    let mut packed: Vec<u8> = Vec::with_capacity(4 + average_artists.len() * (4 + 3 * 4 + 23));
+    packed.extend_from_slice(&(average_artists.len() as u32).to_le_bytes());
+    for (d, _) in average_artists {
+        let id: u32 = d.id.try_into().expect("Artist id greater than u32::MAX");
+        packed.extend_from_slice(&id.to_le_bytes());
+    }
+    for (d, _) in average_artists {
+        for similarity in [
+            d.similarity_to_target_point,
+            d.similarity_to_artist_1,
+            d.similarity_to_artist_2,
+        ] {
+            packed.extend_from_slice(&similarity.to_le_bytes());
+        }
+    }
+
+    let spotify_ids: Vec<&str> = average_artists.iter().map(|(_, id)| *id).collect();
+    packed.extend_from_slice(spotify_ids.join(",").as_bytes());
+    packed
+}
+
+/// Same as `get_average_artists_route`, but returns the results in the packed format produced by
+/// `pack_average_artists`.  Artist metadata and top tracks aren't fetched, and the artists are
+/// ordered by similarity to the target point rather than by the full score.
+#[get(
+    "/average_artists_packed/<artist_1_spotify_id>/<artist_2_spotify_id>?<count>&<artist_1_bias>&\
+     <artist_2_bias>"
+)]
+pub(crate) async fn get_packed_average_artists_route(
+    conn: DbConn,
+    artist_1_spotify_id: String,
+    artist_2_spotify_id: String,
+    count: Option<usize>,
+    artist_1_bias: Option<f32>,
+    artist_2_bias: Option<f32>,
+) -> Result<JSONMimeTypeSetterResponder, String> {
+    track_endpoint_errors(
+        "get_packed_average_artists_route",
+        get_packed_average_artists_route_inner(
+            conn,
+            artist_1_spotify_id,
+            artist_2_spotify_id,
+            count,
+            artist_1_bias,
+            artist_2_bias,
+        )
+        .await,
+    )
+}
+
+async fn get_packed_average_artists_route_inner(
+    conn: DbConn,
+    artist_1_spotify_id: String,
+    artist_2_spotify_id: String,
+    count: Option<usize>,
+    artist_1_bias: Option<f32>,
+    artist_2_bias: Option<f32>,
+) -> Result<JSONMimeTypeSetterResponder, String> {
+    let (_, _, average_artists) = lookup_average_artists(
+        &conn,
+        &artist_1_spotify_id,
+        &artist_2_spotify_id,
+        count,
+        artist_1_bias,
+        artist_2_bias,
+    )
+    .await?;
+
+    let all_artist_internal_ids: Vec<i32> = average_artists.iter().map(|d| d.id as i32).collect();
+    let artist_spotify_ids_by_internal_id: HashMap<i32, String> =
+        get_artist_spotify_ids_by_internal_id(&conn, all_artist_internal_ids)
+            .await
+            .map_err(|err| {
+                error!(
+                    "Error converting artist internal ids to spotify ids after performing \
+                     averaging: {:?}",
+                    err
+                );
+                String::from("Internal database error")
+            })?;
+
+    let average_artists: Vec<(AverageArtistDescriptor, &str)> = average_artists
+        .into_iter()
+        .filter_map(
+            |d| match artist_spotify_ids_by_internal_id.get(&(d.id as i32)) {
+                Some(spotify_id) => Some((d, spotify_id.as_str())),
+                None => {
+                    error!(
+                        "No spotify id found for artist with internal_id={} returned from \
+                         averaging",
+                        d.id
+                    );
+                    None
+                },
+            },
+        )
+        .collect();
+
+    Ok(JSONMimeTypeSetterResponder {
+        inner: pack_average_artists(&average_artists),
+    })
+}
+
+/// Returns the raw and normalized embedding vectors for a single artist, or 404 if the artist isn't
+/// in the embedding.
+#[get("/artist_embedding/<artist_spotify_id>")]
+pub(crate) async fn get_artist_embedding(
+    conn: DbConn,
+    artist_spotify_id: String,
+) -> Result<Option<Json<ArtistEmbeddingResponse>>, String> {
+    let internal_ids_by_spotify_id =
+        get_internal_ids_by_spotify_id(&conn, [artist_spotify_id.clone()].iter()).await?;
+    let internal_id = match internal_ids_by_spotify_id.get(&artist_spotify_id) {
+        Some(id) => *id,
+        None => return Ok(None),
+    };
+
+    let artist_pos = match get_artist_embedding_ctx()
+        .artist_position_by_id
+        .get(&(internal_id as usize))
+    {
+        Some(pos) => pos,
+        None => return Ok(None),
+    };
+
+    Ok(Some(Json(ArtistEmbeddingResponse {
+        spotify_id: artist_spotify_id,
+        internal_id,
+        position: artist_pos.pos.to_vec(),
+        normalized_position: artist_pos.normalized_pos.to_vec(),
+    })))
+}
+
+/// `size` is one of `small`, `medium`, or `large` and defaults to `large`.  The stored image with
+/// the closest width is returned.
+#[get("/artist_image_url/<artist_spotify_id>?<size>")]
 pub(crate) async fn get_artist_image_url(
     artist_spotify_id: String,
-    token_data: &State<Mutex<SpotifyTokenData>>,
+    size: Option<String>,
+    token_data: &State<SpotifyTokenData>,
 ) -> Result<String, String> {
-    let spotify_access_token = {
-        let token_data = &mut *(&*token_data).lock().await;
-        token_data.get().await
-    }?;
+    let size: ImageSize = match size {
+        Some(size) => size.parse()?,
+        None => ImageSize::Large,
+    };
+    let spotify_access_token = token_data.get().await?;
+
+    let artist: Option<Artist> =
+        fetch_artists_with_all_images(&spotify_access_token, &[&artist_spotify_id])
+            .await?
+            .into_iter()
+            .next();
+    let image =
+        match artist.and_then(|artist| artist.images.and_then(|images| size.pick_image(images))) {
+            Some(image) => image,
+            None => return Err(String::from("Not found")),
+        };
+    Ok(image.url)
+}
+
+/// Deletes the metadata cache hashes left over from previous `METADATA_CACHE_SCHEMA_VERSION`s in
+/// the background
+#[post("/purge_stale_cache_versions", data = "<api_token_data>")]
+pub(crate) async fn purge_stale_cache_versions(
+    api_token_data: rocket::Data<'_>,
+) -> Result<status::Custom<String>, String> {
+    if !validate_api_token(api_token_data, "purge_stale_cache_versions").await? {
+        return Ok(status::Custom(
+            Status::Unauthorized,
+            "Invalid API token supplied".into(),
+        ));
+    }
+    if let Some(res) = draining_response() {
+        return Ok(res);
+    }
+
+    spawn_blocking(|| {
+        let _in_flight_guard = shutdown::register_in_flight_task("purge_stale_cache_versions");
+        match purge_stale_versioned_hashes() {
+            Ok(purged_hash_names) => info!(
+                "Purged {} stale cache hashes: {:?}",
+                purged_hash_names.len(),
+                purged_hash_names
+            ),
+            Err(err) => error!("Error purging stale cache hashes: {}", err),
+        }
+    });
+
+    Ok(status::Custom(
+        Status::Accepted,
+        "Started purging stale cache hashes".into(),
+    ))
+}
+
+fn unknown_cache_hash_response(hash_name: &str) -> status::Custom<String> {
+    status::Custom(
+        Status::NotFound,
+        format!("\"{}\" is not a known cache hash", hash_name),
+    )
+}
+
+/// Returns the raw cached JSON for a single entry in one of the cache hashes along with its size
+///
+/// The admin token is read from the `X-Admin-Token` header.
+#[get("/admin/cache/<hash_name>/<key>")]
+pub(crate) async fn get_cache_entry(
+    api_token: AdminTokenHeader,
+    hash_name: String,
+    key: String,
+) -> Result<status::Custom<String>, String> {
+    if !check_admin_token(&api_token.0, "get_cache_entry") {
+        return Ok(status::Custom(
+            Status::Unauthorized,
+            "Invalid API token supplied".into(),
+        ));
+    }
+    if !is_known_cache_hash_name(&hash_name) {
+        return Ok(unknown_cache_hash_response(&hash_name));
+    }
+
+    let entry = spawn_blocking(move || get_raw_hash_item(&hash_name, &key))
+        .await
+        .unwrap()?;
+    let entry = match entry {
+        Some(entry) => entry,
+        None => return Ok(status::Custom(Status::NotFound, "Entry not found".into())),
+    };
+    let body = serde_json::to_string(&entry).map_err(|err| {
+        error!("Error serializing cache entry: {:?}", err);
+        String::from("Internal error")
+    })?;
+    Ok(status::Custom(Status::Ok, body))
+}
+
+/// Deletes a single entry from one of the cache hashes so that it's re-fetched from Spotify the
+/// next time it's needed
+#[delete("/admin/cache/<hash_name>/<key>", data = "<api_token_data>")]
+pub(crate) async fn delete_cache_entry(
+    api_token_data: rocket::Data<'_>,
+    hash_name: String,
+    key: String,
+) -> Result<status::Custom<String>, String> {
+    if !validate_api_token(api_token_data, "delete_cache_entry").await? {
+        return Ok(status::Custom(
+            Status::Unauthorized,
+            "Invalid API token supplied".into(),
+        ));
+    }
+    if !is_known_cache_hash_name(&hash_name) {
+        return Ok(unknown_cache_hash_response(&hash_name));
+    }
+
+    info!("Deleting cache entry {} from {}", key, hash_name);
+    let deleted = spawn_blocking(move || delete_hash_item(&hash_name, &key))
+        .await
+        .unwrap()?;
+    if deleted {
+        Ok(status::Custom(Status::Ok, "Entry deleted".into()))
+    } else {
+        Ok(status::Custom(Status::NotFound, "Entry not found".into()))
+    }
+}
 
-    let artist: Option<Artist> = fetch_artists(&spotify_access_token, &[&artist_spotify_id])
-        .await?
-        .into_iter()
-        .next();
-    let image = match artist
-        .and_then(|artist| artist.images.and_then(|images| images.into_iter().next()))
-    {
-        Some(image) => image,
-        None => return Err(String::from("Not found")),
-    };
-    Ok(image.url)
+/// Returns the number of entries in each of the cache hashes along with Redis memory usage
+///
+/// The admin token is read from the `X-Admin-Token` header.
+#[get("/admin/cache_stats")]
+pub(crate) async fn get_cache_stats_route(
+    api_token: AdminTokenHeader,
+) -> Result<status::Custom<String>, String> {
+    if !check_admin_token(&api_token.0, "get_cache_stats") {
+        return Ok(status::Custom(
+            Status::Unauthorized,
+            "Invalid API token supplied".into(),
+        ));
+    }
+
+    let stats = spawn_blocking(get_cache_stats).await.unwrap()?;
+    let body = serde_json::to_string(&stats).map_err(|err| {
+        error!("Error serializing cache stats: {:?}", err);
+        String::from("Internal error")
+    })?;
+    Ok(status::Custom(Status::Ok, body))
 }
 
 #[post(
@@ -1503,36 +3434,38 @@ pub(crate) async fn get_artist_image_url(
 )]
 pub(crate) async fn refetch_cached_artists_missing_popularity(
     api_token_data: rocket::Data<'_>,
-    token_data: &State<Mutex<SpotifyTokenData>>,
+    token_data: &State<SpotifyTokenData>,
     count: Option<usize>,
 ) -> Result<status::Custom<String>, String> {
-    if !validate_api_token(api_token_data).await? {
+    if !validate_api_token(api_token_data, "refetch_cached_artists_missing_popularity").await? {
         return Ok(status::Custom(
             Status::Unauthorized,
             "Invalid API token supplied".into(),
         ));
     }
+    if let Some(res) = draining_response() {
+        return Ok(res);
+    }
 
-    let spotify_access_token = {
-        let token_data = &mut *(&*token_data).lock().await;
-        token_data.get().await
-    }?;
+    let spotify_access_token = token_data.get().await?;
 
     let mut redis_conn = spawn_blocking(|| get_redis_conn()).await.unwrap()?;
 
     let (mut redis_conn, artist_spotify_ids) =
         spawn_blocking(move || -> Result<(_, Vec<String>), String> {
-            let artist_spotify_ids = redis::cmd("HRANDFIELD")
-                .arg(&CONF.artists_cache_hash_name)
-                .arg(count.unwrap_or(20).to_string())
-                .query::<Vec<String>>(&mut *redis_conn)
-                .map_err(|err| {
-                    error!(
-                        "Error getting random artist keys from Redis cache: {:?}",
-                        err
-                    );
-                    String::from("Redis error")
-                })?;
+            let artist_spotify_ids = timed_redis_command("hrandfield", || {
+                redis::cmd("HRANDFIELD")
+                    .arg(&CONF.artists_cache_hash_name)
+                    .arg(count.unwrap_or(20).to_string())
+                    .query::<Vec<String>>(&mut *redis_conn)
+            })
+            .map_err(|err| {
+                error!(
+                    "Error getting random artist keys from Redis cache: {:?}",
+                    err
+                );
+                String::from("Redis error")
+            })?;
             Ok((redis_conn, artist_spotify_ids))
         })
         .await
@@ -1559,7 +3492,7 @@ pub(crate) async fn refetch_cached_artists_missing_popularity(
         for artist_id in artist_ids_needing_refetch {
             cmd.arg(artist_id);
         }
-        cmd.query::<usize>(&mut *redis_conn)
+        timed_redis_command("hdel", || cmd.query::<usize>(&mut *redis_conn))
     })
     .await
     .unwrap()
@@ -1595,12 +3528,9 @@ pub(crate) struct JSONMimeTypeSetterResponder {
 #[get("/packed_3d_artist_coords")]
 pub(crate) async fn get_packed_3d_artist_coords_route(
     conn: DbConn,
-    token_data: &State<Mutex<SpotifyTokenData>>,
+    token_data: &State<SpotifyTokenData>,
 ) -> Result<JSONMimeTypeSetterResponder, String> {
-    let spotify_access_token = {
-        let token_data = &mut *(&*token_data).lock().await;
-        token_data.get().await
-    }?;
+    let spotify_access_token = token_data.get().await?;
 
     let packed = get_packed_3d_artist_coords(&conn, &spotify_access_token).await?;
     Ok(JSONMimeTypeSetterResponder {
@@ -1608,20 +3538,17 @@ pub(crate) async fn get_packed_3d_artist_coords_route(
     })
 }
 
-#[post("/map_artist_data_by_internal_ids", data = "<artist_internal_ids>")]
-pub(crate) async fn get_artists_by_internal_ids(
-    conn: DbConn,
-    token_data: &State<Mutex<SpotifyTokenData>>,
-    artist_internal_ids: Json<Vec<i32>>,
-) -> Result<Json<Vec<Option<String>>>, String> {
-    let spotify_access_token = {
-        let token_data = &mut *(&*token_data).lock().await;
-        token_data.get().await
-    }?;
+/// Fetches the full artist objects for the provided internal IDs, preserving input order.  IDs
+/// that aren't mapped to a spotify ID are `None`.
+async fn fetch_artists_by_internal_ids(
+    conn: &DbConn,
+    token_data: &State<SpotifyTokenData>,
+    artist_internal_ids: Vec<i32>,
+) -> Result<Vec<Option<Artist>>, String> {
+    let spotify_access_token = token_data.get().await?;
 
-    let artist_internal_ids: Vec<i32> = artist_internal_ids.0;
     let artist_spotify_ids_by_internal_id =
-        get_artist_spotify_ids_by_internal_id(&conn, artist_internal_ids.clone())
+        get_artist_spotify_ids_by_internal_id(conn, artist_internal_ids.clone())
             .await
             .map_err(|err| {
                 error!(
@@ -1641,25 +3568,70 @@ pub(crate) async fn get_artists_by_internal_ids(
 
     let artists = fetch_artists(&spotify_access_token, &artist_spotify_ids).await?;
 
+    Ok(artist_internal_ids
+        .into_iter()
+        .map(|internal_id| {
+            let spotify_id = artist_spotify_ids_by_internal_id.get(&internal_id)?;
+            artists
+                .iter()
+                .find(|artist| artist.id == *spotify_id)
+                .cloned()
+        })
+        .collect())
+}
+
+#[post("/map_artist_data_by_internal_ids", data = "<artist_internal_ids>")]
+pub(crate) async fn get_artists_by_internal_ids(
+    conn: DbConn,
+    token_data: &State<SpotifyTokenData>,
+    artist_internal_ids: Json<Vec<i32>>,
+) -> Result<Json<Vec<Option<String>>>, String> {
+    let artists = fetch_artists_by_internal_ids(&conn, token_data, artist_internal_ids.0).await?;
+
     Ok(Json(
-        artist_internal_ids
+        artists
             .into_iter()
-            .map(|internal_id| {
-                let spotify_id = artist_spotify_ids_by_internal_id.get(&internal_id)?;
-                artists
-                    .iter()
-                    .find(|artist| artist.id == *spotify_id)
-                    .map(|artist| artist.name.clone())
-            })
+            .map(|artist| artist.map(|artist| artist.name))
             .collect(),
     ))
 }
 
-fn pack_artist_relationships(artist_relationships: Vec<Vec<i32>>) -> Vec<u8> {
-    // Encoding:
+/// Like `/map_artist_data_by_internal_ids`, but returns the full artist objects including images
+/// and popularity.
+#[post("/artist_metadata_by_internal_ids", data = "<artist_internal_ids>")]
+pub(crate) async fn get_artist_metadata_by_internal_ids(
+    conn: DbConn,
+    token_data: &State<SpotifyTokenData>,
+    artist_internal_ids: Json<Vec<i32>>,
+) -> Result<Json<Vec<Option<Artist>>>, String> {
+    track_endpoint_errors(
+        "get_artist_metadata_by_internal_ids",
+        fetch_artists_by_internal_ids(&conn, token_data, artist_internal_ids.0)
+            .await
+            .map(Json),
+    )
+}
+
+/// Weight of an artist's first related artist.  Spotify orders related artists by how closely
+/// they match, so each subsequent one is weighted `RELATIONSHIP_WEIGHT_STEP` less.
+const MAX_RELATIONSHIP_WEIGHT: u8 = u8::MAX;
+const RELATIONSHIP_WEIGHT_STEP: u8 = 12;
+
+fn relationship_weight(related_artist_ix: usize) -> u8 {
+    let step = related_artist_ix.min(u8::MAX as usize) as u8;
+    MAX_RELATIONSHIP_WEIGHT.saturating_sub(step.saturating_mul(RELATIONSHIP_WEIGHT_STEP))
+}
+
+/// Takes a list of (related artist internal ID, weight) pairs for each artist.
+fn pack_artist_relationships(artist_relationships: Vec<Vec<(i32, u8)>>) -> Vec<u8> {
+    // Encoding (v2):
     // artist count * u8: related artist count
     // 0-3 bytes of padding to make total byte count divisible by 4
-    // The rest: u32s, in order, for each artist.
+    // u32s, in order, for each artist.
+    // u8 weights for each of the preceding relationships, in the same order
+    // 0-3 bytes of padding to make total byte count divisible by 4
+    //
+    // v1 was the same but without the weights, so consumers can tell them apart by length.
     let mut packed: Vec<u8> = Vec::new();
     for related_artists in &artist_relationships {
         let artist_count = related_artists.len();
@@ -1674,25 +3646,98 @@ fn pack_artist_relationships(artist_relationships: Vec<Vec<i32>>) -> Vec<u8> {
     }
     assert_eq!(packed.len() % 4, 0);
 
+    let mut weights: Vec<u8> = Vec::new();
     for mut related_artists in artist_relationships {
         // Might help with compression ratio, who knows
         related_artists.sort_unstable();
-        for id in related_artists {
+        for (id, weight) in related_artists {
             let bytes: [u8; 4] = unsafe { std::mem::transmute(id as u32) };
             for byte in bytes {
                 packed.push(byte);
             }
+            weights.push(weight);
         }
     }
     assert_eq!(packed.len() % 4, 0);
+
+    packed.extend_from_slice(&weights);
+    let weights_padding_byte_count = (4 - (weights.len() % 4)) % 4;
+    for _ in 0..weights_padding_byte_count {
+        packed.push(0);
+    }
+    assert_eq!(packed.len() % 4, 0);
     packed
 }
 
+/// Packed relationships for a list of artists, along with the internal IDs of artists whose related
+/// artists couldn't be fetched.  Those artists are packed with no relationships so that the rest of
+/// the data is still usable.
+struct PackedArtistRelationships {
+    packed: Vec<u8>,
+    incomplete_artist_internal_ids: Vec<i32>,
+    built_at: std::time::Instant,
+}
+
+/// Minimum time between re-builds of a cached chunk with incomplete artists, so that a failing
+/// related artists endpoint isn't hit again on every request for the chunk
+const INCOMPLETE_ARTIST_RELATIONSHIPS_RETRY_INTERVAL: std::time::Duration =
+    std::time::Duration::from_secs(60 * 5);
+
+impl PackedArtistRelationships {
+    /// Returns `true` if this chunk has incomplete artists and hasn't been re-built within
+    /// `INCOMPLETE_ARTIST_RELATIONSHIPS_RETRY_INTERVAL` of `now`
+    fn should_rebuild(&self, now: std::time::Instant) -> bool {
+        !self.incomplete_artist_internal_ids.is_empty()
+            && now.saturating_duration_since(self.built_at)
+                >= INCOMPLETE_ARTIST_RELATIONSHIPS_RETRY_INTERVAL
+    }
+}
+
+/// Converts the related artists of each of `artist_internal_ids` into `(internal_id, weight)`
+/// pairs.  `related_artists` is in the same order as `artist_internal_ids` and is `None` for
+/// artists whose related artists couldn't be fetched; their internal IDs are returned as well.
+fn build_artist_relationships(
+    artist_internal_ids: &[i32],
+    related_artists: Vec<Option<Vec<String>>>,
+    related_artists_internal_ids_by_spotify_id: &HashMap<String, i32>,
+) -> (Vec<Vec<(i32, u8)>>, Vec<i32>) {
+    assert_eq!(related_artists.len(), artist_internal_ids.len());
+
+    let mut incomplete_artist_internal_ids = Vec::new();
+    let artist_relationships = related_artists
+        .into_iter()
+        .zip(artist_internal_ids)
+        .map(|(related_artists, &artist_internal_id)| {
+            let related_artists = match related_artists {
+                Some(related_artists) => related_artists,
+                None => {
+                    incomplete_artist_internal_ids.push(artist_internal_id);
+                    return Vec::new();
+                },
+            };
+
+            // Weights are based on the position in Spotify's list, so they're computed before
+            // filtering out unmapped artists
+            related_artists
+                .iter()
+                .enumerate()
+                .filter_map(|(related_artist_ix, artist_spotify_id)| {
+                    related_artists_internal_ids_by_spotify_id
+                        .get(artist_spotify_id)
+                        .map(|&internal_id| (internal_id, relationship_weight(related_artist_ix)))
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    (artist_relationships, incomplete_artist_internal_ids)
+}
+
 async fn get_packed_artist_relationships_by_internal_ids_inner(
     conn: &DbConn,
     spotify_access_token: String,
     artist_internal_ids: Vec<i32>,
-) -> Result<Vec<u8>, String> {
+) -> Result<PackedArtistRelationships, String> {
     let tok = start();
     let artist_spotify_ids_by_internal_id =
         get_artist_spotify_ids_by_internal_id(&conn, artist_internal_ids.clone())
@@ -1716,36 +3761,48 @@ async fn get_packed_artist_relationships_by_internal_ids_inner(
         .collect::<Vec<_>>();
 
     let tok = start();
-    let related_artists =
-        get_multiple_related_artists(spotify_access_token, &artist_spotify_ids).await?;
+    let fetched_related_artists =
+        get_multiple_related_artists_partial(spotify_access_token, &artist_spotify_ids).await?;
     mark(tok, "Got related artists");
-    assert_eq!(related_artists.len(), artist_spotify_ids.len());
+    assert_eq!(fetched_related_artists.len(), artist_spotify_ids.len());
+
+    // Artists without a spotify ID have nothing to fetch, so they're given no relationships rather
+    // than being dropped which would misalign the rest of the packed data
+    let mut fetched_related_artists = fetched_related_artists.into_iter();
+    let related_artists = artist_internal_ids
+        .iter()
+        .map(|internal_id| {
+            if artist_spotify_ids_by_internal_id.contains_key(internal_id) {
+                fetched_related_artists.next().unwrap()
+            } else {
+                Some(Vec::new())
+            }
+        })
+        .collect::<Vec<_>>();
 
     let tok = start();
-    let related_artists_internal_ids_by_spotify_id = get_internal_ids_by_spotify_id(
-        &conn,
-        related_artists
-            .iter()
-            .flat_map(|related_artists| related_artists.iter()),
-    )
-    .await?;
+    let related_artists_internal_ids_by_spotify_id =
+        get_internal_ids_by_spotify_id(&conn, related_artists.iter().flatten().flatten()).await?;
     mark(tok, "Mapped back to internal IDs");
 
-    let res = related_artists
-        .into_iter()
-        .map(|related_artists| {
-            related_artists
-                .iter()
-                .filter_map(|artist_spotify_id| {
-                    related_artists_internal_ids_by_spotify_id
-                        .get(artist_spotify_id)
-                        .copied()
-                })
-                .collect::<Vec<_>>()
-        })
-        .collect::<Vec<_>>();
+    let (artist_relationships, incomplete_artist_internal_ids) = build_artist_relationships(
+        &artist_internal_ids,
+        related_artists,
+        &related_artists_internal_ids_by_spotify_id,
+    );
+    if !incomplete_artist_internal_ids.is_empty() {
+        warn!(
+            "Failed to fetch related artists for {}/{} artists; packing them with no relationships",
+            incomplete_artist_internal_ids.len(),
+            artist_internal_ids.len()
+        );
+    }
 
-    Ok(pack_artist_relationships(res))
+    Ok(PackedArtistRelationships {
+        packed: pack_artist_relationships(artist_relationships),
+        incomplete_artist_internal_ids,
+        built_at: std::time::Instant::now(),
+    })
 }
 
 #[post(
@@ -1754,49 +3811,54 @@ async fn get_packed_artist_relationships_by_internal_ids_inner(
 )]
 pub(crate) async fn get_packed_artist_relationships_by_internal_ids(
     conn: DbConn,
-    token_data: &State<Mutex<SpotifyTokenData>>,
+    token_data: &State<SpotifyTokenData>,
     artist_internal_ids: Json<Vec<i32>>,
 ) -> Result<JSONMimeTypeSetterResponder, String> {
-    let spotify_access_token = {
-        let token_data = &mut *(&*token_data).lock().await;
-        token_data.get().await
-    }?;
+    let spotify_access_token = token_data.get().await?;
 
     let artist_internal_ids: Vec<i32> = artist_internal_ids.0;
-    let packed = get_packed_artist_relationships_by_internal_ids_inner(
-        &conn,
-        spotify_access_token,
-        artist_internal_ids,
-    )
-    .await?;
+    let PackedArtistRelationships { packed, .. } =
+        get_packed_artist_relationships_by_internal_ids_inner(
+            &conn,
+            spotify_access_token,
+            artist_internal_ids,
+        )
+        .await?;
     Ok(JSONMimeTypeSetterResponder { inner: packed })
 }
 
 lazy_static::lazy_static! {
-    pub static ref ARTIST_RELATIONSHIPS_BY_INTERNAL_IDS_CACHE:
-        Arc<Mutex<HashMap<(u32, u32), Vec<u8>>>> =
+    /// Chunks that have incomplete artists are re-built the next time they're requested once
+    /// `INCOMPLETE_ARTIST_RELATIONSHIPS_RETRY_INTERVAL` has passed.  Related artists that were
+    /// fetched successfully are cached, so only the incomplete artists are fetched again.
+    static ref ARTIST_RELATIONSHIPS_BY_INTERNAL_IDS_CACHE:
+        Arc<Mutex<HashMap<(u32, u32), PackedArtistRelationships>>> =
             Arc::new(Mutex::new(HashMap::default()));
 }
 
 #[get("/map_artist_relationships_chunk?<chunk_size>&<chunk_ix>")]
 pub(crate) async fn get_artist_relationships_chunk(
     conn: DbConn,
-    token_data: &State<Mutex<SpotifyTokenData>>,
+    token_data: &State<SpotifyTokenData>,
     chunk_size: u32,
     chunk_ix: u32,
 ) -> Result<JSONMimeTypeSetterResponder, String> {
-    let spotify_access_token = {
-        let token_data = &mut *(&*token_data).lock().await;
-        token_data.get().await
-    }?;
+    let spotify_access_token = token_data.get().await?;
 
     let cache_key = (chunk_size, chunk_ix);
     {
         let cache = &mut *ARTIST_RELATIONSHIPS_BY_INTERNAL_IDS_CACHE.lock().await;
-        if let Some(cached_data) = cache.get(&cache_key) {
-            return Ok(JSONMimeTypeSetterResponder {
-                inner: cached_data.clone(),
-            });
+        match cache.get(&cache_key) {
+            Some(cached) if !cached.should_rebuild(std::time::Instant::now()) =>
+                return Ok(JSONMimeTypeSetterResponder {
+                    inner: cached.packed.clone(),
+                }),
+            Some(cached) => info!(
+                "Re-building relationships chunk {:?} to refill {} incomplete artists",
+                cache_key,
+                cached.incomplete_artist_internal_ids.len()
+            ),
+            None => (),
         }
     }
 
@@ -1812,31 +3874,31 @@ pub(crate) async fn get_artist_relationships_chunk(
         .map(|id| id as i32)
         .collect();
 
-    let packed = get_packed_artist_relationships_by_internal_ids_inner(
+    let relationships = get_packed_artist_relationships_by_internal_ids_inner(
         &conn,
         spotify_access_token,
         artist_internal_ids,
     )
     .await?;
+    let packed = relationships.packed.clone();
 
     {
         let cache = &mut *ARTIST_RELATIONSHIPS_BY_INTERNAL_IDS_CACHE.lock().await;
-        cache.insert(cache_key, packed.clone());
+        cache.insert(cache_key, relationships);
     }
 
     Ok(JSONMimeTypeSetterResponder { inner: packed })
 }
 
-#[get("/get_preview_urls_by_internal_id/<artist_internal_id>")]
+/// `market` is the country code of the market to fetch tracks for, defaulting to the US
+#[get("/get_preview_urls_by_internal_id/<artist_internal_id>?<market>")]
 pub(crate) async fn get_preview_urls_by_internal_id(
     conn: DbConn,
-    token_data: &State<Mutex<SpotifyTokenData>>,
+    token_data: &State<SpotifyTokenData>,
     artist_internal_id: i32,
+    market: Option<&str>,
 ) -> Result<Json<Option<Vec<String>>>, String> {
-    let spotify_access_token = {
-        let token_data = &mut *(&*token_data).lock().await;
-        token_data.get().await
-    }?;
+    let spotify_access_token = token_data.get().await?;
 
     let spotify_ids_by_internal_id =
         get_artist_spotify_ids_by_internal_id(&conn, vec![artist_internal_id])
@@ -1854,7 +3916,8 @@ pub(crate) async fn get_preview_urls_by_internal_id(
         None => return Ok(Json(None)),
     };
 
-    let top_tracks = fetch_top_tracks_for_artist(&spotify_access_token, &spotify_id).await?;
+    let top_tracks =
+        fetch_top_tracks_for_artist(&spotify_access_token, &spotify_id, market).await?;
     if top_tracks.is_empty() {
         return Ok(Json(None));
     }
@@ -1893,6 +3956,39 @@ pub(crate) async fn get_top_artists_internal_ids_for_user(
     )))
 }
 
+/// Like `/top_artists_internal_ids_for_user`, but includes the rank and timeframes of each artist
+/// as of the user's most recent update.  Artists are ordered by best rank.
+#[get("/top_artists_detailed_for_user/<user_id>")]
+pub(crate) async fn get_top_artists_detailed_for_user(
+    conn: DbConn,
+    user_id: String,
+) -> Result<Option<Json<Vec<TopArtistDetail>>>, String> {
+    track_endpoint_errors(
+        "get_top_artists_detailed_for_user",
+        get_top_artists_detailed_for_user_inner(conn, user_id).await,
+    )
+}
+
+async fn get_top_artists_detailed_for_user_inner(
+    conn: DbConn,
+    user_id: String,
+) -> Result<Option<Json<Vec<TopArtistDetail>>>, String> {
+    let user = match db_util::get_user_by_spotify_id(&conn, user_id).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+
+    let top_artists = db_util::get_top_artists_detailed_for_user(&conn, user.id)
+        .await
+        .map_err(|err| {
+            error!("Error getting detailed top artists for user: {:?}", err);
+            String::from("Internal DB error")
+        })?;
+    Ok(Some(Json(top_artists)))
+}
+
 #[post(
     "/transfer_user_data_to_external_storage/<user_id>",
     data = "<api_token_data>"
@@ -1902,12 +3998,15 @@ pub(crate) async fn transfer_user_data_to_external_storage(
     conn: DbConn,
     user_id: String,
 ) -> Result<status::Custom<String>, String> {
-    if !validate_api_token(api_token_data).await? {
+    if !validate_api_token(api_token_data, "transfer_user_data_to_external_storage").await? {
         return Ok(status::Custom(
             Status::Unauthorized,
             "Invalid API token supplied".into(),
         ));
     }
+    if let Some(res) = draining_response() {
+        return Ok(res);
+    }
 
     let user = match db_util::get_user_by_spotify_id(&conn, user_id).await? {
         Some(user) => user,
@@ -1924,7 +4023,11 @@ pub(crate) async fn transfer_user_data_to_external_storage(
         );
     }
 
-    crate::external_storage::upload::store_external_user_data(&conn, user.spotify_id).await;
+    if let Err(err) =
+        crate::external_storage::upload::store_external_user_data(&conn, user.spotify_id).await
+    {
+        error!("Error transferring user data to external storage: {}", err);
+    }
     Ok(status::Custom(Status::Ok, String::new()))
 }
 
@@ -1937,12 +4040,15 @@ pub(crate) async fn transfer_user_data_from_external_storage(
     conn: DbConn,
     user_id: String,
 ) -> Result<status::Custom<String>, String> {
-    if !validate_api_token(api_token_data).await? {
+    if !validate_api_token(api_token_data, "transfer_user_data_from_external_storage").await? {
         return Ok(status::Custom(
             Status::Unauthorized,
             "Invalid API token supplied".into(),
         ));
     }
+    if let Some(res) = draining_response() {
+        return Ok(res);
+    }
 
     let user = match db_util::get_user_by_spotify_id(&conn, user_id).await? {
         Some(user) => user,
@@ -1963,8 +4069,40 @@ pub(crate) async fn transfer_user_data_from_external_storage(
     Ok(status::Custom(Status::Ok, String::new()))
 }
 
+const MIN_BULK_TRANSFER_INACTIVE_DAYS: i64 = 30;
+const MAX_BULK_TRANSFER_INACTIVE_DAYS: i64 = 365 * 10;
+/// Size of a single snapshot row in the external storage arrow schema before compression
+const BULK_TRANSFER_UNCOMPRESSED_BYTES_PER_ENTRY: u64 = 8 + 8 + 8 + 4 + 1 + 1;
+
+/// Counts the local artist and track snapshot rows for the user with the provided internal ID.
+async fn count_local_snapshot_entries(conn: &DbConn, user_id: i64) -> Result<(i64, i64), String> {
+    conn.run(move |conn| -> QueryResult<(i64, i64)> {
+        use crate::schema::{artist_rank_snapshots, track_rank_snapshots};
+
+        let artist_count = artist_rank_snapshots::table
+            .filter(artist_rank_snapshots::dsl::user_id.eq(user_id))
+            .count()
+            .get_result(conn)?;
+        let track_count = track_rank_snapshots::table
+            .filter(track_rank_snapshots::dsl::user_id.eq(user_id))
+            .count()
+            .get_result(conn)?;
+        Ok((artist_count, track_count))
+    })
+    .await
+    .map_err(|err| {
+        error!("Error counting local snapshot entries for user: {:?}", err);
+        String::from("Internal DB error")
+    })
+}
+
+/// Moves data for the least recently stored inactive users to external storage.
+///
+/// If `dry_run` is set, nothing is moved and the returned report only contains the number of
+/// entries that would have been transferred for each selected user.
 #[post(
-    "/bulk_transfer_user_data_to_external_storage/<user_count>?<only_already_stored>&<concurrency>",
+    "/bulk_transfer_user_data_to_external_storage/<user_count>?<only_already_stored>&\
+     <concurrency>&<inactive_days>&<dry_run>",
     data = "<api_token_data>"
 )]
 pub(crate) async fn bulk_transfer_user_data_to_external_storage(
@@ -1977,16 +4115,33 @@ pub(crate) async fn bulk_transfer_user_data_to_external_storage(
     user_count: u32,
     only_already_stored: Option<bool>,
     concurrency: Option<usize>,
+    inactive_days: Option<i64>,
+    dry_run: Option<bool>,
 ) -> Result<status::Custom<String>, String> {
-    if !validate_api_token(api_token_data).await? {
+    if !validate_api_token(
+        api_token_data,
+        "bulk_transfer_user_data_to_external_storage",
+    )
+    .await?
+    {
         return Ok(status::Custom(
             Status::Unauthorized,
             "Invalid API token supplied".into(),
         ));
     }
+    if let Some(res) = draining_response() {
+        return Ok(res);
+    }
 
-    // Only transfer data for users that haven't viewed their profile in the past 4 months
-    let cutoff_time: NaiveDateTime = Utc::now().naive_utc() - chrono::Duration::days(120);
+    // Only transfer data for users that haven't viewed their profile in the past `inactive_days`
+    // days.  We enforce a minimum to avoid accidentally archiving users that are still active.
+    let inactive_days = inactive_days
+        .unwrap_or_else(|| dynamic_conf().bulk_transfer_default_inactive_days)
+        .clamp(
+            MIN_BULK_TRANSFER_INACTIVE_DAYS,
+            MAX_BULK_TRANSFER_INACTIVE_DAYS,
+        );
+    let cutoff_time: NaiveDateTime = Utc::now().naive_utc() - chrono::Duration::days(inactive_days);
 
     let users = conn0
         .run(move |conn| {
@@ -2012,17 +4167,57 @@ pub(crate) async fn bulk_transfer_user_data_to_external_storage(
         .iter()
         .map(|user| user.spotify_id.clone())
         .collect::<Vec<_>>();
+    let dry_run = dry_run.unwrap_or(false);
     info!(
-        "Bulk transferring user data for {user_count} users: {:?}",
+        "Bulk transferring user data for {user_count} users inactive for at least {inactive_days} \
+         days (dry_run={dry_run}): {:?}",
         usernames
     );
 
     let concurrency = concurrency.unwrap_or(1).clamp(1, 5);
     let conns = Arc::new(Mutex::new(vec![conn0, conn1, conn2, conn3, conn4]));
-    futures::stream::iter(users)
-        .for_each_concurrent(Some(concurrency), |user| {
+    let user_reports: Vec<BulkTransferUserReport> = futures::stream::iter(users)
+        .map(|user| {
             let conns = Arc::clone(&conns);
             async move {
+                let mut report = BulkTransferUserReport {
+                    spotify_id: user.spotify_id.clone(),
+                    local_artist_entry_count: 0,
+                    local_track_entry_count: 0,
+                    estimated_uncompressed_bytes: 0,
+                    status: BulkTransferUserStatus::Failure,
+                    uploaded_bytes: None,
+                    error: None,
+                };
+                let conn = match conns.lock().await.pop() {
+                    Some(conn) => conn,
+                    None => {
+                        error!("Shouldn't be possible; ran out of connections");
+                        report.error = Some(String::from("Ran out of DB connections"));
+                        return report;
+                    },
+                };
+
+                let (local_artist_entry_count, local_track_entry_count) =
+                    match count_local_snapshot_entries(&conn, user.id).await {
+                        Ok(counts) => counts,
+                        Err(err) => {
+                            conns.lock().await.push(conn);
+                            report.error = Some(err);
+                            return report;
+                        },
+                    };
+                report.local_artist_entry_count = local_artist_entry_count;
+                report.local_track_entry_count = local_track_entry_count;
+                report.estimated_uncompressed_bytes =
+                    (local_artist_entry_count + local_track_entry_count) as u64
+                        * BULK_TRANSFER_UNCOMPRESSED_BYTES_PER_ENTRY;
+                if dry_run {
+                    report.status = BulkTransferUserStatus::DryRun;
+                    conns.lock().await.push(conn);
+                    return report;
+                }
+
                 if !user.external_data_retrieved {
                     warn!(
                         "User {} already has external user data stored; downloading + merging and \
@@ -2031,25 +4226,297 @@ pub(crate) async fn bulk_transfer_user_data_to_external_storage(
                     );
                 }
 
-                let conn = match conns.lock().await.pop() {
-                    Some(conn) => conn,
-                    None => {
-                        error!("Shouldn't be possible; ran out of connections");
-                        return;
-                    },
-                };
-
-                crate::external_storage::upload::store_external_user_data(
+                match crate::external_storage::upload::store_external_user_data(
                     &conn,
                     user.spotify_id.clone(),
                 )
-                .await;
-                info!("Done transferring user data for {}", user.spotify_id);
+                .await
+                {
+                    Ok(stats) => {
+                        report.status = BulkTransferUserStatus::Success;
+                        report.uploaded_bytes = Some(stats.uploaded_bytes);
+                    },
+                    Err(err) => {
+                        report.status = BulkTransferUserStatus::Failure;
+                        report.error = Some(err);
+                    },
+                }
 
                 conns.lock().await.push(conn);
+                report
             }
         })
+        .buffered(concurrency)
+        .collect()
         .await;
 
-    Ok(status::Custom(Status::Ok, String::new()))
+    let report = BulkTransferReport {
+        dry_run,
+        inactive_days,
+        users: user_reports,
+    };
+    let body = serde_json::to_string(&report).map_err(|err| {
+        error!("Error serializing bulk transfer report: {:?}", err);
+        String::from("Internal error")
+    })?;
+    Ok(status::Custom(Status::Ok, body))
+}
+
+const DEFAULT_EXTERNAL_STORAGE_OBJECT_LIST_LIMIT: usize = 100;
+const MAX_EXTERNAL_STORAGE_OBJECT_LIST_LIMIT: usize = 1000;
+
+/// Lists objects in the external storage bucket along with their sizes and last-modified times.
+///
+/// If `key` is provided, the parquet footer for that single object is read instead and its row
+/// counts are returned.
+///
+/// The admin token is read from the `X-Admin-Token` header.
+#[get("/admin/external_storage_objects?<prefix>&<limit>&<key>")]
+pub(crate) async fn list_external_storage_objects(
+    api_token: AdminTokenHeader,
+    prefix: Option<String>,
+    limit: Option<usize>,
+    key: Option<String>,
+) -> Result<status::Custom<String>, String> {
+    if !check_admin_token(&api_token.0, "list_external_storage_objects") {
+        return Ok(status::Custom(
+            Status::Unauthorized,
+            "Invalid API token supplied".into(),
+        ));
+    }
+
+    let body = match key {
+        Some(key) => match crate::external_storage::inspect::inspect_object(key).await {
+            Ok(Some(detail)) => serde_json::to_string(&detail),
+            Ok(None) => return Ok(status::Custom(Status::NotFound, "Object not found".into())),
+            Err(err) => return Err(format!("Error inspecting external storage object: {}", err)),
+        },
+        None => {
+            let limit = limit
+                .unwrap_or(DEFAULT_EXTERNAL_STORAGE_OBJECT_LIST_LIMIT)
+                .min(MAX_EXTERNAL_STORAGE_OBJECT_LIST_LIMIT);
+            match crate::external_storage::inspect::list_objects(prefix, limit).await {
+                Ok(objects) => serde_json::to_string(&objects),
+                Err(err) => return Err(format!("Error listing external storage objects: {}", err)),
+            }
+        },
+    }
+    .map_err(|err| {
+        error!("Error serializing external storage objects: {:?}", err);
+        String::from("Internal error")
+    })?;
+    Ok(status::Custom(Status::Ok, body))
+}
+
+/// Re-reads the dotenv file and swaps in the new dynamic config.  If any of the new values are
+/// invalid, nothing is changed and the error is returned.
+#[post("/admin/reload_config", data = "<api_token_data>")]
+pub(crate) async fn reload_config(
+    api_token_data: rocket::Data<'_>,
+) -> Result<status::Custom<String>, String> {
+    if !validate_api_token(api_token_data, "reload_config").await? {
+        return Ok(status::Custom(
+            Status::Unauthorized,
+            "Invalid API token supplied".into(),
+        ));
+    }
+
+    match reload_dynamic_conf() {
+        Ok(new_conf) => {
+            info!("Reloaded dynamic config: {:?}", new_conf);
+            Ok(status::Custom(Status::Ok, format!("{:?}", new_conf)))
+        },
+        Err(err) => {
+            warn!("Rejected invalid dynamic config reload: {}", err);
+            Ok(status::Custom(Status::BadRequest, err))
+        },
+    }
+}
+
+/// Puts the server into draining mode, after which admin routes that start new work are refused.
+/// Returns the number of tasks that are currently in flight so the operator can wait for them to
+/// finish before deploying.
+#[post("/admin/drain", data = "<api_token_data>")]
+pub(crate) async fn drain(
+    api_token_data: rocket::Data<'_>,
+) -> Result<status::Custom<String>, String> {
+    if !validate_api_token(api_token_data, "drain").await? {
+        return Ok(status::Custom(
+            Status::Unauthorized,
+            "Invalid API token supplied".into(),
+        ));
+    }
+
+    if shutdown::start_draining() {
+        warn!("Server is now draining; new admin-triggered work will be refused");
+    }
+    Ok(status::Custom(
+        Status::Ok,
+        format!(
+            "Draining; {} task(s) in flight",
+            shutdown::in_flight_task_count()
+        ),
+    ))
+}
+
+#[test]
+fn average_artists_are_packed() {
+    let descriptor = |id: usize, similarity: f32| AverageArtistDescriptor {
+        id,
+        similarity_to_target_point: similarity,
+        similarity_to_artist_1: similarity / 2.,
+        similarity_to_artist_2: similarity / 4.,
+    };
+    let packed = pack_average_artists(&[(descriptor(7, 0.8), "abc"), (descriptor(3, 0.4), "de")]);
+
+    let read_u32 =
+        |offset: usize| u32::from_le_bytes(packed[offset..offset + 4].try_into().unwrap());
+    let read_f32 =
+        |offset: usize| f32::from_le_bytes(packed[offset..offset + 4].try_into().unwrap());
+    assert_eq!(read_u32(0), 2);
+    assert_eq!((read_u32(4), read_u32(8)), (7, 3));
+    assert_eq!((read_f32(12), read_f32(16), read_f32(20)), (0.8, 0.4, 0.2));
+    assert_eq!((read_f32(24), read_f32(28), read_f32(32)), (0.4, 0.2, 0.1));
+    assert_eq!(&packed[36..], b"abc,de");
+
+    assert_eq!(pack_average_artists(&[]), vec![0, 0, 0, 0]);
+}
+
+#[test]
+fn day_ids_are_parsed() {
+    assert_eq!(
+        parse_day_id("2021-03-14", "since_day_id").map(|day| day.date()),
+        Ok(NaiveDate::from_ymd(2021, 3, 14))
+    );
+    assert_eq!(
+        parse_day_id("2021-13-14", "since_day_id"),
+        Err("Invalid `since_day_id` provided".to_owned())
+    );
+}
+
+#[test]
+fn timeline_events_are_limited_after_genre_filtering() {
+    let artist_event = |id: usize, day: u32, genre: &str| TimelineEvent {
+        date: NaiveDate::from_ymd(2021, 1, day),
+        id,
+        event_type: TimelineEventType::ArtistFirstSeen {
+            artist: Artist {
+                followers: None,
+                genres: None,
+                id: id.to_string(),
+                images: None,
+                name: String::new(),
+                popularity: None,
+            },
+            genres: vec![genre.to_owned()],
+        },
+    };
+    // The only matching events come after more than `limit` non-matching ones
+    let events = vec![
+        artist_event(1, 1, "pop"),
+        artist_event(2, 2, "pop"),
+        artist_event(3, 3, "pop"),
+        artist_event(4, 4, "jazz"),
+        artist_event(5, 5, "jazz"),
+        artist_event(6, 6, "jazz"),
+    ];
+
+    let events = finalize_timeline_events(events, Some("jazz"), SortOrder::Descending, Some(2));
+    assert_eq!(events.iter().map(|evt| evt.id).collect::<Vec<_>>(), vec![
+        6, 5
+    ]);
+}
+
+#[test]
+fn first_discoverer_is_picked() {
+    let day = |day| NaiveDate::from_ymd(2021, 1, day);
+
+    assert_eq!(pick_first_discoverer(None, None), None);
+    assert_eq!(
+        pick_first_discoverer(Some(day(5)), None),
+        Some(FirstDiscoverer::User1)
+    );
+    assert_eq!(
+        pick_first_discoverer(None, Some(day(5))),
+        Some(FirstDiscoverer::User2)
+    );
+    assert_eq!(
+        pick_first_discoverer(Some(day(5)), Some(day(3))),
+        Some(FirstDiscoverer::User2)
+    );
+    assert_eq!(
+        pick_first_discoverer(Some(day(3)), Some(day(3))),
+        Some(FirstDiscoverer::SameDay)
+    );
+}
+
+#[test]
+fn artists_with_failed_related_artist_fetches_are_packed_as_incomplete() {
+    let related_artists_internal_ids_by_spotify_id: HashMap<String, i32> =
+        [("a".to_owned(), 10), ("b".to_owned(), 20)]
+            .into_iter()
+            .collect();
+    // The related artists of artist 2 failed to fetch
+    let related_artists = vec![
+        Some(vec!["a".to_owned(), "unmapped".to_owned(), "b".to_owned()]),
+        None,
+        Some(vec!["b".to_owned()]),
+    ];
+
+    let (artist_relationships, incomplete_artist_internal_ids) = build_artist_relationships(
+        &[1, 2, 3],
+        related_artists,
+        &related_artists_internal_ids_by_spotify_id,
+    );
+    assert_eq!(artist_relationships, vec![
+        vec![(10, relationship_weight(0)), (20, relationship_weight(2))],
+        Vec::new(),
+        vec![(20, relationship_weight(0))],
+    ]);
+    assert_eq!(incomplete_artist_internal_ids, vec![2]);
+
+    // The incomplete artist still has an entry so that the rest of the chunk lines up
+    let packed = pack_artist_relationships(artist_relationships);
+    assert_eq!(&packed[..4], &[2, 0, 1, 0]);
+}
+
+#[test]
+fn artist_neighborhood_sources_are_merged() {
+    let ids = |ids: &[&str]| ids.iter().map(|&id| id.to_owned()).collect::<Vec<_>>();
+    let merged = merge_artist_neighborhood_sources(
+        "seed",
+        &ids(&["a", "b", "a"]),
+        &ids(&["b", "seed", "c"]),
+    );
+
+    assert_eq!(merged, vec![
+        ("a".to_owned(), NeighborhoodArtistSource::Related),
+        ("b".to_owned(), NeighborhoodArtistSource::Both),
+        ("c".to_owned(), NeighborhoodArtistSource::Embedding),
+    ]);
+}
+
+#[test]
+fn cache_inspection_routes_are_gets() {
+    for route in routes![get_cache_entry, get_cache_stats_route] {
+        assert_eq!(route.method, rocket::http::Method::Get, "{}", route.uri);
+    }
+}
+
+#[test]
+fn incomplete_artist_relationship_chunks_are_rebuilt_after_retry_interval() {
+    let built_at = std::time::Instant::now();
+    let chunk = |incomplete_artist_internal_ids: Vec<i32>| PackedArtistRelationships {
+        packed: Vec::new(),
+        incomplete_artist_internal_ids,
+        built_at,
+    };
+    let later = built_at + INCOMPLETE_ARTIST_RELATIONSHIPS_RETRY_INTERVAL;
+
+    // Served from the cache until the retry interval has passed
+    assert!(!chunk(vec![2]).should_rebuild(built_at));
+    assert!(!chunk(vec![2]).should_rebuild(later - std::time::Duration::from_secs(1)));
+    assert!(chunk(vec![2]).should_rebuild(later));
+    // Complete chunks are never re-built
+    assert!(!chunk(Vec::new()).should_rebuild(later));
 }
@@ -1,5 +1,13 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    artist_followers_history (artist_id, day) {
+        artist_id -> Integer,
+        day -> Date,
+        followers -> Unsigned<Bigint>,
+    }
+}
+
 diesel::table! {
     artist_rank_snapshots (id) {
         id -> Bigint,
@@ -71,6 +79,13 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    tracks_albums (track_id) {
+        track_id -> Integer,
+        album_spotify_id -> Varchar,
+    }
+}
+
 diesel::table! {
     tracks_artists (id) {
         id -> Integer,
@@ -99,9 +114,11 @@ diesel::table! {
         external_data_retrieved -> Bool,
         last_viewed -> Timestamp,
         last_external_data_store -> Timestamp,
+        country -> Nullable<Varchar>,
     }
 }
 
+diesel::joinable!(artist_followers_history -> spotify_items (artist_id));
 diesel::joinable!(artist_rank_snapshots -> spotify_items (mapped_spotify_id));
 diesel::joinable!(artist_rank_snapshots -> users (user_id));
 diesel::joinable!(artists_genres -> spotify_items (artist_id));
@@ -110,6 +127,7 @@ diesel::joinable!(track_rank_snapshots -> spotify_items (mapped_spotify_id));
 diesel::joinable!(track_rank_snapshots -> users (user_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    artist_followers_history,
     artist_rank_snapshots,
     artist_stats_history,
     artists_genres,
@@ -118,6 +136,7 @@ diesel::allow_tables_to_appear_in_same_query!(
     spotify_items,
     track_rank_snapshots,
     track_stats_history,
+    tracks_albums,
     tracks_artists,
     tracks_users_first_seen,
     users,
@@ -1,11 +1,205 @@
+use fnv::{FnvHashMap as HashMap, FnvHashSet as HashSet};
+use futures::{StreamExt, TryStreamExt};
 use rand::prelude::*;
 
 use crate::{
     db_util::stringify_diesel_err,
-    models::{Track, User},
+    models::{Artist, BestRankingQueryResItem, Track, User},
     DbConn,
 };
 
+/// Number of entries stored per timeframe in each snapshot.  Rankings are 0-indexed.
+const MAX_RANKING: f32 = 50.;
+
+/// Number of each user's top artists whose related artists are used to find common ground when
+/// generating a discoveries-only playlist
+const DISCOVERY_SEED_ARTIST_COUNT: usize = 40;
+/// Max number of common-ground artists that tracks are pulled from for discoveries-only playlists
+const MAX_DISCOVERY_ARTIST_COUNT: usize = 30;
+/// Max number of tracks included from each common-ground artist in discoveries-only playlists
+const DISCOVERY_TRACKS_PER_ARTIST: usize = 3;
+
+/// Scores a track for a single user based on the best ranking it has ever reached in each
+/// timeframe across all of the user's snapshots:
+///
+/// ```text
+/// score = sum(weight(timeframe) * (MAX_RANKING - best_ranking(timeframe)) / MAX_RANKING)
+/// ```
+///
+/// Timeframes that the track never charted in contribute nothing.  With `long_term_weight = w`,
+/// the short, medium, and long-term timeframes are weighted `1 - w`, `0.5`, and `w` respectively,
+/// so higher values of `w` favor tracks that have stuck around in the user's charts over ones that
+/// were only briefly popular.
+fn score_track_for_user(
+    best_rankings_by_timeframe: &[Option<u8>; 3],
+    long_term_weight: f32,
+) -> f32 {
+    let timeframe_weights = [1. - long_term_weight, 0.5, long_term_weight];
+    best_rankings_by_timeframe
+        .iter()
+        .zip(timeframe_weights)
+        .map(|(best_ranking, weight)| match best_ranking {
+            Some(ranking) => weight * (MAX_RANKING - *ranking as f32).max(0.) / MAX_RANKING,
+            None => 0.,
+        })
+        .sum()
+}
+
+fn build_track_scores(
+    best_rankings: Vec<BestRankingQueryResItem>,
+    long_term_weight: f32,
+) -> HashMap<String, f32> {
+    let mut best_rankings_by_track_id: HashMap<String, [Option<u8>; 3]> = HashMap::default();
+    for item in best_rankings {
+        let best_rankings_by_timeframe = best_rankings_by_track_id
+            .entry(item.spotify_id)
+            .or_insert([None; 3]);
+        if let Some(best_ranking) = best_rankings_by_timeframe.get_mut(item.timeframe as usize) {
+            *best_ranking = Some(item.ranking);
+        }
+    }
+
+    best_rankings_by_track_id
+        .into_iter()
+        .map(|(track_id, best_rankings_by_timeframe)| {
+            let score = score_track_for_user(&best_rankings_by_timeframe, long_term_weight);
+            (track_id, score)
+        })
+        .collect()
+}
+
+/// Finds artists that are related to the top artists of both users, ordered by how strongly they
+/// are connected to the user they are least connected to.  Each of the `user*_related_artists`
+/// slices contains the related artist IDs of one of that user's seed artists.  Artists in
+/// `exclude` are skipped.
+fn find_common_ground_artists(
+    user1_related_artists: &[&[String]],
+    user2_related_artists: &[&[String]],
+    exclude: &HashSet<&str>,
+    limit: usize,
+) -> Vec<String> {
+    let count_related = |related_artists: &[&[String]]| -> HashMap<String, usize> {
+        let mut counts: HashMap<String, usize> = HashMap::default();
+        for related in related_artists {
+            for artist_id in related.iter() {
+                *counts.entry(artist_id.clone()).or_insert(0) += 1;
+            }
+        }
+        counts
+    };
+    let user1_counts = count_related(user1_related_artists);
+    let user2_counts = count_related(user2_related_artists);
+
+    let mut common_ground: Vec<(String, usize, usize)> = user1_counts
+        .into_iter()
+        .filter(|(artist_id, _)| !exclude.contains(artist_id.as_str()))
+        .filter_map(|(artist_id, user1_count)| {
+            let user2_count = *user2_counts.get(&artist_id)?;
+            Some((artist_id, user1_count, user2_count))
+        })
+        .collect();
+    common_ground.sort_unstable_by(|(id1, a1, b1), (id2, a2, b2)| {
+        (*a2.min(b2), a2 + b2)
+            .cmp(&(*a1.min(b1), a1 + b1))
+            .then_with(|| id1.cmp(id2))
+    });
+
+    common_ground
+        .into_iter()
+        .take(limit)
+        .map(|(artist_id, ..)| artist_id)
+        .collect()
+}
+
+/// Builds a playlist consisting only of tracks that neither user has ever had in their top tracks.
+/// Tracks are pulled from artists that are related to the top artists of both users, so the
+/// playlist consists of new discoveries that should appeal to both of them.
+async fn generate_discoveries_playlist_tracks(
+    spotify_access_token: &str,
+    market: Option<&str>,
+    user1_tracks: &[Track],
+    user2_tracks: &[Track],
+    user1_artists: &[Artist],
+    user2_artists: &[Artist],
+) -> Result<Vec<Track>, String> {
+    let user1_seed_ids: Vec<&str> = user1_artists
+        .iter()
+        .take(DISCOVERY_SEED_ARTIST_COUNT)
+        .map(|artist| artist.id.as_str())
+        .collect();
+    let user2_seed_ids: Vec<&str> = user2_artists
+        .iter()
+        .take(DISCOVERY_SEED_ARTIST_COUNT)
+        .map(|artist| artist.id.as_str())
+        .collect();
+    let mut all_seed_ids: Vec<&str> = user1_seed_ids
+        .iter()
+        .chain(user2_seed_ids.iter())
+        .copied()
+        .collect();
+    all_seed_ids.sort_unstable();
+    all_seed_ids.dedup();
+
+    let related_artists = crate::spotify_api::get_multiple_related_artists(
+        spotify_access_token.to_owned(),
+        &all_seed_ids,
+    )
+    .await?;
+    let related_artists_by_seed_id: HashMap<&str, &[String]> = all_seed_ids
+        .iter()
+        .copied()
+        .zip(related_artists.iter().map(Vec::as_slice))
+        .collect();
+    let get_related = |seed_ids: &[&str]| -> Vec<&[String]> {
+        seed_ids
+            .iter()
+            .map(|id| related_artists_by_seed_id[id])
+            .collect()
+    };
+
+    // Artists that both users already have in common are the most obvious common ground, so they
+    // are considered alongside the related artists
+    let user1_artist_ids: HashSet<&str> = user1_artists.iter().map(|a| a.id.as_str()).collect();
+    let shared_artist_ids: Vec<String> = user2_artists
+        .iter()
+        .filter(|artist| user1_artist_ids.contains(artist.id.as_str()))
+        .map(|artist| artist.id.clone())
+        .collect();
+    let common_ground_artist_ids = find_common_ground_artists(
+        &get_related(&user1_seed_ids),
+        &get_related(&user2_seed_ids),
+        &shared_artist_ids.iter().map(String::as_str).collect(),
+        MAX_DISCOVERY_ARTIST_COUNT.saturating_sub(shared_artist_ids.len()),
+    );
+    let candidate_artist_ids = shared_artist_ids
+        .iter()
+        .chain(common_ground_artist_ids.iter())
+        .take(MAX_DISCOVERY_ARTIST_COUNT);
+
+    let known_track_ids: HashSet<&str> = user1_tracks
+        .iter()
+        .chain(user2_tracks.iter())
+        .map(|track| track.id.as_str())
+        .collect();
+    let top_tracks_by_artist: Vec<Vec<Track>> = futures::stream::iter(candidate_artist_ids)
+        .map(|artist_id| {
+            crate::spotify_api::fetch_top_tracks_for_artist(spotify_access_token, artist_id, market)
+        })
+        .buffered(4)
+        .try_collect()
+        .await?;
+
+    Ok(top_tracks_by_artist
+        .into_iter()
+        .flat_map(|top_tracks| {
+            top_tracks
+                .into_iter()
+                .filter(|track| !known_track_ids.contains(track.id.as_str()))
+                .take(DISCOVERY_TRACKS_PER_ARTIST)
+        })
+        .collect())
+}
+
 pub(crate) async fn generate_shared_playlist_track_spotify_ids(
     conn1: DbConn,
     conn2: DbConn,
@@ -14,11 +208,13 @@ pub(crate) async fn generate_shared_playlist_track_spotify_ids(
     user1: &User,
     user2: &User,
     spotify_access_token: &str,
+    long_term_weight: Option<f32>,
+    discoveries_only: bool,
 ) -> Result<Vec<String>, String> {
     let (user1_id, user2_id) = (user1.id, user2.id);
 
     let (user1_tracks, user2_tracks, user1_artists, user2_artists) = tokio::join!(
-        async move {
+        async {
             let tracks = crate::db_util::get_all_top_tracks_for_user(&conn1, user1_id).await;
             match tracks {
                 Ok(tracks) => {
@@ -33,7 +229,7 @@ pub(crate) async fn generate_shared_playlist_track_spotify_ids(
                 Err(err) => Err(stringify_diesel_err(err)),
             }
         },
-        async move {
+        async {
             let tracks = crate::db_util::get_all_top_tracks_for_user(&conn2, user2_id).await;
             match tracks {
                 Ok(tracks) => {
@@ -82,6 +278,54 @@ pub(crate) async fn generate_shared_playlist_track_spotify_ids(
     let (user1_tracks, user2_tracks, user1_artists, user2_artists) =
         (user1_tracks?, user2_tracks?, user1_artists?, user2_artists?);
 
+    if discoveries_only {
+        // The playlist is created on user 2's account, so tracks are pulled from their market
+        let mut playlist_tracks = generate_discoveries_playlist_tracks(
+            spotify_access_token,
+            user2.country.as_deref(),
+            &user1_tracks,
+            &user2_tracks,
+            &user1_artists,
+            &user2_artists,
+        )
+        .await?;
+        playlist_tracks.sort_unstable_by(|track1, track2| track1.id.cmp(&track2.id));
+        playlist_tracks.dedup_by(|track1, track2| track1.id == track2.id);
+        playlist_tracks.shuffle(&mut rand::thread_rng());
+
+        return Ok(playlist_tracks
+            .into_iter()
+            .map(|track| format!("spotify:track:{track_id}", track_id = track.id))
+            .collect());
+    }
+
+    // Scores are only computed if a weighting was requested; otherwise all tracks are treated
+    // equally and the playlist is shuffled.
+    let track_scores: Option<HashMap<String, f32>> = match long_term_weight {
+        Some(long_term_weight) => {
+            let long_term_weight = long_term_weight.clamp(0., 1.);
+            let (user1_best_rankings, user2_best_rankings) = tokio::try_join!(
+                crate::db_util::get_best_track_rankings_for_user(&conn1, user1),
+                crate::db_util::get_best_track_rankings_for_user(&conn2, user2),
+            )
+            .map_err(stringify_diesel_err)?;
+
+            let mut track_scores = build_track_scores(user1_best_rankings, long_term_weight);
+            for (track_id, score) in build_track_scores(user2_best_rankings, long_term_weight) {
+                *track_scores.entry(track_id).or_insert(0.) += score;
+            }
+            Some(track_scores)
+        },
+        None => None,
+    };
+    let get_score = |track: &Track| -> f32 {
+        track_scores
+            .as_ref()
+            .and_then(|track_scores| track_scores.get(&track.id))
+            .copied()
+            .unwrap_or(0.)
+    };
+
     let mut playlist_tracks: Vec<&Track> = Vec::new();
 
     // Start by just adding all of the tracks for which there is intersection
@@ -100,7 +344,7 @@ pub(crate) async fn generate_shared_playlist_track_spotify_ids(
     });
 
     for artist in artists_intersection {
-        let tangential_tracks_for_artist = user1_tracks
+        let mut tangential_tracks_for_artist: Vec<&Track> = user1_tracks
             .iter()
             .chain(user2_tracks.iter())
             .filter(|track| {
@@ -109,17 +353,52 @@ pub(crate) async fn generate_shared_playlist_track_spotify_ids(
                     .iter()
                     .any(|o_artist| o_artist.id == artist.id)
             })
-            .take(5);
+            .collect();
+        // If weighting, take the best-scoring tracks for the artist rather than the first ones
+        if track_scores.is_some() {
+            tangential_tracks_for_artist
+                .sort_by(|track1, track2| get_score(track2).total_cmp(&get_score(track1)));
+        }
 
-        playlist_tracks.extend(tangential_tracks_for_artist);
+        playlist_tracks.extend(tangential_tracks_for_artist.into_iter().take(5));
     }
 
     playlist_tracks.sort_unstable_by(|track1, track2| track1.id.cmp(&track2.id));
     playlist_tracks.dedup_by(|track1, track2| track1.id == track2.id);
-    playlist_tracks.shuffle(&mut rand::thread_rng());
+    if track_scores.is_some() {
+        playlist_tracks.sort_by(|track1, track2| get_score(track2).total_cmp(&get_score(track1)));
+    } else {
+        playlist_tracks.shuffle(&mut rand::thread_rng());
+    }
 
     Ok(playlist_tracks
         .into_iter()
         .map(|track| format!("spotify:track:{track_id}", track_id = track.id))
         .collect())
 }
+
+#[test]
+fn common_ground_artists_are_related_to_both_users() {
+    let ids = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+    let (a, b, c) = (
+        ids(&["x", "y", "z"]),
+        ids(&["x", "shared"]),
+        ids(&["y", "x", "w"]),
+    );
+    let (d, e) = (ids(&["y", "w"]), ids(&["x", "shared"]));
+
+    let user1_related: Vec<&[String]> = vec![&a, &b, &c];
+    let user2_related: Vec<&[String]> = vec![&d, &e];
+    let exclude: HashSet<&str> = ["shared"].into_iter().collect();
+
+    // `x` is related to 3 of user 1's artists but only 1 of user 2's, while `y` is related to 2
+    // of user 1's artists and 1 of user 2's.  `z` isn't related to any of user 2's artists.
+    assert_eq!(
+        find_common_ground_artists(&user1_related, &user2_related, &exclude, 10),
+        ids(&["x", "y", "w"])
+    );
+    assert_eq!(
+        find_common_ground_artists(&user1_related, &user2_related, &exclude, 1),
+        ids(&["x"])
+    );
+}
@@ -0,0 +1,109 @@
+//! Graceful shutdown support.
+//!
+//! Long-running work that would be left in an inconsistent state if the process exited part-way
+//! through (user updates, external storage transfers, related artist crawls) registers itself in
+//! the in-flight task registry for as long as it runs.  When the server shuts down, background
+//! loops are stopped and we wait up to `DynamicConf::shutdown_grace_period_seconds` for registered
+//! tasks, as well as anything holding an external storage write lock, to finish before exiting.
+//!
+//! Separately, the server can be put into draining mode ahead of a deploy, in which case it
+//! refuses to start new admin-triggered work while continuing to serve regular requests.
+
+use std::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use tokio::sync::watch;
+
+use crate::{conf::dynamic_conf, external_storage::users_with_pending_writes};
+
+static DRAINING: AtomicBool = AtomicBool::new(false);
+static NEXT_TASK_ID: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref IN_FLIGHT_TASKS: DashMap<u64, (String, Instant)> = DashMap::new();
+    static ref SHUTDOWN_TX: watch::Sender<bool> = watch::channel(false).0;
+}
+
+/// Removes its task from the in-flight task registry when dropped
+pub(crate) struct InFlightTaskGuard {
+    id: u64,
+}
+
+impl Drop for InFlightTaskGuard {
+    fn drop(&mut self) { IN_FLIGHT_TASKS.remove(&self.id); }
+}
+
+/// Registers a task that should be given the chance to finish before the process exits.  The task
+/// is considered in-flight until the returned guard is dropped.
+pub(crate) fn register_in_flight_task(name: impl Into<String>) -> InFlightTaskGuard {
+    let id = NEXT_TASK_ID.fetch_add(1, Ordering::Relaxed);
+    IN_FLIGHT_TASKS.insert(id, (name.into(), Instant::now()));
+    InFlightTaskGuard { id }
+}
+
+/// Puts the server into draining mode.  Returns `false` if it was already draining.
+pub(crate) fn start_draining() -> bool { !DRAINING.swap(true, Ordering::Relaxed) }
+
+/// Returns `true` if new admin-triggered work should be refused because the server is draining or
+/// shutting down
+pub(crate) fn is_draining() -> bool { DRAINING.load(Ordering::Relaxed) || *SHUTDOWN_TX.borrow() }
+
+pub(crate) fn in_flight_task_count() -> usize { IN_FLIGHT_TASKS.len() }
+
+/// Resolves once shutdown has started.  Background loops should select on this to stop promptly.
+pub(crate) async fn wait_for_shutdown() {
+    let mut rx = SHUTDOWN_TX.subscribe();
+    // The sender lives in a static and is never dropped, so this can't fail
+    let _ = rx.wait_for(|is_shutting_down| *is_shutting_down).await;
+}
+
+/// Stops background loops and waits for in-flight tasks and external storage writes to finish, up
+/// to the configured grace period.  Anything still running after that is logged.
+pub(crate) async fn shutdown() {
+    SHUTDOWN_TX.send_replace(true);
+
+    let grace_period = Duration::from_secs(dynamic_conf().shutdown_grace_period_seconds);
+    let deadline = Instant::now() + grace_period;
+    info!(
+        "Shutting down; waiting up to {:?} for {} in-flight task(s) to finish...",
+        grace_period,
+        in_flight_task_count()
+    );
+    let pending_writes = users_with_pending_writes();
+    if !pending_writes.is_empty() {
+        info!(
+            "Waiting for external storage writes to finish for {} user(s): {:?}",
+            pending_writes.len(),
+            pending_writes
+        );
+    }
+    while (in_flight_task_count() > 0 || !users_with_pending_writes().is_empty())
+        && Instant::now() < deadline
+    {
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+
+    let pending_writes = users_with_pending_writes();
+    if IN_FLIGHT_TASKS.is_empty() && pending_writes.is_empty() {
+        info!("All in-flight tasks and external storage writes finished");
+        return;
+    }
+    for entry in IN_FLIGHT_TASKS.iter() {
+        let (name, started_at) = entry.value();
+        error!(
+            "In-flight task didn't finish before shutdown: {} (running for {:?})",
+            name,
+            started_at.elapsed()
+        );
+    }
+    for user_spotify_id in pending_writes {
+        error!(
+            "External storage write didn't finish before shutdown for user {}",
+            user_spotify_id
+        );
+    }
+}
@@ -1,32 +1,37 @@
-use std::{
-    sync::Arc,
-    time::{Duration, Instant},
-};
+use std::time::{Duration, Instant};
 
 use chrono::Utc;
 use diesel::prelude::*;
 use fnv::FnvHashMap as HashMap;
+use futures::{stream::FuturesUnordered, StreamExt};
 use reqwest::{self, StatusCode};
 use rocket::http::RawStr;
 use serde::{Deserialize, Serialize};
 use tokio::{
-    sync::{mpsc::channel, Mutex, RwLock},
+    sync::{mpsc::channel, RwLock},
     task::block_in_place,
 };
 
 use crate::{
+    cache::{
+        metadata_cache::{get_local_hash_items, set_local_hash_items},
+        versioned_hash_name,
+    },
     conf::CONF,
     db_util::get_internal_ids_by_spotify_id,
     metrics::{
+        metadata_cache_lookups_total, partial_stats_snapshots_total,
         spotify_api_requests_failure_total, spotify_api_requests_rate_limited_total,
         spotify_api_requests_success_total, spotify_api_requests_total, spotify_api_response_time,
+        spotify_corrupt_top_entities_total, stats_timeframe_fetch_failures_total,
     },
     models::{
-        AccessTokenResponse, Artist, ArtistGenrePair, ArtistSearchResult, CreatePlaylistRequest,
-        GetRelatedArtistsResponse, NewArtistHistoryEntry, NewTrackHistoryEntry, Playlist,
-        SpotifyBatchArtistsResponse, SpotifyBatchTracksResponse, SpotifyResponse, StatsSnapshot,
-        TopArtistsResponse, TopTracksResponse, Track, TrackArtistPair, UpdatePlaylistResponse,
-        User, UserProfile,
+        AccessTokenResponse, Album, Artist, ArtistGenrePair, ArtistSearchResult,
+        CreatePlaylistRequest, GetRelatedArtistsResponse, HasSpotifyId, NewArtistHistoryEntry,
+        NewTrackHistoryEntry, Playlist, SpotifyBatchAlbumsResponse, SpotifyBatchArtistsResponse,
+        SpotifyBatchTracksResponse, SpotifyResponse, StatsSnapshot, TopArtistsResponse,
+        TopTracksResponse, Track, TrackAlbumPair, TrackArtistPair, UpdatePlaylistResponse, User,
+        UserProfile,
     },
     DbConn,
 };
@@ -36,67 +41,178 @@ const _SPOTIFY_USER_RECENTLY_PLAYED_URL: &str =
 const SPOTIFY_USER_PROFILE_INFO_URL: &str = "https://api.spotify.com/v1/me";
 const SPOTIFY_BATCH_TRACKS_URL: &str = "https://api.spotify.com/v1/tracks";
 const SPOTIFY_BATCH_ARTISTS_URL: &str = "https://api.spotify.com/v1/artists";
+const SPOTIFY_BATCH_ALBUMS_URL: &str = "https://api.spotify.com/v1/albums";
+/// The most albums that Spotify will return in a single batch request
+const MAX_BATCH_ALBUM_COUNT: usize = 20;
 const SPOTIFY_APP_TOKEN_URL: &str = "https://accounts.spotify.com/api/token";
-const ENTITY_FETCH_COUNT: usize = 50;
-const REQWEST_CLIENT_LIFETIME_SECS: u64 = 60 * 5;
+/// The most top entities that Spotify will return in a single request
+const MAX_ENTITY_PAGE_SIZE: usize = 50;
+/// How many times a request that timed out is retried before giving up
+const MAX_TIMEOUT_RETRIES: usize = 2;
+/// How long to wait before retrying a rate limited request if Spotify doesn't tell us
+const DEFAULT_RATE_LIMIT_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Errors that can occur when making requests to the Spotify API.  These can be converted into
+/// `String`s for use in routes and other places that deal with stringly-typed errors.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum SpotifyApiError {
+    /// Spotify returned a 429.  `retry_after` is taken from the `Retry-After` header if provided.
+    RateLimited {
+        retry_after: Option<Duration>,
+    },
+    Unauthorized,
+    NotFound,
+    /// Spotify returned a non-success status code or an error payload
+    Upstream {
+        status: u16,
+        message: String,
+    },
+    /// The request timed out, either while connecting or waiting for the response.  These are
+    /// usually caused by transient issues on Spotify's end and are retried a limited number of
+    /// times by the request helpers.
+    Timeout,
+    /// Error communicating with the Spotify API
+    Transport(String),
+    /// The response from Spotify couldn't be decoded
+    Decode(String),
+}
+
+impl std::fmt::Display for SpotifyApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::RateLimited { .. } => write!(f, "Rate limited by the Spotify API"),
+            Self::Unauthorized => write!(f, "Unauthorized to access the Spotify API"),
+            Self::NotFound => write!(f, "Resource not found in the Spotify API"),
+            Self::Upstream { status, message } => write!(
+                f,
+                "Got bad response from Spotify API ({}): {}",
+                status, message
+            ),
+            Self::Timeout => write!(f, "Timed out communicating with the Spotify API"),
+            Self::Transport(_) => write!(f, "Error communicating with the Spotify API"),
+            Self::Decode(_) => write!(f, "Error decoding response from the Spotify API"),
+        }
+    }
+}
+
+impl From<SpotifyApiError> for String {
+    fn from(err: SpotifyApiError) -> Self { err.to_string() }
+}
+
+impl SpotifyApiError {
+    fn from_reqwest_err(err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            warn!("Timed out communicating with Spotify API: {:?}", err);
+            return Self::Timeout;
+        }
+
+        error!("Error communicating with Spotify API: {:?}", err);
+        Self::Transport(err.to_string())
+    }
+
+    /// Builds an error from a non-success response from Spotify, consuming its body
+    async fn from_response(res: reqwest::Response) -> Self {
+        let status = res.status();
+        match status {
+            StatusCode::TOO_MANY_REQUESTS => Self::RateLimited {
+                retry_after: parse_retry_after(res.headers()),
+            },
+            StatusCode::UNAUTHORIZED => Self::Unauthorized,
+            StatusCode::NOT_FOUND => Self::NotFound,
+            _ => {
+                let body = res.text().await;
+                error!(
+                    "Got bad status code of {} from Spotify API: {:?}",
+                    status, body
+                );
+                Self::Upstream {
+                    status: status.as_u16(),
+                    message: body.unwrap_or_default(),
+                }
+            },
+        }
+    }
+}
+
+fn parse_retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let retry_after = headers.get(reqwest::header::RETRY_AFTER)?.to_str().ok()?;
+    retry_after.trim().parse().ok().map(Duration::from_secs)
+}
 
 lazy_static::lazy_static! {
-    static ref REQWEST_CLIENT_CACHE: RwLock<(Instant, reqwest::Client)> = RwLock::new((Instant::now(), reqwest::Client::new()));
+    static ref REQWEST_CLIENT_CACHE: RwLock<(Instant, reqwest::Client)> = RwLock::new((Instant::now(), build_reqwest_client()));
+}
+
+fn build_reqwest_client_with_timeouts(
+    connect_timeout: Duration,
+    request_timeout: Duration,
+    pool_idle_timeout: Duration,
+) -> reqwest::Client {
+    reqwest::Client::builder()
+        .connect_timeout(connect_timeout)
+        .timeout(request_timeout)
+        .pool_idle_timeout(pool_idle_timeout)
+        .build()
+        .expect("Failed to build reqwest client")
+}
+
+fn build_reqwest_client() -> reqwest::Client {
+    build_reqwest_client_with_timeouts(
+        CONF.spotify_api_connect_timeout,
+        CONF.spotify_api_request_timeout,
+        CONF.spotify_api_pool_idle_timeout,
+    )
 }
 
 pub(crate) async fn get_reqwest_client() -> reqwest::Client {
     let client_cache = REQWEST_CLIENT_CACHE.read().await;
-    if client_cache.0.elapsed().as_secs() < REQWEST_CLIENT_LIFETIME_SECS {
+    if client_cache.0.elapsed() < CONF.reqwest_client_lifetime {
         return client_cache.1.clone();
     }
 
     drop(client_cache);
     let mut client_cache = REQWEST_CLIENT_CACHE.write().await;
-    if client_cache.0.elapsed().as_secs() < REQWEST_CLIENT_LIFETIME_SECS {
+    if client_cache.0.elapsed() < CONF.reqwest_client_lifetime {
         return client_cache.1.clone();
     }
 
-    *client_cache = (Instant::now(), reqwest::Client::new());
+    *client_cache = (Instant::now(), build_reqwest_client());
     client_cache.1.clone()
 }
 
-fn get_top_entities_url(entity_type: &str, timeframe: &str) -> String {
+fn get_top_entities_url(entity_type: &str, timeframe: &str, limit: usize, offset: usize) -> String {
     format!(
-        "https://api.spotify.com/v1/me/top/{}?limit={}&time_range={}_term",
-        entity_type, ENTITY_FETCH_COUNT, timeframe
+        "https://api.spotify.com/v1/me/top/{}?limit={}&offset={}&time_range={}_term",
+        entity_type, limit, offset, timeframe
     )
 }
 
+/// Returns the `(limit, offset)` of each request needed to fetch `count` top entities
+fn get_top_entities_pages(count: usize) -> Vec<(usize, usize)> {
+    (0..count)
+        .step_by(MAX_ENTITY_PAGE_SIZE)
+        .map(|offset| ((count - offset).min(MAX_ENTITY_PAGE_SIZE), offset))
+        .collect()
+}
+
 async fn process_spotify_res<R: for<'de> Deserialize<'de> + Clone + std::fmt::Debug>(
     url: &str,
     res: Result<reqwest::Response, reqwest::Error>,
-) -> Result<R, String> {
-    let res = res.map_err(|err| -> String {
-        error!("Error communicating with Spotify API: {:?}", err);
-        "Error communicating with from the Spotify API".into()
-    })?;
+) -> Result<R, SpotifyApiError> {
+    let res = res.map_err(SpotifyApiError::from_reqwest_err)?;
 
     if res.status() == StatusCode::TOO_MANY_REQUESTS {
         warn!("Rate limited when making request to URL={}", url);
-        return Err("Rate Limited".into());
     }
-
     if !res.status().is_success() {
-        error!(
-            "Got bad status code of {} from Spotify API: {:?}",
-            res.status(),
-            res.text().await
-        );
-        return Err("Got bad response from Spotify API".into());
+        return Err(SpotifyApiError::from_response(res).await);
     }
 
-    res.json::<SpotifyResponse<R>>()
-        .await
-        .map_err(|err| -> String {
-            error!("Error decoding response from Spotify API: {:?}.", err,);
-            "Error decoding response from Spotify API".into()
-        })?
-        .into_result()
+    let res: SpotifyResponse<R> = res.json().await.map_err(|err| {
+        error!("Error decoding response from Spotify API: {:?}.", err,);
+        SpotifyApiError::Decode(err.to_string())
+    })?;
+    res.into()
 }
 
 pub(crate) async fn spotify_user_api_request<
@@ -105,11 +221,12 @@ pub(crate) async fn spotify_user_api_request<
     url: &str,
     token: &str,
     endpoint_name: &'static str,
-) -> Result<T, String> {
+) -> Result<T, SpotifyApiError> {
     spotify_api_requests_total(endpoint_name).inc();
     let client = get_reqwest_client().await;
 
     let mut start = Instant::now();
+    let mut timeout_retries = 0;
     loop {
         let res = client.get(url).bearer_auth(token).send().await;
 
@@ -119,9 +236,14 @@ pub(crate) async fn spotify_user_api_request<
                 spotify_api_response_time(endpoint_name).observe(start.elapsed().as_nanos() as u64);
                 return Ok(res);
             },
-            Err(err) if err.contains("Rate Limited") => {
+            Err(SpotifyApiError::RateLimited { retry_after }) => {
                 spotify_api_requests_rate_limited_total(endpoint_name).inc();
-                tokio::time::sleep(Duration::from_secs(5)).await;
+                tokio::time::sleep(retry_after.unwrap_or(DEFAULT_RATE_LIMIT_RETRY_DELAY)).await;
+                start = Instant::now();
+            },
+            Err(SpotifyApiError::Timeout) if timeout_retries < MAX_TIMEOUT_RETRIES => {
+                spotify_api_requests_failure_total(endpoint_name).inc();
+                timeout_retries += 1;
                 start = Instant::now();
             },
             Err(err) => {
@@ -132,7 +254,7 @@ pub(crate) async fn spotify_user_api_request<
     }
 }
 
-pub(crate) async fn get_user_profile_info(token: &str) -> Result<UserProfile, String> {
+pub(crate) async fn get_user_profile_info(token: &str) -> Result<UserProfile, SpotifyApiError> {
     spotify_user_api_request(SPOTIFY_USER_PROFILE_INFO_URL, token, "user_profile_info").await
 }
 
@@ -142,10 +264,11 @@ pub(crate) async fn spotify_server_api_request<
     url: &str,
     params: HashMap<&str, &str>,
     endpoint_name: &'static str,
-) -> Result<T, String> {
+) -> Result<T, SpotifyApiError> {
     let client = get_reqwest_client().await;
 
     let mut start = Instant::now();
+    let mut timeout_retries = 0;
     loop {
         info!(
             "Hitting Spotify API POST at URL {}, params: {:?}",
@@ -164,9 +287,14 @@ pub(crate) async fn spotify_server_api_request<
                 spotify_api_response_time(endpoint_name).observe(start.elapsed().as_nanos() as u64);
                 return Ok(res);
             },
-            Err(err) if err.contains("Rate Limited") => {
+            Err(SpotifyApiError::RateLimited { retry_after }) => {
                 spotify_api_requests_rate_limited_total(endpoint_name).inc();
-                tokio::time::sleep(Duration::from_secs(5)).await;
+                tokio::time::sleep(retry_after.unwrap_or(DEFAULT_RATE_LIMIT_RETRY_DELAY)).await;
+                start = Instant::now();
+            },
+            Err(SpotifyApiError::Timeout) if timeout_retries < MAX_TIMEOUT_RETRIES => {
+                spotify_api_requests_failure_total(endpoint_name).inc();
+                timeout_retries += 1;
                 start = Instant::now();
             },
             Err(err) => {
@@ -183,10 +311,11 @@ pub(crate) async fn spotify_server_get_request<
     bearer_token: &str,
     url: &str,
     endpoint_name: &'static str,
-) -> Result<T, String> {
+) -> Result<T, SpotifyApiError> {
     let client = get_reqwest_client().await;
 
     let mut start = Instant::now();
+    let mut timeout_retries = 0;
     loop {
         info!("Hitting Spotify API GET at URL {}", url,);
         let res = client
@@ -201,13 +330,19 @@ pub(crate) async fn spotify_server_get_request<
                 spotify_api_response_time(endpoint_name).observe(start.elapsed().as_nanos() as u64);
                 return Ok(res);
             },
-            Err(err) if err.contains("Rate Limited") => {
+            Err(SpotifyApiError::RateLimited { retry_after }) => {
                 warn!(
-                    "Rate limited when hitting url={}, waiting 5 seconds before retrying...",
-                    url
+                    "Rate limited when hitting url={}, waiting {:?} before retrying...",
+                    url,
+                    retry_after.unwrap_or(DEFAULT_RATE_LIMIT_RETRY_DELAY)
                 );
                 spotify_api_requests_rate_limited_total(endpoint_name).inc();
-                tokio::time::sleep(Duration::from_secs(5)).await;
+                tokio::time::sleep(retry_after.unwrap_or(DEFAULT_RATE_LIMIT_RETRY_DELAY)).await;
+                start = Instant::now();
+            },
+            Err(SpotifyApiError::Timeout) if timeout_retries < MAX_TIMEOUT_RETRIES => {
+                spotify_api_requests_failure_total(endpoint_name).inc();
+                timeout_retries += 1;
                 start = Instant::now();
             },
             Err(err) => {
@@ -224,10 +359,11 @@ async fn spotify_user_json_api_get_request<
     bearer_token: &str,
     url: String,
     endpoint_name: &'static str,
-) -> Result<R, String> {
+) -> Result<R, SpotifyApiError> {
     let client = get_reqwest_client().await;
 
     let mut start = Instant::now();
+    let mut timeout_retries = 0;
     loop {
         info!("Hitting Spotify API at URL {}", url);
 
@@ -238,13 +374,19 @@ async fn spotify_user_json_api_get_request<
                 spotify_api_response_time(endpoint_name).observe(start.elapsed().as_nanos() as u64);
                 return Ok(res);
             },
-            Err(err) if err.contains("Rate Limited") => {
+            Err(SpotifyApiError::RateLimited { retry_after }) => {
                 warn!(
-                    "Rate limited when hitting url={}, waiting 5 seconds before retrying...",
-                    url
+                    "Rate limited when hitting url={}, waiting {:?} before retrying...",
+                    url,
+                    retry_after.unwrap_or(DEFAULT_RATE_LIMIT_RETRY_DELAY)
                 );
                 spotify_api_requests_rate_limited_total(endpoint_name).inc();
-                tokio::time::sleep(Duration::from_secs(5)).await;
+                tokio::time::sleep(retry_after.unwrap_or(DEFAULT_RATE_LIMIT_RETRY_DELAY)).await;
+                start = Instant::now();
+            },
+            Err(SpotifyApiError::Timeout) if timeout_retries < MAX_TIMEOUT_RETRIES => {
+                spotify_api_requests_failure_total(endpoint_name).inc();
+                timeout_retries += 1;
                 start = Instant::now();
             },
             Err(err) => {
@@ -263,7 +405,7 @@ pub(crate) async fn spotify_user_json_api_request<
     url: &str,
     body: &T,
     endpoint_name: &'static str,
-) -> Result<R, String> {
+) -> Result<R, SpotifyApiError> {
     let client = get_reqwest_client().await;
 
     info!(
@@ -289,17 +431,15 @@ pub(crate) async fn spotify_user_json_api_request<
             } else {
                 spotify_api_requests_failure_total(endpoint_name).inc();
             },
-        Err(err) => {
+        Err(_) => {
             spotify_api_requests_failure_total(endpoint_name).inc();
-            error!("Error communicating with Spotify API: {:?}", err);
-            return Err("Error communicating with from the Spotify API".into());
         },
     }
 
     process_spotify_res(url, res).await
 }
 
-pub(crate) async fn fetch_auth_token() -> Result<AccessTokenResponse, String> {
+pub(crate) async fn fetch_auth_token() -> Result<AccessTokenResponse, SpotifyApiError> {
     let mut params = HashMap::default();
     params.insert("grant_type", "client_credentials");
 
@@ -316,118 +456,181 @@ pub(crate) async fn refresh_user_token(refresh_token: &str) -> Result<String, St
     Ok(res.access_token)
 }
 
+/// Spotify sometimes returns top artists responses where every item is this artist
+const BUGGY_TOP_ARTIST_ID: &str = "7ab5IU6f9rBvhgS4kuQjSh";
+/// If more than this fraction of the items in a top entities timeframe share the same ID, the
+/// response is considered corrupt
+const CORRUPT_TOP_ENTITIES_DUPLICATE_THRESHOLD: f32 = 0.8;
+const CORRUPT_STATS_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Returns `true` if the IDs of the items in a top entities timeframe look like garbage returned
+/// by Spotify rather than the user's actual top entities.
+fn is_corrupt_top_entities_timeframe<'a>(ids: impl Iterator<Item = &'a str>) -> bool {
+    let mut counts_by_id: HashMap<&str, usize> = HashMap::default();
+    let mut total_count = 0usize;
+    for id in ids {
+        *counts_by_id.entry(id).or_insert(0) += 1;
+        total_count += 1;
+    }
+
+    if counts_by_id.len() == 1 && counts_by_id.contains_key(BUGGY_TOP_ARTIST_ID) {
+        return true;
+    }
+    // A single item can't be a duplicate of anything
+    if total_count < 2 {
+        return false;
+    }
+
+    let max_count = counts_by_id.values().copied().max().unwrap_or(0);
+    max_count as f32 / total_count as f32 > CORRUPT_TOP_ENTITIES_DUPLICATE_THRESHOLD
+}
+
+/// Fetches the user's current top tracks and artists for all timeframes.  If Spotify returns
+/// corrupt data, the fetch is retried once after a short delay.  Returns `Ok(None)` if the data
+/// was still corrupt, in which case nothing should be stored for the user.
 pub(crate) async fn fetch_cur_stats(user: &User) -> Result<Option<StatsSnapshot>, String> {
+    if let Some(stats) = fetch_cur_stats_once(user).await? {
+        return Ok(Some(stats));
+    }
+
+    warn!(
+        "Got corrupt top entities from Spotify for user={}; retrying in {:?}...",
+        user.spotify_id, CORRUPT_STATS_RETRY_DELAY
+    );
+    tokio::time::sleep(CORRUPT_STATS_RETRY_DELAY).await;
+    fetch_cur_stats_once(user).await
+}
+
+/// Returns `Ok(None)` if any of the timeframes returned by Spotify were corrupt
+async fn fetch_cur_stats_once(user: &User) -> Result<Option<StatsSnapshot>, String> {
     // Use the user's token to fetch their current stats
     let (tx, mut rx) = channel::<(
         &'static str,
         &'static str,
-        Result<reqwest::Response, String>,
+        Result<Vec<reqwest::Response>, String>,
     )>(6);
 
     // Create tasks for each of the inner requests (we have to make 6; one for each of the three
-    // timeframes, and then that multiplied by each of the two entities (tracks and artists)).
+    // timeframes, and then that multiplied by each of the two entities (tracks and artists)).  If
+    // more entities are fetched than fit in one page, each task fetches its pages in order.
     info!("Kicking off 6 API requests on separate tokio tasks...");
+    let pages = get_top_entities_pages(CONF.top_entity_fetch_count);
     for entity_type in ["tracks", "artists"] {
         for timeframe in ["short", "medium", "long"] {
             let token = user.token.clone();
             let tx = tx.clone();
+            let pages = pages.clone();
 
             tokio::task::spawn(async move {
-                let start = Instant::now();
                 let endpoint_name = match entity_type {
                     "tracks" => "top_tracks",
                     "artists" => "top_artists",
                     _ => unreachable!(),
                 };
-                spotify_api_requests_total(endpoint_name).inc();
 
                 let client = get_reqwest_client().await;
-                let res: Result<reqwest::Response, String> = client
-                    .get(&get_top_entities_url(entity_type, timeframe))
-                    .bearer_auth(token)
-                    .send()
-                    .await
-                    .map_err(|_err| -> String {
-                        "Error requesting latest user stats from the Spotify API".into()
-                    });
-                match &res {
-                    Ok(_) => {
-                        spotify_api_requests_success_total(endpoint_name).inc();
-                        spotify_api_response_time(endpoint_name)
-                            .observe(start.elapsed().as_nanos() as u64);
-                    },
-                    Err(err) => {
-                        spotify_api_requests_failure_total(endpoint_name).inc();
-                        error!("Error fetching top {entity_type} for timeframe {timeframe}: {err}");
-                    },
+                let mut responses = Vec::with_capacity(pages.len());
+                for (limit, offset) in pages {
+                    let start = Instant::now();
+                    spotify_api_requests_total(endpoint_name).inc();
+
+                    let res: Result<reqwest::Response, String> = client
+                        .get(&get_top_entities_url(entity_type, timeframe, limit, offset))
+                        .bearer_auth(&token)
+                        .send()
+                        .await
+                        .map_err(|_err| -> String {
+                            "Error requesting latest user stats from the Spotify API".into()
+                        });
+                    match res {
+                        Ok(res) => {
+                            spotify_api_requests_success_total(endpoint_name).inc();
+                            spotify_api_response_time(endpoint_name)
+                                .observe(start.elapsed().as_nanos() as u64);
+                            responses.push(res);
+                        },
+                        Err(err) => {
+                            spotify_api_requests_failure_total(endpoint_name).inc();
+                            error!(
+                                "Error fetching top {entity_type} for timeframe {timeframe} at \
+                                 offset {offset}: {err}"
+                            );
+                            let _ = tx.send((entity_type, timeframe, Err(err))).await;
+                            return;
+                        },
+                    }
                 }
 
-                let _ = tx.send((entity_type, timeframe, res)).await;
+                let _ = tx.send((entity_type, timeframe, Ok(responses))).await;
             });
         }
     }
 
     let mut stats_snapshot = StatsSnapshot::new(Utc::now().naive_utc());
+    let mut found_corrupt_timeframe = false;
 
-    // Wait for all 6 requests to return back and then
+    // Wait for all 6 requests to return back.  Timeframes that fail to be fetched are left out of
+    // the snapshot rather than failing the whole update.
     info!("Waiting for all 6 inner stats requests to return...");
     for _ in 0..6 {
         match rx.recv().await.unwrap() {
             ("tracks", timeframe, res) => {
-                let res = res?;
-                if res.status() != StatusCode::OK {
+                let top_tracks = match parse_top_tracks_pages(timeframe, res).await {
+                    Ok(top_tracks) => top_tracks,
+                    Err(err) => {
+                        error!("Failed to get top tracks for timeframe {timeframe}: {err}");
+                        stats_timeframe_fetch_failures_total("tracks", timeframe).inc();
+                        continue;
+                    },
+                };
+
+                if is_corrupt_top_entities_timeframe(top_tracks.iter().map(|t| t.id.as_str())) {
                     error!(
-                        "Error fetching top tracks for timeframe {}: got status code {}",
-                        timeframe,
-                        res.status()
+                        "Found mostly duplicate IDs in the top tracks response for timeframe \
+                         {timeframe}; discarding it; user={user:?}"
                     );
-                    if cfg!(debug_assertions) {
-                        error!("Headers: {:?}", res.headers());
-                    }
+                    spotify_corrupt_top_entities_total("tracks").inc();
+                    found_corrupt_timeframe = true;
+                    continue;
                 }
 
-                let parsed_res: TopTracksResponse = if cfg!(debug_assertions) {
-                    let res_text = res.text().await.map_err(|err| -> String {
-                        error!("Error reading top tracks response: {:?}", err);
-                        "Error reading response from Spotify".into()
-                    })?;
-                    serde_json::from_str(&res_text).map_err(|err| -> String {
-                        error!("Error parsing top tracks response; got: {}", res_text);
-                        format!("Error parsing response from Spotify: {:?}", err)
-                    })?
-                } else {
-                    res.json().await.map_err(|err| -> String {
-                        error!("Error parsing top tracks response: {:?}", err);
-                        "Error parsing response from Spotify".into()
-                    })?
-                };
-
-                for top_track in parsed_res.items.into_iter().filter_map(|x| x) {
+                stats_snapshot
+                    .captured_timeframes
+                    .set_captured("tracks", timeframe);
+                for top_track in top_tracks {
                     stats_snapshot.tracks.add_item(timeframe, top_track);
                 }
             },
             ("artists", timeframe, res) => {
-                let parsed_res: TopArtistsResponse =
-                    res?.json().await.map_err(|err| -> String {
-                        error!("Error parsing top artists response: {:?}", err);
-                        "Error parsing response from Spotify".into()
-                    })?;
-
-                if parsed_res
-                    .items
-                    .iter()
-                    .all(|item| item.id == "7ab5IU6f9rBvhgS4kuQjSh")
-                {
+                let top_artists = match parse_top_artists_pages(res).await {
+                    Ok(top_artists) => top_artists,
+                    Err(err) => {
+                        error!("Failed to get top artists for timeframe {timeframe}: {err}");
+                        stats_timeframe_fetch_failures_total("artists", timeframe).inc();
+                        continue;
+                    },
+                };
+
+                if is_corrupt_top_entities_timeframe(
+                    top_artists.iter().map(|artist| artist.id.as_str()),
+                ) {
                     let now_pacific = Utc::now().naive_local();
                     let now_pacific = now_pacific.format("%Y-%m-%d %H:%M:%S").to_string();
                     error!(
-                        "Found the weird buggy artist ID (7ab5IU6f9rBvhgS4kuQjSh) in the top \
-                         tracks response for timeframe {timeframe}; user={user:?}; now={}",
+                        "Found mostly duplicate IDs (probably the weird buggy artist ID \
+                         {BUGGY_TOP_ARTIST_ID}) in the top artists response for timeframe \
+                         {timeframe}; discarding it; user={user:?}; now={}",
                         now_pacific
                     );
+                    spotify_corrupt_top_entities_total("artists").inc();
+                    found_corrupt_timeframe = true;
+                    continue;
                 }
 
-                for top_artist in parsed_res.items.into_iter() {
+                stats_snapshot
+                    .captured_timeframes
+                    .set_captured("artists", timeframe);
+                for top_artist in top_artists {
                     stats_snapshot.artists.add_item(timeframe, top_artist);
                 }
             },
@@ -435,9 +638,95 @@ pub(crate) async fn fetch_cur_stats(user: &User) -> Result<Option<StatsSnapshot>
         }
     }
 
+    if found_corrupt_timeframe {
+        return Ok(None);
+    }
+
+    let captured = &stats_snapshot.captured_timeframes;
+    if captured.is_empty() {
+        return Err("Error fetching user stats from the Spotify API".into());
+    }
+    // The short-term timeframes are the ones that change the most between updates, so there's not
+    // much point in storing an update without them
+    if !captured.is_captured("tracks", "short") || !captured.is_captured("artists", "short") {
+        return Err("Error fetching short-term user stats from the Spotify API".into());
+    }
+    if !captured.is_complete() {
+        warn!(
+            "Only fetched some timeframes for user={}; captured {}",
+            user.spotify_id, captured
+        );
+        partial_stats_snapshots_total().inc();
+    }
+
     Ok(Some(stats_snapshot))
 }
 
+/// Parses and concatenates the pages of a top tracks response in order
+async fn parse_top_tracks_pages(
+    timeframe: &str,
+    pages: Result<Vec<reqwest::Response>, String>,
+) -> Result<Vec<Track>, String> {
+    let mut top_tracks = Vec::new();
+    for res in pages? {
+        top_tracks.extend(parse_top_tracks_response(timeframe, res).await?);
+    }
+    Ok(top_tracks)
+}
+
+async fn parse_top_tracks_response(
+    timeframe: &str,
+    res: reqwest::Response,
+) -> Result<Vec<Track>, String> {
+    if res.status() != StatusCode::OK {
+        error!(
+            "Error fetching top tracks for timeframe {}: got status code {}",
+            timeframe,
+            res.status()
+        );
+        if cfg!(debug_assertions) {
+            error!("Headers: {:?}", res.headers());
+        }
+    }
+
+    let parsed_res: TopTracksResponse = if cfg!(debug_assertions) {
+        let res_text = res.text().await.map_err(|err| -> String {
+            error!("Error reading top tracks response: {:?}", err);
+            "Error reading response from Spotify".into()
+        })?;
+        serde_json::from_str(&res_text).map_err(|err| -> String {
+            error!("Error parsing top tracks response; got: {}", res_text);
+            format!("Error parsing response from Spotify: {:?}", err)
+        })?
+    } else {
+        res.json().await.map_err(|err| -> String {
+            error!("Error parsing top tracks response: {:?}", err);
+            "Error parsing response from Spotify".into()
+        })?
+    };
+
+    Ok(parsed_res.items.into_iter().flatten().collect())
+}
+
+/// Parses and concatenates the pages of a top artists response in order
+async fn parse_top_artists_pages(
+    pages: Result<Vec<reqwest::Response>, String>,
+) -> Result<Vec<Artist>, String> {
+    let mut top_artists = Vec::new();
+    for res in pages? {
+        top_artists.extend(parse_top_artists_response(res).await?);
+    }
+    Ok(top_artists)
+}
+
+async fn parse_top_artists_response(res: reqwest::Response) -> Result<Vec<Artist>, String> {
+    let parsed_res: TopArtistsResponse = res.json().await.map_err(|err| -> String {
+        error!("Error parsing top artists response: {:?}", err);
+        "Error parsing response from Spotify".into()
+    })?;
+    Ok(parsed_res.items)
+}
+
 fn map_timeframe_to_timeframe_id(timeframe: &str) -> u8 {
     match timeframe {
         "short" => 0,
@@ -458,6 +747,7 @@ pub(crate) async fn store_stats_snapshot(
     stats: StatsSnapshot,
 ) -> Result<(), String> {
     let update_time = stats.last_update_time;
+    let captured_timeframes = stats.captured_timeframes;
 
     let genres_by_artist_id: HashMap<String, Vec<String>> = stats
         .artists
@@ -477,9 +767,35 @@ pub(crate) async fn store_stats_snapshot(
     let mapped_artist_spotify_ids =
         crate::db_util::get_internal_ids_by_spotify_id(conn, genres_by_artist_id.keys()).await?;
 
+    // Only top artists include follower counts; the simplified artists attached to tracks don't
+    let followers_by_artist_id: Vec<(i32, u64)> = stats
+        .artists
+        .iter()
+        .flat_map(|(_artist_timeframe, artists)| artists.iter())
+        .filter_map(|artist| {
+            let followers = artist.followers.as_ref()?.total as u64;
+            Some((mapped_artist_spotify_ids[&artist.id], followers))
+        })
+        .collect::<HashMap<_, _>>()
+        .into_iter()
+        .collect();
+    // Follower counts are supplementary, so failing to record them shouldn't prevent the rank
+    // snapshot from being stored
+    if let Err(err) =
+        crate::db_util::record_artist_followers(conn, followers_by_artist_id, update_time.date())
+            .await
+    {
+        error!("Error inserting artist followers history: {:?}", err);
+    }
+
+    // Timeframes that failed to be fetched have no items, but they're skipped explicitly so that
+    // nothing is ever stored for them
     let artist_entries: Vec<NewArtistHistoryEntry> = stats
         .artists
         .into_iter()
+        .filter(|(artist_timeframe, _)| {
+            captured_timeframes.is_captured("artists", artist_timeframe)
+        })
         .flat_map(|(artist_timeframe, artists)| {
             artists
                 .into_iter()
@@ -546,6 +862,22 @@ pub(crate) async fn store_stats_snapshot(
         "Error inserting track/artist metadata into database".into()
     })?;
 
+    let track_album_pairs: Vec<TrackAlbumPair> = stats
+        .tracks
+        .iter()
+        .flat_map(|(_track_timeframe, tracks)| tracks.iter())
+        .map(|track| TrackAlbumPair {
+            track_id: mapped_track_spotify_ids[&track.id],
+            album_spotify_id: track.album.id.clone(),
+        })
+        .collect();
+    crate::db_util::insert_track_album_pairs(conn, track_album_pairs)
+        .await
+        .map_err(|err| -> String {
+            error!("Error inserting track/album mappings: {:?}", err);
+            "Error inserting track/album metadata into database".into()
+        })?;
+
     // Create artist/genre mapping entries for each (artist, genre) pair
     let artist_genre_pairs: Vec<ArtistGenrePair> = genres_by_artist_id
         .into_iter()
@@ -574,6 +906,7 @@ pub(crate) async fn store_stats_snapshot(
     let track_entries: Vec<NewTrackHistoryEntry> = stats
         .tracks
         .into_iter()
+        .filter(|(track_timeframe, _)| captured_timeframes.is_captured("tracks", track_timeframe))
         .flat_map(|(track_timeframe, tracks)| {
             tracks
                 .into_iter()
@@ -624,7 +957,7 @@ async fn fetch_batch_entities<'a, T: for<'de> Deserialize<'de>>(
     token: &str,
     spotify_entity_ids: &[&str],
     endpoint_name: &'static str,
-) -> Result<T, String> {
+) -> Result<T, SpotifyApiError> {
     let url = if base_url.contains('?') {
         base_url.into()
     } else {
@@ -642,48 +975,46 @@ async fn fetch_batch_entities<'a, T: for<'de> Deserialize<'de>>(
             .await
             .map_err(|err| {
                 error!("Error requesting batch data from the Spotify API: {}", err);
-                String::from("Error requesting batch data from the Spotify API")
+                SpotifyApiError::from_reqwest_err(err)
             })?;
 
-        if res.status() == StatusCode::TOO_MANY_REQUESTS {
-            warn!("Rate limited when hitting URL={}", url);
-            spotify_api_requests_rate_limited_total(endpoint_name).inc();
-            tokio::time::sleep(Duration::from_secs(5)).await;
-            continue;
-        }
-
         if res.status().is_success() {
             spotify_api_requests_success_total(endpoint_name).inc();
             spotify_api_response_time(endpoint_name).observe(start.elapsed().as_nanos() as u64);
         } else {
-            error!(
-                "Got bad status code of {} from Spotify API: {:?}",
-                res.status(),
-                res.text().await
-            );
-            spotify_api_requests_failure_total(endpoint_name).inc();
-            return Err("Got bad response from Spotify API".into());
+            match SpotifyApiError::from_response(res).await {
+                SpotifyApiError::RateLimited { retry_after } => {
+                    warn!("Rate limited when hitting URL={}", url);
+                    spotify_api_requests_rate_limited_total(endpoint_name).inc();
+                    tokio::time::sleep(retry_after.unwrap_or(DEFAULT_RATE_LIMIT_RETRY_DELAY)).await;
+                    continue;
+                },
+                err => {
+                    spotify_api_requests_failure_total(endpoint_name).inc();
+                    return Err(err);
+                },
+            }
         }
 
         if cfg!(debug_assertions) {
-            let res = res.text().await.map_err(|err| -> String {
+            let res = res.text().await.map_err(|err| {
                 error!("Error reading response from Spotify API: {:?}", err);
-                "Error reading response from the Spotify API".into()
+                SpotifyApiError::from_reqwest_err(err)
             })?;
-            return serde_json::from_str(&res).map_err(|err| -> String {
+            return serde_json::from_str(&res).map_err(|err| {
                 error!(
                     "Error decoding JSON from Spotify API: {:?}, url={}, res={}",
                     err, url, res
                 );
-                "Error reading data from the Spotify API".into()
+                SpotifyApiError::Decode(err.to_string())
             });
         } else {
-            return res.json().await.map_err(|err| -> String {
+            return res.json().await.map_err(|err| {
                 error!(
                     "Error decoding JSON from Spotify API: {:?}, url={}",
                     err, url
                 );
-                "Error reading data from the Spotify API".into()
+                SpotifyApiError::Decode(err.to_string())
             });
         };
     }
@@ -691,7 +1022,7 @@ async fn fetch_batch_entities<'a, T: for<'de> Deserialize<'de>>(
 
 async fn fetch_with_cache<
     ResponseType: for<'de> Deserialize<'de>,
-    T: Clone + Serialize + for<'de> Deserialize<'de>,
+    T: Clone + Send + Sync + Serialize + for<'de> Deserialize<'de> + 'static,
 >(
     cache_key: &str,
     api_url: &str,
@@ -699,10 +1030,41 @@ async fn fetch_with_cache<
     spotify_access_token: &str,
     spotify_ids: &[&str],
     map_response_to_items: fn(ResponseType) -> Result<Vec<T>, String>,
+    get_item_spotify_id: fn(&T) -> Option<&str>,
 ) -> Result<Vec<T>, String> {
-    // First, try to get as many items as we can from the cache
+    // First, try to get as many items as we can from the in-process cache and then from Redis
     info!("Checking cache for {} spotify ids...", spotify_ids.len());
-    let cache_res = block_in_place(|| crate::cache::get_hash_items::<T>(cache_key, spotify_ids))?;
+    let mut cache_res = get_local_hash_items::<T>(cache_key, spotify_ids);
+    let locally_missing_ids: Vec<&str> = cache_res
+        .iter()
+        .zip(spotify_ids)
+        .filter(|(datum, _)| datum.is_none())
+        .map(|(_, &spotify_id)| spotify_id)
+        .collect();
+    metadata_cache_lookups_total("local", "hit")
+        .inc_by((spotify_ids.len() - locally_missing_ids.len()) as u64);
+    metadata_cache_lookups_total("local", "miss").inc_by(locally_missing_ids.len() as u64);
+
+    if !locally_missing_ids.is_empty() {
+        let redis_res =
+            block_in_place(|| crate::cache::get_hash_items::<T>(cache_key, &locally_missing_ids))?;
+        let redis_hits: Vec<(&str, &T)> = locally_missing_ids
+            .iter()
+            .zip(&redis_res)
+            .filter_map(|(&spotify_id, datum)| Some((spotify_id, datum.as_ref()?)))
+            .collect();
+        metadata_cache_lookups_total("redis", "hit").inc_by(redis_hits.len() as u64);
+        metadata_cache_lookups_total("redis", "miss")
+            .inc_by((redis_res.len() - redis_hits.len()) as u64);
+        set_local_hash_items(cache_key, &redis_hits);
+
+        let mut redis_res = redis_res.into_iter();
+        for datum in &mut cache_res {
+            if datum.is_none() {
+                *datum = redis_res.next().unwrap();
+            }
+        }
+    }
 
     // Fire off a request to Spotify to fill in the missing items
     let mut missing_indices = Vec::new();
@@ -737,14 +1099,34 @@ async fn fetch_with_cache<
             );
         }
 
-        // Update the cache with the missing items
+        // Spotify sometimes returns a different ID than the one we requested for the same entity.
+        // Items are cached under both IDs so that lookups using either of them hit the cache.
+        let aliases = find_spotify_id_aliases(chunk, &fetched_artist_data, get_item_spotify_id);
+        if !aliases.is_empty() {
+            info!(
+                "Spotify returned different IDs than requested: {:?}",
+                aliases
+            );
+        }
+
+        // Update the caches with the missing items
+        let cache_entries = fetched_artist_data
+            .iter()
+            .enumerate()
+            .map(|(i, datum)| (chunk[i], datum))
+            .chain(
+                aliases
+                    .iter()
+                    .map(|&(i, canonical_id)| (canonical_id, &fetched_artist_data[i])),
+            )
+            .collect::<Vec<_>>();
+        set_local_hash_items(cache_key, &cache_entries);
         block_in_place(|| {
-            crate::cache::set_hash_items(
-                cache_key,
-                &fetched_artist_data
+            crate::cache::set_hash_items(cache_key, &cache_entries)?;
+            crate::cache::set_spotify_id_aliases(
+                &aliases
                     .iter()
-                    .enumerate()
-                    .map(|(i, datum)| (chunk[i], datum))
+                    .map(|&(i, canonical_id)| (chunk[i], canonical_id))
                     .collect::<Vec<_>>(),
             )
         })?;
@@ -769,19 +1151,52 @@ async fn fetch_with_cache<
     Ok(combined_results)
 }
 
-pub(crate) async fn fetch_artists(
+/// Returns the index and returned ID of each item whose ID differs from the ID that was requested
+/// for it.  Items are expected to be in the same order as the requested IDs.  Items whose ID
+/// matches a different requested ID are ignored since that indicates that the ordering doesn't line
+/// up rather than an alias.
+fn find_spotify_id_aliases<'a, T>(
+    requested_ids: &[&str],
+    items: &'a [T],
+    get_item_spotify_id: fn(&T) -> Option<&str>,
+) -> Vec<(usize, &'a str)> {
+    items
+        .iter()
+        .enumerate()
+        .filter_map(|(i, item)| {
+            let returned_id = get_item_spotify_id(item)?;
+            let requested_id = *requested_ids.get(i)?;
+            if returned_id == requested_id || requested_ids.contains(&returned_id) {
+                return None;
+            }
+            Some((i, returned_id))
+        })
+        .collect()
+}
+
+/// Fetches artists with all of the image sizes that Spotify provides for them, largest first
+pub(crate) async fn fetch_artists_with_all_images(
     spotify_access_token: &str,
     spotify_ids: &[&str],
 ) -> Result<Vec<Artist>, String> {
-    let mut entities = fetch_with_cache::<SpotifyBatchArtistsResponse, _>(
+    fetch_with_cache::<SpotifyBatchArtistsResponse, _>(
         &CONF.artists_cache_hash_name,
         SPOTIFY_BATCH_ARTISTS_URL,
         "fetch_artists",
         spotify_access_token,
         spotify_ids,
         |res: SpotifyBatchArtistsResponse| Ok(res.artists),
+        |artist| Some(artist.get_spotify_id()),
     )
-    .await?;
+    .await
+}
+
+/// Fetches artists with only their largest image retained
+pub(crate) async fn fetch_artists(
+    spotify_access_token: &str,
+    spotify_ids: &[&str],
+) -> Result<Vec<Artist>, String> {
+    let mut entities = fetch_artists_with_all_images(spotify_access_token, spotify_ids).await?;
 
     for artist in &mut entities {
         if let Some(images) = artist.images.as_mut() {
@@ -805,6 +1220,7 @@ pub(crate) async fn fetch_tracks(
         spotify_access_token,
         spotify_ids,
         |res: SpotifyBatchTracksResponse| Ok(res.tracks),
+        |track| Some(track.get_spotify_id()),
     )
     .await?;
 
@@ -817,6 +1233,31 @@ pub(crate) async fn fetch_tracks(
     Ok(entities)
 }
 
+pub(crate) async fn fetch_albums(
+    spotify_access_token: &str,
+    spotify_ids: &[&str],
+) -> Result<Vec<Album>, String> {
+    // The albums endpoint accepts fewer IDs per request than the others, so requests are split up
+    // here rather than by `fetch_with_cache`
+    let mut albums = Vec::with_capacity(spotify_ids.len());
+    for chunk in spotify_ids.chunks(MAX_BATCH_ALBUM_COUNT) {
+        albums.extend(
+            fetch_with_cache::<SpotifyBatchAlbumsResponse, _>(
+                &CONF.albums_cache_hash_name,
+                SPOTIFY_BATCH_ALBUMS_URL,
+                "fetch_albums",
+                spotify_access_token,
+                chunk,
+                |res: SpotifyBatchAlbumsResponse| Ok(res.albums),
+                |album| Some(album.get_spotify_id()),
+            )
+            .await?,
+        );
+    }
+
+    Ok(albums)
+}
+
 pub(crate) async fn create_playlist(
     bearer_token: &str,
     user: &User,
@@ -882,17 +1323,102 @@ pub(crate) async fn get_related_artists(
     Ok(res.artists)
 }
 
-/// `artist_ids` must not have any duplicates
+/// Max number of related artists requests that are in flight at once
+const RELATED_ARTISTS_CONCURRENCY: usize = 4;
+/// How long to wait for the related artists of a single artist before giving up on it.  This
+/// covers every attempt made by `spotify_user_json_api_get_request` when requests time out, plus
+/// some slack, so that it only kicks in for requests that are stuck in some other way.
+fn related_artists_fetch_timeout() -> Duration {
+    CONF.spotify_api_request_timeout * (MAX_TIMEOUT_RETRIES as u32 + 1) + Duration::from_secs(10)
+}
+
+/// Fetches the related artist IDs for each of `artist_ids` using `fetch`, with at most
+/// `RELATED_ARTISTS_CONCURRENCY` requests in flight at once.  Results are returned in the same
+/// order as `artist_ids`.  Artists that fail to fetch or take longer than `fetch_timeout` are
+/// `None`.
+///
+/// All in-flight requests are owned by the returned future, so they are dropped along with it if
+/// it is cancelled.
+async fn fetch_related_artists_concurrently<'a, F, Fut>(
+    artist_ids: &'a [String],
+    fetch_timeout: Duration,
+    fetch: F,
+) -> Vec<Option<Vec<String>>>
+where
+    F: Fn(&'a str) -> Fut,
+    Fut: std::future::Future<Output = Result<Vec<Artist>, String>>,
+{
+    let fetch = &fetch;
+    let mut fetched = vec![None; artist_ids.len()];
+    let mut pending = artist_ids.iter().enumerate();
+    let mut in_flight = FuturesUnordered::new();
+
+    loop {
+        while in_flight.len() < RELATED_ARTISTS_CONCURRENCY {
+            let (ix, artist_id) = match pending.next() {
+                Some(next) => next,
+                None => break,
+            };
+            let artist_id = artist_id.as_str();
+            in_flight.push(async move {
+                let res = tokio::time::timeout(fetch_timeout, fetch(artist_id)).await;
+                (ix, artist_id, res)
+            });
+        }
+
+        let (ix, artist_id, res) = match in_flight.next().await {
+            Some(res) => res,
+            None => break,
+        };
+        match res {
+            Ok(Ok(related_artists)) =>
+                fetched[ix] = Some(
+                    related_artists
+                        .into_iter()
+                        .map(|artist| artist.id)
+                        .collect(),
+                ),
+            Ok(Err(err)) => error!(
+                "Error fetching related artist for artist_id={}: {:?}",
+                artist_id, err
+            ),
+            Err(_) => error!(
+                "Timed out after {:?} fetching related artists for artist_id={}",
+                fetch_timeout, artist_id
+            ),
+        }
+    }
+
+    fetched
+}
+
+/// `artist_ids` must not have any duplicates.  Artists whose related artists fail to fetch are
+/// given no related artists; use `get_multiple_related_artists_partial` to tell them apart.
 pub(crate) async fn get_multiple_related_artists(
     bearer_token: String,
     artist_ids: &[&str],
 ) -> Result<Vec<Vec<String>>, String> {
+    let related_artists = get_multiple_related_artists_partial(bearer_token, artist_ids).await?;
+    Ok(related_artists
+        .into_iter()
+        .map(Option::unwrap_or_default)
+        .collect())
+}
+
+/// Same as `get_multiple_related_artists`, but artists whose related artists failed to fetch are
+/// `None`.  Failed fetches aren't cached, so they'll be retried by subsequent calls.
+///
+/// `artist_ids` must not have any duplicates
+pub(crate) async fn get_multiple_related_artists_partial(
+    bearer_token: String,
+    artist_ids: &[&str],
+) -> Result<Vec<Option<Vec<String>>>, String> {
     // Pull those from the cache that can be pulled
     let cache_results = block_in_place(|| {
         crate::cache::get_hash_items::<Vec<String>>("related_artists", artist_ids)
     })?;
 
-    let mut output = vec![None; artist_ids.len()];
+    let mut output: Vec<Option<Vec<String>>> = vec![None; artist_ids.len()];
     let mut uncached_ids: Vec<String> = Vec::new();
     for (i, cache_res) in cache_results.into_iter().enumerate() {
         if let Some(related) = cache_res {
@@ -904,75 +1430,12 @@ pub(crate) async fn get_multiple_related_artists(
     }
 
     // Fetch all uncached ids and store in the cache
-    const CONCURRENT_FETCHES: usize = 4;
-    let total_to_fetch = uncached_ids.len();
-    let uncached_ids_clone = uncached_ids.clone();
-    let uncached_ids_clone_2 = uncached_ids_clone.clone();
-    let (tx, rx) = std::sync::mpsc::sync_channel(1);
-
-    let work = Arc::new(Mutex::new(uncached_ids_clone_2));
-
-    for _ in 0..CONCURRENT_FETCHES {
-        let bearer_token = bearer_token.clone();
-        let tx = tx.clone();
-        let work = Arc::clone(&work);
-
-        tokio::task::spawn(async move {
-            loop {
-                let artist_id = match { work.lock().await.pop() } {
-                    Some(id) => id,
-                    None => {
-                        debug!("No more items to fetch, worker exiting");
-                        break;
-                    },
-                };
-
-                let related_artists_res = get_related_artists(&bearer_token, &artist_id).await;
-                if let Err(_) = tx.send((artist_id, related_artists_res)) {
-                    warn!("Receiver dropped; exiting related artists fetch worker");
-                    break;
-                }
-            }
-        });
-    }
-
-    let fetched_results = block_in_place(|| {
-        let mut fetched = vec![Vec::new(); total_to_fetch];
-        let mut fetched_so_far = 0;
-        while fetched_so_far < total_to_fetch {
-            let (artist_id, related_artists) = match rx.recv_timeout(Duration::from_secs(30)) {
-                Ok((artist_id, Ok(res))) => (artist_id, res),
-                Ok((artist_id, Err(err))) => {
-                    error!(
-                        "Error fetching related artist for artist_id={}: {:?}",
-                        artist_id, err
-                    );
-                    (artist_id, Vec::new())
-                },
-                Err(_) => {
-                    error!(
-                        "No response on channel in 30 seconds when fetching related artists; \
-                         giving up"
-                    );
-                    return Err(String::from(
-                        "Error fetching related artists from Spotify API",
-                    ));
-                },
-            };
-            fetched_so_far += 1;
-
-            let ix = uncached_ids_clone
-                .iter()
-                .position(|id| *id == artist_id)
-                .expect("Received artist ID for related artist we didn't ask for");
-            assert!(fetched[ix].is_empty());
-            fetched[ix] = related_artists
-                .into_iter()
-                .map(|artist| artist.id)
-                .collect();
-        }
-        Ok(fetched)
-    })?;
+    let fetched_results = fetch_related_artists_concurrently(
+        &uncached_ids,
+        related_artists_fetch_timeout(),
+        |artist_id| get_related_artists(&bearer_token, artist_id),
+    )
+    .await;
 
     let mut kv_pairs_to_cache: Vec<(&str, Vec<String>)> = Vec::with_capacity(uncached_ids.len());
     for (i, related_artists) in fetched_results.into_iter().enumerate() {
@@ -981,44 +1444,66 @@ pub(crate) async fn get_multiple_related_artists(
             .iter()
             .position(|o_artist_id| *o_artist_id == artist_id.as_str())
             .unwrap();
-        output[output_ix] = Some(related_artists.clone());
+        output[output_ix] = related_artists.clone();
 
-        kv_pairs_to_cache.push((artist_id, related_artists));
+        if let Some(related_artists) = related_artists {
+            kv_pairs_to_cache.push((artist_id, related_artists));
+        }
     }
     block_in_place(|| crate::cache::set_hash_items("related_artists", &kv_pairs_to_cache))?;
 
-    Ok(output
-        .into_iter()
-        .map(|opt| {
-            opt.expect(
-                "All artists must have been filled in at this point by either the cache or \
-                 dynamic fetching",
-            )
-        })
-        .collect())
+    Ok(output)
+}
+
+/// Market used for fetching tracks when the user's market isn't known
+const DEFAULT_MARKET: &str = "us";
+
+/// Returns the lowercased market code if `market` looks like a valid ISO 3166-1 alpha-2 country
+/// code, falling back to `DEFAULT_MARKET` otherwise.
+fn normalize_market(market: Option<&str>) -> String {
+    match market {
+        Some(market) if market.len() == 2 && market.bytes().all(|b| b.is_ascii_alphabetic()) =>
+            market.to_ascii_lowercase(),
+        _ => DEFAULT_MARKET.to_owned(),
+    }
+}
+
+/// Top tracks differ between markets, as do which of them have preview URLs, so they are cached
+/// separately for each market.  The default market's cache key has no market suffix.
+fn get_top_tracks_cache_key(market: &str) -> String {
+    if market == DEFAULT_MARKET {
+        versioned_hash_name("top-tracks")
+    } else {
+        versioned_hash_name(&format!("top-tracks:{}", market))
+    }
 }
 
+/// Fetches the top tracks for the artist in the provided market, defaulting to `DEFAULT_MARKET` if
+/// none is provided.
 pub(crate) async fn fetch_top_tracks_for_artist(
     spotify_access_token: &str,
     artist_spotify_id: &str,
+    market: Option<&str>,
 ) -> Result<Vec<Track>, String> {
     #[derive(Deserialize)]
     struct FetchTopTracksForArtistResponse {
         pub tracks: Vec<Track>,
     }
 
+    let market = normalize_market(market);
     let url = format!(
-        "https://api.spotify.com/v1/artists/{}/top-tracks?market=us",
-        artist_spotify_id
+        "https://api.spotify.com/v1/artists/{}/top-tracks?market={}",
+        artist_spotify_id, market
     );
 
     Ok(fetch_with_cache::<FetchTopTracksForArtistResponse, _>(
-        "top-tracks",
+        &get_top_tracks_cache_key(&market),
         &url,
         "fetch_top_tracks_for_artist",
         spotify_access_token,
         &[artist_spotify_id],
         |res| Ok(vec![res.tracks]),
+        |_| None,
     )
     .await?
     .into_iter()
@@ -1057,14 +1542,273 @@ pub(crate) async fn search_artists(
     let all_spotify_ids = res.artists.items.iter().map(|artist| &artist.id);
     let internal_ids_by_spotify_id = get_internal_ids_by_spotify_id(conn, all_spotify_ids).await?;
 
-    Ok(res
-        .artists
-        .items
+    Ok(build_artist_search_results(
+        res.artists.items,
+        &internal_ids_by_spotify_id,
+    ))
+}
+
+/// Converts artists returned by a Spotify search into search results, ordered by popularity
+/// descending so that the most recognizable match comes first.  Artists without a popularity come
+/// last, and ties keep the order Spotify returned them in.
+fn build_artist_search_results(
+    artists: Vec<Artist>,
+    internal_ids_by_spotify_id: &HashMap<String, i32>,
+) -> Vec<ArtistSearchResult> {
+    let mut results: Vec<ArtistSearchResult> = artists
         .into_iter()
         .map(|artist| ArtistSearchResult {
             internal_id: internal_ids_by_spotify_id.get(&artist.id).copied(),
             spotify_id: artist.id,
             name: artist.name,
+            popularity: artist.popularity,
+            genres: artist.genres.unwrap_or_default(),
         })
-        .collect())
+        .collect();
+    results.sort_by(|a, b| b.popularity.cmp(&a.popularity));
+    results
+}
+
+#[test]
+fn artist_search_results_are_sorted_by_popularity() {
+    let artist = |id: &str, popularity: Option<usize>| Artist {
+        followers: None,
+        genres: Some(vec![format!("{} genre", id)]),
+        id: id.to_owned(),
+        images: None,
+        name: format!("{} name", id),
+        popularity,
+    };
+    let mut internal_ids_by_spotify_id = HashMap::default();
+    internal_ids_by_spotify_id.insert("b".to_owned(), 2);
+
+    let results = build_artist_search_results(
+        vec![
+            artist("a", Some(10)),
+            artist("b", Some(80)),
+            artist("c", None),
+            artist("d", Some(80)),
+        ],
+        &internal_ids_by_spotify_id,
+    );
+
+    let ids: Vec<&str> = results.iter().map(|res| res.spotify_id.as_str()).collect();
+    assert_eq!(ids, vec!["b", "d", "a", "c"]);
+    assert_eq!(results[0].popularity, Some(80));
+    assert_eq!(results[0].internal_id, Some(2));
+    assert_eq!(results[0].genres, vec!["b genre".to_owned()]);
+    assert_eq!(results[3].popularity, None);
+    assert_eq!(results[3].internal_id, None);
+}
+
+#[test]
+fn spotify_id_aliases_are_detected() {
+    let artist = |id: &str| Artist {
+        followers: None,
+        genres: None,
+        id: id.to_owned(),
+        images: None,
+        name: id.to_owned(),
+        popularity: None,
+    };
+    let get_id: fn(&Artist) -> Option<&str> = |artist| Some(artist.get_spotify_id());
+
+    // Spotify answered the request for "b" with the canonical ID "b2"
+    let items = vec![artist("a"), artist("b2"), artist("c")];
+    assert_eq!(
+        find_spotify_id_aliases(&["a", "b", "c"], &items, get_id),
+        vec![(1, "b2")]
+    );
+
+    // Items that match a different requested ID indicate misaligned results, not aliases
+    let items = vec![artist("b"), artist("a")];
+    assert!(find_spotify_id_aliases(&["a", "b"], &items, get_id).is_empty());
+
+    // Entities without IDs can't be aliased
+    assert!(find_spotify_id_aliases(&["a"], &[artist("a2")], |_| None).is_empty());
+}
+
+#[test]
+fn corrupt_top_entities_timeframes_are_detected() {
+    assert!(is_corrupt_top_entities_timeframe(
+        std::iter::repeat(BUGGY_TOP_ARTIST_ID).take(50)
+    ));
+    assert!(is_corrupt_top_entities_timeframe(
+        [BUGGY_TOP_ARTIST_ID].into_iter()
+    ));
+
+    // More than 80% of items sharing an ID is corrupt regardless of which ID it is
+    let mostly_duplicates = ["a"; 9].into_iter().chain(["b"]);
+    assert!(is_corrupt_top_entities_timeframe(mostly_duplicates));
+    let some_duplicates = ["a"; 4].into_iter().chain(["b"]);
+    assert!(!is_corrupt_top_entities_timeframe(some_duplicates));
+
+    assert!(!is_corrupt_top_entities_timeframe(
+        ["a", "b", "c"].into_iter()
+    ));
+    assert!(!is_corrupt_top_entities_timeframe(["a"].into_iter()));
+    assert!(!is_corrupt_top_entities_timeframe(std::iter::empty()));
+}
+
+#[test]
+fn top_entities_are_fetched_in_pages() {
+    assert_eq!(get_top_entities_pages(50), vec![(50, 0)]);
+    assert_eq!(get_top_entities_pages(20), vec![(20, 0)]);
+    assert_eq!(get_top_entities_pages(51), vec![(50, 0), (1, 50)]);
+    assert_eq!(get_top_entities_pages(99), vec![(50, 0), (49, 50)]);
+}
+
+#[tokio::test]
+async fn related_artists_are_fetched_concurrently_in_order() {
+    let in_flight = std::cell::Cell::new(0);
+    let max_in_flight = std::cell::Cell::new(0);
+    let artist_ids: Vec<String> = ["a", "b", "c", "d", "e", "f"]
+        .into_iter()
+        .map(String::from)
+        .collect();
+
+    let fetched =
+        fetch_related_artists_concurrently(&artist_ids, Duration::from_secs(5), |artist_id| {
+            let (in_flight, max_in_flight) = (&in_flight, &max_in_flight);
+            async move {
+                in_flight.set(in_flight.get() + 1);
+                max_in_flight.set(max_in_flight.get().max(in_flight.get()));
+                // Finish out of order to make sure that results are still returned in order
+                for _ in 0..(6 - (artist_id.as_bytes()[0] - b'a') as usize) {
+                    tokio::task::yield_now().await;
+                }
+                in_flight.set(in_flight.get() - 1);
+
+                if artist_id == "b" {
+                    return Err(String::from("Failed to fetch"));
+                }
+                Ok(vec![Artist {
+                    followers: None,
+                    genres: None,
+                    id: format!("{}-related", artist_id),
+                    images: None,
+                    name: String::new(),
+                    popularity: None,
+                }])
+            }
+        })
+        .await;
+
+    assert_eq!(max_in_flight.get(), RELATED_ARTISTS_CONCURRENCY);
+    assert_eq!(fetched, vec![
+        Some(vec!["a-related".to_owned()]),
+        None,
+        Some(vec!["c-related".to_owned()]),
+        Some(vec!["d-related".to_owned()]),
+        Some(vec!["e-related".to_owned()]),
+        Some(vec!["f-related".to_owned()]),
+    ]);
+}
+
+#[tokio::test]
+async fn related_artists_fetches_are_dropped_on_cancellation() {
+    let guard = std::sync::Arc::new(());
+    let started = std::cell::Cell::new(0);
+    let artist_ids: Vec<String> = (0..10).map(|i| i.to_string()).collect();
+
+    let res = tokio::time::timeout(
+        Duration::from_millis(20),
+        fetch_related_artists_concurrently(&artist_ids, Duration::from_secs(5), |_artist_id| {
+            started.set(started.get() + 1);
+            let guard = std::sync::Arc::clone(&guard);
+            async move {
+                let _guard = guard;
+                futures::future::pending::<Result<Vec<Artist>, String>>().await
+            }
+        }),
+    )
+    .await;
+
+    assert!(res.is_err());
+    assert_eq!(started.get(), RELATED_ARTISTS_CONCURRENCY);
+    // All in-flight fetches were dropped along with the cancelled future
+    assert_eq!(std::sync::Arc::strong_count(&guard), 1);
+}
+
+#[tokio::test]
+async fn related_artists_fetch_timeouts_only_affect_the_timed_out_artist() {
+    let artist_ids: Vec<String> = ["a", "b", "c"].into_iter().map(String::from).collect();
+
+    let fetched = fetch_related_artists_concurrently(
+        &artist_ids,
+        Duration::from_millis(20),
+        |artist_id| async move {
+            if artist_id == "b" {
+                return futures::future::pending().await;
+            }
+            Ok(vec![Artist {
+                followers: None,
+                genres: None,
+                id: format!("{}-related", artist_id),
+                images: None,
+                name: String::new(),
+                popularity: None,
+            }])
+        },
+    )
+    .await;
+
+    assert_eq!(fetched, vec![
+        Some(vec!["a-related".to_owned()]),
+        None,
+        Some(vec!["c-related".to_owned()]),
+    ]);
+}
+
+#[tokio::test]
+async fn reqwest_client_requests_time_out() {
+    let client = build_reqwest_client_with_timeouts(
+        Duration::from_millis(200),
+        Duration::from_millis(500),
+        Duration::from_secs(1),
+    );
+
+    // Connections are queued by the OS but the request is never read or responded to
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+
+    let start = Instant::now();
+    let res = client.get(&url).send().await;
+    assert!(res.unwrap_err().is_timeout());
+    assert!(start.elapsed() < Duration::from_secs(2));
+}
+
+#[test]
+fn retry_after_headers_are_parsed() {
+    let mut headers = reqwest::header::HeaderMap::new();
+    assert_eq!(parse_retry_after(&headers), None);
+
+    headers.insert(reqwest::header::RETRY_AFTER, "7".parse().unwrap());
+    assert_eq!(parse_retry_after(&headers), Some(Duration::from_secs(7)));
+
+    // HTTP dates aren't sent by Spotify, so they're ignored in favor of the default delay
+    headers.insert(
+        reqwest::header::RETRY_AFTER,
+        "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+    );
+    assert_eq!(parse_retry_after(&headers), None);
+}
+
+#[test]
+fn top_tracks_markets_are_normalized() {
+    assert_eq!(normalize_market(None), "us");
+    assert_eq!(normalize_market(Some("DE")), "de");
+    assert_eq!(normalize_market(Some("gb")), "gb");
+    assert_eq!(normalize_market(Some("")), "us");
+    assert_eq!(normalize_market(Some("usa")), "us");
+    assert_eq!(normalize_market(Some("u&")), "us");
+
+    assert_eq!(
+        get_top_tracks_cache_key("us"),
+        versioned_hash_name("top-tracks")
+    );
+    assert_eq!(
+        get_top_tracks_cache_key("de"),
+        versioned_hash_name("top-tracks:de")
+    );
 }
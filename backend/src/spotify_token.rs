@@ -1,52 +1,118 @@
 use chrono;
+use futures::Future;
+use tokio::sync::RwLock;
 
+struct TokenState {
+    token: String,
+    expiry: chrono::DateTime<chrono::Local>,
+}
+
+impl TokenState {
+    fn is_expired(&self) -> bool { chrono::Local::now() > self.expiry }
+}
+
+/// Holds the client credentials token used for Spotify API requests that aren't made on behalf of
+/// a user.  Reading a still-valid token only takes a read lock so that concurrent requests don't
+/// serialize on it; the write lock is only taken when the token needs to be refreshed.
 pub(crate) struct SpotifyTokenData {
-    pub token: String,
-    pub expiry: chrono::DateTime<chrono::Local>,
+    state: RwLock<TokenState>,
+}
+
+async fn fetch_token_state() -> Result<TokenState, String> {
+    let crate::models::AccessTokenResponse {
+        access_token,
+        expires_in,
+        ..
+    } = crate::spotify_api::fetch_auth_token().await?;
+    info!(
+        "Got new Spotify access token; expires in: {} seconds",
+        expires_in
+    );
+    let expiry = chrono::Local::now() + chrono::Duration::seconds((expires_in as i64) - 10);
+    info!("Current Spotify access token is good until {}", expiry);
+
+    Ok(TokenState {
+        token: access_token,
+        expiry,
+    })
 }
 
 impl SpotifyTokenData {
     #[allow(clippy::new_without_default)]
     pub(crate) async fn new() -> Self {
-        let mut s = SpotifyTokenData {
-            token: "".into(),
-            expiry: chrono::Local::now(),
-        };
-        s.refresh()
+        let state = fetch_token_state()
             .await
             .expect("Failed to fetch initial spotify token for Rocket managed state");
-        s
+        SpotifyTokenData {
+            state: RwLock::new(state),
+        }
     }
 
-    pub(crate) async fn refresh(&mut self) -> Result<(), String> {
-        let crate::models::AccessTokenResponse {
-            access_token,
-            expires_in,
-            ..
-        } = crate::spotify_api::fetch_auth_token().await?;
-        self.token = access_token;
-        info!(
-            "Got new Spotify access token; expires in: {} seconds",
-            expires_in
-        );
-        self.expiry = chrono::Local::now() + chrono::Duration::seconds((expires_in as i64) - 10);
-        info!("Current Spotify access token is good until {}", self.expiry);
-        Ok(())
+    pub(crate) async fn get(&self) -> Result<String, String> {
+        self.get_or_refresh_with(fetch_token_state).await
     }
 
-    pub(crate) async fn get(&mut self) -> Result<String, String> {
-        let now = chrono::Local::now();
-        if now > self.expiry {
+    async fn get_or_refresh_with<F: Future<Output = Result<TokenState, String>>>(
+        &self,
+        refresh: impl FnOnce() -> F,
+    ) -> Result<String, String> {
+        {
+            let state = self.state.read().await;
+            if !state.is_expired() {
+                return Ok(state.token.clone());
+            }
+        }
+
+        let mut state = self.state.write().await;
+        // Another request may have already refreshed the token while we were waiting for the lock
+        if state.is_expired() {
             info!(
                 "Current token expired at {} (it's {} now); refreshing...",
-                self.expiry, now
+                state.expiry,
+                chrono::Local::now()
             );
-            self.refresh().await?;
+            *state = refresh().await?;
         }
-        info!(
-            "Current token doesn't expire until {} and is still valid.",
-            self.expiry
-        );
-        Ok(self.token.clone())
+        Ok(state.token.clone())
+    }
+}
+
+#[tokio::test]
+async fn expired_token_is_only_refreshed_once() {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    let token_data = Arc::new(SpotifyTokenData {
+        state: RwLock::new(TokenState {
+            token: "old".into(),
+            expiry: chrono::Local::now() - chrono::Duration::seconds(1),
+        }),
+    });
+    let refresh_count = Arc::new(AtomicUsize::new(0));
+
+    let handles = (0..8)
+        .map(|_| {
+            let token_data = Arc::clone(&token_data);
+            let refresh_count = Arc::clone(&refresh_count);
+            tokio::spawn(async move {
+                token_data
+                    .get_or_refresh_with(|| async move {
+                        refresh_count.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                        Ok(TokenState {
+                            token: "new".into(),
+                            expiry: chrono::Local::now() + chrono::Duration::seconds(60),
+                        })
+                    })
+                    .await
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for handle in handles {
+        assert_eq!(handle.await.unwrap(), Ok("new".to_owned()));
     }
+    assert_eq!(refresh_count.load(Ordering::SeqCst), 1);
 }
@@ -3,12 +3,22 @@ use std::cmp::Reverse;
 use chrono::NaiveDateTime;
 use fnv::{FnvHashMap as HashMap, FnvHashSet as HashSet};
 
-use crate::models::{Artist, TimeFrames};
+use crate::models::{
+    AlbumRankQueryResItem, AlbumTrackCount, Artist, GenreBreakdownItem, GenreRankQueryResItem,
+    TimeFrames,
+};
 
 /// This is a pretty arbitrary algorithm with the goal of assigning a score to an item based on how
 /// many total items there are and the item's rank in the collection.  It is used to construct the
 /// genres treemap on the frontend.
+///
+/// Items ranked at or beyond `total_items` get a score of zero.  Scores are computed out of 50
+/// items in some places, and users may have up to 99 items stored for each timeframe.
 fn weight_data_point(total_items: usize, ranking: usize) -> usize {
+    if ranking >= total_items {
+        return 0;
+    }
+
     (((total_items - ranking) as f32)
         .powf(2.7 * ((total_items - ranking) as f32 / total_items as f32))) as usize
 }
@@ -65,6 +75,97 @@ pub(crate) fn get_top_genres_by_artists(
     (all_timestamps, counts_by_genre)
 }
 
+/// Computes the number of artists and a rank-weighted score for each genre in each timeframe of a
+/// single update.  Genres are sorted by score descending.
+pub(crate) fn compute_genre_breakdown(
+    items: &[GenreRankQueryResItem],
+) -> TimeFrames<GenreBreakdownItem> {
+    let mut artists_by_timeframe: [HashSet<i32>; 3] = Default::default();
+    for item in items {
+        artists_by_timeframe[item.timeframe as usize].insert(item.mapped_spotify_id);
+    }
+
+    let mut counts_by_timeframe: [HashMap<&str, (usize, usize)>; 3] = Default::default();
+    for item in items {
+        let genre = match &item.genre {
+            Some(genre) => genre,
+            None => continue,
+        };
+        let total_items = artists_by_timeframe[item.timeframe as usize].len();
+        // Rankings are stored separately for each timeframe, so guard against gaps
+        let ranking = (item.ranking as usize).min(total_items - 1);
+
+        let (artist_count, score) = counts_by_timeframe[item.timeframe as usize]
+            .entry(genre.as_str())
+            .or_insert((0, 0));
+        *artist_count += 1;
+        *score += weight_data_point(total_items, ranking);
+    }
+
+    let mut breakdown = TimeFrames::default();
+    for (timeframe_id, counts_by_genre) in counts_by_timeframe.into_iter().enumerate() {
+        let mut items: Vec<GenreBreakdownItem> = counts_by_genre
+            .into_iter()
+            .map(|(genre, (artist_count, score))| GenreBreakdownItem {
+                genre: genre.to_owned(),
+                artist_count,
+                score,
+            })
+            .collect();
+        items.sort_unstable_by(|a, b| b.score.cmp(&a.score).then_with(|| a.genre.cmp(&b.genre)));
+
+        for item in items {
+            breakdown.add_item_by_id(timeframe_id as u8, item);
+        }
+    }
+    breakdown
+}
+
+/// Aggregates the tracks in a user's current top tracks by album, counting the distinct tracks from
+/// each album and finding the best ranking any of them has in each timeframe.  Tracks without a
+/// known album are skipped, as are albums with fewer than `min_track_count` tracks.  Sorted by
+/// track count and then by best ranking across timeframes.
+pub(crate) fn compute_album_track_counts(
+    items: &[AlbumRankQueryResItem],
+    min_track_count: usize,
+) -> Vec<AlbumTrackCount> {
+    let mut tracks_by_album: HashMap<&str, (HashSet<i32>, [Option<u8>; 3])> = HashMap::default();
+    for item in items {
+        let album_spotify_id = match &item.album_spotify_id {
+            Some(album_spotify_id) => album_spotify_id,
+            None => continue,
+        };
+        let (track_ids, best_ranks) = tracks_by_album
+            .entry(album_spotify_id.as_str())
+            .or_insert_with(Default::default);
+        track_ids.insert(item.mapped_spotify_id);
+        let best_rank = &mut best_ranks[item.timeframe as usize];
+        *best_rank = Some(best_rank.map_or(item.ranking, |rank| rank.min(item.ranking)));
+    }
+
+    let mut counts: Vec<AlbumTrackCount> = tracks_by_album
+        .into_iter()
+        .filter(|(_, (track_ids, _))| track_ids.len() >= min_track_count)
+        .map(
+            |(album_spotify_id, (track_ids, best_ranks))| AlbumTrackCount {
+                album_spotify_id: album_spotify_id.to_owned(),
+                track_count: track_ids.len(),
+                best_ranks,
+            },
+        )
+        .collect();
+    // Every album has at least one track, so it has a ranking in at least one timeframe
+    let best_overall_rank =
+        |count: &AlbumTrackCount| count.best_ranks.iter().flatten().min().copied();
+    counts.sort_unstable_by(|a, b| {
+        b.track_count
+            .cmp(&a.track_count)
+            .then_with(|| best_overall_rank(a).cmp(&best_overall_rank(b)))
+            .then_with(|| a.album_spotify_id.cmp(&b.album_spotify_id))
+    });
+    counts
+}
+
 /// Gets a list of all tracks for a given artist that a user has ever had in their top tracks for
 /// any time period, sorted by their frequency of appearance and ranking when appeared.
 pub(crate) fn compute_track_popularity_scores(
@@ -89,21 +190,56 @@ pub(crate) fn compute_track_popularity_scores(
     top_tracks
 }
 
+/// Multipliers applied to artists' genre ranking scores depending on the timeframe they were ranked
+/// in.  Appearing in a longer timeframe reflects more sustained affinity than a one-off appearance
+/// in the short timeframe, so longer timeframes are weighted more heavily by default.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct TimeframeWeights {
+    pub short: f32,
+    pub medium: f32,
+    pub long: f32,
+}
+
+impl Default for TimeframeWeights {
+    fn default() -> Self {
+        TimeframeWeights {
+            short: 1.,
+            medium: 1.5,
+            long: 2.,
+        }
+    }
+}
+
+impl TimeframeWeights {
+    fn get(&self, timeframe: &str) -> f32 {
+        match timeframe {
+            "short" => self.short,
+            "medium" => self.medium,
+            "long" => self.long,
+            _ => panic!("Invalid timeframe passed to `TimeframeWeights::get`"),
+        }
+    }
+}
+
 pub(crate) fn compute_genre_ranking_history(
     updates: Vec<(NaiveDateTime, TimeFrames<crate::db_util::ArtistRanking>)>,
+    timeframe_weights: TimeframeWeights,
 ) -> (Vec<NaiveDateTime>, Vec<(String, f32)>, TimeFrames<usize>) {
     let timestamps: Vec<NaiveDateTime> = updates.iter().map(|(ts, _)| ts.clone()).collect();
 
     // Compute rankings for each artist within the genre according to its cumulative score based
-    // off of ranking, scaling back linearly as updates get older.  We may want to re-think this
-    // ranking strategy in the future.
+    // off of ranking and timeframe, scaling back linearly as updates get older.  We may want to
+    // re-think this ranking strategy in the future.
     let update_count = updates.len();
     let mut rankings_by_artist_spotify_id: HashMap<String, f32> = HashMap::default();
     for (i, (_ts, timeframes)) in updates.iter().enumerate() {
-        for (_timeframe, rankings) in timeframes.iter() {
+        for (timeframe, rankings) in timeframes.iter() {
+            let timeframe_weight = timeframe_weights.get(timeframe);
             for ranking in rankings {
                 let recency_factor = ((i + 1) as f32) / (update_count as f32);
-                let score = weight_data_point(50, ranking.ranking as usize) as f32 * recency_factor;
+                let score = weight_data_point(50, ranking.ranking as usize) as f32
+                    * recency_factor
+                    * timeframe_weight;
 
                 let entry = rankings_by_artist_spotify_id
                     .entry(ranking.artist_spotify_id.clone())
@@ -130,3 +266,98 @@ pub(crate) fn compute_genre_ranking_history(
 
     (timestamps, artist_rankings, popularity_history)
 }
+
+#[test]
+fn genre_ranking_weights_timeframes() {
+    use crate::db_util::ArtistRanking;
+
+    let ranking = |artist_spotify_id: &str, ranking: u8| ArtistRanking {
+        artist_spotify_id: artist_spotify_id.to_owned(),
+        ranking,
+    };
+    let build_updates = || {
+        let ts = |day: u32| chrono::NaiveDate::from_ymd(2021, 1, day).and_hms(0, 0, 0);
+        vec![
+            (ts(1), TimeFrames {
+                short: vec![ranking("one_off", 0)],
+                medium: Vec::new(),
+                long: vec![ranking("sustained", 0)],
+            }),
+            (ts(2), TimeFrames {
+                short: vec![ranking("one_off", 0)],
+                medium: Vec::new(),
+                long: vec![ranking("sustained", 0)],
+            }),
+        ]
+    };
+
+    // With equal weights, the artists have identical histories and so identical scores
+    let equal_weights = TimeframeWeights {
+        short: 1.,
+        medium: 1.,
+        long: 1.,
+    };
+    let (_, equal_rankings, equal_popularity_history) =
+        compute_genre_ranking_history(build_updates(), equal_weights);
+    assert_eq!(equal_rankings[0].1, equal_rankings[1].1);
+
+    let (timestamps, rankings, popularity_history) =
+        compute_genre_ranking_history(build_updates(), TimeframeWeights::default());
+    assert_eq!(timestamps.len(), 2);
+    assert_eq!(rankings[0].0, "sustained");
+    assert_eq!(rankings[1].0, "one_off");
+    assert!(rankings[0].1 > rankings[1].1);
+
+    // Popularity history is reported per-timeframe and isn't affected by the weights
+    assert_eq!(popularity_history.short, equal_popularity_history.short);
+    assert_eq!(popularity_history.long, equal_popularity_history.long);
+    assert_eq!(popularity_history.short, popularity_history.long);
+    assert_eq!(popularity_history.medium, vec![0, 0]);
+}
+
+#[test]
+fn rankings_past_total_items_are_weighted_zero() {
+    assert!(weight_data_point(50, 0) > weight_data_point(50, 49));
+    assert!(weight_data_point(50, 49) > 0);
+    assert_eq!(weight_data_point(50, 50), 0);
+    assert_eq!(weight_data_point(50, 98), 0);
+}
+
+#[test]
+fn album_track_counts_are_aggregated() {
+    let item =
+        |track_id: i32, album: Option<&str>, timeframe: u8, ranking: u8| AlbumRankQueryResItem {
+            mapped_spotify_id: track_id,
+            track_spotify_id: format!("track{}", track_id),
+            album_spotify_id: album.map(String::from),
+            timeframe,
+            ranking,
+        };
+    let items = vec![
+        item(1, Some("a"), 0, 4),
+        item(1, Some("a"), 2, 1),
+        item(2, Some("a"), 0, 2),
+        item(3, Some("b"), 1, 0),
+        item(4, Some("b"), 1, 7),
+        item(5, Some("c"), 0, 0),
+        item(6, None, 0, 1),
+    ];
+
+    let counts = compute_album_track_counts(&items, 2);
+    assert_eq!(counts, vec![
+        AlbumTrackCount {
+            album_spotify_id: "b".to_owned(),
+            track_count: 2,
+            best_ranks: [None, Some(0), None],
+        },
+        AlbumTrackCount {
+            album_spotify_id: "a".to_owned(),
+            track_count: 2,
+            best_ranks: [Some(2), None, Some(1)],
+        },
+    ]);
+
+    let counts = compute_album_track_counts(&items, 1);
+    assert_eq!(counts.len(), 3);
+    assert_eq!(counts[2].album_spotify_id, "c");
+}
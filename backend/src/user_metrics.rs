@@ -0,0 +1,97 @@
+//! Periodically samples aggregate user counts from the database and exports them as gauges.
+//!
+//! Sampling runs on a connection checked out directly from the pool rather than as part of a
+//! request.  Each pass is bounded by a timeout and the query itself is capped server-side, so a
+//! slow database can't cause sampling passes to pile up.
+
+use std::time::Duration;
+
+use chrono::Utc;
+use diesel::{prelude::*, sql_types::Datetime, MysqlConnection};
+use rocket::{fairing::AdHoc, Orbit, Rocket};
+use rocket_sync_db_pools::ConnectionPool;
+
+use crate::{
+    conf::dynamic_conf,
+    metrics::{
+        users_overdue_for_update, users_total, users_updated_last_day, users_with_external_data,
+    },
+    models::UserCountsQueryResItem,
+    shutdown::wait_for_shutdown,
+    DbConn,
+};
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+const SAMPLE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Users whose last update is further than this many multiples of the minimum update interval in
+/// the past are considered overdue.
+const OVERDUE_UPDATE_INTERVAL_MULTIPLIER: i32 = 4;
+
+fn query_user_counts(conn: &mut MysqlConnection) -> QueryResult<UserCountsQueryResItem> {
+    let now = Utc::now().naive_utc();
+    let recently_updated_cutoff = now - chrono::Duration::days(1);
+    let overdue_cutoff =
+        now - dynamic_conf().min_update_interval * OVERDUE_UPDATE_INTERVAL_MULTIPLIER;
+
+    diesel::sql_query(
+        r#"
+            SELECT /*+ MAX_EXECUTION_TIME(10000) */
+                COUNT(*) AS `total_users`,
+                COUNT(CASE WHEN `last_update_time` >= ? THEN 1 END) AS `recently_updated_users`,
+                COUNT(CASE WHEN `last_update_time` < ? THEN 1 END) AS `overdue_users`,
+                COUNT(CASE WHEN `external_data_retrieved` = FALSE THEN 1 END)
+                    AS `external_data_users`
+            FROM `users`
+        "#,
+    )
+    .bind::<Datetime, _>(recently_updated_cutoff)
+    .bind::<Datetime, _>(overdue_cutoff)
+    .get_result(conn)
+}
+
+async fn sample_user_counts(pool: &ConnectionPool<DbConn, MysqlConnection>) -> Result<(), String> {
+    let conn = pool
+        .get()
+        .await
+        .ok_or_else(|| "Failed to check out a database connection".to_owned())?;
+    let counts = conn
+        .run(query_user_counts)
+        .await
+        .map_err(|err| format!("Error querying user counts: {:?}", err))?;
+
+    users_total().set(counts.total_users);
+    users_updated_last_day().set(counts.recently_updated_users);
+    users_overdue_for_update().set(counts.overdue_users);
+    users_with_external_data().set(counts.external_data_users);
+    Ok(())
+}
+
+async fn run_user_metrics_sampler(pool: ConnectionPool<DbConn, MysqlConnection>) {
+    loop {
+        match tokio::time::timeout(SAMPLE_TIMEOUT, sample_user_counts(&pool)).await {
+            Ok(Ok(())) => (),
+            Ok(Err(err)) => error!("Error sampling user metrics: {}", err),
+            Err(_) => error!("Timed out sampling user metrics"),
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(SAMPLE_INTERVAL) => (),
+            _ = wait_for_shutdown() => return,
+        }
+    }
+}
+
+/// Starts the user metrics sampler once the server has launched and the database pool exists.
+pub(crate) fn user_metrics_fairing() -> AdHoc {
+    AdHoc::on_liftoff("User Metrics Sampler", |rocket: &Rocket<Orbit>| {
+        Box::pin(async move {
+            match DbConn::pool(rocket) {
+                Some(pool) => {
+                    tokio::task::spawn(run_user_metrics_sampler(pool.clone()));
+                },
+                None => error!("Database pool not initialized; user metrics won't be sampled"),
+            }
+        })
+    })
+}
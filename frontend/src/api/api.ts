@@ -2,7 +2,14 @@ import dayjs from 'dayjs';
 
 import { API_BASE_URL } from 'src/conf';
 import { getSentry } from 'src/sentry';
-import { Artist, RelatedArtistsGraphRes, TimeFrames, TimelineData, Track } from 'src/types';
+import {
+  Artist,
+  Image,
+  RelatedArtistsGraphRes,
+  TimeFrames,
+  TimelineData,
+  Track,
+} from 'src/types';
 
 export const getUrl = (path: string) => `${API_BASE_URL}${path}`;
 
@@ -29,6 +36,29 @@ export const fetchUserStats = (username: string) =>
     artists: TimeFrames<Artist>;
   } | null>(getUrl(`/stats/${username}`));
 
+/**
+ * Returns the user's stats snapshot closest to `date` (formatted like `2021-03-14`), or `null` if
+ * they have no snapshots within two weeks of it.
+ */
+export const fetchUserStatsSnapshot = (username: string, date: string) =>
+  getJsonEndpoint<{
+    last_update_time: string;
+    tracks: TimeFrames<Track>;
+    artists: TimeFrames<Artist>;
+  } | null>(getUrl(`/stats/${username}/snapshot?date=${encodeURIComponent(date)}`));
+
+/**
+ * Returns the user's single top artist, track, and genre for each timeframe.  Each timeframe has at
+ * most one item.
+ */
+export const fetchUserStatsSummary = (username: string) =>
+  getJsonEndpoint<{
+    last_update_time: string;
+    top_artist: TimeFrames<Artist>;
+    top_track: TimeFrames<Track>;
+    top_genre: TimeFrames<string>;
+  } | null>(getUrl(`/stats/${username}/summary`));
+
 export const fetchArtistStats = (
   username: string,
   artistId: string
@@ -39,9 +69,79 @@ export const fetchArtistStats = (
   tracks_by_id: { [trackId: string]: Track };
 } | null> => getJsonEndpoint(getUrl(`/stats/${username}/artist/${artistId}`));
 
+/**
+ * Returns the rank history of each of the provided artists in the same order, with `null` for
+ * artists that have never been in the user's top artists.  At most 20 artists can be requested.
+ */
+export const fetchArtistRankHistories = async (
+  username: string,
+  artistIDs: string[]
+): Promise<([string, [number | null, number | null, number | null]][] | null)[] | null> => {
+  const res = await fetch(getUrl(`/stats/${username}/artist_rank_histories`), {
+    method: 'POST',
+    headers: { 'Content-Type': 'application/json' },
+    body: JSON.stringify(artistIDs),
+  });
+  if (res.status === 404) {
+    return null;
+  } else if (!res.ok) {
+    throw await res.text();
+  }
+  return res.json();
+};
+
 export const fetchGenreHistory = (username: string) =>
   getJsonEndpoint(getUrl(`/stats/${username}/genre_history`));
 
+export interface GenreBreakdownItem {
+  genre: string;
+  artist_count: number;
+  score: number;
+}
+
+export const fetchGenreBreakdown = (username: string) =>
+  getJsonEndpoint<{
+    last_update_time: string;
+    genres: TimeFrames<GenreBreakdownItem>;
+  }>(getUrl(`/stats/${username}/genres`));
+
+/**
+ * Returns `[genre, artistCount]` pairs for every genre the user has ever had in their top artists,
+ * sorted by artist count descending.
+ */
+export const fetchAllGenres = (username: string) =>
+  getJsonEndpoint<[string, number][]>(getUrl(`/stats/${username}/all_genres`));
+
+/**
+ * Returns artists that the user saw in their top artists for the first time on or after
+ * `sinceDayID` (formatted like `2021-03-14`), most recently discovered first.
+ */
+export const fetchNewArtists = (username: string, sinceDayID: string, limit?: number) =>
+  getJsonEndpoint<{ artist: Artist; first_seen: string }[] | null>(
+    getUrl(
+      `/stats/${username}/new_artists?since_day_id=${encodeURIComponent(sinceDayID)}${
+        limit === undefined ? '' : `&limit=${limit}`
+      }`
+    )
+  );
+
+export interface AlbumStatsItem {
+  album: {
+    artists: { name: string; id: string }[];
+    id: string;
+    images: Image[];
+    name: string;
+  };
+  track_count: number;
+  /** Best ranking of any of the album's tracks in the short, medium, and long timeframes */
+  best_ranks: [number | null, number | null, number | null];
+}
+
+export const fetchAlbumStats = (username: string) =>
+  getJsonEndpoint<{ last_update_time: string; albums: AlbumStatsItem[] }>(
+    getUrl(`/stats/${username}/albums`)
+  );
+
 export const fetchTimelineEvents = async (username: string | null, startOfCurMonthS: string) => {
   if (!username) {
     return null;
@@ -60,6 +160,11 @@ export const fetchTimelineEvents = async (username: string | null, startOfCurMon
   );
 };
 
+export interface UserComparisonDataStatus {
+  stored_track_count: number;
+  stored_artist_count: number;
+}
+
 export const fetchComparison = (
   user1: string,
   user2: string
@@ -68,8 +173,24 @@ export const fetchComparison = (
   tracks: Track[];
   user1_username: string;
   user2_username: string;
+  user1_data_status: UserComparisonDataStatus;
+  user2_data_status: UserComparisonDataStatus;
 } | null> => getJsonEndpoint(getUrl(`/compare/${user1}/${user2}`));
 
+/**
+ * Returns when each user first had the artist in their top artists (`null` if they never did) and
+ * which of them had it first.
+ */
+export const fetchWhoFirst = (
+  user1: string,
+  user2: string,
+  artistID: string
+): Promise<{
+  user1_first_seen: string | null;
+  user2_first_seen: string | null;
+  first: 'user1' | 'user2' | 'same_day' | null;
+} | null> => getJsonEndpoint(getUrl(`/compare/${user1}/${user2}/who_first/${artistID}`));
+
 export const fetchRelatedArtistsForUser = async (
   userID: string
 ): Promise<RelatedArtistsGraphRes | null> => {
@@ -93,6 +214,24 @@ export const fetchRelatedArtists = async (
   return getJsonEndpoint(url);
 };
 
+export interface NeighborhoodArtist {
+  id: string;
+  name: string;
+  /** Position in the artist embedding, or `null` if the artist isn't in it */
+  position: number[] | null;
+  popularity: number | null;
+  source: 'embedding' | 'related' | 'both';
+}
+
+/**
+ * Returns the union of the artist's Spotify related artists and its `count` nearest neighbors in
+ * the artist embedding, tagged with where each came from.
+ */
+export const fetchArtistNeighborhood = (artistID: string, count?: number) =>
+  getJsonEndpoint<{ artists: NeighborhoodArtist[] }>(
+    getUrl(`/artist_neighborhood/${artistID}${count === undefined ? '' : `?count=${count}`}`)
+  );
+
 export const getUserDisplayName = async (username: string): Promise<string> => {
   const res = await fetch(getUrl(`/display_name/${username}`)).then(async (res) => {
     if (!res.ok) {
@@ -107,3 +246,23 @@ export const getUserDisplayName = async (username: string): Promise<string> => {
   }
   return res;
 };
+
+export const getUserExists = (username: string): Promise<boolean> =>
+  getJsonEndpoint<boolean>(getUrl(`/user_exists/${username}`)).then((exists) => !!exists);
+
+/**
+ * Returns a map of username to display name.  Users that don't exist are omitted.
+ */
+export const getUserDisplayNames = async (
+  usernames: string[]
+): Promise<Record<string, string>> => {
+  const res = await fetch(getUrl('/display_names'), {
+    method: 'POST',
+    headers: { 'Content-Type': 'application/json' },
+    body: JSON.stringify(usernames),
+  });
+  if (!res.ok) {
+    throw await res.text();
+  }
+  return res.json();
+};
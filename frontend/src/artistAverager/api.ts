@@ -34,5 +34,44 @@ export const getAverageArtists = (
     res.json()
   );
 
+export interface PackedAverageArtists {
+  internalIDs: Uint32Array;
+  /**
+   * Flattened `[similarityToTargetPoint, similarityToArtist1, similarityToArtist2]` triples for
+   * each artist
+   */
+  similarities: Float32Array;
+  spotifyIDs: string[];
+}
+
+/**
+ * Fetches averaged artists in a compact binary format containing just IDs and similarities, for
+ * uses that don't need full artist metadata or top tracks.
+ */
+export const getPackedAverageArtists = async (
+  artist1SpotifyID: string,
+  artist2SpotifyID: string,
+  count?: number
+): Promise<PackedAverageArtists> => {
+  const url = `${API_BASE_URL}/average_artists_packed/${artist1SpotifyID}/${artist2SpotifyID}${
+    count === undefined ? '' : `?count=${count}`
+  }`;
+  const res = await fetch(url);
+  if (!res.ok) {
+    throw await res.text();
+  }
+  const packed = await res.arrayBuffer();
+
+  const artistCount = new Uint32Array(packed, 0, 1)[0];
+  const internalIDs = new Uint32Array(packed, 4, artistCount);
+  const similarities = new Float32Array(packed, 4 + artistCount * 4, artistCount * 3);
+  const spotifyIDsStr = new TextDecoder().decode(new Uint8Array(packed, 4 + artistCount * 16));
+  return {
+    internalIDs,
+    similarities,
+    spotifyIDs: spotifyIDsStr ? spotifyIDsStr.split(',') : [],
+  };
+};
+
 export const getArtistImageURL = (artistSpotifyID: string): Promise<string> =>
   fetch(`${API_BASE_URL}/artist_image_url/${artistSpotifyID}`).then((res) => res.text());
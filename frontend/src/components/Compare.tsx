@@ -116,6 +116,15 @@ const CompareInner: React.FC<{ mobile: boolean }> = ({ mobile }) => {
     return <div className="compare loading">Loading...</div>;
   }
 
+  const untrackedUsernames = [
+    { username: data.user1_username, status: data.user1_data_status },
+    { username: data.user2_username, status: data.user2_data_status },
+  ]
+    .filter(
+      ({ status }) => status && status.stored_track_count === 0 && status.stored_artist_count === 0
+    )
+    .map(({ username }) => username);
+
   return (
     <div className="compare">
       <div className="compare-content">
@@ -130,7 +139,14 @@ const CompareInner: React.FC<{ mobile: boolean }> = ({ mobile }) => {
           </Link>
         </h1>
 
-        {data.tracks.length === 0 && data.artists.length === 0 ? (
+        {untrackedUsernames.length > 0 ? (
+          untrackedUsernames.map((username) => (
+            <p key={username} style={{ fontSize: 20, textAlign: 'center' }}>
+              <b>{username}</b> hasn&apos;t been tracked long enough for there to be any data to
+              compare yet. Check back in a day or two!
+            </p>
+          ))
+        ) : data.tracks.length === 0 && data.artists.length === 0 ? (
           <>
             Amazing - there is absolutely no musical overlap between these two people! You&apos;re
             truly polar opposites of musical taste.
@@ -6,7 +6,7 @@ import { UnimplementedError, UnreachableException } from 'ameo-utils';
 import type { UnrealBloomPass } from 'three/examples/jsm/postprocessing/UnrealBloomPass';
 import type Stats from 'three/examples/jsm/libs/stats.module';
 
-import { fetchPackedArtistPositions, getAllTopArtistInternalIDsForUser } from './api';
+import { fetchPackedArtistPositions, getTopArtistsDetailedForUser } from './api';
 import {
   BASE_ARTIST_GEOMETRY_SIZE,
   getArtistSize,
@@ -23,19 +23,25 @@ import {
   getArtistFlyToDurationMs,
   HIGHLIGHTED_ARTIST_COLOR,
   getHighlightedArtistsIntraOpacity,
-  INITIAL_ORBIT_POSITION,
+  INITIAL_ORBIT_DIRECTION,
   INITIAL_CAMERA_ROTATION,
-  INITIAL_ORBIT_TARGET,
   FRAME_TIMING_BUFFER_SIZE,
   getSecondsBetweenPositionUpdates,
   DEFAULT_QUALITY,
   getBloomedConnectionOpacity,
   getHighlightedArtistsInterOpacity,
   BASE_ARTIST_COLOR,
+  BASE_CONNECTION_COLOR,
+  FORCE_RENDER_LABEL_DEBOUNCE_MS,
+  MUSIC_CROSSFADE_ENABLED,
 } from './conf';
 import DataFetchClient, { ArtistMapDataWithId, ArtistRelationshipData } from './DataFetchClient';
 import { MovementInputHandler } from './MovementInputHandler';
-import type { WasmClient } from './WasmClient/WasmClient.worker';
+import type {
+  GalaxyBounds,
+  RelationshipsLoadingProgress,
+  WasmClient,
+} from './WasmClient/WasmClient.worker';
 import { UIEventRegistry } from './OverlayUI/OverlayUI';
 import MusicManager from './MusicManager';
 import { clamp, delay } from 'src/util2';
@@ -79,6 +85,25 @@ export const getUserSpotifyID = (): string | null => {
   return localStorage.getItem('userSpotifyID');
 };
 
+const EXCLUDED_ARTIST_IDS_LOCALSTORAGE_KEY = 'excludedArtistIDs';
+
+/**
+ * Returns the IDs of artists that the user never wants to have played, persisted across visits
+ */
+export const getExcludedArtistIDs = (): number[] => {
+  try {
+    const excludedArtistIDs = JSON.parse(
+      localStorage.getItem(EXCLUDED_ARTIST_IDS_LOCALSTORAGE_KEY) ?? '[]'
+    );
+    return Array.isArray(excludedArtistIDs)
+      ? excludedArtistIDs.filter((id): id is number => typeof id === 'number')
+      : [];
+  } catch (err) {
+    console.warn('Invalid excluded artist IDs in localStorage: ', err);
+    return [];
+  }
+};
+
 export const initArtistMapInst = async (canvas: HTMLCanvasElement): Promise<ArtistMapInst> => {
   const [
     artistColorsByID,
@@ -117,22 +142,57 @@ export const initArtistMapInst = async (canvas: HTMLCanvasElement): Promise<Arti
     Stats,
   };
 
-  const allArtistData = await wasmClient.getAllArtistData();
+  const [allArtistData, galaxyBounds] = await Promise.all([
+    wasmClient.getAllArtistData(),
+    wasmClient.getGalaxyBounds(),
+  ] as const);
 
-  const inst = new ArtistMapInst(THREE, THREE_EXTRA, canvas, allArtistData, artistColorsByID);
+  // Connections are rendered in a uniform color on mobile to save memory
+  if (getIsMobile()) {
+    await wasmClient.setConnectionColoringEnabled(false);
+  }
+  // Enabled before any draw commands are emitted so that all of them use the same encoding
+  if (MUSIC_CROSSFADE_ENABLED) {
+    await wasmClient.setCrossfadeEnabled(true);
+  }
+
+  // Nothing is playing yet, so there are no draw commands to process
+  const excludedArtistIDs = getExcludedArtistIDs();
+  if (excludedArtistIDs.length > 0) {
+    await wasmClient.setExcludedArtists(new Uint32Array(excludedArtistIDs));
+  }
+
+  const inst = new ArtistMapInst(
+    THREE,
+    THREE_EXTRA,
+    canvas,
+    allArtistData,
+    artistColorsByID,
+    galaxyBounds
+  );
   dataFetchClient.fetchArtistRelationships(0);
 
   // Set highlighted artists.
   const userSpotifyID = getUserSpotifyID();
   if (userSpotifyID) {
-    getAllTopArtistInternalIDsForUser(userSpotifyID).then((artistIDs) =>
-      inst.setHighlightedArtistIDs(artistIDs)
+    getTopArtistsDetailedForUser(userSpotifyID).then((topArtists) =>
+      inst.setHighlightedArtistIDs(
+        topArtists.map((artist) => artist.internal_id),
+        topArtists.map((artist) => getHighlightWeight(artist.best_rank))
+      )
     );
   }
 
   return inst;
 };
 
+/**
+ * Maps an artist's best rank among the user's current top artists to a highlight weight.  Artists
+ * that were top artists in the past but aren't currently get the lowest weight.
+ */
+const getHighlightWeight = (bestRank: number | null): number =>
+  bestRank === null ? 64 : Math.max(255 - bestRank * 3, 96);
+
 let VEC3_IDENTITY: THREE.Vector3;
 
 enum DrawCommand {
@@ -210,8 +270,11 @@ export class ArtistMapInst {
   private highlightedArtistsIntraLines: THREE.LineSegments | null = null;
   private artistDataByID: Map<number, { pos: THREE.Vector3; popularity: number }> = new Map();
   private pendingDrawCommands: Uint32Array[] = [];
+  private forceRenderLabelTimeout: ReturnType<typeof setTimeout> | null = null;
   private artistMeshes: THREE.InstancedMesh;
   private artistColorsByID: Map<number, readonly [number, number, number]> | null = null;
+  private galaxyBounds: GalaxyBounds;
+  public relationshipsLoadingProgress: RelationshipsLoadingProgress | null = null;
   private playingArtistScale = 1;
   private playingArtistGeometry: THREE.Mesh | null = null;
   private movementInputHandler: MovementInputHandler;
@@ -306,19 +369,15 @@ export class ArtistMapInst {
         this.cameraOverrides.movement = {
           path: new this.THREE.QuadraticBezierCurve3(
             this.camera.position.clone(),
-            new this.THREE.Vector3(0, 0, 0),
-            new this.THREE.Vector3(
-              INITIAL_ORBIT_POSITION.x,
-              INITIAL_ORBIT_POSITION.y,
-              INITIAL_ORBIT_POSITION.z
-            )
+            this.getGalaxyCenter(),
+            this.getInitialOrbitPosition()
           ),
           startTime: new Date().getTime(),
           flightDurationMs: 2800,
           artistID: null,
           callback: () => {
             this.cameraOverrides.direction = {
-              target: new this.THREE.Vector3(0, 0, 0),
+              target: this.getGalaxyCenter(),
               pivotCoefficient: 0.973,
             };
           },
@@ -331,6 +390,45 @@ export class ArtistMapInst {
     },
   });
 
+  /**
+   * Returns the camera's view cone for culling artist geometry.  Only available in pointerlock mode
+   * since the position used for rendering decisions in other modes isn't the camera's position.
+   */
+  private getViewCone(): { forward: [number, number, number]; fovDegrees: number } | undefined {
+    if (this.controls.type !== 'pointerlock') {
+      return undefined;
+    }
+
+    const forward = this.camera.getWorldDirection(new this.THREE.Vector3());
+    const halfVerticalFov = this.THREE.MathUtils.degToRad(this.camera.fov) / 2;
+    const horizontalFovDegrees = this.THREE.MathUtils.radToDeg(
+      2 * Math.atan(Math.tan(halfVerticalFov) * this.camera.aspect)
+    );
+    return {
+      forward: [forward.x, forward.y, forward.z],
+      fovDegrees: Math.max(this.camera.fov, horizontalFovDegrees),
+    };
+  }
+
+  private getGalaxyCenter(): THREE.Vector3 {
+    const [x, y, z] = this.galaxyBounds.center;
+    return new this.THREE.Vector3(x, y, z);
+  }
+
+  /**
+   * Returns the position from which the whole galaxy is in view when orbiting its center
+   */
+  private getInitialOrbitPosition(radiusMultiplier = 1): THREE.Vector3 {
+    const direction = new this.THREE.Vector3(
+      INITIAL_ORBIT_DIRECTION.x,
+      INITIAL_ORBIT_DIRECTION.y,
+      INITIAL_ORBIT_DIRECTION.z
+    ).normalize();
+    return direction
+      .multiplyScalar(this.galaxyBounds.suggestedOrbitRadius * radiusMultiplier)
+      .add(this.getGalaxyCenter());
+  }
+
   private lookAtArtistID(artistID: number) {
     const pos = this.artistDataByID.get(artistID)?.pos;
     if (!pos) {
@@ -338,9 +436,15 @@ export class ArtistMapInst {
     }
 
     if (this.controls.type === 'orbit') {
-      wasmClient
-        .forceRenderArtistLabel(artistID)
-        .then((drawCommands) => this.pendingDrawCommands.push(drawCommands));
+      if (this.forceRenderLabelTimeout !== null) {
+        clearTimeout(this.forceRenderLabelTimeout);
+      }
+      this.forceRenderLabelTimeout = setTimeout(() => {
+        this.forceRenderLabelTimeout = null;
+        wasmClient
+          .forceRenderArtistLabel(artistID)
+          .then((drawCommands) => this.pendingDrawCommands.push(drawCommands));
+      }, FORCE_RENDER_LABEL_DEBOUNCE_MS);
     }
 
     this.cameraOverrides.direction = { target: pos, pivotCoefficient: CAMERA_PIVOT_COEFFICIENT };
@@ -450,7 +554,22 @@ export class ArtistMapInst {
     }
   }
 
-  public setHighlightedArtistIDs(artistIDs: number[]) {
+  /**
+   * Sets the artists that are never played, either automatically or manually.  The list is
+   * persisted so that it's applied again on later visits.
+   */
+  public setExcludedArtistIDs(artistIDs: number[]) {
+    localStorage.setItem(EXCLUDED_ARTIST_IDS_LOCALSTORAGE_KEY, JSON.stringify(artistIDs));
+    wasmClient
+      .setExcludedArtists(new Uint32Array(artistIDs))
+      .then((drawCommands) => this.pendingDrawCommands.push(drawCommands));
+  }
+
+  /**
+   * `weights` optionally holds the highlight weight (0-255) of each artist, parallel to
+   * `artistIDs`.  Higher-weighted artists are rendered more prominently.
+   */
+  public setHighlightedArtistIDs(artistIDs: number[], weights?: number[]) {
     // Wasm client is the source of truth for what artists are rendered.  We indicate to it that highlighted artists have changed
     // and let it deal with dispatching draw commands to re-render them and possibly de-render the old ones.
     const curPosition = this.camera.position.clone();
@@ -460,9 +579,16 @@ export class ArtistMapInst {
         curPosition.x,
         curPosition.y,
         curPosition.z,
-        this.controls.type !== 'orbit'
+        new Uint8Array(weights ?? [])
       )
-      .then((drawCommands) => this.pendingDrawCommands.push(drawCommands));
+      .then(async (drawCommands) => {
+        // Weights change the colors of highlighted artists, so pick up the new ones before the
+        // highlighted artists' geometry is re-added
+        if (weights) {
+          this.artistColorsByID = await wasmClient.getArtistColorsByID();
+        }
+        this.pendingDrawCommands.push(drawCommands);
+      });
 
     // Setting these here allows us to know whether or not an artist is highlighted during rendering.  We don't do that now, but
     // will do it once the draw commands returned above are processed.
@@ -501,7 +627,8 @@ export class ArtistMapInst {
     THREE_EXTRA: ThreeExtra,
     canvas: HTMLCanvasElement,
     allArtistData: Float32Array,
-    artistColorsByID: Map<number, readonly [number, number, number]>
+    artistColorsByID: Map<number, readonly [number, number, number]>,
+    galaxyBounds: GalaxyBounds
   ) {
     this.THREE = THREE;
     this.THREE_EXTRA = THREE_EXTRA;
@@ -510,6 +637,7 @@ export class ArtistMapInst {
     this.cachedCanvasWidth = canvas.width;
     this.cachedCanvasHeight = canvas.height;
     this.artistColorsByID = artistColorsByID;
+    this.galaxyBounds = galaxyBounds;
 
     this.renderer = new THREE.WebGLRenderer({
       canvas,
@@ -692,11 +820,7 @@ export class ArtistMapInst {
     window.addEventListener('resize', () => this.handleResize());
 
     this.initControls('orbit');
-    this.camera.position.set(
-      INITIAL_ORBIT_POSITION.x * (this.isMobile ? 1.4 : 1),
-      INITIAL_ORBIT_POSITION.y * (this.isMobile ? 1.4 : 1),
-      INITIAL_ORBIT_POSITION.z * (this.isMobile ? 1.4 : 1)
-    );
+    this.camera.position.copy(this.getInitialOrbitPosition(this.isMobile ? 1.4 : 1));
     this.camera.rotation.set(
       INITIAL_CAMERA_ROTATION.x,
       INITIAL_CAMERA_ROTATION.y,
@@ -773,6 +897,7 @@ export class ArtistMapInst {
         .transitionToOrbitMode()
         .then((drawCommands) => this.pendingDrawCommands.push(drawCommands));
     }
+    wasmClient.setMode(controlMode !== 'orbit');
     this.eventRegistry.controlMode = controlMode;
     this.eventRegistry.onControlModeChange(controlMode);
 
@@ -807,7 +932,7 @@ export class ArtistMapInst {
       }
       case 'orbit': {
         const controls = new this.THREE_EXTRA.OrbitControls(this.camera, this.renderer.domElement);
-        controls.target.set(INITIAL_ORBIT_TARGET.x, INITIAL_ORBIT_TARGET.y, INITIAL_ORBIT_TARGET.z);
+        controls.target.copy(this.getGalaxyCenter());
         controls.autoRotate = true;
         controls.autoRotateSpeed = 0.048;
         controls.enableDamping = true;
@@ -891,8 +1016,7 @@ export class ArtistMapInst {
         new Uint32Array(artistData.map(({ id }) => id)),
         curPos.x,
         curPos.y,
-        curPos.z,
-        this.controls.type !== 'orbit'
+        curPos.z
       )
       .then((drawCommands) => {
         if (drawCommands.length === 0) {
@@ -954,7 +1078,13 @@ export class ArtistMapInst {
         relationshipData.chunkSize,
         relationshipData.chunkIx
       )
-      .then((connectionsDataBuffer) => this.updateConnectionsBuffer(connectionsDataBuffer));
+      .then((connectionsDataBuffer) => {
+        this.updateConnectionsBuffer(connectionsDataBuffer);
+        return wasmClient.getLoadingProgress();
+      })
+      .then((progress) => {
+        this.relationshipsLoadingProgress = progress;
+      });
 
     // Fetch the next chunk
     dataFetchClient.fetchArtistRelationships(relationshipData.chunkIx + 1);
@@ -978,10 +1108,24 @@ export class ArtistMapInst {
     if (connectionsColorBuffer.length % 6 !== 0) {
       throw new UnreachableException('Expected multiple of 6 for colors buffer');
     }
-    this.bloomedConnectionsGeometry.setAttribute(
-      'color',
-      new this.THREE.Uint8ClampedBufferAttribute(connectionsColorBuffer, 3, true)
-    );
+
+    // The colors buffer is empty if connection coloring is disabled, in which case all connections
+    // are rendered in the same color
+    const material = this.bloomedConnectionsMesh.material as THREE.LineBasicMaterial;
+    const useVertexColors = connectionsColorBuffer.length > 0;
+    if (material.vertexColors !== useVertexColors) {
+      material.vertexColors = useVertexColors;
+      material.color.set(useVertexColors ? 0xffffff : BASE_CONNECTION_COLOR);
+      material.needsUpdate = true;
+    }
+    if (useVertexColors) {
+      this.bloomedConnectionsGeometry.setAttribute(
+        'color',
+        new this.THREE.Uint8ClampedBufferAttribute(connectionsColorBuffer, 3, true)
+      );
+    } else {
+      this.bloomedConnectionsGeometry.deleteAttribute('color');
+    }
     this.bloomedConnectionsGeometry.computeBoundingSphere();
   }
 
@@ -1241,7 +1385,7 @@ export class ArtistMapInst {
           projectedNextPos.x,
           projectedNextPos.y,
           projectedNextPos.z,
-          this.controls.type !== 'orbit'
+          this.getViewCone()
         )
         .then((commands) => {
           this.wasmPositionHandlerIsRunning = false;
@@ -1323,10 +1467,6 @@ export class ArtistMapInst {
   }
 
   private processDrawCommands(commands: Uint32Array) {
-    if (commands.length % 2 !== 0) {
-      throw new UnreachableException('Invalid command count');
-    }
-
     this.artistMeshes.instanceMatrix.updateRange.count = -1;
     this.artistMeshes.instanceMatrix.updateRange.offset = 0;
     this.artistMeshes.instanceMatrix.needsUpdate = false;
@@ -1334,15 +1474,31 @@ export class ArtistMapInst {
     this.artistMeshes.instanceColor!.updateRange.offset = 0;
     this.artistMeshes.instanceColor!.needsUpdate = false;
 
-    const cmdCount = commands.length / 2;
-
     const artistIDsToRender = [];
     const artistIDsToRemove = [];
     const artistIDsToFetch = [];
 
-    for (let i = 0; i < cmdCount; i++) {
-      const command = commands[i * 2] as DrawCommand;
-      const artistID = commands[i * 2 + 1];
+    let offset = 0;
+    while (offset < commands.length) {
+      if (offset + 1 >= commands.length) {
+        throw new UnreachableException('Invalid command count');
+      }
+      const command = commands[offset] as DrawCommand;
+      const artistID = commands[offset + 1];
+      offset += 2;
+
+      // With crossfading enabled, music commands are followed by the fade duration in milliseconds
+      let fadeSecs: number | undefined;
+      if (
+        MUSIC_CROSSFADE_ENABLED &&
+        (command === DrawCommand.StartPlayingMusic || command === DrawCommand.StopPlayingMusic)
+      ) {
+        if (offset >= commands.length) {
+          throw new UnreachableException('Missing music fade duration');
+        }
+        fadeSecs = commands[offset] / 1000;
+        offset += 1;
+      }
 
       switch (command) {
         case DrawCommand.AddLabel: {
@@ -1398,7 +1554,7 @@ export class ArtistMapInst {
               });
           };
 
-          this.musicManager.startPlaying(artistID, artistPos, onEnded).then(() => {
+          this.musicManager.startPlaying(artistID, artistPos, onEnded, fadeSecs).then(() => {
             const isActuallyPlaying = this.musicManager.curPlaying?.artistID === artistID;
             if (!isActuallyPlaying) {
               return;
@@ -1413,7 +1569,7 @@ export class ArtistMapInst {
         }
         case DrawCommand.StopPlayingMusic: {
           this.eventRegistry.curPlaying = null;
-          this.musicManager.stopPlaying(artistID);
+          this.musicManager.stopPlaying(artistID, fadeSecs);
           this.removePlayingArtistGeometry(artistID);
           break;
         }
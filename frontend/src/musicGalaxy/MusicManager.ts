@@ -137,7 +137,8 @@ export default class MusicManager {
   public async startPlaying(
     artistID: number,
     pos: { x: number; y: number; z: number },
-    onEnded: () => void
+    onEnded: () => void,
+    fadeInSecs = MUSIC_FADE_IN_TIME_SECS
   ) {
     this.pendingPlayingArtistID = artistID;
     if (this.curPlaying) {
@@ -198,14 +199,14 @@ export default class MusicManager {
 
     const gain = this.ctx.createGain();
     gain.gain.setValueAtTime(0, this.ctx.currentTime);
-    gain.gain.linearRampToValueAtTime(1, this.ctx.currentTime + MUSIC_FADE_IN_TIME_SECS);
+    gain.gain.linearRampToValueAtTime(1, this.ctx.currentTime + fadeInSecs);
     panner.connect(gain);
     gain.connect(this.mainGain);
 
     this.curPlaying = { artistID, pos, panner, gain, startTime: this.ctx.currentTime };
   }
 
-  public stopPlaying(artistID: number) {
+  public stopPlaying(artistID: number, fadeOutSecs = MUSIC_FADE_OUT_TIME_SECS) {
     console.log(`stop playback; artistID=${artistID}, curID=${this.curPlaying?.artistID}`);
     if (this.pendingPlayingArtistID === artistID) {
       this.pendingPlayingArtistID = null;
@@ -225,14 +226,11 @@ export default class MusicManager {
     const timeToWait = MIN_MUSIC_PLAY_TIME_SECS - timePlayed;
     setTimeout(() => {
       curPlaying.gain.gain.cancelScheduledValues(0);
-      curPlaying.gain.gain.linearRampToValueAtTime(
-        0,
-        this.ctx.currentTime + MUSIC_FADE_OUT_TIME_SECS
-      );
+      curPlaying.gain.gain.linearRampToValueAtTime(0, this.ctx.currentTime + fadeOutSecs);
       setTimeout(() => {
         curPlaying.gain.disconnect(this.mainGain);
         curPlaying.panner.disconnect(curPlaying.gain);
-      }, MUSIC_FADE_OUT_TIME_SECS * 1000);
+      }, fadeOutSecs * 1000);
     }, timeToWait * 1000);
   }
 
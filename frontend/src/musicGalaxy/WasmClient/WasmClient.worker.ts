@@ -1,5 +1,25 @@
 import * as Comlink from 'comlink';
 
+export interface RelationshipsLoadingProgress {
+  receivedChunkCount: number;
+  /**
+   * 0 until the first chunk has been received
+   */
+  expectedChunkCount: number;
+  totalRelationshipsParsed: number;
+  connectionsRendered: number;
+}
+
+export interface GalaxyBounds {
+  min: [number, number, number];
+  max: [number, number, number];
+  center: [number, number, number];
+  /**
+   * Distance from the center at which the whole galaxy is in view
+   */
+  suggestedOrbitRadius: number;
+}
+
 export class WasmClient {
   private engine: typeof import('./engine');
   private ctxPtr: number;
@@ -25,11 +45,44 @@ export class WasmClient {
     return Comlink.transfer(allArtistData, [allArtistData.buffer]);
   }
 
+  public getGalaxyBounds(): GalaxyBounds {
+    const bounds = this.engine.get_galaxy_bounds(this.ctxPtr);
+    return {
+      min: [bounds[0], bounds[1], bounds[2]],
+      max: [bounds[3], bounds[4], bounds[5]],
+      center: [bounds[6], bounds[7], bounds[8]],
+      suggestedOrbitRadius: bounds[9],
+    };
+  }
+
+  /**
+   * Returns the mean position of the provided artists, ignoring any that aren't loaded, or `null`
+   * if none of them are.  If `includeNearestArtist` is set, the ID of the artist closest to that
+   * position is returned as well.
+   */
+  public getArtistsCentroid(
+    artistIDs: Uint32Array,
+    includeNearestArtist: boolean
+  ): { centroid: [number, number, number]; nearestArtistID: number | null } | null {
+    const res = this.engine.get_artists_centroid(this.ctxPtr, artistIDs, includeNearestArtist);
+    if (res.length === 0) {
+      return null;
+    }
+
+    // The nearest artist ID is stored as the raw bits of the fourth element
+    const nearestArtistID =
+      res.length > 3 ? new Uint32Array(res.buffer, res.byteOffset + 3 * 4, 1)[0] : null;
+    return { centroid: [res[0], res[1], res[2]], nearestArtistID };
+  }
+
   public isReady() {
     return !!this.engine && !!this.ctxPtr;
   }
 
   /**
+   * If `viewCone` is provided, geometry isn't rendered for artists well outside of the camera's
+   * view.  `fovDegrees` should be the camera's widest FOV.
+   *
    * Returns set of draw commands to execute
    */
   public handleNewPosition(
@@ -39,7 +92,8 @@ export class WasmClient {
     projectedNextX: number,
     projectedNextY: number,
     projectedNextZ: number,
-    isFlyMode: boolean
+    viewCone?: { forward: [number, number, number]; fovDegrees: number },
+    maxGeometryCommands?: number
   ) {
     const drawCommands = this.engine.handle_new_position(
       this.ctxPtr,
@@ -49,11 +103,40 @@ export class WasmClient {
       projectedNextX,
       projectedNextY,
       projectedNextZ,
-      isFlyMode
+      undefined,
+      viewCone?.forward[0] ?? NaN,
+      viewCone?.forward[1] ?? NaN,
+      viewCone?.forward[2] ?? NaN,
+      viewCone?.fovDegrees ?? NaN,
+      maxGeometryCommands
     );
     return Comlink.transfer(drawCommands, [drawCommands.buffer]);
   }
 
+  /**
+   * Applies artist geometry updates that were left out of previous `handleNewPosition` calls
+   * because they were over `maxGeometryCommands`.  Calling `handleNewPosition` again with the same
+   * position does the same thing.
+   */
+  public flushDeferredGeometryUpdates(maxGeometryCommands?: number) {
+    const drawCommands = this.engine.flush_deferred_geometry_updates(
+      this.ctxPtr,
+      maxGeometryCommands
+    );
+    return Comlink.transfer(drawCommands, [drawCommands.buffer]);
+  }
+
+  /**
+   * Applies each position in `positions` (packed as `[x, y, z, x, y, z, ...]`) in order.  Used to
+   * replay recorded trajectories.
+   *
+   * Returns the draw commands for each step concatenated, each preceded by its length
+   */
+  public handlePositionBatch(positions: Float32Array, isFlyMode: boolean) {
+    const drawCommands = this.engine.handle_position_batch(this.ctxPtr, positions, isFlyMode);
+    return Comlink.transfer(drawCommands, [drawCommands.buffer]);
+  }
+
   /**
    * Returns set of draw commands to execute
    */
@@ -61,8 +144,7 @@ export class WasmClient {
     artistIDs: Uint32Array,
     curX: number,
     curY: number,
-    curZ: number,
-    isFlyMode: boolean
+    curZ: number
   ) {
     const drawCommands = this.engine.handle_received_artist_names(
       this.ctxPtr,
@@ -70,7 +152,7 @@ export class WasmClient {
       curX,
       curY,
       curZ,
-      isFlyMode
+      undefined
     );
     return Comlink.transfer(drawCommands, [drawCommands.buffer]);
   }
@@ -130,20 +212,85 @@ export class WasmClient {
     ]);
   }
 
+  /**
+   * Adds the related artists of a single artist, such as one that was just clicked, without
+   * waiting for its relationship chunk to load.  Returns the new connection data buffer to be
+   * rendered.
+   */
+  public addSingleArtistRelationships(
+    artistID: number,
+    relatedArtistIDs: Uint32Array
+  ): { connectionsBuffer: Float32Array; connectionsColorBuffer: Uint8ClampedArray } {
+    this.engine.add_single_artist_relationships(this.ctxPtr, artistID, relatedArtistIDs);
+
+    const connectionsBuffer = this.getConnectionsBuffer();
+    const connectionsColorBuffer = this.getConnectionsColorBuffer();
+    return Comlink.transfer({ connectionsBuffer, connectionsColorBuffer }, [
+      connectionsBuffer.buffer,
+      connectionsColorBuffer.buffer,
+    ]);
+  }
+
+  public getLoadingProgress(): RelationshipsLoadingProgress {
+    const [receivedChunkCount, expectedChunkCount, totalRelationshipsParsed, connectionsRendered] =
+      this.engine.get_loading_progress(this.ctxPtr);
+    return {
+      receivedChunkCount,
+      expectedChunkCount,
+      totalRelationshipsParsed,
+      connectionsRendered,
+    };
+  }
+
+  /**
+   * Returns the fraction of the `totalChunks` relationship chunks that have been received, from 0
+   * to 1
+   */
+  public getRelationshipCoverage(totalChunks: number): number {
+    return this.engine.get_relationship_coverage(this.ctxPtr, totalChunks);
+  }
+
+  /**
+   * Returns the sorted indices of all received relationship chunks so that missing ones can be
+   * re-requested
+   */
+  public getReceivedChunkIndices(): Uint32Array {
+    return this.engine.get_received_chunk_indices(this.ctxPtr);
+  }
+
+  /**
+   * Returns `[artistID, renderStateBits, popularity]` triplets for every artist with a non-empty
+   * render state.  See `get_render_state_snapshot` in the engine for the meaning of the bits.
+   */
+  public getRenderStateSnapshot(): Uint32Array {
+    const snapshot = this.engine.get_render_state_snapshot(this.ctxPtr);
+    return Comlink.transfer(snapshot, [snapshot.buffer]);
+  }
+
+  public getRenderedLabelIDs(): Uint32Array {
+    const labelIDs = this.engine.get_rendered_label_ids(this.ctxPtr);
+    return Comlink.transfer(labelIDs, [labelIDs.buffer]);
+  }
+
+  /**
+   * `weights` holds the highlight weight (0-255) of each artist in `artistIDs`.  If empty, all
+   * highlighted artists are weighted equally.
+   */
   public setHighlightedArtists(
     artistIDs: Uint32Array,
     curX: number,
     curY: number,
     curZ: number,
-    isFlyMode: boolean
+    weights: Uint8Array = new Uint8Array()
   ) {
     const drawCommands = this.engine.handle_set_highlighted_artists(
       this.ctxPtr,
       artistIDs,
+      weights,
       curX,
       curY,
       curZ,
-      isFlyMode
+      undefined
     );
     return Comlink.transfer(drawCommands, [drawCommands.buffer]);
   }
@@ -153,6 +300,15 @@ export class WasmClient {
     return Comlink.transfer(drawCommands, [drawCommands.buffer]);
   }
 
+  /**
+   * Sets the artists that are never played.  Returns draw commands that stop the currently playing
+   * artist if it's excluded.
+   */
+  public setExcludedArtists(artistIDs: Uint32Array) {
+    const drawCommands = this.engine.set_excluded_artists(this.ctxPtr, artistIDs);
+    return Comlink.transfer(drawCommands, [drawCommands.buffer]);
+  }
+
   public getHighlightedConnectionsBackbone(highlightedArtistIDs: Uint32Array): {
     intra: Float32Array;
     inter: Float32Array;
@@ -166,6 +322,27 @@ export class WasmClient {
     };
   }
 
+  /**
+   * Returns the IDs of all artists related to the provided one.  Artists that aren't in the
+   * galaxy are only included if `includeOutOfSet` is set.
+   */
+  public getRelatedArtistIDs(artistID: number, includeOutOfSet: boolean): Uint32Array {
+    const relatedArtistIDs = this.engine.get_related_artist_ids(
+      this.ctxPtr,
+      artistID,
+      includeOutOfSet
+    );
+    return Comlink.transfer(relatedArtistIDs, [relatedArtistIDs.buffer]);
+  }
+
+  /**
+   * Sets whether the user is flying around the galaxy or in orbit mode.  All handlers use the
+   * mode set here.
+   */
+  public setMode(isFlyMode: boolean) {
+    this.engine.set_mode(this.ctxPtr, isFlyMode);
+  }
+
   /**
    * Clears all existing labels and renders the special orbit-mode labels
    *
@@ -195,6 +372,51 @@ export class WasmClient {
     ]);
   }
 
+  /**
+   * When disabled, the returned connections color buffers will be empty and connections should be
+   * rendered in a uniform color.  Saves memory on low-end devices.
+   */
+  public setConnectionColoringEnabled(enabled: boolean) {
+    this.engine.set_connection_coloring_enabled(this.ctxPtr, enabled);
+  }
+
+  /**
+   * When enabled, start and stop playing music draw commands are three words long, with the third
+   * holding the fade duration in milliseconds.
+   */
+  public setCrossfadeEnabled(enabled: boolean) {
+    this.engine.set_crossfade_enabled(this.ctxPtr, enabled);
+  }
+
+  /**
+   * Sets how many highlighted artists are labeled when transitioning to orbit mode and the min
+   * distances between those labels.  Distances need to be scaled to match the embedding.
+   */
+  public setOrbitLabelParams(
+    topCount: number,
+    randomCount: number,
+    minDist: number,
+    randomMinDist: number
+  ) {
+    this.engine.set_orbit_label_params(this.ctxPtr, topCount, randomCount, minDist, randomMinDist);
+  }
+
+  /**
+   * Returns a new artist relationships connections buffer to be rendered
+   */
+  public setMaxConnectionLength(maxConnectionLength: number): {
+    connectionsBuffer: Float32Array;
+    connectionsColorBuffer: Uint8ClampedArray;
+  } {
+    this.engine.set_max_connection_length(this.ctxPtr, maxConnectionLength);
+    const connectionsBuffer = this.getConnectionsBuffer();
+    const connectionsColorBuffer = this.getConnectionsColorBuffer();
+    return Comlink.transfer({ connectionsBuffer, connectionsColorBuffer }, [
+      connectionsBuffer.buffer,
+      connectionsColorBuffer.buffer,
+    ]);
+  }
+
   /**
    * Returns set of draw commands to execute
    */
@@ -202,6 +424,41 @@ export class WasmClient {
     return this.engine.play_last_artist(this.ctxPtr);
   }
 
+  /**
+   * Overrides the colors of the provided artists.  Overrides persist until
+   * `clearArtistColorOverrides` is called.
+   *
+   * Returns the updated colors of all artists
+   */
+  public setArtistColors(
+    colorsByArtistID: Map<number, readonly [number, number, number]>
+  ): Map<number, readonly [number, number, number]> {
+    const packed = new ArrayBuffer(colorsByArtistID.size * 16);
+    const u32View = new Uint32Array(packed);
+    const f32View = new Float32Array(packed);
+    let i = 0;
+    for (const [artistID, [r, g, b]] of colorsByArtistID) {
+      u32View[i] = artistID;
+      f32View[i + 1] = r;
+      f32View[i + 2] = g;
+      f32View[i + 3] = b;
+      i += 4;
+    }
+
+    this.engine.set_artist_colors(this.ctxPtr, new Uint8Array(packed));
+    return this.getArtistColorsByID();
+  }
+
+  /**
+   * Reverts all colors set via `setArtistColors`.
+   *
+   * Returns the updated colors of all artists
+   */
+  public clearArtistColorOverrides(): Map<number, readonly [number, number, number]> {
+    this.engine.clear_artist_color_overrides(this.ctxPtr);
+    return this.getArtistColorsByID();
+  }
+
   public getArtistColorsByID(): Map<number, readonly [number, number, number]> {
     const artistColorsBufferPtr = this.engine.get_artist_colors_buffer_ptr(this.ctxPtr);
     const artistColorsBufferLength = this.engine.get_artist_colors_buffer_length(this.ctxPtr);
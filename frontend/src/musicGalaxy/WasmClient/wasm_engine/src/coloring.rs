@@ -5,12 +5,14 @@ use palette::{
     Gradient, LinSrgb,
 };
 
-use super::ArtistMapCtx;
+use super::{connection_weight_factor, ArtistMapCtx};
 
 const NOISE_POS_DIVISOR: f64 = 84_000.;
 const BASE_ARTIST_COLOR: u32 = 0x1586a6;
 const BASE_CONNECTION_COLOR: u32 = 0x0072dd;
 pub const COLOR_NOISE_SEED: u32 = 1238432211;
+/// Brightness multiplier applied to the colors of highlighted artists with a weight of 0
+const MIN_HIGHLIGHT_BRIGHTNESS: f64 = 0.55;
 
 fn expand_range(byte: u8) -> f64 { byte as f64 / 255. }
 
@@ -66,9 +68,14 @@ lazy_static! {
 impl ArtistMapCtx {
     #[inline(never)]
     pub fn populate_connection_colors_buffer(&mut self) {
+        if !self.connection_coloring_enabled {
+            self.connection_colors_buffer = Vec::new();
+            return;
+        }
+
         self.connection_colors_buffer = Vec::with_capacity(self.connections_buffer.len() * 6);
 
-        for pos in &self.connections_buffer {
+        for (pos, &weight) in self.connections_buffer.iter().zip(&self.connection_weights) {
             let midpoint = [
                 (pos[0][0] + pos[1][0]) / 2.,
                 (pos[0][1] + pos[1][1]) / 2.,
@@ -82,9 +89,12 @@ impl ArtistMapCtx {
             ]);
             let color = CONNECTION_COLOR_GRADIENT.get(val);
             let (r, g, b) = color.into_components();
-            let r = (r * 255.) as u8;
-            let g = (g * 255.) as u8;
-            let b = (b * 255.) as u8;
+            // Stronger connections are brighter.  The background is black, so this is equivalent to
+            // making them more opaque.
+            let brightness = 1. + connection_weight_factor(weight) as f64;
+            let r = ((r * brightness).min(1.) * 255.) as u8;
+            let g = ((g * brightness).min(1.) * 255.) as u8;
+            let b = ((b * brightness).min(1.) * 255.) as u8;
             self.connection_colors_buffer.push(r);
             self.connection_colors_buffer.push(g);
             self.connection_colors_buffer.push(b);
@@ -98,18 +108,68 @@ impl ArtistMapCtx {
     pub fn populate_artist_color_buffer(&mut self) {
         self.artist_colors_buffer = Vec::with_capacity(self.all_artists.len());
 
-        for (artist_id, artist) in &self.all_artists {
+        for (artist_ix, (artist_id, artist)) in self.all_artists.iter().enumerate() {
+            if let Some(&color) = self.artist_color_overrides.get(artist_id) {
+                self.artist_colors_buffer.push((*artist_id, color));
+                continue;
+            }
+
             let val = self.color_noise.get([
                 artist.position[0] as f64 / NOISE_POS_DIVISOR,
                 artist.position[1] as f64 / NOISE_POS_DIVISOR,
                 artist.position[2] as f64 / NOISE_POS_DIVISOR,
             ]);
             let color = ARTIST_COLOR_GRADIENT.get(val);
-            let (r, g, b) = color.into_components();
+            let (mut r, mut g, mut b) = color.into_components();
+            // Artists with lower highlight weights are dimmed
+            if let Some(&weight) = self.highlight_weight_by_index.get(&artist_ix) {
+                let brightness = MIN_HIGHLIGHT_BRIGHTNESS
+                    + (1. - MIN_HIGHLIGHT_BRIGHTNESS) * (weight as f64 / 255.);
+                r *= brightness;
+                g *= brightness;
+                b *= brightness;
+            }
             self.artist_colors_buffer
                 .push((unsafe { std::mem::transmute(*artist_id) }, [
                     r as f32, g as f32, b as f32,
                 ]));
         }
     }
+
+    /// Records color overrides from packed `[artist_id: u32, r: f32, g: f32, b: f32]` tuples and
+    /// applies them to the artist colors buffer
+    pub fn set_artist_colors(&mut self, packed: &[u8]) {
+        for tuple in packed.chunks_exact(16) {
+            let read_u32 = |offset: usize| {
+                u32::from_le_bytes([
+                    tuple[offset],
+                    tuple[offset + 1],
+                    tuple[offset + 2],
+                    tuple[offset + 3],
+                ])
+            };
+            let artist_id = read_u32(0);
+            let color = [
+                f32::from_bits(read_u32(4)),
+                f32::from_bits(read_u32(8)),
+                f32::from_bits(read_u32(12)),
+            ];
+            self.artist_color_overrides.insert(artist_id, color);
+
+            if let Some(&artist_ix) = self.artists_indices_by_id.get(&artist_id) {
+                if let Some(entry) = self.artist_colors_buffer.get_mut(artist_ix) {
+                    entry.1 = color;
+                }
+            }
+        }
+    }
+
+    pub fn clear_artist_color_overrides(&mut self) {
+        if self.artist_color_overrides.is_empty() {
+            return;
+        }
+
+        self.artist_color_overrides.clear();
+        self.populate_artist_color_buffer();
+    }
 }
@@ -23,33 +23,194 @@ extern "C" {
 
 bitflags! {
     pub struct ArtistRenderState: u8 {
-        const RENDER_LABEL = 0b0000_0001;
         const RENDER_CONNECTIONS = 0b0000_0010;
         /// Should actually render the artist
         const RENDER_GEOMETRY = 0b0000_0100;
         /// Name has been received from spotify and we can actually render it
         const HAS_NAME = 0b0000_1000;
         const IS_HIGHLIGHTED = 0b0001_0000;
+        /// A fetch of the artist's name has already been requested
+        const NAME_REQUESTED = 0b0010_0000;
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LabelState {
+    NotRequested,
+    /// The label should be rendered, but we're waiting on the artist's name to arrive
+    FetchPending,
+    /// An add label command has been emitted and the label counts towards
+    /// `total_rendered_label_count`
+    Rendered,
+}
+
 #[derive(Clone)]
 pub struct ArtistState {
     pub position: [f32; 3],
     pub popularity: u8,
     pub render_state: ArtistRenderState,
+    pub label_state: LabelState,
+}
+
+impl ArtistState {
+    /// Requests that the artist's label be rendered or removed, pushing any needed draw commands.
+    /// `total_rendered_label_count` is only updated when a label is actually added or removed, so
+    /// it stays accurate when a label is removed while its name is still being fetched.
+    fn set_label_requested(
+        &mut self,
+        artist_id: u32,
+        requested: bool,
+        total_rendered_label_count: &mut usize,
+        draw_commands: &mut Vec<u32>,
+    ) {
+        match (self.label_state, requested) {
+            (LabelState::NotRequested, true) =>
+                if self.render_state.contains(ArtistRenderState::HAS_NAME) {
+                    self.label_state = LabelState::Rendered;
+                    *total_rendered_label_count += 1;
+                    draw_commands.push(ADD_LABEL_CMD);
+                    draw_commands.push(artist_id);
+                } else {
+                    self.label_state = LabelState::FetchPending;
+                    // If the name has already been requested, the label will be rendered once it
+                    // arrives and there's no need to fetch it again
+                    if !self
+                        .render_state
+                        .contains(ArtistRenderState::NAME_REQUESTED)
+                    {
+                        self.render_state
+                            .set(ArtistRenderState::NAME_REQUESTED, true);
+                        draw_commands.push(FETCH_ARTIST_DATA_CMD);
+                        draw_commands.push(artist_id);
+                    }
+                },
+            (LabelState::FetchPending, false) => {
+                // Nothing has been rendered yet; the label just won't be added once the name
+                // arrives
+                self.label_state = LabelState::NotRequested;
+            },
+            (LabelState::Rendered, false) => {
+                self.label_state = LabelState::NotRequested;
+                if *total_rendered_label_count == 0 {
+                    warn!(
+                        "Total rendered label count accounting error; was zero and tried to \
+                         subtract one when removing artist label"
+                    );
+                }
+                *total_rendered_label_count = total_rendered_label_count.saturating_sub(1);
+                draw_commands.push(REMOVE_LABEL_CMD);
+                draw_commands.push(artist_id);
+            },
+            (LabelState::NotRequested, false)
+            | (LabelState::FetchPending, true)
+            | (LabelState::Rendered, true) => (),
+        }
+    }
 }
 
 #[derive(Default)]
 pub struct ArtistRelationship {
     pub related_artist_index: usize,
     pub connections_buffer_index: Option<usize>,
+    /// How strongly the artists are related, 0-255.  `DEFAULT_CONNECTION_WEIGHT` if the
+    /// relationship data didn't include weights.
+    pub weight: u8,
 }
 
 #[derive(Default)]
 pub struct ArtistRelationships {
     pub count: usize,
     pub related_artist_indices: [ArtistRelationship; MAX_RELATED_ARTIST_COUNT],
+    /// IDs of related artists that aren't in the embedding and so can't be rendered
+    pub out_of_set_related_artist_ids: Vec<u32>,
+    /// `true` once connections for these relationships have been added to `connections_buffer`.
+    /// Some relationships may not have been rendered even if this is set.
+    pub connections_built: bool,
+}
+
+/// Controls how many highlighted artists are labeled when transitioning to orbit mode and how
+/// spread out those labels are.  Distances are in world units, so they need to be adjusted for
+/// embeddings with a different scale.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OrbitLabelParams {
+    /// Max number of labels placed on the artists furthest from any existing label
+    pub top_count: usize,
+    /// Max number of labels placed on random artists after the top ones
+    pub random_count: usize,
+    /// Top labels stop being placed once the furthest artist is at most this far from a label
+    pub min_distance: f32,
+    /// Random labels are only placed on artists further than this from any existing label
+    pub random_min_distance: f32,
+}
+
+impl Default for OrbitLabelParams {
+    fn default() -> Self {
+        OrbitLabelParams {
+            top_count: 7,
+            random_count: 12,
+            min_distance: 10_000.,
+            random_min_distance: 26_200.,
+        }
+    }
+}
+
+/// The direction the camera is facing, used to avoid rendering geometry for artists behind it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ViewCone {
+    /// Normalized
+    pub forward: [f32; 3],
+    /// Cosine of the half-angle of the cone within which unrendered artists start being rendered
+    pub cos_enter_half_angle: f32,
+    /// Cosine of the half-angle of the cone outside of which rendered artists are removed
+    pub cos_exit_half_angle: f32,
+}
+
+impl ViewCone {
+    /// Returns `None`, meaning that culling is disabled, if any of the inputs are NaN or the
+    /// forward vector is zero
+    pub fn new(forward: [f32; 3], fov_degrees: f32) -> Option<Self> {
+        if fov_degrees.is_nan() || forward.iter().any(|val| val.is_nan()) {
+            return None;
+        }
+        let len = distance(&forward, &[0.; 3]);
+        if len == 0. || !len.is_finite() {
+            return None;
+        }
+
+        let half_fov = fov_degrees / 2.;
+        let half_angle_cos =
+            |margin_degrees: f32| (half_fov + margin_degrees).min(180.).to_radians().cos();
+        Some(ViewCone {
+            forward: [forward[0] / len, forward[1] / len, forward[2] / len],
+            cos_enter_half_angle: half_angle_cos(VIEW_CONE_ENTER_MARGIN_DEGREES),
+            cos_exit_half_angle: half_angle_cos(VIEW_CONE_EXIT_MARGIN_DEGREES),
+        })
+    }
+
+    /// `is_rendered` determines whether the artist is checked against the enter or the exit cone.
+    /// `distance` is the distance between `camera_pos` and `artist_pos`.
+    pub fn contains(
+        &self,
+        camera_pos: &[f32; 3],
+        artist_pos: &[f32; 3],
+        distance: f32,
+        is_rendered: bool,
+    ) -> bool {
+        if distance < VIEW_CONE_MIN_CULL_DISTANCE {
+            return true;
+        }
+
+        let mut dot = 0.;
+        for dim_ix in 0..3 {
+            dot += (artist_pos[dim_ix] - camera_pos[dim_ix]) * self.forward[dim_ix];
+        }
+        let cos_angle = dot / distance;
+        if is_rendered {
+            cos_angle >= self.cos_exit_half_angle
+        } else {
+            cos_angle >= self.cos_enter_half_angle
+        }
+    }
 }
 
 pub struct ArtistMapCtx {
@@ -62,6 +223,8 @@ pub struct ArtistMapCtx {
     pub playing_music_artist_id: Option<u32>,
     pub most_recently_played_artist_ids: VecDeque<u32>,
     pub connections_buffer: Vec<[[f32; 3]; 2]>,
+    /// Weights of the relationships rendered in `connections_buffer`, parallel to it
+    pub connection_weights: Vec<u8>,
     pub rendered_connections: HashSet<(usize, usize)>,
     pub did_set_highlighted_artists: bool,
     pub last_force_labeled_artist_id: Option<u32>,
@@ -69,18 +232,87 @@ pub struct ArtistMapCtx {
     pub quality: u8,
     pub manual_play_artist_id: Option<u32>,
     pub received_chunks: HashSet<(u32, u32)>,
+    pub orbit_label_params: OrbitLabelParams,
+    /// Indices of artists whose relationships were added individually with
+    /// `add_single_artist_relationships` rather than as part of a chunk
+    pub expanded_artist_indices: HashSet<usize>,
+    /// Chunk size of the first received relationship chunk, used to estimate how many chunks
+    /// there are in total
+    pub relationship_chunk_size: Option<u32>,
+    /// Total number of relationships in all received chunks, including ones to artists that
+    /// aren't in the embedding
+    pub total_relationships_parsed: usize,
     pub color_noise: noise::SuperSimplex,
     pub connection_colors_buffer: Vec<u8>,
+    /// If `false`, `connection_colors_buffer` is left empty and connections are expected to be
+    /// rendered in a uniform color
+    pub connection_coloring_enabled: bool,
     pub artist_colors_buffer: Vec<(u32, [f32; 3])>,
+    /// Colors set by JS for specific artists which take precedence over the generated ones
+    pub artist_color_overrides: HashMap<u32, [f32; 3]>,
+    /// Highlight weights (0-255) of highlighted artists by index into `all_artists`.  Highlighted
+    /// artists without an entry have full weight, so this is empty when all highlighted artists
+    /// are weighted uniformly.
+    pub highlight_weight_by_index: HashMap<usize, u8>,
+    /// Connections longer than this are never rendered, regardless of quality
+    pub max_connection_length: f32,
+    /// `true` when the user is flying around the galaxy, `false` when in orbit mode.  Determines
+    /// whether music is played and how labels are picked.
+    pub is_fly_mode: bool,
+    /// Per-dimension minimums and maximums of all artist positions
+    pub galaxy_mins: [f32; 3],
+    pub galaxy_maxs: [f32; 3],
+    /// View cone passed to the most recent call to `handle_new_position`, if culling was enabled
+    pub last_view_cone: Option<ViewCone>,
+    /// `true` if the most recent geometry update left some geometry changes undone because it was
+    /// over its command budget
+    pub has_deferred_geometry_updates: bool,
+    /// When enabled, start and stop playing music commands are followed by a third word holding
+    /// the duration in milliseconds to fade the music in or out over
+    pub crossfade_enabled: bool,
+    /// Artists that are never played, either automatically or manually
+    pub excluded_artist_ids: HashSet<u32>,
 }
 
 const DISTANCE_MULTIPLIER: [f32; 3] = [50500., 50400., 54130.];
 const LABEL_RENDER_DISTANCE: f32 = 16320.;
 const MAX_MUSIC_PLAY_DISTANCE: f32 = 13740.;
 const MAX_RECENTLY_PLAYED_ARTISTS_TO_TRACK: usize = 12;
+const DEFAULT_MUSIC_FADE_IN_MS: u32 = 350;
+const DEFAULT_MUSIC_FADE_OUT_MS: u32 = 3600;
+const MIN_MUSIC_FADE_MS: u32 = 250;
+/// Distance past the max music play distance at which music is faded out as quickly as possible
+const MUSIC_FADE_OVERSHOOT_DISTANCE: f32 = 4000.;
 const MAX_RELATED_ARTIST_COUNT: usize = 20;
-const MAX_EXTRA_RANDOM_HIGHLIGHTED_ARTIST_ORBIT_MODE_LABEL_COUNT: usize = 12;
+/// Weight given to all relationships when the relationship data doesn't include weights.  Renders
+/// connections the same as they were before weights were introduced.
+const DEFAULT_CONNECTION_WEIGHT: u8 = 128;
 const DEFAULT_QUALITY: u8 = 7;
+/// Quality is clamped to this.  The render heuristics do work proportional to the distance between
+/// the quality and `DEFAULT_QUALITY`, and values much past this render everything anyway.
+const MAX_QUALITY: u8 = 15;
+/// Added to the bottom of the range of random values generated when deciding whether to render a
+/// connection on mobile.  Negative values make connections less likely to be rendered at all
+/// distances, since mobile devices struggle with the same connection density as desktop.
+const MOBILE_CONNECTION_RENDER_RNG_ADJUSTMENT: f64 = -0.25;
+/// How far ahead of the projected next position to look when prefetching artist names in fly mode,
+/// as a multiple of the distance between the current and projected next positions
+const LABEL_PREFETCH_LOOKAHEAD_MULTIPLIER: f32 = 10.;
+const MAX_PREFETCHED_ARTIST_NAMES_PER_FRAME: usize = 12;
+/// When geometry updates are over budget, each point of popularity makes an artist's geometry as
+/// much of a priority as if it were this much closer
+const GEOMETRY_PRIORITY_DISTANCE_PER_POPULARITY: f32 = 200.;
+/// The suggested orbit radius is the radius of the galaxy's bounding sphere multiplied by this.
+/// It's far enough away that the whole sphere fits in view with the default FOV.
+const SUGGESTED_ORBIT_RADIUS_MULTIPLIER: f32 = 1.5;
+/// Added to half of the camera's FOV to determine the cone within which artist geometry starts
+/// being rendered.  Generous so that geometry is already there when it comes into view.
+const VIEW_CONE_ENTER_MARGIN_DEGREES: f32 = 30.;
+/// Added to half of the camera's FOV to determine the cone outside of which artist geometry is
+/// removed.  Wider than the enter cone so that geometry doesn't pop in and out as the camera turns.
+const VIEW_CONE_EXIT_MARGIN_DEGREES: f32 = 50.;
+/// Artists closer than this to the camera are never culled by the view cone
+const VIEW_CONE_MIN_CULL_DISTANCE: f32 = 9000.;
 /// IDS of artists to be rendered when in orbit control mode.  Represent a wide variety of different
 /// artists from disparate parts of the galaxy.
 const ORBIT_LABEL_ARTIST_IDS: &[u32] = &[
@@ -126,6 +358,7 @@ impl Default for ArtistMapCtx {
             playing_music_artist_id: None,
             most_recently_played_artist_ids: VecDeque::new(),
             connections_buffer: Vec::new(),
+            connection_weights: Vec::new(),
             rendered_connections: HashSet::default(),
             did_set_highlighted_artists: false,
             last_force_labeled_artist_id: None,
@@ -133,9 +366,24 @@ impl Default for ArtistMapCtx {
             quality: DEFAULT_QUALITY,
             manual_play_artist_id: None,
             received_chunks: HashSet::default(),
+            orbit_label_params: OrbitLabelParams::default(),
+            expanded_artist_indices: HashSet::default(),
+            relationship_chunk_size: None,
+            total_relationships_parsed: 0,
             color_noise: noise::SuperSimplex::new().set_seed(COLOR_NOISE_SEED),
             connection_colors_buffer: Vec::new(),
+            connection_coloring_enabled: true,
             artist_colors_buffer: Vec::new(),
+            artist_color_overrides: HashMap::default(),
+            highlight_weight_by_index: HashMap::default(),
+            max_connection_length: f32::INFINITY,
+            is_fly_mode: false,
+            galaxy_mins: [0.; 3],
+            galaxy_maxs: [0.; 3],
+            last_view_cone: None,
+            has_deferred_geometry_updates: false,
+            crossfade_enabled: false,
+            excluded_artist_ids: HashSet::default(),
         }
     }
 }
@@ -147,7 +395,9 @@ impl ArtistMapCtx {
         self.all_artists
             .iter()
             .filter_map(|(id, state)| {
-                if self.most_recently_played_artist_ids.contains(id) {
+                if self.most_recently_played_artist_ids.contains(id)
+                    || self.excluded_artist_ids.contains(id)
+                {
                     None
                 } else {
                     let dist = distance(&state.position, &cur_position);
@@ -162,10 +412,35 @@ impl ArtistMapCtx {
             .map(|(id, _)| id)
     }
 
-    pub fn start_playing_artist_id(&mut self, draw_commands: &mut Vec<u32>, artist_id: u32) {
-        debug!("Starting music for artist id={}", artist_id);
-        draw_commands.push(START_PLAYING_MUSIC_CMD);
+    /// Pushes a start or stop playing music command, including the fade duration if crossfading is
+    /// enabled
+    fn push_music_command(
+        &self,
+        draw_commands: &mut Vec<u32>,
+        cmd: u32,
+        artist_id: u32,
+        fade_ms: u32,
+    ) {
+        draw_commands.push(cmd);
         draw_commands.push(artist_id);
+        if self.crossfade_enabled {
+            draw_commands.push(fade_ms);
+        }
+    }
+
+    pub fn start_playing_artist_id(
+        &mut self,
+        draw_commands: &mut Vec<u32>,
+        artist_id: u32,
+        fade_in_ms: u32,
+    ) {
+        debug!("Starting music for artist id={}", artist_id);
+        self.push_music_command(
+            draw_commands,
+            START_PLAYING_MUSIC_CMD,
+            artist_id,
+            fade_in_ms,
+        );
         self.playing_music_artist_id = Some(artist_id);
         self.manual_play_artist_id = None;
 
@@ -187,15 +462,18 @@ impl ArtistMapCtx {
         cur_x: f32,
         cur_y: f32,
         cur_z: f32,
+        fade_in_ms: u32,
     ) {
         let next_artist_to_play = self.get_next_artist_to_play(cur_x, cur_y, cur_z);
         if let Some(next_artist_to_play) = next_artist_to_play {
-            self.start_playing_artist_id(draw_commands, next_artist_to_play);
+            self.start_playing_artist_id(draw_commands, next_artist_to_play, fade_in_ms);
         } else {
             self.playing_music_artist_id = None;
         }
     }
 
+    /// If `cur_x` is finite, the closest artist that hasn't been played recently starts playing.
+    /// It fades in at most as slowly as the stopped music fades out so that they crossfade.
     pub fn stop_playing_music(
         &mut self,
         artist_id: u32,
@@ -204,9 +482,14 @@ impl ArtistMapCtx {
         cur_y: f32,
         cur_z: f32,
         force_do_not_record_as_recently_played: bool,
+        fade_out_ms: u32,
     ) {
-        draw_commands.push(STOP_PLAYING_MUSIC_CMD);
-        draw_commands.push(artist_id);
+        self.push_music_command(
+            draw_commands,
+            STOP_PLAYING_MUSIC_CMD,
+            artist_id,
+            fade_out_ms,
+        );
 
         if !force_do_not_record_as_recently_played {
             self.most_recently_played_artist_ids.push_front(artist_id);
@@ -217,7 +500,13 @@ impl ArtistMapCtx {
         }
 
         if cur_x.is_finite() {
-            self.maybe_start_playing_new_music(draw_commands, cur_x, cur_y, cur_z);
+            self.maybe_start_playing_new_music(
+                draw_commands,
+                cur_x,
+                cur_y,
+                cur_z,
+                fade_out_ms.min(DEFAULT_MUSIC_FADE_IN_MS),
+            );
         }
     }
 
@@ -230,17 +519,13 @@ impl ArtistMapCtx {
             .next()
             .unwrap_or_default();
 
-        let quality_rng_adjustment = get_connection_render_quality_rng_adjustment(self.quality);
+        let quality_rng_adjustment =
+            get_connection_render_quality_rng_adjustment(self.quality, self.is_mobile);
 
         for artist_id in new_artist_ids {
             let src_artist_ix = *self.artists_indices_by_id.get(artist_id).unwrap();
-            let src = &self.all_artists[src_artist_ix].1;
-            let relationship_state = &mut self.all_artist_relationships[src_artist_ix];
 
-            if relationship_state.related_artist_indices[0]
-                .connections_buffer_index
-                .is_some()
-            {
+            if self.all_artist_relationships[src_artist_ix].connections_built {
                 warn!(
                     "Double-received relationship data for artist_id={}",
                     artist_id
@@ -248,30 +533,87 @@ impl ArtistMapCtx {
                 continue;
             }
 
-            for relationship in
-                &mut relationship_state.related_artist_indices[..relationship_state.count]
-            {
-                let related_artist_ix = relationship.related_artist_index;
-                let dst = &self.all_artists[related_artist_ix].1;
+            self.render_artist_connections(src_artist_ix, quality_rng_adjustment);
+        }
+    }
 
-                let should_render = should_render_connection(quality_rng_adjustment, &src, &dst);
-                if !should_render {
-                    continue;
-                }
+    /// Adds connections from the artist to each of its related artists to `connections_buffer`,
+    /// skipping ones that have already been rendered from the other direction.
+    fn render_artist_connections(&mut self, src_artist_ix: usize, quality_rng_adjustment: f64) {
+        let max_connection_length = self.max_connection_length;
+        let src = &self.all_artists[src_artist_ix].1;
+        let relationship_state = &mut self.all_artist_relationships[src_artist_ix];
+        relationship_state.connections_built = true;
 
-                // Skip rendering connection if one already exists from the other direction
-                let connection_key = (
-                    src_artist_ix.min(related_artist_ix),
-                    src_artist_ix.max(related_artist_ix),
-                );
-                if !self.rendered_connections.insert(connection_key) {
-                    continue;
-                }
+        for relationship in
+            &mut relationship_state.related_artist_indices[..relationship_state.count]
+        {
+            let related_artist_ix = relationship.related_artist_index;
+            let dst = &self.all_artists[related_artist_ix].1;
+
+            if distance(&src.position, &dst.position) > max_connection_length {
+                continue;
+            }
+
+            let should_render =
+                should_render_connection(quality_rng_adjustment, &src, &dst, relationship.weight);
+            if !should_render {
+                continue;
+            }
+
+            // Skip rendering connection if one already exists from the other direction
+            let connection_key = (
+                src_artist_ix.min(related_artist_ix),
+                src_artist_ix.max(related_artist_ix),
+            );
+            if !self.rendered_connections.insert(connection_key) {
+                continue;
+            }
+
+            self.connections_buffer.push([src.position, dst.position]);
+            self.connection_weights.push(relationship.weight);
+            relationship.connections_buffer_index = Some(self.connections_buffer.len() - 1);
+        }
+    }
 
-                self.connections_buffer.push([src.position, dst.position]);
-                relationship.connections_buffer_index = Some(self.connections_buffer.len() - 1);
+    /// Throws away all rendered connections and re-builds them from all received relationship
+    /// chunks, taking into account the current quality and max connection length.
+    pub fn rebuild_connections_buffer(&mut self) {
+        for relationships in &mut self.all_artist_relationships {
+            relationships.connections_built = false;
+            for relationship in &mut relationships.related_artist_indices {
+                relationship.connections_buffer_index = None;
             }
         }
+        self.connections_buffer.clear();
+        self.connection_weights.clear();
+        self.rendered_connections.clear();
+
+        info!(
+            "Updating connections buffer with connections from {} chunks: {:?}",
+            self.received_chunks.len(),
+            self.received_chunks
+        );
+
+        let mut chunks_to_rerender = self.received_chunks.iter().cloned().collect::<Vec<_>>();
+        chunks_to_rerender.sort_unstable();
+
+        for (chunk_ix, chunk_size) in chunks_to_rerender {
+            self.update_connections_buffer(chunk_size, chunk_ix);
+        }
+
+        let quality_rng_adjustment =
+            get_connection_render_quality_rng_adjustment(self.quality, self.is_mobile);
+        let mut expanded_artist_indices = self
+            .expanded_artist_indices
+            .iter()
+            .copied()
+            .collect::<Vec<_>>();
+        expanded_artist_indices.sort_unstable();
+        for artist_ix in expanded_artist_indices {
+            self.render_artist_connections(artist_ix, quality_rng_adjustment);
+        }
+        self.populate_connection_colors_buffer();
     }
 
     pub fn add_highlighted_artist_orbit_labels(&mut self, draw_commands: &mut Vec<u32>) {
@@ -291,37 +633,55 @@ impl ArtistMapCtx {
             })
             .collect();
 
-        let all_highlighted_artists: Vec<(u32, [f32; 3])> = self
+        let all_highlighted_artists: Vec<(u32, [f32; 3], u8)> = self
             .all_artists
             .iter()
-            .filter_map(|(id, state)| {
+            .enumerate()
+            .filter_map(|(artist_ix, (id, state))| {
                 if state
                     .render_state
                     .contains(ArtistRenderState::IS_HIGHLIGHTED)
                 {
-                    Some((*id, state.position))
+                    let weight = highlight_weight(&self.highlight_weight_by_index, artist_ix);
+                    Some((*id, state.position, weight))
                 } else {
                     None
                 }
             })
             .collect();
 
-        // Find up to 7 of the highlighted artists that have the highest min distance to any of the
-        // always-rendered orbit labels
-        for _ in 0..7 {
+        if all_highlighted_artists.is_empty() {
+            return;
+        }
+        let OrbitLabelParams {
+            top_count,
+            random_count,
+            min_distance: top_min_distance,
+            random_min_distance,
+        } = self.orbit_label_params;
+
+        // Find up to `top_count` of the highlighted artists that have the highest min distance to
+        // any of the always-rendered orbit labels.  Distances are scaled by highlight weight so
+        // that higher-weighted artists are preferred.
+        for _ in 0..top_count {
             let highlighted_artist_with_largest_min_distance_to_existing_label: Option<(u32, f32)> =
                 all_highlighted_artists
                     .iter()
-                    .map(|(id, position)| {
+                    .map(|(id, position, weight)| {
                         let min_distance = rendered_label_positions
                             .iter()
                             .map(|label_position| FloatOrd(distance(position, label_position)))
                             .min()
-                            .unwrap()
-                            .0;
-                        (*id, min_distance)
+                            .map(|FloatOrd(min_distance)| min_distance)
+                            .unwrap_or(f32::INFINITY);
+                        let weighted_min_distance =
+                            min_distance * (0.5 + 0.5 * (*weight as f32 / 255.));
+                        (*id, min_distance, weighted_min_distance)
+                    })
+                    .max_by_key(|(_id, _min_distance, weighted_min_distance)| {
+                        FloatOrd(*weighted_min_distance)
                     })
-                    .max_by_key(|(_id, min_distance)| FloatOrd(*min_distance));
+                    .map(|(id, min_distance, _)| (id, min_distance));
 
             let (artist_id, min_distance_to_existing_label) =
                 match highlighted_artist_with_largest_min_distance_to_existing_label {
@@ -329,7 +689,7 @@ impl ArtistMapCtx {
                     None => return,
                 };
 
-            if min_distance_to_existing_label <= 10_000. {
+            if min_distance_to_existing_label <= top_min_distance {
                 info!(
                     "Custom label min distance to existing label is too small; not rendering any \
                      more custom labels",
@@ -340,50 +700,47 @@ impl ArtistMapCtx {
 
             let artist_ix = self.artists_indices_by_id.get(&artist_id).unwrap();
             let artist_state = &mut self.all_artists[*artist_ix].1;
-            artist_state
-                .render_state
-                .set(ArtistRenderState::RENDER_LABEL, true);
-            draw_commands.push(FETCH_ARTIST_DATA_CMD);
-            draw_commands.push(artist_id);
+            artist_state.set_label_requested(
+                artist_id,
+                true,
+                &mut self.total_rendered_label_count,
+                draw_commands,
+            );
 
             // Take this label into account when picking others to render as well
             rendered_label_positions.push(artist_state.position);
         }
 
-        // Also render up to `MAX_EXTRA_RANDOM_HIGHLIGHTED_ARTIST_ORBIT_MODE_LABEL_COUNT` additional
-        // random artists that are further than the min distance threshold from any of the
-        // always-rendered orbit labels
+        // Also render up to `random_count` additional random artists that are further than the
+        // random min distance threshold from any of the already-rendered labels
         let mut rendered_random_artist_count = 0usize;
         for _ in 0..100 {
-            if rendered_random_artist_count
-                >= MAX_EXTRA_RANDOM_HIGHLIGHTED_ARTIST_ORBIT_MODE_LABEL_COUNT
-            {
-                info!(
-                    "Rendered {} extra random artists!",
-                    MAX_EXTRA_RANDOM_HIGHLIGHTED_ARTIST_ORBIT_MODE_LABEL_COUNT
-                );
+            if rendered_random_artist_count >= random_count {
+                info!("Rendered {} extra random artists!", random_count);
                 return;
             }
 
-            let (random_artist_id, position) = all_highlighted_artists.choose(rng()).unwrap();
+            let (random_artist_id, position, _weight) =
+                all_highlighted_artists.choose(rng()).unwrap();
             let min_distance = rendered_label_positions
                 .iter()
                 .map(|label_position| FloatOrd(distance(position, label_position)))
                 .min()
-                .unwrap()
-                .0;
+                .map(|FloatOrd(min_distance)| min_distance)
+                .unwrap_or(f32::INFINITY);
 
-            if min_distance <= 26_200. {
+            if min_distance <= random_min_distance {
                 continue;
             }
 
             let artist_ix = self.artists_indices_by_id.get(&random_artist_id).unwrap();
             let artist_state = &mut self.all_artists[*artist_ix].1;
-            artist_state
-                .render_state
-                .set(ArtistRenderState::RENDER_LABEL, true);
-            draw_commands.push(FETCH_ARTIST_DATA_CMD);
-            draw_commands.push(*random_artist_id);
+            artist_state.set_label_requested(
+                *random_artist_id,
+                true,
+                &mut self.total_rendered_label_count,
+                draw_commands,
+            );
 
             // Take this label into account when picking others to render as well
             rendered_label_positions.push(artist_state.position);
@@ -413,12 +770,31 @@ fn maybe_init() {
     })
 }
 
+/// Returns the highlight weight of the artist at `artist_ix`, defaulting to full weight
+fn highlight_weight(highlight_weight_by_index: &HashMap<usize, u8>, artist_ix: usize) -> u8 {
+    highlight_weight_by_index
+        .get(&artist_ix)
+        .copied()
+        .unwrap_or(u8::MAX)
+}
+
+/// Scales a score multiplier applied to highlighted artists towards 1 (no effect) for artists with
+/// lower highlight weights.  Full weight returns `multiplier` unchanged.
+fn scale_highlight_multiplier(multiplier: f32, highlight_weight: u8) -> f32 {
+    if highlight_weight == u8::MAX {
+        return multiplier;
+    }
+
+    1. - (1. - multiplier) * (highlight_weight as f32 / 255.)
+}
+
 pub fn should_render_label(
     total_rendered_label_count: usize,
     artist_state: &ArtistState,
     distance: f32,
     is_mobile: bool,
     quality: u8,
+    highlight_weight: u8,
 ) -> bool {
     if distance < 6800. {
         return true;
@@ -439,468 +815,1577 @@ pub fn should_render_label(
         .render_state
         .contains(ArtistRenderState::IS_HIGHLIGHTED)
     {
-        score *= 0.3338
+        score *= scale_highlight_multiplier(0.3338, highlight_weight);
     }
 
     if is_mobile {
         score *= 1.1347;
     }
 
+    score *= get_label_quality_score_multiplier(quality);
+
+    score <= LABEL_RENDER_DISTANCE
+}
+
+/// Lower quality makes labels harder to render and higher quality makes them easier, mirroring
+/// `should_render_artist`.  Each quality step above the default shrinks label scores by 7%, so at
+/// `MAX_QUALITY` unhighlighted labels render from nearly twice as far away.
+fn get_label_quality_score_multiplier(quality: u8) -> f32 {
+    let mut multiplier = 1.;
     let mut quality_diff = DEFAULT_QUALITY as i8 - quality as i8;
     while quality_diff > 0 {
-        score *= 0.95;
+        multiplier *= 1.087;
         quality_diff -= 1;
     }
-    while quality_diff > 0 {
-        score *= 1.087;
+    while quality_diff < 0 {
+        multiplier *= 0.93;
         quality_diff += 1;
     }
-
-    score <= LABEL_RENDER_DISTANCE
+    multiplier
 }
 
-#[wasm_bindgen]
-pub fn create_artist_map_ctx() -> *mut ArtistMapCtx {
-    maybe_init();
+impl ArtistMapCtx {
+    /// Builds a context from packed artist positions in the same format accepted by
+    /// `decode_and_record_packed_artist_positions`.  Unlike `create_artist_map_ctx`, this doesn't
+    /// touch any JS APIs, so it can be used natively.
+    pub fn from_packed(packed: &[u8], is_mobile: bool) -> Self {
+        let mut ctx = Self::default();
+        ctx.record_packed_artist_positions(packed, is_mobile);
+        ctx
+    }
 
-    Box::into_raw(Box::new(ArtistMapCtx::default()))
-}
+    /// Returns total number of artists in the embedding
+    pub fn record_packed_artist_positions(&mut self, packed: &[u8], is_mobile: bool) -> usize {
+        // `packed` isn't guaranteed to be aligned when it doesn't come from the JS side, so all
+        // reads out of it are unaligned
+        let ptr = packed.as_ptr() as *const u32;
+        let count = unsafe { ptr.read_unaligned() } as usize;
+
+        self.all_artists.reserve(count);
+        self.all_artist_relationships.reserve(count);
+        self.sorted_artist_ids.reserve(count);
+        self.artists_indices_by_id.reserve(count);
+        self.is_mobile = is_mobile;
+
+        let mut maxs = [f32::NEG_INFINITY; 3];
+        let mut mins = [f32::INFINITY; 3];
+
+        let has_popularities = packed.len() > 4 + count * 4 + count * 3 * 4;
+        let popularities_byte_offset = 4 + count * 4 + count * 3 * 4;
+        let popularities_ptr = unsafe { (ptr as *const u8).add(popularities_byte_offset) };
+
+        let ptr = unsafe { ptr.add(1) };
+        for i in 0..count {
+            unsafe {
+                let id: u32 = ptr.add(i).read_unaligned();
+                let mut pos: [f32; 3] =
+                    (ptr.add(count + i * 3) as *const [f32; 3]).read_unaligned();
+                for (dim_ix, val) in pos.iter_mut().enumerate() {
+                    *val *= DISTANCE_MULTIPLIER[dim_ix];
+                }
 
-/// Returns total number of artists in the embedding
-#[wasm_bindgen]
-pub fn decode_and_record_packed_artist_positions(
-    ctx: *mut ArtistMapCtx,
-    packed: Vec<u8>,
-    is_mobile: bool,
-) -> usize {
-    let ctx = unsafe { &mut *ctx };
+                for (dim_ix, val) in pos.iter().enumerate() {
+                    maxs[dim_ix] = maxs[dim_ix].max(*val);
+                    mins[dim_ix] = mins[dim_ix].min(*val);
+                }
 
-    let ptr = packed.as_ptr() as *const u32;
-    let count = unsafe { *ptr } as usize;
+                let state = ArtistState {
+                    position: pos.clone(),
+                    popularity: if has_popularities {
+                        *popularities_ptr.add(i)
+                    } else {
+                        20
+                    },
+                    render_state: ArtistRenderState::empty(),
+                    label_state: LabelState::NotRequested,
+                };
+                self.all_artists.push((id, state));
+                self.all_artist_relationships.push(Default::default());
+                self.sorted_artist_ids.push(id);
 
-    ctx.all_artists.reserve(count);
-    ctx.all_artist_relationships.reserve(count);
-    ctx.sorted_artist_ids.reserve(count);
-    ctx.artists_indices_by_id.reserve(count);
-    ctx.is_mobile = is_mobile;
+                self.artists_indices_by_id
+                    .insert(id, self.all_artists.len() - 1);
+            }
+        }
 
-    let mut maxs = [f32::NEG_INFINITY; 3];
-    let mut mins = [f32::INFINITY; 3];
+        self.sorted_artist_ids.sort_unstable();
+        if count > 0 {
+            self.galaxy_mins = mins;
+            self.galaxy_maxs = maxs;
+        }
 
-    let has_popularities = packed.len() > 4 + count * 4 + count * 3 * 4;
-    let popularities_byte_offset = 4 + count * 4 + count * 3 * 4;
-    let popularities_ptr = unsafe { (ptr as *const u8).add(popularities_byte_offset) };
+        info!("Successfully parsed + stored {} artist positions", count);
 
-    let ptr = unsafe { ptr.add(1) };
-    for i in 0..count {
-        unsafe {
-            let id: u32 = *ptr.add(i);
-            let pos: &[f32; 3] = &*(ptr.add(count + i * 3) as *const _);
-            let mut pos: [f32; 3] = *pos;
-            for (dim_ix, val) in pos.iter_mut().enumerate() {
-                *val *= DISTANCE_MULTIPLIER[dim_ix];
-            }
+        self.populate_artist_color_buffer();
 
-            for (dim_ix, val) in pos.iter().enumerate() {
-                maxs[dim_ix] = maxs[dim_ix].max(*val);
-                mins[dim_ix] = mins[dim_ix].min(*val);
-            }
+        count
+    }
 
-            let state = ArtistState {
-                position: pos.clone(),
-                popularity: if has_popularities {
-                    *popularities_ptr.add(i)
-                } else {
-                    20
+    /// Returns a vector of draw commands
+    pub fn handle_received_artist_names(
+        &mut self,
+        artist_ids: Vec<u32>,
+        cur_x: f32,
+        cur_y: f32,
+        cur_z: f32,
+    ) -> Vec<u32> {
+        let mut draw_commands: Vec<u32> = Vec::new();
+
+        for artist_id in artist_ids {
+            let (artist_ix, artist_state) = match self.artists_indices_by_id.get(&artist_id) {
+                Some(&ix) => (ix, &mut self.all_artists[ix].1),
+                None => {
+                    error!(
+                        "Artist not in embedding but received name for it; artist_id={}",
+                        artist_id
+                    );
+                    continue;
                 },
-                render_state: ArtistRenderState::empty(),
             };
-            ctx.all_artists.push((id, state));
-            ctx.all_artist_relationships.push(Default::default());
-            ctx.sorted_artist_ids.push(id);
-
-            ctx.artists_indices_by_id
-                .insert(id, ctx.all_artists.len() - 1);
-        }
-    }
 
-    ctx.sorted_artist_ids.sort_unstable();
+            if artist_state
+                .render_state
+                .contains(ArtistRenderState::HAS_NAME)
+            {
+                warn!(
+                    "Received artist name multiple times for artist_id={}",
+                    artist_id
+                );
+                continue;
+            }
 
-    info!("Successfully parsed + stored {} artist positions", count);
+            artist_state
+                .render_state
+                .set(ArtistRenderState::HAS_NAME, true);
 
-    ctx.populate_artist_color_buffer();
+            // The label may have been removed while the name was being fetched, in which case it's
+            // no longer pending and shouldn't be rendered
+            if artist_state.label_state != LabelState::FetchPending {
+                continue;
+            }
 
-    count
-}
+            let distance = distance(&artist_state.position, &[cur_x, cur_y, cur_z]);
+            let should_render = !self.is_fly_mode
+                || should_render_label(
+                    self.total_rendered_label_count,
+                    artist_state,
+                    distance,
+                    self.is_mobile,
+                    self.quality,
+                    highlight_weight(&self.highlight_weight_by_index, artist_ix),
+                );
+            if should_render {
+                artist_state.label_state = LabelState::Rendered;
+                self.total_rendered_label_count += 1;
+                draw_commands.push(ADD_LABEL_CMD);
+                draw_commands.push(artist_id);
+            } else {
+                artist_state.label_state = LabelState::NotRequested;
+            }
+        }
 
-#[wasm_bindgen]
-pub fn get_all_artist_data(ctx: *mut ArtistMapCtx) -> Vec<f32> {
-    let ctx = unsafe { &mut *ctx };
+        draw_commands
+    }
 
-    let mut out: Vec<f32> = Vec::with_capacity(ctx.all_artists.len() * 5);
-    unsafe { out.set_len(ctx.all_artists.len() * 5) };
+    /// Returns a vector of draw commands
+    pub fn handle_new_position(
+        &mut self,
+        cur_x: f32,
+        cur_y: f32,
+        cur_z: f32,
+        projected_next_x: f32,
+        projected_next_y: f32,
+        projected_next_z: f32,
+        view_cone: Option<ViewCone>,
+        max_geometry_commands: Option<usize>,
+    ) -> Vec<u32> {
+        let is_fly_mode = self.is_fly_mode;
+        if self.last_position[0] == cur_x
+            && self.last_position[1] == cur_y
+            && self.last_position[2] == cur_z
+            && self.last_view_cone == view_cone
+        {
+            return self.flush_deferred_geometry_updates(max_geometry_commands);
+        }
+        self.last_position = [cur_x, cur_y, cur_z];
+        self.last_view_cone = view_cone;
+
+        // 0: label to add
+        // 1: label to remove
+        // 2: artist geometry to add
+        // 3: artist geomety to remove
+        // 4: fetch artist data
+        // 5: start playing music, followed by artist ID and fade in ms if crossfading is enabled
+        // 6: stop playing music, followed by artist ID and fade out ms if crossfading is enabled
+        let mut render_commands: Vec<u32> = Vec::new();
+
+        // When flying, look ahead along the direction of travel for artists that will need labels
+        // soon so that their names can be fetched before we get there.
+        let prefetch_position = if is_fly_mode {
+            let cur_pos = [cur_x, cur_y, cur_z];
+            let projected_next_pos = [projected_next_x, projected_next_y, projected_next_z];
+            if cur_pos == projected_next_pos {
+                None
+            } else {
+                let mut prefetch_position = cur_pos;
+                for dim_ix in 0..3 {
+                    prefetch_position[dim_ix] += (projected_next_pos[dim_ix] - cur_pos[dim_ix])
+                        * LABEL_PREFETCH_LOOKAHEAD_MULTIPLIER;
+                }
+                Some(prefetch_position)
+            }
+        } else {
+            None
+        };
+        let mut prefetch_candidates: Vec<(FloatOrd<f32>, u32)> = Vec::new();
+        let mut geometry_updates: Vec<GeometryUpdate> = Vec::new();
+
+        for (artist_ix, (artist_id, artist_state)) in self.all_artists.iter_mut().enumerate() {
+            let distance = distance(&artist_state.position, &self.last_position);
+            let highlight_weight = highlight_weight(&self.highlight_weight_by_index, artist_ix);
+
+            let should_render_label = should_render_label(
+                self.total_rendered_label_count,
+                artist_state,
+                distance,
+                self.is_mobile,
+                self.quality,
+                highlight_weight,
+            );
+            if is_fly_mode {
+                artist_state.set_label_requested(
+                    *artist_id,
+                    should_render_label,
+                    &mut self.total_rendered_label_count,
+                    &mut render_commands,
+                );
+            }
 
-    for (i, (artist_id, state)) in ctx.all_artists.iter().enumerate() {
-        out[i * 5] = unsafe { std::mem::transmute(*artist_id) };
+            if let Some(prefetch_position) = &prefetch_position {
+                if artist_state.label_state == LabelState::NotRequested
+                    && !artist_state
+                        .render_state
+                        .intersects(ArtistRenderState::HAS_NAME | ArtistRenderState::NAME_REQUESTED)
+                {
+                    let prefetch_distance =
+                        self::distance(&artist_state.position, prefetch_position);
+                    if should_render_label(
+                        self.total_rendered_label_count,
+                        artist_state,
+                        prefetch_distance,
+                        self.is_mobile,
+                        self.quality,
+                        highlight_weight,
+                    ) {
+                        prefetch_candidates.push((FloatOrd(prefetch_distance), *artist_id));
+                    }
+                }
+            }
 
-        for (dim_ix, val_for_dim) in state.position.iter().enumerate() {
-            out[i * 5 + 1 + dim_ix] = *val_for_dim;
+            geometry_updates.extend(get_geometry_update(
+                artist_ix,
+                artist_state,
+                distance,
+                &self.last_position,
+                view_cone,
+                self.is_mobile,
+                is_fly_mode,
+                self.quality,
+                highlight_weight,
+            ));
         }
+        self.apply_geometry_updates(
+            geometry_updates,
+            max_geometry_commands,
+            &mut render_commands,
+        );
 
-        out[i * 5 + 4] = unsafe { std::mem::transmute(state.popularity as u32) };
-    }
-
-    out
-}
+        // Prefetch names for the artists closest to where we're headed, leaving the rest for later
+        // frames.  Their labels aren't requested, so they'll be added as usual once we get close
+        // enough.
+        if prefetch_candidates.len() > MAX_PREFETCHED_ARTIST_NAMES_PER_FRAME {
+            prefetch_candidates.select_nth_unstable(MAX_PREFETCHED_ARTIST_NAMES_PER_FRAME);
+            prefetch_candidates.truncate(MAX_PREFETCHED_ARTIST_NAMES_PER_FRAME);
+        }
+        for (_, artist_id) in prefetch_candidates {
+            let artist_index = self.artists_indices_by_id[&artist_id];
+            self.all_artists[artist_index]
+                .1
+                .render_state
+                .set(ArtistRenderState::NAME_REQUESTED, true);
+            render_commands.push(FETCH_ARTIST_DATA_CMD);
+            render_commands.push(artist_id);
+        }
 
-// TODO: SIMD-ify maybe idk
-fn distance(a: &[f32; 3], b: &[f32; 3]) -> f32 {
-    let mut sum = 0.;
-    for (a, b) in a.iter().zip(b.iter()) {
-        sum += (a - b).powi(2);
-    }
-    sum.sqrt()
-}
+        // If in fly mode, don't play any music
+        if !is_fly_mode {
+            return render_commands;
+        }
 
-/// Returns a vector of draw commands
-#[wasm_bindgen]
-pub fn handle_received_artist_names(
-    ctx: *mut ArtistMapCtx,
-    artist_ids: Vec<u32>,
-    cur_x: f32,
-    cur_y: f32,
-    cur_z: f32,
-    is_fly_mode: bool,
-) -> Vec<u32> {
-    let ctx = unsafe { &mut *ctx };
+        let projected_next_pos = [projected_next_x, projected_next_y, projected_next_z];
+        match self.playing_music_artist_id {
+            Some(artist_id) => {
+                let was_manual_play = self.manual_play_artist_id == Some(artist_id);
 
-    let mut draw_commands: Vec<u32> = Vec::new();
+                let artist_index = self.artists_indices_by_id.get(&artist_id).unwrap();
+                let distance_to_listener = distance(
+                    &self.all_artists[*artist_index].1.position,
+                    &projected_next_pos,
+                );
+                let max_distance = if was_manual_play {
+                    MAX_MUSIC_PLAY_DISTANCE * 2.
+                } else {
+                    MAX_MUSIC_PLAY_DISTANCE
+                };
 
-    for artist_id in artist_ids {
-        let artist_state = match ctx.artists_indices_by_id.get(&artist_id) {
-            Some(ix) => &mut ctx.all_artists[*ix].1,
+                if distance_to_listener > max_distance {
+                    debug!(
+                        "Stopping music for artist_id={} due to movement out of range",
+                        artist_id
+                    );
+                    self.stop_playing_music(
+                        artist_id,
+                        &mut render_commands,
+                        projected_next_x,
+                        projected_next_y,
+                        projected_next_z,
+                        false,
+                        get_music_fade_out_ms(distance_to_listener - max_distance),
+                    );
+                }
+            },
             None => {
-                error!(
-                    "Artist not in embedding but received name for it; artist_id={}",
-                    artist_id
+                self.maybe_start_playing_new_music(
+                    &mut render_commands,
+                    projected_next_x,
+                    projected_next_y,
+                    projected_next_z,
+                    DEFAULT_MUSIC_FADE_IN_MS,
                 );
-                continue;
             },
-        };
+        }
 
-        if artist_state
-            .render_state
-            .contains(ArtistRenderState::HAS_NAME)
-        {
-            warn!(
-                "Received artist name multiple times for artist_id={}",
-                artist_id
-            );
-            continue;
-        }
+        render_commands
+    }
 
-        artist_state
-            .render_state
-            .set(ArtistRenderState::HAS_NAME, true);
+    /// Adds or removes artist geometry for the provided updates.  If there are more updates than
+    /// `max_geometry_commands`, only the highest-priority ones are applied and the rest are left
+    /// for `flush_deferred_geometry_updates`.
+    fn apply_geometry_updates(
+        &mut self,
+        mut geometry_updates: Vec<GeometryUpdate>,
+        max_geometry_commands: Option<usize>,
+        render_commands: &mut Vec<u32>,
+    ) {
+        self.has_deferred_geometry_updates = false;
+        if let Some(max_geometry_commands) = max_geometry_commands {
+            if geometry_updates.len() > max_geometry_commands {
+                if max_geometry_commands > 0 {
+                    geometry_updates
+                        .select_nth_unstable_by_key(max_geometry_commands - 1, |update| {
+                            update.priority
+                        });
+                }
+                geometry_updates.truncate(max_geometry_commands);
+                self.has_deferred_geometry_updates = true;
+            }
+        }
 
-        let distance = distance(&artist_state.position, &[cur_x, cur_y, cur_z]);
-        if artist_state
-            .render_state
-            .contains(ArtistRenderState::RENDER_LABEL)
-            && (!is_fly_mode
-                || should_render_label(
-                    ctx.total_rendered_label_count,
-                    artist_state,
-                    distance,
-                    ctx.is_mobile,
-                    ctx.quality,
-                ))
+        for GeometryUpdate {
+            artist_ix,
+            should_render,
+            ..
+        } in geometry_updates
         {
-            ctx.total_rendered_label_count += 1;
-            draw_commands.push(ADD_LABEL_CMD);
-            draw_commands.push(artist_id);
-        } else {
+            let (artist_id, artist_state) = &mut self.all_artists[artist_ix];
+            render_commands.push(if should_render {
+                ADD_ARTIST_GEOMETRY_CMD
+            } else {
+                REMOVE_ARTIST_GEOMETRY_CMD
+            });
+            render_commands.push(*artist_id);
             artist_state
                 .render_state
-                .set(ArtistRenderState::RENDER_LABEL, false);
+                .set(ArtistRenderState::RENDER_GEOMETRY, should_render);
         }
     }
 
-    draw_commands
-}
+    /// Applies geometry updates that were deferred by a previous call to `handle_new_position`
+    /// because it was over its command budget.  Updates are re-computed for the most recent
+    /// position, so this converges to the same geometry as an unbudgeted call would have rendered.
+    ///
+    /// Returns a list of draw commands to execute, which is empty if nothing was deferred.
+    pub fn flush_deferred_geometry_updates(
+        &mut self,
+        max_geometry_commands: Option<usize>,
+    ) -> Vec<u32> {
+        if !self.has_deferred_geometry_updates {
+            return Vec::new();
+        }
 
-fn should_render_artist(
-    distance: f32,
-    popularity: u8,
-    render_state: &ArtistRenderState,
-    is_mobile: bool,
-    is_fly_mode: bool,
-    quality: u8,
-) -> bool {
-    if distance < 9000. {
-        return true;
-    }
+        let mut geometry_updates: Vec<GeometryUpdate> = Vec::new();
+        for (artist_ix, (_artist_id, artist_state)) in self.all_artists.iter().enumerate() {
+            geometry_updates.extend(get_geometry_update(
+                artist_ix,
+                artist_state,
+                distance(&artist_state.position, &self.last_position),
+                &self.last_position,
+                self.last_view_cone,
+                self.is_mobile,
+                self.is_fly_mode,
+                self.quality,
+                highlight_weight(&self.highlight_weight_by_index, artist_ix),
+            ));
+        }
 
-    if render_state.contains(ArtistRenderState::IS_HIGHLIGHTED) {
-        return true;
+        let mut render_commands = Vec::new();
+        self.apply_geometry_updates(
+            geometry_updates,
+            max_geometry_commands,
+            &mut render_commands,
+        );
+        render_commands
     }
 
-    let mut score = distance;
-    score -= (popularity as f32).powi(3) * 0.1;
-
-    if is_mobile {
-        score *= 1.56;
-    }
+    /// Applies each `[x, y, z]` position in `positions` in order as if they were passed to
+    /// `handle_new_position` one after another, using the following position as the projected next
+    /// position.  View cone culling is disabled.
+    ///
+    /// Returns the draw commands for all steps concatenated, each preceded by the number of `u32`s
+    /// of draw commands it produced.
+    pub fn handle_position_batch(&mut self, positions: &[f32]) -> Vec<u32> {
+        if positions.len() % 3 != 0 {
+            error!(
+                "Position batch length must be a multiple of 3; ignoring trailing {} values",
+                positions.len() % 3
+            );
+        }
 
-    let mut quality_diff = DEFAULT_QUALITY as i8 - quality as i8;
-    while quality_diff > 0 {
-        score *= if is_mobile { 1.2 } else { 1.34 };
-        quality_diff -= 1;
-    }
-    while quality_diff < 0 {
-        score *= if is_fly_mode { 0.808 } else { 0.72 };
-        quality_diff += 1;
+        let positions: Vec<[f32; 3]> = positions
+            .chunks_exact(3)
+            .map(|pos| [pos[0], pos[1], pos[2]])
+            .collect();
+        let mut draw_commands = Vec::new();
+        for (step_ix, cur_pos) in positions.iter().enumerate() {
+            let projected_next_pos = positions.get(step_ix + 1).unwrap_or(cur_pos);
+            let step_draw_commands = self.handle_new_position(
+                cur_pos[0],
+                cur_pos[1],
+                cur_pos[2],
+                projected_next_pos[0],
+                projected_next_pos[1],
+                projected_next_pos[2],
+                None,
+                None,
+            );
+            draw_commands.push(step_draw_commands.len() as u32);
+            draw_commands.extend(step_draw_commands);
+        }
+        draw_commands
     }
 
-    score < 36_800.
-}
+    /// Returns a vector of draw commands
+    pub fn on_music_finished_playing(
+        &mut self,
+        artist_id: u32,
+        cur_x: f32,
+        cur_y: f32,
+        cur_z: f32,
+    ) -> Vec<u32> {
+        let mut draw_commands = Vec::new();
 
-static mut RNG: *mut pcg::Pcg = std::ptr::null_mut();
+        debug!("Music finished playing for artist id={}", artist_id);
 
-fn rng() -> &'static mut pcg::Pcg { unsafe { &mut *RNG } }
+        if self.playing_music_artist_id != Some(artist_id) {
+            return draw_commands;
+        }
 
-fn get_connection_render_quality_rng_adjustment(quality: u8) -> f64 {
-    let mut quality_rng_adjustment = -0.1;
+        self.stop_playing_music(
+            artist_id,
+            &mut draw_commands,
+            cur_x,
+            cur_y,
+            cur_z,
+            false,
+            DEFAULT_MUSIC_FADE_OUT_MS,
+        );
 
-    let mut quality_diff: i8 = DEFAULT_QUALITY as i8 - quality as i8;
-    // If quality is lower than default (difference is positive), we decrease the bottom range of
-    // generated random values for the score so that it's less likely at all distances that the
-    // connection is rendered
-    //
-    // rng range goes from (0, 1) to something like (-0.2, 1).
-    while quality_diff > 0 {
-        quality_rng_adjustment -= 0.15;
-        quality_diff -= 1;
-    }
-    // If quality is higher than default (difference is negative), we increase the bottom range of
-    // generated random values for the score so that it's less likely at all distances that the
-    // connection is rendered
-    //
-    // rng range goes from (0, 1) to something like (0.2, 1).
-    while quality_diff < 0 {
-        quality_rng_adjustment += 0.1;
-        quality_diff += 1;
+        draw_commands
     }
 
-    quality_rng_adjustment
-}
+    /// Returns connection buffer length
+    pub fn handle_artist_relationship_data(
+        &mut self,
+        packed_relationship_data: &[u8],
+        chunk_size: u32,
+        chunk_ix: u32,
+    ) -> usize {
+        let is_new_chunk = self.received_chunks.insert((chunk_ix, chunk_size));
+        self.relationship_chunk_size.get_or_insert(chunk_size);
+
+        let artist_ids = self
+            .sorted_artist_ids
+            .chunks(chunk_size as usize)
+            .skip(chunk_ix as usize)
+            .next()
+            .unwrap_or_default();
+        let artist_ids_byte_offset = artist_ids.len() + 4 - (artist_ids.len() % 4);
 
-fn should_render_connection(
-    quality_rng_adjustment: f64,
-    src: &ArtistState,
-    dst: &ArtistState,
-) -> bool {
-    let val = rng().gen_range(quality_rng_adjustment, 1.0f64);
-    let dist = distance(&src.position, &dst.position);
+        assert_eq!(packed_relationship_data.len() % 4, 0);
+        let total_relationship_count: usize = packed_relationship_data[..artist_ids.len()]
+            .iter()
+            .map(|&count| count as usize)
+            .sum();
+        // Newer payloads have a weight for each relationship after the related artist IDs.  Older
+        // ones end right after the IDs.
+        let weights_byte_offset = artist_ids_byte_offset + total_relationship_count * 4;
+        let weights = if packed_relationship_data.len() > weights_byte_offset {
+            Some(&packed_relationship_data[weights_byte_offset..])
+        } else {
+            None
+        };
+        let u32_view = unsafe {
+            std::slice::from_raw_parts(
+                packed_relationship_data
+                    .as_ptr()
+                    .add(artist_ids_byte_offset) as *const u32,
+                (packed_relationship_data.len() - artist_ids_byte_offset) / 4,
+            )
+        };
 
-    if dist > 70000. {
-        return false;
-    } else if dist > 25000. {
-        return val > 0.994;
-    } else if dist > 17000. {
-        return val > 0.82;
-    } else if dist > 8000. {
-        return val > 0.28;
-    } else {
-        return val > 0.185;
+        let mut offset = 0;
+        for i in 0..artist_ids.len() {
+            let artist_id = artist_ids[i];
+            let artist_index = *self.artists_indices_by_id.get(&artist_id).unwrap();
+            let relationship_state = &mut self.all_artist_relationships[artist_index];
+
+            let count = packed_relationship_data[i] as usize;
+            let mut actual_count = 0;
+            relationship_state.out_of_set_related_artist_ids.clear();
+            for relationship_ix in 0..count {
+                let related_artist_id = u32_view[offset + relationship_ix];
+                let related_artist_index = match self.artists_indices_by_id.get(&related_artist_id)
+                {
+                    Some(ix) => *ix,
+                    // It's possible the artist is related to one that's not in the embedding
+                    None => {
+                        relationship_state
+                            .out_of_set_related_artist_ids
+                            .push(related_artist_id);
+                        continue;
+                    },
+                };
+
+                relationship_state.related_artist_indices[actual_count] = ArtistRelationship {
+                    related_artist_index,
+                    connections_buffer_index: None,
+                    weight: weights
+                        .map(|weights| weights[offset + relationship_ix])
+                        .unwrap_or(DEFAULT_CONNECTION_WEIGHT),
+                };
+                actual_count += 1;
+            }
+            relationship_state.count = actual_count;
+
+            offset += count;
+        }
+
+        assert_eq!(offset, total_relationship_count);
+        if is_new_chunk {
+            self.total_relationships_parsed += total_relationship_count;
+        }
+        let weights_byte_count = if weights.is_some() {
+            (total_relationship_count + 3) / 4 * 4
+        } else {
+            0
+        };
+        assert_eq!(
+            weights_byte_offset + weights_byte_count,
+            packed_relationship_data.len()
+        );
+        self.update_connections_buffer(chunk_size, chunk_ix);
+        self.populate_connection_colors_buffer();
+
+        self.connections_buffer.len() * 6
     }
-}
 
-const ADD_LABEL_CMD: u32 = 0u32;
-const REMOVE_LABEL_CMD: u32 = 1u32;
-const ADD_ARTIST_GEOMETRY_CMD: u32 = 2u32;
-const REMOVE_ARTIST_GEOMETRY_CMD: u32 = 3u32;
-const FETCH_ARTIST_DATA_CMD: u32 = 4u32;
-const START_PLAYING_MUSIC_CMD: u32 = 5u32;
-const STOP_PLAYING_MUSIC_CMD: u32 = 6u32;
+    /// Records the related artists of a single artist and renders connections to them without
+    /// waiting for the chunk containing it to be loaded.  Used to expand the neighbors of artists
+    /// as they're clicked.
+    ///
+    /// Returns connection buffer length
+    pub fn add_single_artist_relationships(
+        &mut self,
+        artist_id: u32,
+        packed_related_ids: &[u32],
+    ) -> usize {
+        let artist_ix = match self.artists_indices_by_id.get(&artist_id) {
+            Some(ix) => *ix,
+            None => {
+                warn!(
+                    "Received relationships for artist_id={} which isn't in the embedding",
+                    artist_id
+                );
+                return self.connections_buffer.len() * 6;
+            },
+        };
 
-/// Returns a vector of draw commands
-#[wasm_bindgen]
-pub fn handle_new_position(
-    ctx: *mut ArtistMapCtx,
-    cur_x: f32,
-    cur_y: f32,
-    cur_z: f32,
-    projected_next_x: f32,
-    projected_next_y: f32,
-    projected_next_z: f32,
-    is_fly_mode: bool,
-) -> Vec<u32> {
-    let ctx = unsafe { &mut *ctx };
+        let relationship_state = &mut self.all_artist_relationships[artist_ix];
+        let mut actual_count = 0;
+        relationship_state.out_of_set_related_artist_ids.clear();
+        for &related_artist_id in packed_related_ids {
+            let related_artist_index = match self.artists_indices_by_id.get(&related_artist_id) {
+                Some(ix) => *ix,
+                None => {
+                    relationship_state
+                        .out_of_set_related_artist_ids
+                        .push(related_artist_id);
+                    continue;
+                },
+            };
+            if actual_count == MAX_RELATED_ARTIST_COUNT {
+                warn!(
+                    "Too many related artists for artist_id={}; ignoring the rest",
+                    artist_id
+                );
+                break;
+            }
 
-    if ctx.last_position[0] == cur_x
-        && ctx.last_position[1] == cur_y
-        && ctx.last_position[2] == cur_z
-    {
-        return Vec::new();
+            relationship_state.related_artist_indices[actual_count] = ArtistRelationship {
+                related_artist_index,
+                connections_buffer_index: None,
+                weight: DEFAULT_CONNECTION_WEIGHT,
+            };
+            actual_count += 1;
+        }
+        relationship_state.count = actual_count;
+        self.expanded_artist_indices.insert(artist_ix);
+
+        let quality_rng_adjustment =
+            get_connection_render_quality_rng_adjustment(self.quality, self.is_mobile);
+        self.render_artist_connections(artist_ix, quality_rng_adjustment);
+        self.populate_connection_colors_buffer();
+
+        self.connections_buffer.len() * 6
     }
-    ctx.last_position = [cur_x, cur_y, cur_z];
 
-    // 0: label to add
-    // 1: label to remove
-    // 2: artist geometry to add
-    // 3: artist geomety to remove
-    // 4: fetch artist data
-    // 5: start playing music, followed by artist ID
-    // 6: stop playing music, followed by artist ID
-    let mut render_commands: Vec<u32> = Vec::new();
+    /// Returns a list of draw commands to execute
+    /// `weights` holds the highlight weight (0-255) of each highlighted artist, parallel to
+    /// `highlighted_artist_ids`.  Higher-weighted artists are rendered and labeled from further
+    /// away and colored more brightly.  If it's empty, all highlighted artists have full weight.
+    pub fn handle_set_highlighted_artists(
+        &mut self,
+        highlighted_artist_ids: Vec<u32>,
+        weights: Vec<u8>,
+        cur_x: f32,
+        cur_y: f32,
+        cur_z: f32,
+    ) -> Vec<u32> {
+        let cur_pos = [cur_x, cur_y, cur_z];
 
-    for (artist_id, artist_state) in ctx.all_artists.iter_mut() {
-        let distance = distance(&artist_state.position, &ctx.last_position);
+        let mut draw_commands = Vec::new();
 
-        let should_render_label = should_render_label(
-            ctx.total_rendered_label_count,
-            artist_state,
-            distance,
-            ctx.is_mobile,
-            ctx.quality,
-        );
-        if should_render_label
-            != artist_state
-                .render_state
-                .contains(ArtistRenderState::RENDER_LABEL)
-            && is_fly_mode
-        {
-            artist_state
+        let weights = if !weights.is_empty() && weights.len() != highlighted_artist_ids.len() {
+            error!(
+                "Got {} highlight weights for {} highlighted artists; ignoring weights",
+                weights.len(),
+                highlighted_artist_ids.len()
+            );
+            Vec::new()
+        } else {
+            weights
+        };
+        let had_weights = !self.highlight_weight_by_index.is_empty();
+        self.highlight_weight_by_index.clear();
+
+        // First, un-mark all artists as highlighted.  If they should no longer be rendered,
+        // dispatch draw commands to remove them.
+        for (artist_id, state) in self.all_artists.iter_mut() {
+            let was_highlighted = state
                 .render_state
-                .toggle(ArtistRenderState::RENDER_LABEL);
+                .contains(ArtistRenderState::IS_HIGHLIGHTED);
+            if !was_highlighted {
+                continue;
+            }
 
-            if should_render_label {
-                if artist_state
-                    .render_state
-                    .contains(ArtistRenderState::HAS_NAME)
-                {
-                    // Render artist label
-                    render_commands.push(ADD_LABEL_CMD);
-                    ctx.total_rendered_label_count += 1;
-                } else {
-                    // Fetch artist name
-                    render_commands.push(FETCH_ARTIST_DATA_CMD);
-                }
+            state.render_state.toggle(ArtistRenderState::IS_HIGHLIGHTED);
+            let distance_to_artist = distance(&state.position, &cur_pos);
+            let should_render = should_render_artist(
+                distance_to_artist,
+                state.popularity,
+                &state.render_state,
+                self.is_mobile,
+                self.is_fly_mode,
+                self.quality,
+                u8::MAX,
+            );
+            if should_render {
+                draw_commands.push(ADD_ARTIST_GEOMETRY_CMD);
+                draw_commands.push(*artist_id);
             } else {
-                // Remove artist label
-                render_commands.push(1);
-                if ctx.total_rendered_label_count == 0 {
-                    warn!(
-                        "Total rendered label count accounting error; was zero and tried to \
-                         subtract one when removing artist label"
-                    );
-                }
-                ctx.total_rendered_label_count = ctx.total_rendered_label_count.saturating_sub(1);
+                draw_commands.push(REMOVE_ARTIST_GEOMETRY_CMD);
+                draw_commands.push(*artist_id);
             }
-            render_commands.push(*artist_id);
         }
 
-        let should_render_geometry = should_render_artist(
-            distance,
-            artist_state.popularity,
-            &artist_state.render_state,
-            ctx.is_mobile,
-            is_fly_mode,
-            ctx.quality,
-        );
-        if should_render_geometry
-            != artist_state
+        for (i, highlighted_artist_id) in highlighted_artist_ids.into_iter().enumerate() {
+            let artist_index = match self.artists_indices_by_id.get(&highlighted_artist_id) {
+                Some(&id) => id,
+                None => continue,
+            };
+            let (_, state) = &mut self.all_artists[artist_index];
+            state
                 .render_state
-                .contains(ArtistRenderState::RENDER_GEOMETRY)
-        {
-            if should_render_geometry {
-                render_commands.push(2);
-            } else {
-                render_commands.push(3);
+                .set(ArtistRenderState::IS_HIGHLIGHTED, true);
+            if let Some(&weight) = weights.get(i) {
+                self.highlight_weight_by_index.insert(artist_index, weight);
             }
-            render_commands.push(*artist_id);
+            draw_commands.push(ADD_ARTIST_GEOMETRY_CMD);
+            draw_commands.push(highlighted_artist_id);
+        }
 
-            artist_state
-                .render_state
-                .toggle(ArtistRenderState::RENDER_GEOMETRY);
+        if had_weights || !self.highlight_weight_by_index.is_empty() {
+            self.populate_artist_color_buffer();
         }
-    }
 
-    // If in fly mode, don't play any music
-    if !is_fly_mode {
-        return render_commands;
+        if !self.is_fly_mode {
+            info!("Highlighted artists set and is not fly mode; adding custom labels...");
+            self.add_highlighted_artist_orbit_labels(&mut draw_commands);
+        }
+        self.did_set_highlighted_artists = true;
+
+        draw_commands
     }
 
-    let projected_next_pos = [projected_next_x, projected_next_y, projected_next_z];
-    match ctx.playing_music_artist_id {
-        Some(artist_id) => {
-            let was_manual_play = ctx.manual_play_artist_id == Some(artist_id);
+    /// Returns a list of draw commands to execute
+    pub fn handle_artist_manual_play(&mut self, artist_id: u32) -> Vec<u32> {
+        info!("Handling manual play for artist_id={}", artist_id);
 
-            let artist_index = ctx.artists_indices_by_id.get(&artist_id).unwrap();
-            let distance_to_listener = distance(
-                &ctx.all_artists[*artist_index].1.position,
-                &projected_next_pos,
-            );
-            let max_distance = if was_manual_play {
-                MAX_MUSIC_PLAY_DISTANCE * 2.
-            } else {
-                MAX_MUSIC_PLAY_DISTANCE
-            };
+        let mut draw_commands = Vec::new();
 
-            if distance_to_listener > max_distance {
-                debug!(
-                    "Stopping music for artist_id={} due to movement out of range",
-                    artist_id
-                );
-                ctx.stop_playing_music(
-                    artist_id,
-                    &mut render_commands,
-                    projected_next_x,
-                    projected_next_y,
-                    projected_next_z,
-                    false,
-                );
-            }
-        },
-        None => {
-            ctx.maybe_start_playing_new_music(
-                &mut render_commands,
-                projected_next_x,
-                projected_next_y,
-                projected_next_z,
+        if self.excluded_artist_ids.contains(&artist_id) {
+            info!(
+                "Not playing artist_id={} because it has been excluded",
+                artist_id
             );
-        },
-    }
+            return draw_commands;
+        }
 
-    render_commands
-}
+        if let Some(playing_artist_id) = self.playing_music_artist_id {
+            if playing_artist_id == artist_id {
+                return draw_commands;
+            }
 
-/// Returns a vector of draw commands
-#[wasm_bindgen]
-pub fn on_music_finished_playing(
-    ctx: *mut ArtistMapCtx,
-    artist_id: u32,
-    cur_x: f32,
-    cur_y: f32,
-    cur_z: f32,
-) -> Vec<u32> {
-    let ctx = unsafe { &mut *ctx };
-    let mut draw_commands = Vec::new();
+            self.stop_playing_music(
+                playing_artist_id,
+                &mut draw_commands,
+                std::f32::NEG_INFINITY,
+                std::f32::NEG_INFINITY,
+                std::f32::NEG_INFINITY,
+                true,
+                // The user explicitly picked a new artist, so switch over quickly
+                MIN_MUSIC_FADE_MS,
+            );
+        }
 
-    debug!("Music finished playing for artist id={}", artist_id);
+        self.start_playing_artist_id(&mut draw_commands, artist_id, DEFAULT_MUSIC_FADE_IN_MS);
+        self.manual_play_artist_id = Some(artist_id);
 
-    if ctx.playing_music_artist_id != Some(artist_id) {
-        return draw_commands;
+        draw_commands
     }
 
-    ctx.stop_playing_music(artist_id, &mut draw_commands, cur_x, cur_y, cur_z, false);
+    /// Returns a list of draw commands to execute
+    pub fn play_last_artist(&mut self) -> Vec<u32> {
+        let last_played_artist_id = match self.most_recently_played_artist_ids.pop_front() {
+            Some(id) => id,
+            None => {
+                info!("No last played artist; not playing last played artist");
+                return Vec::new();
+            },
+        };
 
-    draw_commands
-}
+        info!("Playing last played artist_id={}", last_played_artist_id);
 
-/// Returns connection buffer length
-#[wasm_bindgen]
+        self.handle_artist_manual_play(last_played_artist_id)
+    }
+
+    /// Returns the IDs of all artists related to the provided one.  If `include_out_of_set` is set,
+    /// related artists that aren't in the embedding are included at the end.
+    ///
+    /// Returns an empty list if relationship data hasn't been received for the artist yet.
+    pub fn get_related_artist_ids(&self, artist_id: u32, include_out_of_set: bool) -> Vec<u32> {
+        let artist_index = match self.artists_indices_by_id.get(&artist_id) {
+            Some(ix) => *ix,
+            None => return Vec::new(),
+        };
+        let state = &self.all_artist_relationships[artist_index];
+        let mut related_artist_ids: Vec<u32> = state.related_artist_indices[..state.count]
+            .iter()
+            .map(|relationship| self.all_artists[relationship.related_artist_index].0)
+            .filter(|&related_artist_id| related_artist_id != artist_id)
+            .collect();
+        if include_out_of_set {
+            related_artist_ids.extend_from_slice(&state.out_of_set_related_artist_ids);
+        }
+
+        related_artist_ids
+    }
+
+    pub fn get_connections_for_artists(
+        &self,
+        artist_ids: Vec<u32>,
+        constrain_destinations_to_set: bool,
+    ) -> Vec<f32> {
+        let mut all_connections: Vec<(u32, u32)> = Vec::new();
+
+        fn sort_pair(pair: (u32, u32)) -> (u32, u32) {
+            if pair.0 < pair.1 {
+                pair
+            } else {
+                (pair.1, pair.0)
+            }
+        }
+
+        for &artist_id in &artist_ids {
+            let artist_index = match self.artists_indices_by_id.get(&artist_id) {
+                Some(ix) => *ix,
+                None => continue,
+            };
+            let state = &self.all_artist_relationships[artist_index];
+            for relationship in &state.related_artist_indices[..state.count] {
+                let related_artist_id = self.all_artists[relationship.related_artist_index].0;
+                if artist_id == related_artist_id {
+                    continue;
+                }
+
+                let pair = if constrain_destinations_to_set {
+                    if !artist_ids.contains(&related_artist_id) {
+                        continue;
+                    }
+
+                    (artist_id, related_artist_id)
+                } else {
+                    if artist_ids.contains(&related_artist_id) {
+                        continue;
+                    }
+
+                    (artist_id, related_artist_id)
+                };
+                all_connections.push(sort_pair(pair));
+            }
+        }
+
+        // Emit connections in a stable order so that the output only depends on the set of input
+        // artists and not the order in which they were provided
+        all_connections.sort_unstable();
+        all_connections.dedup();
+
+        let mut points: Vec<f32> = Vec::with_capacity(all_connections.len() * 6);
+        for (artist_id_0, artist_id_1) in all_connections {
+            let pos_0 = &self.all_artists[self.artists_indices_by_id[&artist_id_0]]
+                .1
+                .position;
+            let pos_1 = &self.all_artists[self.artists_indices_by_id[&artist_id_1]]
+                .1
+                .position;
+
+            points.extend_from_slice(pos_0);
+            points.extend_from_slice(pos_1);
+        }
+
+        points
+    }
+
+    pub fn set_mode(&mut self, is_fly_mode: bool) { self.is_fly_mode = is_fly_mode; }
+
+    pub fn galaxy_center(&self) -> [f32; 3] {
+        let mut center = [0.; 3];
+        for (dim_ix, val) in center.iter_mut().enumerate() {
+            *val = (self.galaxy_mins[dim_ix] + self.galaxy_maxs[dim_ix]) / 2.;
+        }
+        center
+    }
+
+    /// Returns [min_x, min_y, min_z, max_x, max_y, max_z, center_x, center_y, center_z,
+    /// suggested_orbit_radius]
+    pub fn get_galaxy_bounds(&self) -> Vec<f32> {
+        let center = self.galaxy_center();
+        let bounding_sphere_radius = distance(&center, &self.galaxy_maxs);
+
+        let mut bounds = Vec::with_capacity(10);
+        bounds.extend_from_slice(&self.galaxy_mins);
+        bounds.extend_from_slice(&self.galaxy_maxs);
+        bounds.extend_from_slice(&center);
+        bounds.push(bounding_sphere_radius * SUGGESTED_ORBIT_RADIUS_MULTIPLIER);
+        bounds
+    }
+
+    /// Returns the mean position [x, y, z] of the provided artists, skipping any that aren't in the
+    /// embedding, or an empty vector if none of them are.  If `include_nearest_artist` is set, the
+    /// ID of the artist nearest to the centroid is appended with its bits stored as an `f32`.
+    pub fn get_artists_centroid(
+        &self,
+        artist_ids: &[u32],
+        include_nearest_artist: bool,
+    ) -> Vec<f32> {
+        let mut sum = [0.0f64; 3];
+        let mut count = 0usize;
+        for artist_id in artist_ids {
+            let artist_ix = match self.artists_indices_by_id.get(artist_id) {
+                Some(&ix) => ix,
+                None => continue,
+            };
+            let position = &self.all_artists[artist_ix].1.position;
+            for (dim_sum, &val) in sum.iter_mut().zip(position) {
+                *dim_sum += val as f64;
+            }
+            count += 1;
+        }
+        if count == 0 {
+            return Vec::new();
+        }
+
+        let centroid = sum.map(|dim_sum| (dim_sum / count as f64) as f32);
+        let mut out = centroid.to_vec();
+        if include_nearest_artist {
+            let nearest_artist_id = self
+                .all_artists
+                .iter()
+                .min_by_key(|(_, state)| FloatOrd(distance(&state.position, &centroid)))
+                .map(|(id, _)| *id);
+            if let Some(nearest_artist_id) = nearest_artist_id {
+                out.push(f32::from_bits(nearest_artist_id));
+            }
+        }
+        out
+    }
+
+    pub fn transition_to_orbit_mode(&mut self) -> Vec<u32> {
+        self.is_fly_mode = false;
+        self.last_force_labeled_artist_id = None;
+
+        let mut draw_commands = Vec::new();
+
+        if let Some(playing_artist_id) = self.playing_music_artist_id {
+            self.stop_playing_music(
+                playing_artist_id,
+                &mut draw_commands,
+                std::f32::NEG_INFINITY,
+                std::f32::NEG_INFINITY,
+                std::f32::NEG_INFINITY,
+                true,
+                DEFAULT_MUSIC_FADE_OUT_MS,
+            );
+        }
+        self.most_recently_played_artist_ids.clear();
+
+        for (id, state) in self.all_artists.iter_mut() {
+            state.set_label_requested(
+                *id,
+                false,
+                &mut self.total_rendered_label_count,
+                &mut draw_commands,
+            );
+        }
+
+        // Render the special orbit-mode labels
+        for artist_id in ORBIT_LABEL_ARTIST_IDS {
+            let artist_index = match self.artists_indices_by_id.get(artist_id) {
+                Some(&id) => id,
+                None => continue,
+            };
+            let (_, state) = &mut self.all_artists[artist_index];
+            state.set_label_requested(
+                *artist_id,
+                true,
+                &mut self.total_rendered_label_count,
+                &mut draw_commands,
+            );
+        }
+
+        if self.did_set_highlighted_artists {
+            info!(
+                "Transitioned to orbit mode and highlighted artists set; adding in extra labels..."
+            );
+            self.add_highlighted_artist_orbit_labels(&mut draw_commands);
+        }
+
+        draw_commands
+    }
+
+    pub fn force_render_artist_label(&mut self, artist_id: u32) -> Vec<u32> {
+        let mut draw_commands = Vec::new();
+
+        // Repeatedly force-rendering the same artist (e.g. hovering over it multiple times) would
+        // otherwise remove and re-add its label each time
+        if self.last_force_labeled_artist_id == Some(artist_id) {
+            return draw_commands;
+        }
+
+        if let Some(last_force_rendered_artist_id) = self.last_force_labeled_artist_id.take() {
+            info!(
+                "De-rendering last force-rendered artist id={}",
+                last_force_rendered_artist_id
+            );
+            match self
+                .artists_indices_by_id
+                .get(&last_force_rendered_artist_id)
+            {
+                Some(&ix) => {
+                    let (_, state) = &mut self.all_artists[ix];
+                    state.set_label_requested(
+                        last_force_rendered_artist_id,
+                        false,
+                        &mut self.total_rendered_label_count,
+                        &mut draw_commands,
+                    );
+                },
+                None => error!(
+                    "Last force-rendered artist id={} not in embedding",
+                    last_force_rendered_artist_id
+                ),
+            }
+        } else {
+            info!("No last force-rendered artist id; not de-rendering");
+        }
+
+        let artist_index = match self.artists_indices_by_id.get(&artist_id) {
+            Some(ix) => *ix,
+            None => return draw_commands,
+        };
+        let (_, state) = &mut self.all_artists[artist_index];
+
+        // If the label is already rendered, do nothing
+        if state.label_state != LabelState::NotRequested {
+            info!("Force-rendering already rendered artist label; doing nothing.");
+            self.last_force_labeled_artist_id = None;
+            return draw_commands;
+        } else {
+            info!(
+                "Artist label not currently rendered; setting `last_force_labeled_artist_id` to {}",
+                artist_id
+            );
+            self.last_force_labeled_artist_id = Some(artist_id);
+        }
+
+        state.set_label_requested(
+            artist_id,
+            true,
+            &mut self.total_rendered_label_count,
+            &mut draw_commands,
+        );
+
+        draw_commands
+    }
+
+    /// Sets the render quality.  Supported values are `0..=MAX_QUALITY`; anything higher is
+    /// clamped.
+    pub fn set_quality(&mut self, new_quality: u8) {
+        if new_quality > MAX_QUALITY {
+            warn!(
+                "Quality {} is out of range; clamping to {}",
+                new_quality, MAX_QUALITY
+            );
+        }
+        let new_quality = new_quality.min(MAX_QUALITY);
+        self.quality = new_quality;
+
+        if new_quality > DEFAULT_QUALITY {
+            info!("Not updating connections buffer because new quality is greater than default");
+            return;
+        }
+
+        // Re-build connections geometry to take into account new quality
+        info!(
+            "Set quality to {}; building new connections data buffer...",
+            new_quality
+        );
+        self.rebuild_connections_buffer();
+    }
+
+    /// Returns [received_chunk_count, expected_chunk_count, total_relationships_parsed,
+    /// connections_rendered].  `expected_chunk_count` is 0 until the first chunk is received.
+    pub fn get_loading_progress(&self) -> Vec<u32> {
+        let expected_chunk_count = match self.relationship_chunk_size {
+            Some(chunk_size) if chunk_size > 0 => {
+                let chunk_size = chunk_size as usize;
+                (self.sorted_artist_ids.len() + chunk_size - 1) / chunk_size
+            },
+            _ => 0,
+        };
+
+        vec![
+            self.received_chunks.len() as u32,
+            expected_chunk_count as u32,
+            self.total_relationships_parsed as u32,
+            self.connections_buffer.len() as u32,
+        ]
+    }
+
+    /// Returns the sorted indices of all received relationship chunks.  Only chunks with the same
+    /// size as the first received one are included since indices of differently-sized chunks refer
+    /// to different ranges of artists.
+    pub fn get_received_chunk_indices(&self) -> Vec<u32> {
+        let chunk_size = match self.relationship_chunk_size {
+            Some(chunk_size) => chunk_size,
+            None => return Vec::new(),
+        };
+
+        let mut chunk_indices = self
+            .received_chunks
+            .iter()
+            .filter(|&&(_chunk_ix, size)| size == chunk_size)
+            .map(|&(chunk_ix, _size)| chunk_ix)
+            .collect::<Vec<_>>();
+        chunk_indices.sort_unstable();
+        chunk_indices
+    }
+
+    /// Returns the fraction of the `total_chunks` relationship chunks that have been received, from
+    /// 0 to 1.  Returns 0 if `total_chunks` is 0.
+    pub fn get_relationship_coverage(&self, total_chunks: u32) -> f32 {
+        if total_chunks == 0 {
+            return 0.;
+        }
+
+        let received_chunk_count = self
+            .get_received_chunk_indices()
+            .into_iter()
+            .filter(|&chunk_ix| chunk_ix < total_chunks)
+            .count();
+        received_chunk_count as f32 / total_chunks as f32
+    }
+
+    /// Returns `(artist_id, render_state_bits, popularity)` triplets for every artist with a
+    /// non-empty render state.  See `get_render_state_snapshot` for the meaning of the bits.
+    pub fn get_render_state_snapshot(&self) -> Vec<u32> {
+        let mut snapshot = Vec::new();
+        for (artist_id, state) in &self.all_artists {
+            if state.render_state.is_empty() {
+                continue;
+            }
+
+            snapshot.push(*artist_id);
+            snapshot.push(state.render_state.bits() as u32);
+            snapshot.push(state.popularity as u32);
+        }
+        snapshot
+    }
+
+    /// Returns the IDs of all artists whose labels are currently rendered.  Labels that are waiting
+    /// on the artist's name to be fetched aren't included.
+    pub fn get_rendered_label_ids(&self) -> Vec<u32> {
+        self.all_artists
+            .iter()
+            .filter(|(_, state)| state.label_state == LabelState::Rendered)
+            .map(|(artist_id, _)| *artist_id)
+            .collect()
+    }
+
+    /// Replaces the set of artists that are never played.  If the currently playing artist is
+    /// excluded, it's stopped and the closest non-excluded artist starts playing instead.
+    ///
+    /// Returns a list of draw commands to execute
+    pub fn set_excluded_artists(&mut self, artist_ids: Vec<u32>) -> Vec<u32> {
+        self.excluded_artist_ids = artist_ids.into_iter().collect();
+
+        let mut draw_commands = Vec::new();
+        let playing_artist_id = match self.playing_music_artist_id {
+            Some(artist_id) if self.excluded_artist_ids.contains(&artist_id) => artist_id,
+            _ => return draw_commands,
+        };
+
+        info!(
+            "Stopping music for artist_id={} because it has been excluded",
+            playing_artist_id
+        );
+        // Music is only played automatically in fly mode
+        let [cur_x, cur_y, cur_z] = if self.is_fly_mode {
+            self.last_position
+        } else {
+            [std::f32::NEG_INFINITY; 3]
+        };
+        self.stop_playing_music(
+            playing_artist_id,
+            &mut draw_commands,
+            cur_x,
+            cur_y,
+            cur_z,
+            true,
+            MIN_MUSIC_FADE_MS,
+        );
+        draw_commands
+    }
+
+    /// Only affects commands emitted after this is called; commands that were already emitted keep
+    /// the encoding that was active when they were created.
+    pub fn set_crossfade_enabled(&mut self, enabled: bool) { self.crossfade_enabled = enabled; }
+
+    pub fn set_connection_coloring_enabled(&mut self, enabled: bool) {
+        if self.connection_coloring_enabled == enabled {
+            return;
+        }
+
+        self.connection_coloring_enabled = enabled;
+        self.populate_connection_colors_buffer();
+    }
+
+    pub fn set_orbit_label_params(&mut self, params: OrbitLabelParams) {
+        info!("Set orbit label params to {:?}", params);
+        self.orbit_label_params = params;
+    }
+
+    pub fn set_max_connection_length(&mut self, max_connection_length: f32) {
+        if self.max_connection_length == max_connection_length {
+            return;
+        }
+        self.max_connection_length = max_connection_length;
+
+        info!(
+            "Set max connection length to {}; building new connections data buffer...",
+            max_connection_length
+        );
+        self.rebuild_connections_buffer();
+    }
+}
+
+#[wasm_bindgen]
+pub fn create_artist_map_ctx() -> *mut ArtistMapCtx {
+    maybe_init();
+
+    Box::into_raw(Box::new(ArtistMapCtx::default()))
+}
+
+/// Returns total number of artists in the embedding
+#[wasm_bindgen]
+pub fn decode_and_record_packed_artist_positions(
+    ctx: *mut ArtistMapCtx,
+    packed: Vec<u8>,
+    is_mobile: bool,
+) -> usize {
+    let ctx = unsafe { &mut *ctx };
+    ctx.record_packed_artist_positions(&packed, is_mobile)
+}
+
+#[wasm_bindgen]
+pub fn get_all_artist_data(ctx: *mut ArtistMapCtx) -> Vec<f32> {
+    let ctx = unsafe { &mut *ctx };
+
+    let mut out: Vec<f32> = Vec::with_capacity(ctx.all_artists.len() * 5);
+    unsafe { out.set_len(ctx.all_artists.len() * 5) };
+
+    for (i, (artist_id, state)) in ctx.all_artists.iter().enumerate() {
+        out[i * 5] = unsafe { std::mem::transmute(*artist_id) };
+
+        for (dim_ix, val_for_dim) in state.position.iter().enumerate() {
+            out[i * 5 + 1 + dim_ix] = *val_for_dim;
+        }
+
+        out[i * 5 + 4] = unsafe { std::mem::transmute(state.popularity as u32) };
+    }
+
+    out
+}
+
+// TODO: SIMD-ify maybe idk
+fn distance(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+    let mut sum = 0.;
+    for (a, b) in a.iter().zip(b.iter()) {
+        sum += (a - b).powi(2);
+    }
+    sum.sqrt()
+}
+
+/// Returns a vector of draw commands
+///
+/// `is_fly_mode` is deprecated in favor of `set_mode`; if provided, it updates the stored mode
+/// before handling the names.
+#[wasm_bindgen]
+pub fn handle_received_artist_names(
+    ctx: *mut ArtistMapCtx,
+    artist_ids: Vec<u32>,
+    cur_x: f32,
+    cur_y: f32,
+    cur_z: f32,
+    is_fly_mode: Option<bool>,
+) -> Vec<u32> {
+    let ctx = unsafe { &mut *ctx };
+    if let Some(is_fly_mode) = is_fly_mode {
+        ctx.set_mode(is_fly_mode);
+    }
+    ctx.handle_received_artist_names(artist_ids, cur_x, cur_y, cur_z)
+}
+
+fn should_render_artist(
+    distance: f32,
+    popularity: u8,
+    render_state: &ArtistRenderState,
+    is_mobile: bool,
+    is_fly_mode: bool,
+    quality: u8,
+    highlight_weight: u8,
+) -> bool {
+    if distance < 9000. {
+        return true;
+    }
+
+    // Fully-weighted highlighted artists are always rendered.  Lower-weighted ones are made easier
+    // to render in proportion to their weight.
+    let is_highlighted = render_state.contains(ArtistRenderState::IS_HIGHLIGHTED);
+    if is_highlighted && highlight_weight == u8::MAX {
+        return true;
+    }
+
+    let mut score = distance;
+    score -= (popularity as f32).powi(3) * 0.1;
+
+    if is_mobile {
+        score *= 1.56;
+    }
+
+    let mut quality_diff = DEFAULT_QUALITY as i8 - quality as i8;
+    while quality_diff > 0 {
+        score *= if is_mobile { 1.2 } else { 1.34 };
+        quality_diff -= 1;
+    }
+    while quality_diff < 0 {
+        score *= if is_fly_mode { 0.808 } else { 0.72 };
+        quality_diff += 1;
+    }
+
+    if is_highlighted {
+        score *= scale_highlight_multiplier(0., highlight_weight);
+    }
+
+    score < 36_800.
+}
+
+/// A change to whether an artist's geometry is rendered
+struct GeometryUpdate {
+    artist_ix: usize,
+    /// Lower values are applied first when over the geometry command budget
+    priority: FloatOrd<f32>,
+    should_render: bool,
+}
+
+/// Returns an update if the artist's geometry should be added or removed.  Closer and more popular
+/// artists get higher priority.
+fn get_geometry_update(
+    artist_ix: usize,
+    artist_state: &ArtistState,
+    distance: f32,
+    cur_position: &[f32; 3],
+    view_cone: Option<ViewCone>,
+    is_mobile: bool,
+    is_fly_mode: bool,
+    quality: u8,
+    highlight_weight: u8,
+) -> Option<GeometryUpdate> {
+    let is_geometry_rendered = artist_state
+        .render_state
+        .contains(ArtistRenderState::RENDER_GEOMETRY);
+    let should_render = should_render_artist(
+        distance,
+        artist_state.popularity,
+        &artist_state.render_state,
+        is_mobile,
+        is_fly_mode,
+        quality,
+        highlight_weight,
+    ) && view_cone.map_or(true, |view_cone| {
+        view_cone.contains(
+            cur_position,
+            &artist_state.position,
+            distance,
+            is_geometry_rendered,
+        )
+    });
+    if should_render == is_geometry_rendered {
+        return None;
+    }
+
+    Some(GeometryUpdate {
+        artist_ix,
+        priority: FloatOrd(
+            distance - artist_state.popularity as f32 * GEOMETRY_PRIORITY_DISTANCE_PER_POPULARITY,
+        ),
+        should_render,
+    })
+}
+
+static mut RNG: *mut pcg::Pcg = std::ptr::null_mut();
+
+#[cfg(not(test))]
+fn rng() -> &'static mut pcg::Pcg { unsafe { &mut *RNG } }
+
+/// `RNG` is seeded from JS by `maybe_init`, which can't run natively.  Tests get a fixed seed
+/// instead, with one RNG per thread so that they can run in parallel.
+#[cfg(test)]
+fn rng() -> &'static mut pcg::Pcg {
+    thread_local! {
+        static TEST_RNG: *mut pcg::Pcg = {
+            let seed: u64 = 0x5EED;
+            Box::into_raw(Box::new(pcg::Pcg::from_seed(seed.into())))
+        };
+    }
+    TEST_RNG.with(|rng| unsafe { &mut **rng })
+}
+
+/// Music is faded out more quickly the further the listener got past the max play distance before
+/// it was stopped since that means they're moving quickly
+fn get_music_fade_out_ms(distance_overshoot: f32) -> u32 {
+    let overshoot_factor = (distance_overshoot / MUSIC_FADE_OVERSHOOT_DISTANCE).clamp(0., 1.);
+    let fade_range = (DEFAULT_MUSIC_FADE_OUT_MS - MIN_MUSIC_FADE_MS) as f32;
+    DEFAULT_MUSIC_FADE_OUT_MS - (fade_range * overshoot_factor) as u32
+}
+
+fn get_connection_render_quality_rng_adjustment(quality: u8, is_mobile: bool) -> f64 {
+    let mut quality_rng_adjustment = -0.1;
+    if is_mobile {
+        quality_rng_adjustment += MOBILE_CONNECTION_RENDER_RNG_ADJUSTMENT;
+    }
+
+    let mut quality_diff: i8 = DEFAULT_QUALITY as i8 - quality as i8;
+    // If quality is lower than default (difference is positive), we decrease the bottom range of
+    // generated random values for the score so that it's less likely at all distances that the
+    // connection is rendered
+    //
+    // rng range goes from (0, 1) to something like (-0.2, 1).
+    while quality_diff > 0 {
+        quality_rng_adjustment -= 0.15;
+        quality_diff -= 1;
+    }
+    // If quality is higher than default (difference is negative), we increase the bottom range of
+    // generated random values for the score so that it's less likely at all distances that the
+    // connection is rendered
+    //
+    // rng range goes from (0, 1) to something like (0.2, 1).
+    while quality_diff < 0 {
+        quality_rng_adjustment += 0.1;
+        quality_diff += 1;
+    }
+
+    quality_rng_adjustment
+}
+
+/// Returns a value between -0.5 and 0.5 indicating how much stronger or weaker a relationship is
+/// than the default
+fn connection_weight_factor(weight: u8) -> f32 {
+    (weight as f32 - DEFAULT_CONNECTION_WEIGHT as f32) / 255.
+}
+
+fn should_render_connection(
+    quality_rng_adjustment: f64,
+    src: &ArtistState,
+    dst: &ArtistState,
+    weight: u8,
+) -> bool {
+    let val = rng().gen_range(quality_rng_adjustment, 1.0f64);
+    // Stronger connections are treated as shorter so that they're rendered at longer distances
+    let dist =
+        distance(&src.position, &dst.position) * (1. - connection_weight_factor(weight) * 0.8);
+
+    if dist > 70000. {
+        return false;
+    } else if dist > 25000. {
+        return val > 0.994;
+    } else if dist > 17000. {
+        return val > 0.82;
+    } else if dist > 8000. {
+        return val > 0.28;
+    } else {
+        return val > 0.185;
+    }
+}
+
+const ADD_LABEL_CMD: u32 = 0u32;
+const REMOVE_LABEL_CMD: u32 = 1u32;
+const ADD_ARTIST_GEOMETRY_CMD: u32 = 2u32;
+const REMOVE_ARTIST_GEOMETRY_CMD: u32 = 3u32;
+const FETCH_ARTIST_DATA_CMD: u32 = 4u32;
+const START_PLAYING_MUSIC_CMD: u32 = 5u32;
+const STOP_PLAYING_MUSIC_CMD: u32 = 6u32;
+
+/// Returns a vector of draw commands
+///
+/// `is_fly_mode` is deprecated in favor of `set_mode`; if provided, it updates the stored mode
+/// before handling the new position.
+///
+/// `forward_*` is the direction the camera is facing and `fov_degrees` is its widest FOV.  If
+/// provided, geometry isn't added for artists that are well outside of the camera's view.  Pass
+/// NaN for any of them to disable culling.
+#[wasm_bindgen]
+pub fn handle_new_position(
+    ctx: *mut ArtistMapCtx,
+    cur_x: f32,
+    cur_y: f32,
+    cur_z: f32,
+    projected_next_x: f32,
+    projected_next_y: f32,
+    projected_next_z: f32,
+    is_fly_mode: Option<bool>,
+    forward_x: f32,
+    forward_y: f32,
+    forward_z: f32,
+    fov_degrees: f32,
+    max_geometry_commands: Option<u32>,
+) -> Vec<u32> {
+    let ctx = unsafe { &mut *ctx };
+    if let Some(is_fly_mode) = is_fly_mode {
+        ctx.set_mode(is_fly_mode);
+    }
+    ctx.handle_new_position(
+        cur_x,
+        cur_y,
+        cur_z,
+        projected_next_x,
+        projected_next_y,
+        projected_next_z,
+        ViewCone::new([forward_x, forward_y, forward_z], fov_degrees),
+        max_geometry_commands.map(|max| max as usize),
+    )
+}
+
+/// Applies geometry updates that were deferred by `handle_new_position` because they were over its
+/// `max_geometry_commands` budget, for use when the camera is stationary.  Returns a list of draw
+/// commands to execute.
+#[wasm_bindgen]
+pub fn flush_deferred_geometry_updates(
+    ctx: *mut ArtistMapCtx,
+    max_geometry_commands: Option<u32>,
+) -> Vec<u32> {
+    let ctx = unsafe { &mut *ctx };
+    ctx.flush_deferred_geometry_updates(max_geometry_commands.map(|max| max as usize))
+}
+
+/// Applies a trajectory of positions packed as `[x, y, z, x, y, z, ...]`.  See
+/// `ArtistMapCtx::handle_position_batch` for the output format.
+#[wasm_bindgen]
+pub fn handle_position_batch(
+    ctx: *mut ArtistMapCtx,
+    positions: Vec<f32>,
+    is_fly_mode: bool,
+) -> Vec<u32> {
+    let ctx = unsafe { &mut *ctx };
+    ctx.set_mode(is_fly_mode);
+    ctx.handle_position_batch(&positions)
+}
+
+/// Returns a vector of draw commands
+#[wasm_bindgen]
+pub fn on_music_finished_playing(
+    ctx: *mut ArtistMapCtx,
+    artist_id: u32,
+    cur_x: f32,
+    cur_y: f32,
+    cur_z: f32,
+) -> Vec<u32> {
+    let ctx = unsafe { &mut *ctx };
+    ctx.on_music_finished_playing(artist_id, cur_x, cur_y, cur_z)
+}
+
+/// Returns connection buffer length
+#[wasm_bindgen]
 pub fn handle_artist_relationship_data(
     ctx: *mut ArtistMapCtx,
     packed_relationship_data: Vec<u8>,
@@ -908,428 +2393,1437 @@ pub fn handle_artist_relationship_data(
     chunk_ix: u32,
 ) -> usize {
     let ctx = unsafe { &mut *ctx };
-    ctx.received_chunks.insert((chunk_ix, chunk_size));
+    ctx.handle_artist_relationship_data(&packed_relationship_data, chunk_size, chunk_ix)
+}
+
+/// Returns connection buffer length
+#[wasm_bindgen]
+pub fn add_single_artist_relationships(
+    ctx: *mut ArtistMapCtx,
+    artist_id: u32,
+    packed_related_ids: Vec<u32>,
+) -> usize {
+    let ctx = unsafe { &mut *ctx };
+    ctx.add_single_artist_relationships(artist_id, &packed_related_ids)
+}
+
+#[wasm_bindgen]
+pub fn get_connections_buffer_ptr(ctx: *mut ArtistMapCtx) -> *const f32 {
+    let ctx = unsafe { &mut *ctx };
+    ctx.connections_buffer.as_ptr() as *const f32
+}
+
+#[wasm_bindgen]
+pub fn get_connections_buffer_length(ctx: *mut ArtistMapCtx) -> usize {
+    let ctx = unsafe { &mut *ctx };
+    ctx.connections_buffer.len() * 6
+}
+
+#[wasm_bindgen]
+pub fn get_connections_color_buffer_ptr(ctx: *mut ArtistMapCtx) -> *const f32 {
+    let ctx = unsafe { &mut *ctx };
+    ctx.connection_colors_buffer.as_ptr() as *const f32
+}
+
+#[wasm_bindgen]
+pub fn get_connections_color_buffer_length(ctx: *mut ArtistMapCtx) -> usize {
+    let ctx = unsafe { &mut *ctx };
+    ctx.connection_colors_buffer.len()
+}
+
+#[wasm_bindgen]
+pub fn get_artist_colors_buffer_ptr(ctx: *mut ArtistMapCtx) -> *const f32 {
+    let ctx = unsafe { &mut *ctx };
+    ctx.artist_colors_buffer.as_ptr() as *const f32
+}
+
+#[wasm_bindgen]
+pub fn get_artist_colors_buffer_length(ctx: *mut ArtistMapCtx) -> usize {
+    let ctx = unsafe { &mut *ctx };
+    ctx.artist_colors_buffer.len() * 4
+}
+
+/// Overrides the colors of specific artists.  `packed` consists of `[artist_id: u32, r: f32, g:
+/// f32, b: f32]` tuples.  Overrides persist until `clear_artist_color_overrides` is called.
+///
+/// Returns the new length of the artist colors buffer.
+#[wasm_bindgen]
+pub fn set_artist_colors(ctx: *mut ArtistMapCtx, packed: Vec<u8>) -> usize {
+    let ctx = unsafe { &mut *ctx };
+    ctx.set_artist_colors(&packed);
+    ctx.artist_colors_buffer.len() * 4
+}
+
+/// Reverts all artist colors set via `set_artist_colors` to their generated colors.
+///
+/// Returns the new length of the artist colors buffer.
+#[wasm_bindgen]
+pub fn clear_artist_color_overrides(ctx: *mut ArtistMapCtx) -> usize {
+    let ctx = unsafe { &mut *ctx };
+    ctx.clear_artist_color_overrides();
+    ctx.artist_colors_buffer.len() * 4
+}
+
+#[wasm_bindgen]
+pub fn get_memory() -> JsValue { wasm_bindgen::memory() }
+
+/// Returns a list of draw commands to execute
+///
+/// `is_fly_mode` is deprecated in favor of `set_mode`; if provided, it updates the stored mode
+/// before setting the highlighted artists.
+#[wasm_bindgen]
+pub fn handle_set_highlighted_artists(
+    ctx: *mut ArtistMapCtx,
+    highlighted_artist_ids: Vec<u32>,
+    weights: Vec<u8>,
+    cur_x: f32,
+    cur_y: f32,
+    cur_z: f32,
+    is_fly_mode: Option<bool>,
+) -> Vec<u32> {
+    let ctx = unsafe { &mut *ctx };
+    if let Some(is_fly_mode) = is_fly_mode {
+        ctx.set_mode(is_fly_mode);
+    }
+    ctx.handle_set_highlighted_artists(highlighted_artist_ids, weights, cur_x, cur_y, cur_z)
+}
+
+/// Returns a list of draw commands to execute
+#[wasm_bindgen]
+pub fn handle_artist_manual_play(ctx: *mut ArtistMapCtx, artist_id: u32) -> Vec<u32> {
+    let ctx = unsafe { &mut *ctx };
+    ctx.handle_artist_manual_play(artist_id)
+}
+
+/// Returns a list of draw commands to execute
+#[wasm_bindgen]
+pub fn play_last_artist(ctx: *mut ArtistMapCtx) -> Vec<u32> {
+    let ctx = unsafe { &mut *ctx };
+    ctx.play_last_artist()
+}
+
+/// Returns the IDs of all artists related to the provided one.  If `include_out_of_set` is set,
+/// related artists that aren't in the embedding are included at the end.
+///
+/// Returns an empty list if relationship data hasn't been received for the artist yet.
+#[wasm_bindgen]
+pub fn get_related_artist_ids(
+    ctx: *mut ArtistMapCtx,
+    artist_id: u32,
+    include_out_of_set: bool,
+) -> Vec<u32> {
+    let ctx = unsafe { &mut *ctx };
+    ctx.get_related_artist_ids(artist_id, include_out_of_set)
+}
+
+#[wasm_bindgen]
+pub fn get_connections_for_artists(
+    ctx: *mut ArtistMapCtx,
+    artist_ids: Vec<u32>,
+    constrain_destinations_to_set: bool,
+) -> Vec<f32> {
+    let ctx = unsafe { &mut *ctx };
+    ctx.get_connections_for_artists(artist_ids, constrain_destinations_to_set)
+}
+
+#[wasm_bindgen]
+pub fn get_galaxy_bounds(ctx: *mut ArtistMapCtx) -> Vec<f32> {
+    let ctx = unsafe { &mut *ctx };
+    ctx.get_galaxy_bounds()
+}
+
+#[wasm_bindgen]
+pub fn get_artists_centroid(
+    ctx: *mut ArtistMapCtx,
+    artist_ids: Vec<u32>,
+    include_nearest_artist: bool,
+) -> Vec<f32> {
+    let ctx = unsafe { &mut *ctx };
+    ctx.get_artists_centroid(&artist_ids, include_nearest_artist)
+}
+
+/// Sets whether the user is currently flying around the galaxy (`true`) or in orbit mode (`false`).
+/// Handlers use the stored mode when deciding what to render and whether to play music.
+#[wasm_bindgen]
+pub fn set_mode(ctx: *mut ArtistMapCtx, is_fly_mode: bool) {
+    let ctx = unsafe { &mut *ctx };
+    ctx.set_mode(is_fly_mode)
+}
+
+#[wasm_bindgen]
+pub fn transition_to_orbit_mode(ctx: *mut ArtistMapCtx) -> Vec<u32> {
+    let ctx = unsafe { &mut *ctx };
+    ctx.transition_to_orbit_mode()
+}
+
+#[wasm_bindgen]
+pub fn force_render_artist_label(ctx: *mut ArtistMapCtx, artist_id: u32) -> Vec<u32> {
+    let ctx = unsafe { &mut *ctx };
+    ctx.force_render_artist_label(artist_id)
+}
+
+/// Supported quality values are 0 through 15; higher values are clamped to 15.
+#[wasm_bindgen]
+pub fn set_quality(ctx: *mut ArtistMapCtx, new_quality: u8) {
+    let ctx = unsafe { &mut *ctx };
+    ctx.set_quality(new_quality)
+}
+
+#[wasm_bindgen]
+pub fn get_loading_progress(ctx: *mut ArtistMapCtx) -> Vec<u32> {
+    let ctx = unsafe { &mut *ctx };
+    ctx.get_loading_progress()
+}
+
+/// Returns the fraction of relationship chunks that have been received, from 0 to 1
+#[wasm_bindgen]
+pub fn get_relationship_coverage(ctx: *mut ArtistMapCtx, total_chunks: u32) -> f32 {
+    let ctx = unsafe { &mut *ctx };
+    ctx.get_relationship_coverage(total_chunks)
+}
+
+/// Returns the sorted indices of all received relationship chunks so that missing ones can be
+/// re-requested
+#[wasm_bindgen]
+pub fn get_received_chunk_indices(ctx: *mut ArtistMapCtx) -> Vec<u32> {
+    let ctx = unsafe { &mut *ctx };
+    ctx.get_received_chunk_indices()
+}
+
+/// Returns `(artist_id, render_state_bits, popularity)` triplets for every artist with a non-empty
+/// render state.  Render state bits are:
+///
+/// - `0b0000_0010`: connections should be rendered
+/// - `0b0000_0100`: geometry is rendered
+/// - `0b0000_1000`: the artist's name has been received
+/// - `0b0001_0000`: the artist is highlighted
+/// - `0b0010_0000`: a fetch of the artist's name has been requested
+///
+/// Rendered labels aren't part of the render state; use `get_rendered_label_ids` for those.
+#[wasm_bindgen]
+pub fn get_render_state_snapshot(ctx: *mut ArtistMapCtx) -> Vec<u32> {
+    let ctx = unsafe { &mut *ctx };
+    ctx.get_render_state_snapshot()
+}
+
+/// Returns the IDs of all artists whose labels are currently rendered
+#[wasm_bindgen]
+pub fn get_rendered_label_ids(ctx: *mut ArtistMapCtx) -> Vec<u32> {
+    let ctx = unsafe { &mut *ctx };
+    ctx.get_rendered_label_ids()
+}
+
+/// When disabled, the connection colors buffer is freed and no longer built, and its length will be
+/// 0. Enabled by default.
+#[wasm_bindgen]
+pub fn set_connection_coloring_enabled(ctx: *mut ArtistMapCtx, enabled: bool) {
+    let ctx = unsafe { &mut *ctx };
+    ctx.set_connection_coloring_enabled(enabled)
+}
+
+/// Replaces the set of artists that are never played.  Returns a list of draw commands to execute,
+/// which stop the currently playing artist if it's excluded.
+#[wasm_bindgen]
+pub fn set_excluded_artists(ctx: *mut ArtistMapCtx, artist_ids: Vec<u32>) -> Vec<u32> {
+    let ctx = unsafe { &mut *ctx };
+    ctx.set_excluded_artists(artist_ids)
+}
+
+/// When enabled, start and stop playing music commands are three words long instead of two, with
+/// the third holding the fade duration in milliseconds.  Disabled by default.
+#[wasm_bindgen]
+pub fn set_crossfade_enabled(ctx: *mut ArtistMapCtx, enabled: bool) {
+    let ctx = unsafe { &mut *ctx };
+    ctx.set_crossfade_enabled(enabled)
+}
+
+#[wasm_bindgen]
+pub fn set_max_connection_length(ctx: *mut ArtistMapCtx, max_connection_length: f32) {
+    let ctx = unsafe { &mut *ctx };
+    ctx.set_max_connection_length(max_connection_length)
+}
+
+/// Sets how many highlighted artists are labeled when transitioning to orbit mode and the min
+/// distances between those labels.  Applies to the next transition.
+#[wasm_bindgen]
+pub fn set_orbit_label_params(
+    ctx: *mut ArtistMapCtx,
+    top_count: usize,
+    random_count: usize,
+    min_dist: f32,
+    random_min_dist: f32,
+) {
+    let ctx = unsafe { &mut *ctx };
+    ctx.set_orbit_label_params(OrbitLabelParams {
+        top_count,
+        random_count,
+        min_distance: min_dist,
+        random_min_distance: random_min_dist,
+    })
+}
 
-    let artist_ids = ctx
+/// Packs `(id, world_position, popularity)` tuples into the format accepted by
+/// `ArtistMapCtx::from_packed`.
+#[cfg(test)]
+fn build_packed_artist_positions(artists: &[(u32, [f32; 3], u8)]) -> Vec<u8> {
+    let mut packed = Vec::new();
+    packed.extend_from_slice(&(artists.len() as u32).to_le_bytes());
+    for (id, ..) in artists {
+        packed.extend_from_slice(&id.to_le_bytes());
+    }
+    for (_, position, _) in artists {
+        for (dim_ix, val) in position.iter().enumerate() {
+            packed.extend_from_slice(&(val / DISTANCE_MULTIPLIER[dim_ix]).to_le_bytes());
+        }
+    }
+    for (_, _, popularity) in artists {
+        packed.push(*popularity);
+    }
+    packed
+}
+
+/// Packs relationship data for all artists in `ctx` as a single chunk in the format accepted by
+/// `handle_artist_relationship_data`.
+#[cfg(test)]
+fn build_packed_relationships(ctx: &ArtistMapCtx, related: &[(u32, &[u32])]) -> Vec<u8> {
+    fn related_ids_for<'a>(related: &[(u32, &'a [u32])], artist_id: u32) -> &'a [u32] {
+        related
+            .iter()
+            .find(|(id, _)| *id == artist_id)
+            .map(|(_, related_ids)| *related_ids)
+            .unwrap_or_default()
+    }
+
+    let mut packed: Vec<u8> = ctx
         .sorted_artist_ids
-        .chunks(chunk_size as usize)
-        .skip(chunk_ix as usize)
-        .next()
-        .unwrap_or_default();
-    let artist_ids_byte_offset = artist_ids.len() + 4 - (artist_ids.len() % 4);
-
-    assert_eq!(packed_relationship_data.len() % 4, 0);
-    let u32_view = unsafe {
-        std::slice::from_raw_parts(
-            packed_relationship_data
-                .as_ptr()
-                .add(artist_ids_byte_offset) as *const u32,
-            (packed_relationship_data.len() - artist_ids_byte_offset) / 4,
-        )
-    };
+        .iter()
+        .map(|&artist_id| related_ids_for(related, artist_id).len() as u8)
+        .collect();
+    let artist_ids_byte_offset = packed.len() + 4 - (packed.len() % 4);
+    packed.resize(artist_ids_byte_offset, 0);
+    for &artist_id in &ctx.sorted_artist_ids {
+        for related_artist_id in related_ids_for(related, artist_id) {
+            packed.extend_from_slice(&related_artist_id.to_le_bytes());
+        }
+    }
+    packed
+}
 
-    let mut offset = 0;
-    for i in 0..artist_ids.len() {
-        let artist_id = artist_ids[i];
-        let artist_index = *ctx.artists_indices_by_id.get(&artist_id).unwrap();
-        let relationship_state = &mut ctx.all_artist_relationships[artist_index];
+/// Returns the artist IDs of all commands of type `cmd` in `draw_commands`
+#[cfg(test)]
+fn get_command_artist_ids(draw_commands: &[u32], cmd: u32) -> Vec<u32> {
+    draw_commands
+        .chunks_exact(2)
+        .filter(|command| command[0] == cmd)
+        .map(|command| command[1])
+        .collect()
+}
 
-        let count = packed_relationship_data[i] as usize;
-        let mut actual_count = 0;
-        for relationship_ix in 0..count {
-            let related_artist_id = u32_view[offset + relationship_ix];
-            let related_artist_index = match ctx.artists_indices_by_id.get(&related_artist_id) {
-                Some(ix) => *ix,
-                // It's possible the artist is related to one that's not in the embedding
-                None => continue,
-            };
+#[test]
+fn from_packed_decodes_artists() {
+    let ctx = ArtistMapCtx::from_packed(
+        &build_packed_artist_positions(&[(30, [1000., 0., -500.], 80), (10, [0., 0., 0.], 5)]),
+        true,
+    );
 
-            relationship_state.related_artist_indices[actual_count] = ArtistRelationship {
-                related_artist_index,
-                connections_buffer_index: None,
-            };
-            actual_count += 1;
+    assert!(ctx.is_mobile);
+    assert_eq!(ctx.sorted_artist_ids, vec![10, 30]);
+    let (id, state) = &ctx.all_artists[ctx.artists_indices_by_id[&30]];
+    assert_eq!(*id, 30);
+    assert_eq!(state.popularity, 80);
+    for (actual, expected) in state.position.iter().zip([1000., 0., -500.].iter()) {
+        assert!((actual - expected).abs() < 0.01);
+    }
+    assert_eq!(ctx.artist_colors_buffer.len(), 2);
+}
+
+#[test]
+fn labels_render_based_on_distance_and_popularity() {
+    let ctx = ArtistMapCtx::from_packed(
+        &build_packed_artist_positions(&[(1, [0., 0., 0.], 0), (2, [0., 0., 0.], 100)]),
+        false,
+    );
+    let unpopular = &ctx.all_artists[ctx.artists_indices_by_id[&1]].1;
+    let popular = &ctx.all_artists[ctx.artists_indices_by_id[&2]].1;
+
+    assert!(should_render_label(
+        0,
+        unpopular,
+        100.,
+        false,
+        DEFAULT_QUALITY,
+        u8::MAX
+    ));
+    assert!(!should_render_label(
+        0,
+        unpopular,
+        200_000.,
+        false,
+        DEFAULT_QUALITY,
+        u8::MAX
+    ));
+    assert!(!should_render_label(
+        0,
+        unpopular,
+        20_000.,
+        false,
+        DEFAULT_QUALITY,
+        u8::MAX
+    ));
+    assert!(should_render_label(
+        0,
+        popular,
+        20_000.,
+        false,
+        DEFAULT_QUALITY,
+        u8::MAX
+    ));
+    // Lots of labels already being rendered makes it harder to render more
+    assert!(!should_render_label(
+        500,
+        popular,
+        20_000.,
+        false,
+        DEFAULT_QUALITY,
+        u8::MAX
+    ));
+}
+
+/// Splits draw commands emitted with crossfading enabled into `(cmd, artist_id, fade_ms)` tuples.
+/// `fade_ms` is only set for music commands.
+#[cfg(test)]
+fn parse_crossfade_draw_commands(draw_commands: &[u32]) -> Vec<(u32, u32, Option<u32>)> {
+    let mut parsed = Vec::new();
+    let mut offset = 0;
+    while offset < draw_commands.len() {
+        let (cmd, artist_id) = (draw_commands[offset], draw_commands[offset + 1]);
+        if cmd == START_PLAYING_MUSIC_CMD || cmd == STOP_PLAYING_MUSIC_CMD {
+            parsed.push((cmd, artist_id, Some(draw_commands[offset + 2])));
+            offset += 3;
+        } else {
+            parsed.push((cmd, artist_id, None));
+            offset += 2;
         }
-        relationship_state.count = actual_count;
+    }
+    parsed
+}
+
+#[test]
+fn music_transitions() {
+    let mut ctx = ArtistMapCtx::from_packed(
+        &build_packed_artist_positions(&[
+            (1, [0., 0., 0.], 20),
+            (2, [1000., 0., 0.], 20),
+            (3, [500_000., 0., 0.], 20),
+        ]),
+        false,
+    );
+
+    // Music is never played in orbit mode
+    let draw_commands = ctx.handle_new_position(10., 0., 0., 10., 0., 0., None, None);
+    assert!(get_command_artist_ids(&draw_commands, START_PLAYING_MUSIC_CMD).is_empty());
+    assert_eq!(ctx.playing_music_artist_id, None);
+
+    // Flying close to an artist starts playing their music
+    ctx.set_mode(true);
+    let draw_commands = ctx.handle_new_position(10., 1., 0., 10., 1., 0., None, None);
+    assert_eq!(
+        get_command_artist_ids(&draw_commands, START_PLAYING_MUSIC_CMD),
+        vec![1]
+    );
+    assert_eq!(ctx.playing_music_artist_id, Some(1));
+
+    // When it finishes, the closest artist that hasn't been played recently is played next
+    let draw_commands = ctx.on_music_finished_playing(1, 10., 0., 0.);
+    assert_eq!(
+        get_command_artist_ids(&draw_commands, STOP_PLAYING_MUSIC_CMD),
+        vec![1]
+    );
+    assert_eq!(
+        get_command_artist_ids(&draw_commands, START_PLAYING_MUSIC_CMD),
+        vec![2]
+    );
+
+    // Finished events for artists that aren't playing are ignored
+    assert!(ctx.on_music_finished_playing(1, 10., 0., 0.).is_empty());
+
+    // Manually playing an artist works regardless of distance and fetches its name
+    let draw_commands = ctx.handle_artist_manual_play(3);
+    assert_eq!(
+        get_command_artist_ids(&draw_commands, STOP_PLAYING_MUSIC_CMD),
+        vec![2]
+    );
+    assert_eq!(
+        get_command_artist_ids(&draw_commands, START_PLAYING_MUSIC_CMD),
+        vec![3]
+    );
+    assert_eq!(
+        get_command_artist_ids(&draw_commands, FETCH_ARTIST_DATA_CMD),
+        vec![3]
+    );
+    assert_eq!(ctx.manual_play_artist_id, Some(3));
+    // Manually stopped artists aren't recorded as recently played
+    assert_eq!(ctx.most_recently_played_artist_ids, VecDeque::from(vec![1]));
+}
+
+#[test]
+fn music_crossfades_include_fade_durations() {
+    assert_eq!(get_music_fade_out_ms(0.), DEFAULT_MUSIC_FADE_OUT_MS);
+    assert_eq!(get_music_fade_out_ms(-10.), DEFAULT_MUSIC_FADE_OUT_MS);
+    assert_eq!(
+        get_music_fade_out_ms(MUSIC_FADE_OVERSHOOT_DISTANCE),
+        MIN_MUSIC_FADE_MS
+    );
+    assert_eq!(get_music_fade_out_ms(1_000_000.), MIN_MUSIC_FADE_MS);
+    let partial_fade_ms = get_music_fade_out_ms(MUSIC_FADE_OVERSHOOT_DISTANCE / 2.);
+    assert!(partial_fade_ms > MIN_MUSIC_FADE_MS && partial_fade_ms < DEFAULT_MUSIC_FADE_OUT_MS);
+
+    let mut ctx = ArtistMapCtx::from_packed(
+        &build_packed_artist_positions(&[(1, [0., 0., 0.], 20), (2, [20_000., 0., 0.], 20)]),
+        false,
+    );
+    ctx.set_mode(true);
+    ctx.set_crossfade_enabled(true);
+    let music_commands = |draw_commands: &[u32]| {
+        parse_crossfade_draw_commands(draw_commands)
+            .into_iter()
+            .filter(|&(_, _, fade_ms)| fade_ms.is_some())
+            .collect::<Vec<_>>()
+    };
 
-        offset += count;
-    }
+    let draw_commands = ctx.handle_new_position(10., 1., 0., 10., 1., 0., None, None);
+    assert_eq!(music_commands(&draw_commands), vec![(
+        START_PLAYING_MUSIC_CMD,
+        1,
+        Some(DEFAULT_MUSIC_FADE_IN_MS)
+    )]);
+
+    // Jumping far out of range fades out quickly and the next artist fades in just as fast
+    let draw_commands = ctx.handle_new_position(20., 1., 0., 19_000., 0., 0., None, None);
+    assert_eq!(music_commands(&draw_commands), vec![
+        (STOP_PLAYING_MUSIC_CMD, 1, Some(MIN_MUSIC_FADE_MS)),
+        (START_PLAYING_MUSIC_CMD, 2, Some(MIN_MUSIC_FADE_MS)),
+    ]);
+
+    let draw_commands = ctx.handle_artist_manual_play(1);
+    assert_eq!(music_commands(&draw_commands), vec![
+        (STOP_PLAYING_MUSIC_CMD, 2, Some(MIN_MUSIC_FADE_MS)),
+        (START_PLAYING_MUSIC_CMD, 1, Some(DEFAULT_MUSIC_FADE_IN_MS)),
+    ]);
+
+    // The old two-word encoding is used when crossfading is disabled
+    ctx.set_crossfade_enabled(false);
+    let draw_commands = ctx.on_music_finished_playing(1, 10., 0., 0.);
+    assert_eq!(draw_commands[..2], [STOP_PLAYING_MUSIC_CMD, 1]);
+    assert_eq!(
+        get_command_artist_ids(&draw_commands, STOP_PLAYING_MUSIC_CMD),
+        vec![1]
+    );
+}
 
+#[test]
+fn excluded_artists_are_never_played() {
+    let mut ctx = ArtistMapCtx::from_packed(
+        &build_packed_artist_positions(&[(1, [0., 0., 0.], 20), (2, [1000., 0., 0.], 20)]),
+        false,
+    );
+    ctx.set_mode(true);
+
+    let draw_commands = ctx.handle_new_position(10., 1., 0., 10., 1., 0., None, None);
     assert_eq!(
-        artist_ids_byte_offset + offset * 4,
-        packed_relationship_data.len()
+        get_command_artist_ids(&draw_commands, START_PLAYING_MUSIC_CMD),
+        vec![1]
     );
-    ctx.update_connections_buffer(chunk_size, chunk_ix);
-    ctx.populate_connection_colors_buffer();
 
-    ctx.connections_buffer.len() * 6
-}
+    // Excluding the playing artist stops it and switches to the closest remaining one
+    let draw_commands = ctx.set_excluded_artists(vec![1]);
+    assert_eq!(
+        get_command_artist_ids(&draw_commands, STOP_PLAYING_MUSIC_CMD),
+        vec![1]
+    );
+    assert_eq!(
+        get_command_artist_ids(&draw_commands, START_PLAYING_MUSIC_CMD),
+        vec![2]
+    );
+    assert_eq!(ctx.playing_music_artist_id, Some(2));
 
-#[wasm_bindgen]
-pub fn get_connections_buffer_ptr(ctx: *mut ArtistMapCtx) -> *const f32 {
-    let ctx = unsafe { &mut *ctx };
-    ctx.connections_buffer.as_ptr() as *const f32
-}
+    // Excluded artists can't be played manually either
+    assert!(ctx.handle_artist_manual_play(1).is_empty());
+    assert_eq!(ctx.playing_music_artist_id, Some(2));
 
-#[wasm_bindgen]
-pub fn get_connections_buffer_length(ctx: *mut ArtistMapCtx) -> usize {
-    let ctx = unsafe { &mut *ctx };
-    ctx.connections_buffer.len() * 6
+    // Nothing changes if the playing artist isn't excluded
+    assert!(ctx.set_excluded_artists(vec![1, 99]).is_empty());
+    assert_eq!(ctx.get_next_artist_to_play(10., 1., 0.), Some(2));
+    ctx.set_excluded_artists(Vec::new());
+    assert_eq!(ctx.get_next_artist_to_play(10., 1., 0.), Some(1));
 }
 
-#[wasm_bindgen]
-pub fn get_connections_color_buffer_ptr(ctx: *mut ArtistMapCtx) -> *const f32 {
-    let ctx = unsafe { &mut *ctx };
-    ctx.connection_colors_buffer.as_ptr() as *const f32
+#[test]
+fn connections_are_deduplicated() {
+    let mut ctx = ArtistMapCtx::from_packed(
+        &build_packed_artist_positions(&[
+            (1, [0., 0., 0.], 20),
+            (2, [1000., 0., 0.], 20),
+            (3, [0., 1000., 0.], 20),
+        ]),
+        false,
+    );
+    // Connections shorter than 8000 are always rendered at this quality
+    ctx.quality = 10;
+
+    let packed_relationships =
+        build_packed_relationships(&ctx, &[(1, &[2, 3, 99]), (2, &[1]), (3, &[1])]);
+    let connections_buffer_len = ctx.handle_artist_relationship_data(&packed_relationships, 3, 0);
+
+    // 1-2 and 1-3 are each only rendered once even though they're listed in both directions
+    assert_eq!(connections_buffer_len, 2 * 6);
+    assert_eq!(ctx.connections_buffer.len(), 2);
+    assert_eq!(ctx.get_related_artist_ids(1, false), vec![2, 3]);
+    assert_eq!(ctx.get_related_artist_ids(1, true), vec![2, 3, 99]);
+
+    ctx.set_max_connection_length(500.);
+    assert!(ctx.connections_buffer.is_empty());
+    ctx.set_max_connection_length(f32::INFINITY);
+    assert_eq!(ctx.connections_buffer.len(), 2);
 }
 
-#[wasm_bindgen]
-pub fn get_connections_color_buffer_length(ctx: *mut ArtistMapCtx) -> usize {
-    let ctx = unsafe { &mut *ctx };
-    ctx.connection_colors_buffer.len()
+#[test]
+fn rebuilding_connections_does_not_duplicate_segments() {
+    let mut ctx = ArtistMapCtx::from_packed(
+        &build_packed_artist_positions(&[
+            (1, [0., 0., 0.], 20),
+            (2, [1000., 0., 0.], 20),
+            (3, [0., 1000., 0.], 20),
+            (4, [100_000., 0., 0.], 20),
+        ]),
+        false,
+    );
+    ctx.quality = 10;
+    ctx.max_connection_length = 10_000.;
+
+    // The first relationship of artist 1 is too long to be rendered
+    let packed_relationships =
+        build_packed_relationships(&ctx, &[(1, &[4, 2, 3]), (2, &[3]), (3, &[1])]);
+    ctx.handle_artist_relationship_data(&packed_relationships, 4, 0);
+    assert_eq!(ctx.connections_buffer.len(), 3);
+
+    let assert_no_duplicates = |ctx: &ArtistMapCtx| {
+        let mut segments: Vec<_> = ctx
+            .connections_buffer
+            .iter()
+            .map(|[src, dst]| {
+                let (src, dst) = (src.map(f32::to_bits), dst.map(f32::to_bits));
+                (src.min(dst), src.max(dst))
+            })
+            .collect();
+        let segment_count = segments.len();
+        segments.sort_unstable();
+        segments.dedup();
+        assert_eq!(segments.len(), segment_count);
+    };
+
+    for _ in 0..2 {
+        ctx.rebuild_connections_buffer();
+        assert_eq!(ctx.connections_buffer.len(), 3);
+        assert_no_duplicates(&ctx);
+    }
+    // Processing the chunk again after a rebuild doesn't add anything
+    ctx.update_connections_buffer(4, 0);
+    assert_eq!(ctx.connections_buffer.len(), 3);
+
+    ctx.set_quality(DEFAULT_QUALITY);
+    ctx.set_quality(10);
+    assert_no_duplicates(&ctx);
+    for artist_id in [1, 2, 3] {
+        let artist_ix = ctx.artists_indices_by_id[&artist_id];
+        assert!(ctx.all_artist_relationships[artist_ix].connections_built);
+    }
 }
 
-#[wasm_bindgen]
-pub fn get_artist_colors_buffer_ptr(ctx: *mut ArtistMapCtx) -> *const f32 {
-    let ctx = unsafe { &mut *ctx };
-    ctx.artist_colors_buffer.as_ptr() as *const f32
+#[test]
+fn single_artist_relationships_are_rendered() {
+    let mut ctx = ArtistMapCtx::from_packed(
+        &build_packed_artist_positions(&[
+            (1, [0., 0., 0.], 20),
+            (2, [1000., 0., 0.], 20),
+            (3, [0., 1000., 0.], 20),
+        ]),
+        false,
+    );
+    ctx.quality = 10;
+
+    assert_eq!(ctx.add_single_artist_relationships(1, &[2, 99]), 6);
+    assert_eq!(ctx.get_related_artist_ids(1, true), vec![2, 99]);
+
+    // The 2-1 connection was already rendered from the other direction
+    assert_eq!(ctx.add_single_artist_relationships(2, &[1, 3]), 2 * 6);
+    assert_eq!(ctx.connections_buffer.len(), 2);
+
+    // Individually added relationships survive rebuilds
+    ctx.set_max_connection_length(f32::INFINITY);
+    assert_eq!(ctx.connections_buffer.len(), 2);
 }
 
-#[wasm_bindgen]
-pub fn get_artist_colors_buffer_length(ctx: *mut ArtistMapCtx) -> usize {
-    let ctx = unsafe { &mut *ctx };
-    ctx.artist_colors_buffer.len() * 4
+#[test]
+fn render_state_snapshot_includes_rendered_artists() {
+    let mut ctx = ArtistMapCtx::from_packed(
+        &build_packed_artist_positions(&[
+            (1, [0., 0., 0.], 20),
+            (2, [1000., 0., 0.], 40),
+            (3, [0., 1000., 0.], 60),
+        ]),
+        false,
+    );
+    assert!(ctx.get_render_state_snapshot().is_empty());
+    assert!(ctx.get_rendered_label_ids().is_empty());
+
+    let artist_1 = &mut ctx.all_artists[ctx.artists_indices_by_id[&1]].1;
+    artist_1
+        .render_state
+        .set(ArtistRenderState::RENDER_GEOMETRY, true);
+    let artist_2 = &mut ctx.all_artists[ctx.artists_indices_by_id[&2]].1;
+    artist_2.render_state.set(
+        ArtistRenderState::HAS_NAME | ArtistRenderState::IS_HIGHLIGHTED,
+        true,
+    );
+    artist_2.label_state = LabelState::Rendered;
+    // Labels waiting on names aren't rendered yet
+    ctx.all_artists[ctx.artists_indices_by_id[&3]].1.label_state = LabelState::FetchPending;
+
+    let mut snapshot = ctx
+        .get_render_state_snapshot()
+        .chunks_exact(3)
+        .map(|triplet| (triplet[0], triplet[1], triplet[2]))
+        .collect::<Vec<_>>();
+    snapshot.sort_unstable();
+    assert_eq!(snapshot, vec![(1, 0b0000_0100, 20), (2, 0b0001_1000, 40)]);
+    assert_eq!(ctx.get_rendered_label_ids(), vec![2]);
 }
 
-#[wasm_bindgen]
-pub fn get_memory() -> JsValue { wasm_bindgen::memory() }
+#[test]
+fn relationship_coverage_is_reported() {
+    let mut ctx = ArtistMapCtx::from_packed(
+        &build_packed_artist_positions(&[
+            (1, [0., 0., 0.], 20),
+            (2, [1000., 0., 0.], 20),
+            (3, [0., 1000., 0.], 20),
+            (4, [1000., 1000., 0.], 20),
+        ]),
+        false,
+    );
+    assert_eq!(ctx.get_relationship_coverage(4), 0.);
+    assert!(ctx.get_received_chunk_indices().is_empty());
 
-/// Returns a list of draw commands to execute
-#[wasm_bindgen]
-pub fn handle_set_highlighted_artists(
-    ctx: *mut ArtistMapCtx,
-    highlighted_artist_ids: Vec<u32>,
-    cur_x: f32,
-    cur_y: f32,
-    cur_z: f32,
-    is_fly_mode: bool,
-) -> Vec<u32> {
-    let ctx = unsafe { &mut *ctx };
-    let cur_pos = [cur_x, cur_y, cur_z];
+    // Each chunk holds a single artist with no relationships
+    ctx.handle_artist_relationship_data(&[0, 0, 0, 0], 1, 2);
+    ctx.handle_artist_relationship_data(&[0, 0, 0, 0], 1, 0);
+    ctx.handle_artist_relationship_data(&[0, 0, 0, 0], 1, 2);
 
-    let mut draw_commands = Vec::new();
+    assert_eq!(ctx.get_received_chunk_indices(), vec![0, 2]);
+    assert_eq!(ctx.get_relationship_coverage(4), 0.5);
+    assert_eq!(ctx.get_relationship_coverage(0), 0.);
+}
 
-    // First, un-mark all artists as highlighted.  If they should no longer be rendered, dispatch
-    // draw commands to remove them.
-    for (artist_id, state) in ctx.all_artists.iter_mut() {
-        let was_highlighted = state
-            .render_state
-            .contains(ArtistRenderState::IS_HIGHLIGHTED);
-        if !was_highlighted {
-            continue;
-        }
-
-        state.render_state.toggle(ArtistRenderState::IS_HIGHLIGHTED);
-        let distance_to_artist = distance(&state.position, &cur_pos);
-        let should_render = should_render_artist(
-            distance_to_artist,
-            state.popularity,
-            &state.render_state,
-            ctx.is_mobile,
-            is_fly_mode,
-            ctx.quality,
-        );
-        if should_render {
-            draw_commands.push(ADD_ARTIST_GEOMETRY_CMD);
-            draw_commands.push(*artist_id);
-        } else {
-            draw_commands.push(REMOVE_ARTIST_GEOMETRY_CMD);
-            draw_commands.push(*artist_id);
+#[test]
+fn connections_for_artists_are_deterministic() {
+    let mut ctx = ArtistMapCtx::from_packed(
+        &build_packed_artist_positions(&[
+            (10, [0., 0., 0.], 20),
+            (3, [1., 2., 3.], 20),
+            (7, [2., 4., 6.], 20),
+            (42, [3., 6., 9.], 20),
+            (5, [4., 8., 12.], 20),
+            (8, [5., 10., 15.], 20),
+        ]),
+        false,
+    );
+    let packed_relationships = build_packed_relationships(&ctx, &[
+        (10, &[3, 7, 42]),
+        (3, &[10, 5, 8]),
+        (7, &[42, 10]),
+        (42, &[8, 5, 3]),
+        (5, &[7]),
+        (8, &[10, 3, 7, 42]),
+    ]);
+    ctx.handle_artist_relationship_data(&packed_relationships, 6, 0);
+
+    for &constrain_destinations_to_set in &[true, false] {
+        let points = ctx.get_connections_for_artists(vec![3, 7, 42], constrain_destinations_to_set);
+        assert!(!points.is_empty());
+        assert_eq!(points.len() % 6, 0);
+        for reordered in &[vec![42, 3, 7], vec![7, 42, 3], vec![3, 42, 7, 3]] {
+            assert_eq!(
+                points,
+                ctx.get_connections_for_artists(reordered.clone(), constrain_destinations_to_set)
+            );
         }
     }
+}
 
-    for highlighted_artist_id in highlighted_artist_ids {
-        let artist_index = match ctx.artists_indices_by_id.get(&highlighted_artist_id) {
-            Some(&id) => id,
-            None => continue,
-        };
-        let (_, state) = &mut ctx.all_artists[artist_index];
-        state
-            .render_state
-            .set(ArtistRenderState::IS_HIGHLIGHTED, true);
-        draw_commands.push(ADD_ARTIST_GEOMETRY_CMD);
-        draw_commands.push(highlighted_artist_id);
-    }
+#[test]
+fn artist_color_overrides_survive_recoloring() {
+    let mut ctx = ArtistMapCtx::from_packed(
+        &build_packed_artist_positions(&[(1, [0., 0., 0.], 0), (2, [1000., 0., 0.], 0)]),
+        false,
+    );
+    let generated_color = ctx.artist_colors_buffer[ctx.artists_indices_by_id[&2]].1;
 
-    if !is_fly_mode {
-        info!("Highlighted artists set and is not fly mode; adding custom labels...");
-        ctx.add_highlighted_artist_orbit_labels(&mut draw_commands);
+    let mut packed = Vec::new();
+    packed.extend_from_slice(&2u32.to_le_bytes());
+    for val in [1f32, 0.84, 0.] {
+        packed.extend_from_slice(&val.to_le_bytes());
     }
-    ctx.did_set_highlighted_artists = true;
+    ctx.set_artist_colors(&packed);
+    assert_eq!(
+        ctx.artist_colors_buffer[ctx.artists_indices_by_id[&2]],
+        (2, [1., 0.84, 0.])
+    );
 
-    draw_commands
+    ctx.populate_artist_color_buffer();
+    assert_eq!(
+        ctx.artist_colors_buffer[ctx.artists_indices_by_id[&2]],
+        (2, [1., 0.84, 0.])
+    );
+
+    ctx.clear_artist_color_overrides();
+    assert_eq!(
+        ctx.artist_colors_buffer[ctx.artists_indices_by_id[&2]].1,
+        generated_color
+    );
 }
 
-/// Returns a list of draw commands to execute
-#[wasm_bindgen]
-pub fn handle_artist_manual_play(ctx: *mut ArtistMapCtx, artist_id: u32) -> Vec<u32> {
-    info!("Handling manual play for artist_id={}", artist_id);
-    let ctx = unsafe { &mut *ctx };
+#[test]
+fn set_quality_clamps_out_of_range_values() {
+    let mut ctx = ArtistMapCtx::default();
+    ctx.set_quality(255);
+    assert_eq!(ctx.quality, MAX_QUALITY);
 
-    let mut draw_commands = Vec::new();
+    ctx.set_quality(3);
+    assert_eq!(ctx.quality, 3);
+}
 
-    if let Some(playing_artist_id) = ctx.playing_music_artist_id {
-        if playing_artist_id == artist_id {
-            return draw_commands;
+#[test]
+fn label_count_increases_with_quality() {
+    let count_labels = |quality: u8| {
+        let mut count = 0;
+        for popularity in (0..=100).step_by(5) {
+            let artist_state = ArtistState {
+                position: [0., 0., 0.],
+                popularity,
+                render_state: ArtistRenderState::empty(),
+                label_state: LabelState::NotRequested,
+            };
+            for distance in (6_000..30_000).step_by(250) {
+                if should_render_label(0, &artist_state, distance as f32, false, quality, u8::MAX) {
+                    count += 1;
+                }
+            }
         }
+        count
+    };
 
-        ctx.stop_playing_music(
-            playing_artist_id,
-            &mut draw_commands,
-            std::f32::NEG_INFINITY,
-            std::f32::NEG_INFINITY,
-            std::f32::NEG_INFINITY,
-            true,
+    let (low, default, high) = (
+        count_labels(5),
+        count_labels(DEFAULT_QUALITY),
+        count_labels(9),
+    );
+    assert!(low < default, "{} < {}", low, default);
+    assert!(default < high, "{} < {}", default, high);
+}
+
+#[test]
+fn each_quality_step_above_default_relaxes_labels() {
+    for quality in DEFAULT_QUALITY..MAX_QUALITY {
+        let cur = get_label_quality_score_multiplier(quality);
+        let next = get_label_quality_score_multiplier(quality + 1);
+        assert!(
+            next <= cur * 0.95,
+            "quality={}; {} -> {}",
+            quality,
+            cur,
+            next
         );
     }
+}
 
-    ctx.start_playing_artist_id(&mut draw_commands, artist_id);
-    ctx.manual_play_artist_id = Some(artist_id);
-
-    draw_commands
+#[test]
+fn label_rendering_is_pinned_for_known_inputs() {
+    // (distance, popularity, quality, is_mobile, expected)
+    let cases: &[(f32, u8, u8, bool, bool)] = &[
+        // Close labels are always rendered
+        (5_000., 0, 1, true, true),
+        (17_000., 0, DEFAULT_QUALITY, false, false),
+        (17_000., 0, DEFAULT_QUALITY + 1, false, true),
+        (17_000., 20, DEFAULT_QUALITY, false, true),
+        (16_000., 0, DEFAULT_QUALITY, false, true),
+        (16_000., 0, DEFAULT_QUALITY - 1, false, false),
+        (15_000., 0, DEFAULT_QUALITY, true, false),
+        (30_000., 100, DEFAULT_QUALITY, false, true),
+        (30_000., 50, 10, false, false),
+        (30_000., 50, MAX_QUALITY, false, true),
+    ];
+
+    for &(distance, popularity, quality, is_mobile, expected) in cases {
+        let artist_state = ArtistState {
+            position: [0., 0., 0.],
+            popularity,
+            render_state: ArtistRenderState::empty(),
+            label_state: LabelState::NotRequested,
+        };
+        assert_eq!(
+            should_render_label(0, &artist_state, distance, is_mobile, quality, u8::MAX),
+            expected,
+            "distance={}; popularity={}; quality={}; is_mobile={}",
+            distance,
+            popularity,
+            quality,
+            is_mobile
+        );
+    }
 }
 
-/// Returns a list of draw commands to execute
-#[wasm_bindgen]
-pub fn play_last_artist(ctx: *mut ArtistMapCtx) -> Vec<u32> {
-    let ctx = unsafe { &mut *ctx };
+#[test]
+fn highlight_weights_scale_highlight_bonuses() {
+    let highlighted = ArtistRenderState::IS_HIGHLIGHTED;
+    assert!(should_render_artist(
+        200_000.,
+        0,
+        &highlighted,
+        false,
+        true,
+        DEFAULT_QUALITY,
+        255
+    ));
+    assert!(!should_render_artist(
+        200_000.,
+        0,
+        &highlighted,
+        false,
+        true,
+        DEFAULT_QUALITY,
+        0
+    ));
+
+    let mut ctx = ArtistMapCtx::from_packed(
+        &build_packed_artist_positions(&[(1, [0., 0., 0.], 0), (2, [1., 0., 0.], 0)]),
+        false,
+    );
+    ctx.set_mode(true);
+    let uniform_colors = ctx.artist_colors_buffer.clone();
 
-    let last_played_artist_id = match ctx.most_recently_played_artist_ids.pop_front() {
-        Some(id) => id,
-        None => {
-            info!("No last played artist; not playing last played artist");
-            return Vec::new();
-        },
-    };
+    ctx.handle_set_highlighted_artists(vec![1, 2], Vec::new(), 0., 0., 0.);
+    assert!(ctx.highlight_weight_by_index.is_empty());
+    assert_eq!(ctx.artist_colors_buffer, uniform_colors);
 
-    info!("Playing last played artist_id={}", last_played_artist_id);
+    ctx.handle_set_highlighted_artists(vec![1, 2], vec![255, 0], 0., 0., 0.);
+    let weighted_ix = ctx.artists_indices_by_id[&2];
+    assert_eq!(ctx.highlight_weight_by_index[&weighted_ix], 0);
+    assert!(ctx.artist_colors_buffer[weighted_ix].1[0] < uniform_colors[weighted_ix].1[0]);
 
-    handle_artist_manual_play(ctx, last_played_artist_id)
+    ctx.handle_set_highlighted_artists(vec![1, 2], Vec::new(), 0., 0., 0.);
+    assert_eq!(ctx.artist_colors_buffer, uniform_colors);
 }
 
-#[wasm_bindgen]
-pub fn get_connections_for_artists(
-    ctx: *mut ArtistMapCtx,
-    artist_ids: Vec<u32>,
-    constrain_destinations_to_set: bool,
-) -> Vec<f32> {
-    let ctx = unsafe { &mut *ctx };
-
-    let mut all_connections: HashSet<(u32, u32)> = HashSet::default();
-    let mut points: Vec<f32> = Vec::new();
-
-    fn sort_pair(pair: (u32, u32)) -> (u32, u32) {
-        if pair.0 < pair.1 {
-            pair
-        } else {
-            (pair.1, pair.0)
+#[test]
+fn orbit_label_params_control_label_spread() {
+    let count_orbit_labels = |params: OrbitLabelParams| {
+        let mut ctx = ArtistMapCtx::from_packed(
+            &build_packed_artist_positions(&[
+                (1, [0., 0., 0.], 0),
+                (2, [20_000., 0., 0.], 0),
+                (3, [40_000., 0., 0.], 0),
+                (4, [60_000., 0., 0.], 0),
+                (5, [80_000., 0., 0.], 0),
+            ]),
+            false,
+        );
+        for (_id, state) in &mut ctx.all_artists {
+            state
+                .render_state
+                .set(ArtistRenderState::IS_HIGHLIGHTED, true);
         }
-    }
-
-    for &artist_id in &artist_ids {
-        let artist_index = match ctx.artists_indices_by_id.get(&artist_id) {
-            Some(ix) => *ix,
-            None => continue,
-        };
-        let state = &ctx.all_artist_relationships[artist_index];
-        for relationship in &state.related_artist_indices[..state.count] {
-            let related_artist_id = ctx.all_artists[relationship.related_artist_index].0;
-            let related_artist_position = &ctx.all_artists[relationship.related_artist_index]
-                .1
-                .position;
-            if artist_id == related_artist_id {
-                continue;
-            }
-
-            let pair = if constrain_destinations_to_set {
-                if !artist_ids.contains(&related_artist_id) {
-                    continue;
-                }
+        ctx.set_orbit_label_params(params);
 
-                (artist_id, related_artist_id)
-            } else {
-                if artist_ids.contains(&related_artist_id) {
-                    continue;
-                }
+        let mut draw_commands = Vec::new();
+        ctx.add_highlighted_artist_orbit_labels(&mut draw_commands);
+        get_command_artist_ids(&draw_commands, FETCH_ARTIST_DATA_CMD).len()
+    };
 
-                (artist_id, related_artist_id)
-            };
-            let pair = sort_pair(pair);
-            if !all_connections.contains(&pair) {
-                all_connections.insert(pair);
+    // Every artist is further than the default min distance from its neighbors
+    assert_eq!(count_orbit_labels(OrbitLabelParams::default()), 5);
+    assert_eq!(
+        count_orbit_labels(OrbitLabelParams {
+            top_count: 2,
+            ..OrbitLabelParams::default()
+        }),
+        2
+    );
+    // Once both ends and the middle are labeled, the remaining artists are too close to them
+    assert_eq!(
+        count_orbit_labels(OrbitLabelParams {
+            min_distance: 25_000.,
+            ..OrbitLabelParams::default()
+        }),
+        3
+    );
+}
 
-                let pos_0 = &ctx.all_artists[artist_index].1.position;
-                let pos_1 = related_artist_position;
+#[test]
+fn budgeted_geometry_updates_converge() {
+    let artists: Vec<(u32, [f32; 3], u8)> = (0..60)
+        .map(|i| (i + 1, [i as f32 * 1_000., 0., 0.], (i * 37 % 100) as u8))
+        .collect();
+    let packed = build_packed_artist_positions(&artists);
+    let mut unbudgeted = ArtistMapCtx::from_packed(&packed, false);
+    let mut budgeted = ArtistMapCtx::from_packed(&packed, false);
+    unbudgeted.set_mode(true);
+    budgeted.set_mode(true);
+
+    let rendered_geometry = |ctx: &ArtistMapCtx| -> Vec<u32> {
+        ctx.all_artists
+            .iter()
+            .filter(|(_id, state)| {
+                state
+                    .render_state
+                    .contains(ArtistRenderState::RENDER_GEOMETRY)
+            })
+            .map(|(id, _state)| *id)
+            .collect()
+    };
+    let geometry_command_count = |draw_commands: &[u32]| {
+        get_command_artist_ids(draw_commands, ADD_ARTIST_GEOMETRY_CMD).len()
+            + get_command_artist_ids(draw_commands, REMOVE_ARTIST_GEOMETRY_CMD).len()
+    };
 
-                points.extend_from_slice(pos_0);
-                points.extend_from_slice(pos_1);
-            }
+    for pos in [[0., 0., 0.], [5_000_000., 0., 0.], [30_000., 0., 0.]] {
+        unbudgeted.handle_new_position(pos[0], pos[1], pos[2], pos[0], pos[1], pos[2], None, None);
+        let expected = rendered_geometry(&unbudgeted);
+
+        let mut draw_commands = budgeted.handle_new_position(
+            pos[0],
+            pos[1],
+            pos[2],
+            pos[0],
+            pos[1],
+            pos[2],
+            None,
+            Some(4),
+        );
+        let mut call_count = 1;
+        while !draw_commands.is_empty() {
+            assert!(geometry_command_count(&draw_commands) <= 4);
+            // Staying in place applies deferred updates
+            draw_commands = budgeted.handle_new_position(
+                pos[0],
+                pos[1],
+                pos[2],
+                pos[0],
+                pos[1],
+                pos[2],
+                None,
+                Some(4),
+            );
+            call_count += 1;
+            assert!(call_count < 100, "Geometry updates didn't converge");
         }
+
+        assert!(!budgeted.has_deferred_geometry_updates);
+        assert_eq!(rendered_geometry(&budgeted), expected);
     }
 
-    points
+    // Popularity and proximity are both taken into account when prioritizing
+    let mut ctx = ArtistMapCtx::from_packed(
+        &build_packed_artist_positions(&[
+            (1, [5_000., 0., 0.], 0),
+            (2, [1_000., 0., 0.], 0),
+            (3, [6_000., 0., 0.], 100),
+        ]),
+        false,
+    );
+    ctx.set_mode(true);
+    let draw_commands = ctx.handle_new_position(0., 0., 0., 0., 0., 0., None, Some(2));
+    let mut added = get_command_artist_ids(&draw_commands, ADD_ARTIST_GEOMETRY_CMD);
+    added.sort_unstable();
+    assert_eq!(added, vec![2, 3]);
+    assert!(ctx.has_deferred_geometry_updates);
+
+    let draw_commands = ctx.flush_deferred_geometry_updates(None);
+    assert_eq!(
+        get_command_artist_ids(&draw_commands, ADD_ARTIST_GEOMETRY_CMD),
+        vec![1]
+    );
+    assert!(!ctx.has_deferred_geometry_updates);
 }
 
-#[wasm_bindgen]
-pub fn transition_to_orbit_mode(ctx: *mut ArtistMapCtx) -> Vec<u32> {
-    let ctx = unsafe { &mut *ctx };
-    ctx.last_force_labeled_artist_id = None;
-
-    let mut draw_commands = Vec::new();
+#[test]
+fn label_removed_while_name_is_pending_is_not_counted() {
+    let mut ctx = ArtistMapCtx::from_packed(
+        &build_packed_artist_positions(&[(1, [0., 0., 0.], 0)]),
+        false,
+    );
+    ctx.set_mode(true);
 
-    if let Some(playing_artist_id) = ctx.playing_music_artist_id {
-        ctx.stop_playing_music(
-            playing_artist_id,
-            &mut draw_commands,
-            std::f32::NEG_INFINITY,
-            std::f32::NEG_INFINITY,
-            std::f32::NEG_INFINITY,
-            true,
-        );
-    }
-    ctx.most_recently_played_artist_ids.clear();
+    // Flying close to the artist requests its name
+    let draw_commands = ctx.handle_new_position(10., 0., 0., 10., 0., 0., None, None);
+    assert_eq!(
+        get_command_artist_ids(&draw_commands, FETCH_ARTIST_DATA_CMD),
+        vec![1]
+    );
+    assert_eq!(ctx.all_artists[0].1.label_state, LabelState::FetchPending);
+    assert_eq!(ctx.total_rendered_label_count, 0);
+
+    // Flying away before the name arrives cancels the label without touching the count
+    let draw_commands = ctx.handle_new_position(5_000_000., 0., 0., 5_000_000., 0., 0., None, None);
+    assert!(get_command_artist_ids(&draw_commands, REMOVE_LABEL_CMD).is_empty());
+    assert_eq!(ctx.all_artists[0].1.label_state, LabelState::NotRequested);
+    assert_eq!(ctx.total_rendered_label_count, 0);
+
+    // The name arriving late doesn't render the label
+    let draw_commands = ctx.handle_received_artist_names(vec![1], 5_000_000., 0., 0.);
+    assert!(get_command_artist_ids(&draw_commands, ADD_LABEL_CMD).is_empty());
+    assert_eq!(ctx.total_rendered_label_count, 0);
+
+    // Coming back renders the label directly since the name is known, and leaving removes it
+    let draw_commands = ctx.handle_new_position(10., 0., 0., 10., 0., 0., None, None);
+    assert_eq!(get_command_artist_ids(&draw_commands, ADD_LABEL_CMD), vec![
+        1
+    ]);
+    assert_eq!(ctx.total_rendered_label_count, 1);
+    let draw_commands = ctx.handle_new_position(5_000_000., 0., 0., 5_000_000., 0., 0., None, None);
+    assert_eq!(
+        get_command_artist_ids(&draw_commands, REMOVE_LABEL_CMD),
+        vec![1]
+    );
+    assert_eq!(ctx.total_rendered_label_count, 0);
+}
 
-    for (id, state) in ctx.all_artists.iter_mut() {
-        if state.render_state.contains(ArtistRenderState::RENDER_LABEL) {
-            state.render_state.remove(ArtistRenderState::RENDER_LABEL);
-            draw_commands.push(REMOVE_LABEL_CMD);
-            draw_commands.push(*id);
-        }
-    }
+#[test]
+fn label_rendered_once_name_arrives() {
+    let mut ctx = ArtistMapCtx::from_packed(
+        &build_packed_artist_positions(&[(1, [0., 0., 0.], 0)]),
+        false,
+    );
+    ctx.set_mode(true);
+
+    ctx.handle_new_position(10., 0., 0., 10., 0., 0., None, None);
+    let draw_commands = ctx.handle_received_artist_names(vec![1], 10., 0., 0.);
+    assert_eq!(get_command_artist_ids(&draw_commands, ADD_LABEL_CMD), vec![
+        1
+    ]);
+    assert_eq!(ctx.all_artists[0].1.label_state, LabelState::Rendered);
+    assert_eq!(ctx.total_rendered_label_count, 1);
+
+    // Names received more than once are ignored
+    assert!(ctx
+        .handle_received_artist_names(vec![1], 10., 0., 0.)
+        .is_empty());
+    assert_eq!(ctx.total_rendered_label_count, 1);
+}
 
-    // Render the special orbit-mode labels
-    for artist_id in ORBIT_LABEL_ARTIST_IDS {
-        let artist_index = match ctx.artists_indices_by_id.get(artist_id) {
-            Some(&id) => id,
-            None => continue,
-        };
-        let (_, state) = &mut ctx.all_artists[artist_index];
+#[test]
+fn galaxy_bounds_cover_all_artists() {
+    let ctx = ArtistMapCtx::from_packed(
+        &build_packed_artist_positions(&[
+            (1, [-100., 0., 50.], 0),
+            (2, [300., 200., 50.], 0),
+            (3, [100., -200., 250.], 0),
+        ]),
+        false,
+    );
 
-        state
-            .render_state
-            .set(ArtistRenderState::RENDER_LABEL, true);
-        if state.render_state.contains(ArtistRenderState::HAS_NAME) {
-            draw_commands.push(ADD_LABEL_CMD);
-        } else {
-            draw_commands.push(FETCH_ARTIST_DATA_CMD);
-        }
-        draw_commands.push(*artist_id);
+    let bounds = ctx.get_galaxy_bounds();
+    let expected = [-100., -200., 50., 300., 200., 250., 100., 0., 150.];
+    for (actual, expected) in bounds.iter().zip(expected.iter()) {
+        assert!((actual - expected).abs() < 0.1, "{:?}", bounds);
     }
+    let bounding_sphere_radius = (200.0f32.powi(2) * 2. + 100.0f32.powi(2)).sqrt();
+    assert!((bounds[9] - bounding_sphere_radius * SUGGESTED_ORBIT_RADIUS_MULTIPLIER).abs() < 0.1);
+}
 
-    if ctx.did_set_highlighted_artists {
-        info!("Transitioned to orbit mode and highlighted artists set; adding in extra labels...");
-        ctx.add_highlighted_artist_orbit_labels(&mut draw_commands);
-    }
+#[test]
+fn view_cone_culls_geometry_behind_camera() {
+    let mut ctx = ArtistMapCtx::from_packed(
+        &build_packed_artist_positions(&[
+            (1, [20_000., 0., 0.], 100),
+            (2, [-20_000., 0., 0.], 100),
+            (3, [0., 0., 5_000.], 100),
+        ]),
+        false,
+    );
+    ctx.set_mode(true);
+
+    // Culling is disabled with NaN inputs
+    assert!(ViewCone::new([f32::NAN, 0., 0.], 90.).is_none());
+    assert!(ViewCone::new([1., 0., 0.], f32::NAN).is_none());
+
+    // Facing +x, the artist behind the camera isn't rendered but the very close one is
+    let facing_pos_x = ViewCone::new([2., 0., 0.], 90.);
+    let draw_commands = ctx.handle_new_position(0., 0., 0., 0., 0., 0., facing_pos_x, None);
+    let mut added = get_command_artist_ids(&draw_commands, ADD_ARTIST_GEOMETRY_CMD);
+    added.sort_unstable();
+    assert_eq!(added, vec![1, 3]);
+
+    // Turning just past the enter cone but within the exit cone keeps the geometry around
+    let half_angle = (45. + VIEW_CONE_ENTER_MARGIN_DEGREES + 10.0f32).to_radians();
+    let slightly_turned = ViewCone::new([half_angle.cos(), half_angle.sin(), 0.], 90.);
+    let draw_commands = ctx.handle_new_position(0., 0., 0., 0., 0., 0., slightly_turned, None);
+    assert!(get_command_artist_ids(&draw_commands, REMOVE_ARTIST_GEOMETRY_CMD).is_empty());
+
+    // Turning around swaps which artist is rendered, even without moving
+    let facing_neg_x = ViewCone::new([-1., 0., 0.], 90.);
+    let draw_commands = ctx.handle_new_position(0., 0., 0., 0., 0., 0., facing_neg_x, None);
+    assert_eq!(
+        get_command_artist_ids(&draw_commands, REMOVE_ARTIST_GEOMETRY_CMD),
+        vec![1]
+    );
+    assert_eq!(
+        get_command_artist_ids(&draw_commands, ADD_ARTIST_GEOMETRY_CMD),
+        vec![2]
+    );
+}
 
-    draw_commands
+#[test]
+fn relationship_weights_are_decoded() {
+    let artists = [
+        (1, [0., 0., 0.], 20),
+        (2, [1000., 0., 0.], 20),
+        (3, [0., 1000., 0.], 20),
+    ];
+    let related: &[(u32, &[u32])] = &[(1, &[2, 3]), (2, &[1]), (3, &[1, 2])];
+    let get_weights = |ctx: &ArtistMapCtx, artist_id: u32| -> Vec<u8> {
+        let relationships = &ctx.all_artist_relationships[ctx.artists_indices_by_id[&artist_id]];
+        relationships.related_artist_indices[..relationships.count]
+            .iter()
+            .map(|relationship| relationship.weight)
+            .collect()
+    };
+
+    // Payloads without weights give all relationships the default weight
+    let mut ctx = ArtistMapCtx::from_packed(&build_packed_artist_positions(&artists), false);
+    let packed_relationships = build_packed_relationships(&ctx, related);
+    ctx.handle_artist_relationship_data(&packed_relationships, 3, 0);
+    assert_eq!(get_weights(&ctx, 1), vec![DEFAULT_CONNECTION_WEIGHT; 2]);
+    assert_eq!(ctx.connection_weights.len(), ctx.connections_buffer.len());
+
+    let mut ctx = ArtistMapCtx::from_packed(&build_packed_artist_positions(&artists), false);
+    let mut packed_relationships = build_packed_relationships(&ctx, related);
+    packed_relationships.extend_from_slice(&[255, 15, 200, 255, 100, 0, 0, 0]);
+    ctx.handle_artist_relationship_data(&packed_relationships, 3, 0);
+    assert_eq!(get_weights(&ctx, 1), vec![255, 15]);
+    assert_eq!(get_weights(&ctx, 2), vec![200]);
+    assert_eq!(get_weights(&ctx, 3), vec![255, 100]);
+    assert_eq!(ctx.connection_weights.len(), ctx.connections_buffer.len());
+    assert_eq!(
+        ctx.connection_colors_buffer.len(),
+        ctx.connections_buffer.len() * 6
+    );
 }
 
-#[wasm_bindgen]
-pub fn force_render_artist_label(ctx: *mut ArtistMapCtx, artist_id: u32) -> Vec<u32> {
-    let ctx = unsafe { &mut *ctx };
+#[test]
+fn connection_colors_buffer_is_freed_when_coloring_disabled() {
+    let mut ctx = ArtistMapCtx::from_packed(
+        &build_packed_artist_positions(&[(1, [0., 0., 0.], 20), (2, [1000., 0., 0.], 20)]),
+        false,
+    );
+    ctx.quality = 10;
+    let packed_relationships = build_packed_relationships(&ctx, &[(1, &[2]), (2, &[1])]);
+    ctx.handle_artist_relationship_data(&packed_relationships, 2, 0);
+    assert_eq!(ctx.connection_colors_buffer.len(), 6);
+
+    ctx.set_connection_coloring_enabled(false);
+    assert!(ctx.connection_colors_buffer.is_empty());
+    assert_eq!(ctx.connection_colors_buffer.capacity(), 0);
+    ctx.rebuild_connections_buffer();
+    assert!(ctx.connection_colors_buffer.is_empty());
+    assert_eq!(ctx.connections_buffer.len(), 1);
+
+    ctx.set_connection_coloring_enabled(true);
+    assert_eq!(ctx.connection_colors_buffer.len(), 6);
+}
 
-    let mut draw_commands = Vec::new();
+#[test]
+fn loading_progress_tracks_received_chunks() {
+    let mut ctx = ArtistMapCtx::from_packed(
+        &build_packed_artist_positions(&[
+            (1, [0., 0., 0.], 20),
+            (2, [1000., 0., 0.], 20),
+            (3, [0., 1000., 0.], 20),
+        ]),
+        false,
+    );
+    ctx.quality = 10;
+    assert_eq!(ctx.get_loading_progress(), vec![0, 0, 0, 0]);
+
+    // Chunks of 2 artists; the first holds artists 1 and 2.  Artist 3 has no relationships, so
+    // the packed data is the same as it would be for just the first chunk.
+    let chunk = build_packed_relationships(&ctx, &[(1, &[2, 99]), (2, &[1])]);
+    ctx.handle_artist_relationship_data(&chunk, 2, 0);
+    assert_eq!(ctx.get_loading_progress(), vec![1, 2, 3, 1]);
+
+    // Receiving the same chunk again doesn't count it twice
+    ctx.handle_artist_relationship_data(&chunk, 2, 0);
+    assert_eq!(ctx.get_loading_progress(), vec![1, 2, 3, 1]);
+}
 
-    if let Some(last_force_rendered_artist_id) = ctx.last_force_labeled_artist_id {
-        info!(
-            "De-rendering last force-rendered artist id={}",
-            last_force_rendered_artist_id
+#[test]
+fn position_batch_matches_individual_positions() {
+    let build_ctx = || {
+        let mut ctx = ArtistMapCtx::from_packed(
+            &build_packed_artist_positions(&[(1, [0., 0., 0.], 20), (2, [100_000., 0., 0.], 20)]),
+            false,
         );
-        let last_force_rendered_artist_index = match ctx
-            .artists_indices_by_id
-            .get(&last_force_rendered_artist_id)
-        {
-            Some(ix) => *ix,
-            None => return draw_commands,
-        };
-        let (_, state) = &mut ctx.all_artists[last_force_rendered_artist_index];
-        state
-            .render_state
-            .set(ArtistRenderState::RENDER_LABEL, false);
-        draw_commands.push(REMOVE_LABEL_CMD);
-        draw_commands.push(last_force_rendered_artist_id);
-    } else {
-        info!("No last force-rendered artist id; not de-rendering");
+        ctx.set_mode(true);
+        ctx
+    };
+    let trajectory: &[[f32; 3]] = &[[10., 0., 0.], [20., 0., 0.], [100_000., 0., 0.], [
+        100_010., 0., 0.,
+    ]];
+
+    let mut ctx = build_ctx();
+    let mut expected = Vec::new();
+    for (step_ix, pos) in trajectory.iter().enumerate() {
+        let next = trajectory.get(step_ix + 1).unwrap_or(pos);
+        let step_draw_commands = ctx.handle_new_position(
+            pos[0], pos[1], pos[2], next[0], next[1], next[2], None, None,
+        );
+        expected.push(step_draw_commands);
     }
 
-    let artist_index = match ctx.artists_indices_by_id.get(&artist_id) {
-        Some(ix) => *ix,
-        None => return draw_commands,
-    };
-    let (_, state) = &mut ctx.all_artists[artist_index];
+    let mut ctx = build_ctx();
+    let flat_positions: Vec<f32> = trajectory.iter().flatten().copied().collect();
+    let draw_commands = ctx.handle_position_batch(&flat_positions);
 
-    // If the label is already rendered, do nothing
-    if state.render_state.contains(ArtistRenderState::RENDER_LABEL) {
-        info!("Force-rendering already rendered artist label; doing nothing.");
-        ctx.last_force_labeled_artist_id = None;
-        return draw_commands;
-    } else {
-        info!(
-            "Artist label not currently rendered; setting `last_force_labeled_artist_id` to {}",
-            artist_id
-        );
-        ctx.last_force_labeled_artist_id = Some(artist_id);
+    let mut steps = Vec::new();
+    let mut offset = 0;
+    while offset < draw_commands.len() {
+        let len = draw_commands[offset] as usize;
+        steps.push(draw_commands[offset + 1..offset + 1 + len].to_vec());
+        offset += 1 + len;
     }
+    assert_eq!(steps, expected);
 
-    state
-        .render_state
-        .set(ArtistRenderState::RENDER_LABEL, true);
-    draw_commands.push(
-        if state.render_state.contains(ArtistRenderState::HAS_NAME) {
-            ADD_LABEL_CMD
-        } else {
-            FETCH_ARTIST_DATA_CMD
-        },
+    // Music starts near the first artist and switches to the second one when about to fly over to
+    // it
+    assert_eq!(
+        get_command_artist_ids(&steps[0], START_PLAYING_MUSIC_CMD),
+        vec![1]
     );
-    draw_commands.push(artist_id);
-
-    draw_commands
+    assert_eq!(
+        get_command_artist_ids(&steps[1], STOP_PLAYING_MUSIC_CMD),
+        vec![1]
+    );
+    assert_eq!(ctx.playing_music_artist_id, Some(2));
 }
 
-#[wasm_bindgen]
-pub fn set_quality(ctx: *mut ArtistMapCtx, new_quality: u8) {
-    let ctx = unsafe { &mut *ctx };
-    ctx.quality = new_quality;
+#[test]
+fn force_rendering_artist_labels() {
+    let mut ctx = ArtistMapCtx::from_packed(
+        &build_packed_artist_positions(&[(1, [0., 0., 0.], 0), (2, [1., 0., 0.], 0)]),
+        false,
+    );
 
-    if new_quality > DEFAULT_QUALITY {
-        info!("Not updating connections buffer because new quality is greater than default");
-        return;
-    }
+    let draw_commands = ctx.force_render_artist_label(1);
+    assert_eq!(
+        get_command_artist_ids(&draw_commands, FETCH_ARTIST_DATA_CMD),
+        vec![1]
+    );
+    assert_eq!(ctx.last_force_labeled_artist_id, Some(1));
+
+    // Forcing the same artist again while its name is pending doesn't re-fetch it
+    assert!(ctx.force_render_artist_label(1).is_empty());
+    assert_eq!(ctx.all_artists[0].1.label_state, LabelState::FetchPending);
+
+    let draw_commands = ctx.handle_received_artist_names(vec![1], 0., 0., 0.);
+    assert_eq!(get_command_artist_ids(&draw_commands, ADD_LABEL_CMD), vec![
+        1
+    ]);
+    assert!(ctx.force_render_artist_label(1).is_empty());
 
-    // Re-build connections geometry to take into account new quality
-    info!(
-        "Set quality to {}; building new connections data buffer...",
-        new_quality
+    // Switching to another artist removes the previous label.  Switching back doesn't re-fetch
+    // the first artist's name since it's already known.
+    let draw_commands = ctx.force_render_artist_label(2);
+    assert_eq!(
+        get_command_artist_ids(&draw_commands, REMOVE_LABEL_CMD),
+        vec![1]
     );
-    for relationships in &mut ctx.all_artist_relationships {
-        for relationship in &mut relationships.related_artist_indices {
-            relationship.connections_buffer_index = None;
-        }
-    }
-    ctx.connections_buffer.clear();
-    ctx.rendered_connections.clear();
+    let draw_commands = ctx.force_render_artist_label(1);
+    assert!(get_command_artist_ids(&draw_commands, FETCH_ARTIST_DATA_CMD).is_empty());
+    assert_eq!(get_command_artist_ids(&draw_commands, ADD_LABEL_CMD), vec![
+        1
+    ]);
+    assert_eq!(ctx.total_rendered_label_count, 1);
+
+    // Force-rendering an artist that isn't in the embedding still removes the previous label
+    let draw_commands = ctx.force_render_artist_label(999);
+    assert_eq!(
+        get_command_artist_ids(&draw_commands, REMOVE_LABEL_CMD),
+        vec![1]
+    );
+    assert_eq!(ctx.last_force_labeled_artist_id, None);
+    assert_eq!(ctx.total_rendered_label_count, 0);
+}
 
-    info!(
-        "Updating connections buffer with connections from {} chunks: {:?}",
-        ctx.received_chunks.len(),
-        ctx.received_chunks
+#[test]
+fn artists_centroid_skips_unknown_artists() {
+    let ctx = ArtistMapCtx::from_packed(
+        &build_packed_artist_positions(&[
+            (1, [0., 0., 0.], 0),
+            (2, [10., 20., -30.], 0),
+            (3, [4., 9., -16.], 0),
+        ]),
+        false,
     );
 
-    let mut chunks_to_rerender = ctx.received_chunks.iter().cloned().collect::<Vec<_>>();
-    chunks_to_rerender.sort_unstable();
+    assert!(ctx.get_artists_centroid(&[], true).is_empty());
+    assert!(ctx.get_artists_centroid(&[100, 200], true).is_empty());
+
+    assert_eq!(ctx.get_artists_centroid(&[1, 2, 100], false), vec![
+        5., 10., -15.
+    ]);
+
+    let centroid = ctx.get_artists_centroid(&[1, 2, 100], true);
+    assert_eq!(&centroid[..3], &[5., 10., -15.]);
+    assert_eq!(centroid[3].to_bits(), 3);
+}
 
-    for (chunk_ix, chunk_size) in chunks_to_rerender {
-        ctx.update_connections_buffer(chunk_size, chunk_ix);
+#[test]
+fn mobile_renders_fewer_connections() {
+    let mut artists = Vec::new();
+    for i in 0..40u32 {
+        artists.push((i + 1, [(i * 400) as f32, ((i % 5) * 900) as f32, 0.], 20));
     }
-    ctx.populate_connection_colors_buffer();
+    let related = (1..=40u32)
+        .map(|id| {
+            (
+                id,
+                (1..=40u32)
+                    .filter(|&other| other != id)
+                    .take(MAX_RELATED_ARTIST_COUNT)
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .collect::<Vec<_>>();
+    let related = related
+        .iter()
+        .map(|(id, related_ids)| (*id, related_ids.as_slice()))
+        .collect::<Vec<_>>();
+
+    let count_connections = |is_mobile: bool| -> usize {
+        let mut ctx =
+            ArtistMapCtx::from_packed(&build_packed_artist_positions(&artists), is_mobile);
+        let packed_relationships = build_packed_relationships(&ctx, &related);
+        *rng() = pcg::Pcg::from_seed(0x5EEDu64.into());
+        ctx.handle_artist_relationship_data(&packed_relationships, 40, 0);
+        ctx.connections_buffer.len()
+    };
+
+    let desktop_count = count_connections(false);
+    let mobile_count = count_connections(true);
+    assert!(desktop_count > 0);
+    assert!(
+        mobile_count < desktop_count,
+        "mobile={} desktop={}",
+        mobile_count,
+        desktop_count
+    );
 }
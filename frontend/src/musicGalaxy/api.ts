@@ -2,6 +2,7 @@ import { UnreachableException } from 'ameo-utils';
 
 import { API_BASE_URL } from 'src/conf';
 import { getSentry } from 'src/sentry';
+import type { Artist } from 'src/types';
 import { delay } from 'src/util2';
 
 async function retryRequest(req: () => Promise<Response>, retries = 18, delayMs = 300) {
@@ -47,9 +48,27 @@ export const getArtistDataByInternalIDs = (internalIDs: number[]): Promise<(stri
     return res.json();
   });
 
+/**
+ * Like `getArtistDataByInternalIDs` but returns full artist objects, including images and
+ * popularity
+ */
+export const getArtistMetadataByInternalIDs = (internalIDs: number[]): Promise<(Artist | null)[]> =>
+  retryRequest(() =>
+    fetch(`${API_BASE_URL}/artist_metadata_by_internal_ids`, {
+      method: 'POST',
+      body: JSON.stringify(internalIDs),
+    })
+  ).then(async (res) => {
+    if (!res.ok) {
+      throw await res.text();
+    }
+
+    return res.json();
+  });
+
 export const getArtistRelationshipsByInternalIDs = (internalIDs: number[]): Promise<ArrayBuffer> =>
   retryRequest(() =>
-    fetch(`${API_BASE_URL}/map_artist_relationships_by_internal_ids?rev=ily`, {
+    fetch(`${API_BASE_URL}/map_artist_relationships_by_internal_ids?rev=ily2`, {
       method: 'POST',
       body: JSON.stringify(internalIDs),
     })
@@ -71,7 +90,7 @@ export const getArtistRelationshipsChunk = async (
         `${API_BASE_URL.replace(
           'spotifytrack.net',
           'spotifytrack.b-cdn.net'
-        )}/map_artist_relationships_chunk?chunk_ix=${chunkIx}&chunk_size=${chunkSize}&rev=ily`
+        )}/map_artist_relationships_chunk?chunk_ix=${chunkIx}&chunk_size=${chunkSize}&rev=ily2`
       ).then(async (res) => {
         if (!res.ok) {
           throw await res.text();
@@ -105,8 +124,38 @@ export const fetchPackedArtistPositions = (): Promise<ArrayBuffer> =>
     return res.arrayBuffer();
   });
 
-export const getPreviewURLsByInternalID = (internalID: number): Promise<string[] | null> =>
-  retryRequest(() => fetch(`${API_BASE_URL}/get_preview_urls_by_internal_id/${internalID}`)).then(
+/**
+ * Guesses the user's Spotify market from the region of their browser's language, since preview URLs
+ * are frequently missing for tracks outside of the market they were fetched for.
+ */
+const getBrowserMarket = (): string | null => {
+  const region = navigator.language?.split('-')[1];
+  return region && /^[a-zA-Z]{2}$/.test(region) ? region.toLowerCase() : null;
+};
+
+export const getPreviewURLsByInternalID = (internalID: number): Promise<string[] | null> => {
+  const market = getBrowserMarket();
+  const url = `${API_BASE_URL}/get_preview_urls_by_internal_id/${internalID}${
+    market ? `?market=${market}` : ''
+  }`;
+  return retryRequest(() => fetch(url)).then(async (res) => {
+    if (!res.ok) {
+      throw await res.text();
+    }
+
+    return res.json();
+  });
+};
+
+export interface TopArtistDetail {
+  internal_id: number;
+  best_rank: number | null;
+  timeframes_seen: ('short' | 'medium' | 'long')[];
+  first_seen: string;
+}
+
+export const getTopArtistsDetailedForUser = (userID: string): Promise<TopArtistDetail[]> =>
+  retryRequest(() => fetch(`${API_BASE_URL}/top_artists_detailed_for_user/${userID}`)).then(
     async (res) => {
       if (!res.ok) {
         throw await res.text();
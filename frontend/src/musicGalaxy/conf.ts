@@ -16,23 +16,27 @@ export const FRAME_TIMING_BUFFER_SIZE = Math.round(60 * 2);
 export const MOVEMENT_SPEED_UNITS_PER_SECOND = 3020;
 export const SHIFT_SPEED_MULTIPLIER = 2.395;
 export const MAX_ARTIST_PLAY_CLICK_DISTANCE = 30_000;
-export const INITIAL_ORBIT_POSITION = {
-  x: -4160.963871145082,
-  y: -83571.31242528294,
-  z: 132532.40676410656,
+/**
+ * Direction from the center of the galaxy to the initial orbit camera position.  The distance is
+ * determined by the galaxy's bounds.
+ */
+export const INITIAL_ORBIT_DIRECTION = {
+  x: -0.03239,
+  y: -0.39141,
+  z: 0.91965,
 };
 export const INITIAL_CAMERA_ROTATION = {
   x: 0.7313413434972131,
   y: -0.08025528825788147,
   z: 0.07181496403499675,
 };
-export const INITIAL_ORBIT_TARGET = {
-  x: 1631.6685787933548,
-  y: -13573.465913281103,
-  z: -31932.713515335818,
-};
 
 export const PLAYING_ARTIST_LABEL_FADE_OUT_TIME_MS = 2800;
+/**
+ * Force-rendering artist labels while looking at artists is deferred by this long so that quickly
+ * moving across many artists doesn't add and remove a label for each of them.
+ */
+export const FORCE_RENDER_LABEL_DEBOUNCE_MS = 120;
 
 export const BASE_ARTIST_GEOMETRY_SIZE = 1.7;
 export const ARTIST_GEOMETRY_OPACITY = 0.2;
@@ -94,6 +98,12 @@ export const MUSIC_FADE_OUT_TIME_SECS = 3.6;
 export const MUSIC_DISTANCE_ROLLOFF_FACTOR = 0.84;
 export const SPEED_BOOST_MUSIC_DISTANCE_ROLLOFF_FACTOR = 0.6;
 export const MIN_MUSIC_PLAY_TIME_SECS = 0.8;
+/**
+ * If enabled, the engine picks how quickly music fades in and out based on how abruptly the artist
+ * went out of range.  Must be set before any draw commands are emitted since it changes their
+ * encoding.
+ */
+export const MUSIC_CROSSFADE_ENABLED = true;
 export const DEFAULT_VOLUME = 0.6;
 
 export const getArtistSize = (
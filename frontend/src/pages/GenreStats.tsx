@@ -19,6 +19,8 @@ interface GenreStats {
   top_artists: [string, number][];
   timestamps: string[];
   popularity_history: TimeFrames<number>;
+  related_genres: [string, number][]; // (genre, count of shared artists)
+  representative_images: string[];
 }
 
 // const EveryNoiseLink = ({ genre }: { genre: string }) => {
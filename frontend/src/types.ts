@@ -82,10 +82,12 @@ type TimelineEventData =
   | {
       type: 'artistFirstSeen';
       artist: Artist;
+      genres: string[];
     }
   | {
       type: 'topTrackFirstSeen';
       track: Track;
+      primary_genre: string | null;
     };
 
 export type TimelineEvent = {